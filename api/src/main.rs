@@ -1,41 +1,358 @@
+use actix_web::error::{ErrorInternalServerError, InternalError};
+use actix_web::http::header;
 use actix_web::{
-    http::StatusCode, rt, web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
+    dev, http::StatusCode, middleware, rt, web, App, Error, FromRequest, HttpRequest, HttpResponse,
+    HttpServer, Responder,
 };
 use actix_ws::{AggregatedMessage, Session};
 use actix_ws::{CloseCode, CloseReason};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use chrono::{DateTime, Utc};
 use clap::Parser;
+use futures_util::future::LocalBoxFuture;
 use futures_util::StreamExt as _;
 use gps_tracker::actions::CoordinatesData;
 use gps_tracker::config::{Config, WebConfig};
-use gps_tracker::db::Db;
-use gps_tracker::user::User;
+use gps_tracker::context::AppContext;
+use gps_tracker::device::{Device, DeviceData};
+use gps_tracker::logging;
+use gps_tracker::metrics::Metrics;
+use gps_tracker::store::BoundingBox;
+use gps_tracker::user::{User, UserData};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use surrealdb::sql::Datetime;
+use surrealdb::RecordId;
+use tracing::{debug, error, info, warn, Instrument};
 
 #[derive(Debug, Clone, Parser, Serialize, Deserialize)]
 pub struct Args {
+    /// Config file, `config.toml` by default. `APP_CONFIG_PATH` takes precedence.
     #[arg(short, long)]
-    pub config_path: String,
+    pub config_path: Option<String>,
+    /// Overrides a config key, e.g. `--set web.port=4091`.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
 }
 
-async fn users() -> impl Responder {
-    match User::new().await {
-        Ok(user) => match user.get_users().await {
-            Ok(data) => HttpResponse::Ok().json(data),
-            Err(error) => HttpResponse::build(StatusCode::BAD_REQUEST).body(error),
+/// The user making a request, authenticated with HTTP basic auth, and the
+/// devices it owns. Every route but `/metrics` takes one and only serves
+/// the caller's own devices.
+#[derive(Debug)]
+pub struct Caller {
+    pub user: UserData,
+    pub devices: Vec<DeviceData>,
+}
+
+impl Caller {
+    fn owns(&self, client_id: u32) -> bool {
+        self.devices.iter().any(|data| data.client_id == client_id)
+    }
+
+    fn owns_device(&self, id: &RecordId) -> bool {
+        self.devices.iter().any(|data| data.id.as_ref() == Some(id))
+    }
+
+    /// Username and password of a basic `Authorization` header.
+    fn credentials(req: &HttpRequest) -> Option<(String, String)> {
+        let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+        let encoded = value.strip_prefix("Basic ")?;
+        let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some((username.to_string(), password.to_string()))
+    }
+
+    fn unauthorized(error: String) -> Error {
+        let response = HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"gps-tracker\""))
+            .body(error.clone());
+        InternalError::from_response(error, response).into()
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        let credentials = Self::credentials(req);
+        let context = req.app_data::<web::Data<AppContext>>().cloned();
+        let verified = req.app_data::<web::Data<VerifiedCredentials>>().cloned();
+        Box::pin(async move {
+            let Some((username, password)) = credentials else {
+                return Err(Self::unauthorized("credentials required".to_string()));
+            };
+            let (Some(context), Some(verified)) = (context, verified) else {
+                return Err(ErrorInternalServerError("application context missing"));
+            };
+            let stored = context
+                .store
+                .user_by_username(&username)
+                .await
+                .map_err(ErrorInternalServerError)?
+                .filter(|data| verified.contains(&username, &password, &data.password));
+            let user = match stored {
+                Some(data) if data.disabled => {
+                    return Err(Self::unauthorized("user is disabled".to_string()))
+                }
+                Some(data) => data,
+                None => {
+                    let data = User::new(context.store.clone())
+                        .get_by_username_and_password(&username, &password)
+                        .await
+                        .map_err(Self::unauthorized)?;
+                    verified.insert(&username, &password, &data.password);
+                    data
+                }
+            };
+            // Without an id, `get_devices` would return every device.
+            let Some(id) = user.id.clone() else {
+                return Err(Self::unauthorized("user has no id".to_string()));
+            };
+            let devices = Device::new(context.store.clone())
+                .get_devices(Some(&id))
+                .await
+                .map_err(ErrorInternalServerError)?;
+            Ok(Self { user, devices })
+        })
+    }
+}
+
+/// Passwords that recently passed the Argon2 check, so that repeated
+/// requests and websocket connects skip it. Only keyed tags of the password
+/// and the stored hash are kept; a password reset changes the stored hash,
+/// so the next request is checked again.
+#[derive(Debug)]
+pub struct VerifiedCredentials {
+    ttl: Duration,
+    keys: RandomState,
+    /// Tag and time of the last check, by username.
+    entries: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl VerifiedCredentials {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            keys: RandomState::new(),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `password` passed the check against `stored` within the TTL.
+    fn contains(&self, username: &str, password: &str, stored: &str) -> bool {
+        let tag = self.keys.hash_one((password, stored));
+        self.lock()
+            .get(username)
+            .is_some_and(|(cached, checked)| *cached == tag && checked.elapsed() < self.ttl)
+    }
+
+    fn insert(&self, username: &str, password: &str, stored: &str) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let tag = self.keys.hash_one((password, stored));
+        self.lock()
+            .insert(username.to_string(), (tag, Instant::now()));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, (u64, Instant)>> {
+        match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+fn device_not_found(client_id: u32) -> HttpResponse {
+    HttpResponse::build(StatusCode::NOT_FOUND).body(format!("device {} not found", client_id))
+}
+
+/// The caller's own user; other users are not listed.
+async fn users(caller: Caller) -> impl Responder {
+    HttpResponse::Ok().json(vec![caller.user])
+}
+
+/// Devices owned by a user, ordered by client id. Only the caller's own
+/// username is found.
+async fn user_devices(caller: Caller, username: web::Path<String>) -> impl Responder {
+    if *username != caller.user.username {
+        return HttpResponse::build(StatusCode::NOT_FOUND)
+            .body(format!("user {} not found", username));
+    }
+    HttpResponse::Ok().json(caller.devices)
+}
+
+async fn devices(context: web::Data<AppContext>, caller: Caller) -> impl Responder {
+    match context.store.device_statuses().await {
+        Ok(data) => HttpResponse::Ok().json(
+            data.into_iter()
+                .filter(|status| caller.owns(status.client_id))
+                .collect::<Vec<_>>(),
+        ),
+        Err(error) => HttpResponse::build(StatusCode::BAD_REQUEST).body(error),
+    }
+}
+
+/// Sessions of a device, newest first.
+async fn device_sessions(
+    context: web::Data<AppContext>,
+    caller: Caller,
+    client_id: web::Path<u32>,
+) -> impl Responder {
+    let client_id = client_id.into_inner();
+    if !caller.owns(client_id) {
+        return device_not_found(client_id);
+    }
+    let device = Device::new(context.store.clone());
+    match device.get_sessions(client_id).await {
+        Ok(data) => HttpResponse::Ok().json(data),
+        Err(error) => HttpResponse::build(StatusCode::NOT_FOUND).body(error),
+    }
+}
+
+/// Time range of a track. With a session, `from` and `to` default to its
+/// login and logout and may only narrow it.
+#[derive(Debug, Deserialize)]
+pub struct TrackQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Session id, e.g. `sessions:1`.
+    pub session: Option<String>,
+}
+
+/// Positions of a device between `from` and `to`, or during a session,
+/// oldest first.
+async fn track(
+    context: web::Data<AppContext>,
+    caller: Caller,
+    client_id: web::Path<u32>,
+    query: web::Query<TrackQuery>,
+) -> impl Responder {
+    let client_id = client_id.into_inner();
+    if !caller.owns(client_id) {
+        return device_not_found(client_id);
+    }
+    let (from, to) = match &query.session {
+        Some(session) => {
+            let id = match session.parse::<RecordId>() {
+                Ok(id) => id,
+                Err(error) => {
+                    return HttpResponse::build(StatusCode::BAD_REQUEST).body(error.to_string())
+                }
+            };
+            let device = Device::new(context.store.clone());
+            let session = match device.get_session(client_id, &id).await {
+                Ok(session) => session,
+                Err(error) => return HttpResponse::build(StatusCode::NOT_FOUND).body(error),
+            };
+            let from = match query.from {
+                Some(from) => Datetime::from(from).max(session.login.clone()),
+                None => session.login.clone(),
+            };
+            let to = match query.to {
+                Some(to) => Datetime::from(to).min(session.end()),
+                None => session.end(),
+            };
+            (from, to)
+        }
+        None => match (query.from, query.to) {
+            (Some(from), Some(to)) => (Datetime::from(from), Datetime::from(to)),
+            _ => {
+                return HttpResponse::build(StatusCode::BAD_REQUEST)
+                    .body("from and to are required without a session")
+            }
         },
+    };
+    match context.store.track(client_id, from, to).await {
+        Ok(rows) => {
+            HttpResponse::Ok().json(rows.into_iter().map(Data::from).collect::<Vec<Data>>())
+        }
+        Err(error) => HttpResponse::build(StatusCode::BAD_REQUEST).body(error),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AreaQuery {
+    pub south: f64,
+    pub west: f64,
+    pub north: f64,
+    pub east: f64,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+/// Positions of the caller's devices inside a bounding box between `from`
+/// and `to`.
+async fn positions(
+    context: web::Data<AppContext>,
+    caller: Caller,
+    query: web::Query<AreaQuery>,
+) -> impl Responder {
+    let area = BoundingBox {
+        south: query.south,
+        west: query.west,
+        north: query.north,
+        east: query.east,
+    };
+    if let Err(error) = area.validate() {
+        return HttpResponse::build(StatusCode::BAD_REQUEST).body(error);
+    }
+    // `None` would mean every owner's devices.
+    let Some(owner) = caller.user.id.as_ref() else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    match context
+        .store
+        .area(
+            area,
+            Some(owner),
+            Datetime::from(query.from),
+            Datetime::from(query.to),
+        )
+        .await
+    {
+        Ok(rows) => {
+            HttpResponse::Ok().json(rows.into_iter().map(Data::from).collect::<Vec<Data>>())
+        }
         Err(error) => HttpResponse::build(StatusCode::BAD_REQUEST).body(error),
     }
 }
 
+/// Metrics of the API process: websocket subscribers and its database
+/// calls. The UDP server's are on `server.metrics_host`.
+async fn metrics() -> impl Responder {
+    match Metrics::global().render() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(prometheus::TEXT_FORMAT)
+            .body(body),
+        Err(error) => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).body(error),
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct Data {
-    pub user_id: String,
+    pub device_id: String,
     pub lat: f64,
     pub lon: f64,
     pub timestamp: Datetime,
 }
 
+impl From<CoordinatesData> for Data {
+    fn from(data: CoordinatesData) -> Self {
+        Self {
+            device_id: data.device.to_string(),
+            lat: data.latitude,
+            lon: data.longitude,
+            timestamp: data.timestamp,
+        }
+    }
+}
+
 async fn close_session_with_error(session: Session, error: String) {
     if let Err(error) = session
         .close(Some(CloseReason {
@@ -44,80 +361,153 @@ async fn close_session_with_error(session: Session, error: String) {
         }))
         .await
     {
-        eprintln!("SESSION ERROR: {:?}", error);
+        warn!(?error, "unable to close websocket session");
+    }
+}
+
+async fn close_session_on_shutdown(session: Session) {
+    if let Err(error) = session
+        .close(Some(CloseReason {
+            code: CloseCode::Away,
+            description: Some("server is shutting down".to_string()),
+        }))
+        .await
+    {
+        warn!(?error, "unable to close websocket session");
     }
 }
 
-async fn ws(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+/// Forwards the online, offline and address-changed events persisted by the
+/// UDP server for the caller's devices until the client goes away or the
+/// server shuts down.
+async fn stream_device_events(session: Session, context: &AppContext, caller: &Caller) {
+    let shutdown = &context.shutdown;
+    match context.store.watch_session_events().await {
+        Ok(mut events_stream) => {
+            let subscribers = &Metrics::global().websocket_subscribers;
+            subscribers.inc();
+            loop {
+                // Dropping `events_stream` stops the watch.
+                let event = tokio::select! {
+                    _ = shutdown.wait() => {
+                        close_session_on_shutdown(session).await;
+                        break;
+                    }
+                    result = events_stream.next() => match result {
+                        Some(result) => result,
+                        None => break,
+                    },
+                };
+                if !caller.owns(event.session.client_id) {
+                    continue;
+                }
+                match serde_json::to_string(&event) {
+                    Ok(value) => {
+                        if let Err(error) = session.clone().text(value).await {
+                            warn!(?error, "unable to send device event");
+                            break;
+                        }
+                    }
+                    Err(error) => {
+                        close_session_with_error(session, error.to_string()).await;
+                        break;
+                    }
+                }
+            }
+            subscribers.dec();
+        }
+        Err(error) => close_session_with_error(session, error).await,
+    }
+}
+
+async fn ws(
+    req: HttpRequest,
+    stream: web::Payload,
+    context: web::Data<AppContext>,
+    caller: Caller,
+) -> Result<HttpResponse, Error> {
     let (res, session, stream) = actix_ws::handle(&req, stream)?;
 
     let mut stream = stream.aggregate_continuations().max_continuation_size(1024);
 
-    rt::spawn(async move {
-        while let Some(msg) = stream.next().await {
-            match msg {
-                Ok(AggregatedMessage::Text(_)) => match Db::connect().await {
-                    Ok(db) => {
-                        match db
-                            .client
-                            .select::<Vec<CoordinatesData>>("coordinates")
-                            .live()
-                            .await
-                        {
+    let span = tracing::info_span!("websocket", peer = ?req.peer_addr());
+    rt::spawn(
+        async move {
+            let shutdown = context.shutdown.clone();
+            loop {
+                let msg = tokio::select! {
+                    _ = shutdown.wait() => {
+                        close_session_on_shutdown(session).await;
+                        break;
+                    }
+                    msg = stream.next() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                };
+                match msg {
+                    Ok(AggregatedMessage::Text(text)) if text.trim() == "events" => {
+                        stream_device_events(session, &context, &caller).await;
+                        break;
+                    }
+                    Ok(AggregatedMessage::Text(_)) => {
+                        match context.store.watch_positions().await {
                             Ok(mut coords_stream) => {
                                 let _session = session.clone();
+                                let subscribers = &Metrics::global().websocket_subscribers;
+                                subscribers.inc();
 
-                                while let Some(result) = coords_stream.next().await {
-                                    if let Ok(item) = result {
-                                        let data = item.data;
-                                        match serde_json::to_string(&Data {
-                                            user_id: data.user.to_string(),
-                                            lat: data.latitude,
-                                            lon: data.longitude,
-                                            timestamp: data.timestamp,
-                                        }) {
-                                            Ok(value) => {
-                                                if let Err(error) =
-                                                    _session.clone().text(value).await
-                                                {
-                                                    eprintln!("SENDING ERROR: {:?}", error);
-                                                    break;
-                                                }
-                                            }
-                                            Err(error) => {
-                                                close_session_with_error(
-                                                    _session,
-                                                    error.to_string(),
-                                                )
-                                                .await;
+                                loop {
+                                    // Dropping `coords_stream` stops the watch.
+                                    let data = tokio::select! {
+                                        _ = shutdown.wait() => {
+                                            close_session_on_shutdown(_session).await;
+                                            break;
+                                        }
+                                        result = coords_stream.next() => match result {
+                                            Some(result) => result,
+                                            None => break,
+                                        },
+                                    };
+                                    if !caller.owns_device(&data.device) {
+                                        continue;
+                                    }
+                                    match serde_json::to_string(&Data::from(data)) {
+                                        Ok(value) => {
+                                            if let Err(error) = _session.clone().text(value).await {
+                                                warn!(?error, "unable to send coordinates");
                                                 break;
                                             }
                                         }
+                                        Err(error) => {
+                                            close_session_with_error(_session, error.to_string())
+                                                .await;
+                                            break;
+                                        }
                                     }
                                 }
+                                subscribers.dec();
+                                break;
                             }
                             Err(error) => {
-                                close_session_with_error(session, error.to_string()).await;
+                                close_session_with_error(session, error).await;
                                 break;
                             }
                         }
                     }
-                    Err(error) => {
-                        close_session_with_error(session, error).await;
+                    Ok(AggregatedMessage::Close(msg)) => {
+                        debug!(reason = ?msg, "websocket closed by client");
+                        if let Err(error) = session.close(msg).await {
+                            warn!(?error, "unable to close websocket session")
+                        }
                         break;
                     }
-                },
-                Ok(AggregatedMessage::Close(msg)) => {
-                    println!("CLOSED WS:{:?}", msg);
-                    if let Err(error) = session.close(msg).await {
-                        eprintln!("SESSION CLOSED ERROR: {:?}", error)
-                    }
-                    break;
+                    _ => {}
                 }
-                _ => {}
             }
         }
-    });
+        .instrument(span),
+    );
 
     Ok(res)
 }
@@ -125,26 +515,53 @@ async fn ws(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Erro
 fn app_config(config: &mut web::ServiceConfig) {
     config
         .service(web::resource("/users").get(users))
+        .service(web::resource("/users/{username}/devices").get(user_devices))
+        .service(web::resource("/devices").get(devices))
+        .service(web::resource("/devices/{client_id}/sessions").get(device_sessions))
+        .service(web::resource("/devices/{client_id}/track").get(track))
+        .service(web::resource("/positions").get(positions))
+        .service(web::resource("/metrics").get(metrics))
         .service(web::resource("/ws").get(ws));
 }
 
 #[actix_web::main]
 async fn main() -> Result<(), String> {
     let args = Args::parse();
-    let config_path = args.config_path;
-    match Config::load(Some(config_path)).await {
-        Ok(config) => {
-            let web_config: WebConfig = config.web;
-            println!(
-                "Web Server Address: {}:{}",
-                &web_config.host, &web_config.port
-            );
-            match HttpServer::new(move || App::new().configure(app_config))
-                .workers(4)
-                .bind((web_config.host, web_config.port as u16))
+    let config = Config::load_with(args.config_path, &args.overrides).await?;
+    logging::init(&config.log)?;
+    match AppContext::init(config).await {
+        Ok(context) => {
+            let web_config: WebConfig = context.config.web.clone();
+            let context = web::Data::new(context);
+            let verified = web::Data::new(VerifiedCredentials::new(Duration::from_secs(
+                web_config.credential_cache_ttl_secs,
+            )));
+            info!(host = %web_config.host, port = web_config.port, "web server listening");
+            let shutdown = context.shutdown.clone();
+            match HttpServer::new(move || {
+                App::new()
+                    .wrap(middleware::Logger::default())
+                    .app_data(context.clone())
+                    .app_data(verified.clone())
+                    .configure(app_config)
+            })
+            .workers(4)
+            .disable_signals()
+            .shutdown_timeout(web_config.shutdown_grace_period_secs)
+            .bind((web_config.host, web_config.port as u16))
             {
                 Ok(server) => {
-                    if let Err(error) = server.run().await {
+                    let server = server.run();
+                    let handle = server.handle();
+                    rt::spawn(async move {
+                        if let Err(error) = shutdown.listen_for_signals().await {
+                            error!(%error, "unable to listen for shutdown signals");
+                        }
+                        // Websocket sessions send their close frames as soon as
+                        // the shutdown is triggered; then wait for connections.
+                        handle.stop(true).await;
+                    });
+                    if let Err(error) = server.await {
                         return Err(error.to_string());
                     }
                     Ok(())
@@ -161,15 +578,364 @@ mod test_server {
 
     use super::*;
     use actix_web::test;
-    use std::env;
+    use gps_tracker::device::{DeviceData, DEFAULT_KIND, DEFAULT_PROTOCOL};
+    use gps_tracker::store::{MemoryStore, SharedStore};
+    use gps_tracker::user::UserData;
+    use std::sync::Arc;
+
+    async fn add_user(store: &SharedStore, name: &str, username: &str, password: &str) -> UserData {
+        User::new(store.clone())
+            .create(name, username, password)
+            .await
+            .unwrap()
+    }
+
+    async fn add_device(
+        store: &SharedStore,
+        owner: &UserData,
+        serial: &str,
+        key: &str,
+        client_id: u32,
+    ) -> DeviceData {
+        let data = DeviceData {
+            id: None,
+            serial: serial.to_string(),
+            name: owner.name.clone(),
+            kind: DEFAULT_KIND.to_string(),
+            owner: owner.id.clone().unwrap(),
+            protocol: DEFAULT_PROTOCOL.to_string(),
+            credentials: String::new(),
+            client_id,
+            disabled: false,
+        };
+        Device::new(store.clone()).create(data, key).await.unwrap()
+    }
+
+    fn verified() -> web::Data<VerifiedCredentials> {
+        web::Data::new(VerifiedCredentials::new(Duration::from_secs(60)))
+    }
+
+    /// A GET request made as `username`.
+    fn get_as(uri: &str, username: &str, password: &str) -> test::TestRequest {
+        let credentials = STANDARD.encode(format!("{}:{}", username, password));
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+    }
+
+    /// A GET request made as the user `root`.
+    fn get(uri: &str) -> test::TestRequest {
+        get_as(uri, "root", "notsecurepassword")
+    }
+
     #[actix_web::test]
     async fn test_users() {
-        env::set_var("APP_CONFIG_PATH", "../config.toml");
-        let app = test::init_service(App::new().configure(app_config)).await;
-        let req = test::TestRequest::get().uri("/users").to_request();
+        let store: SharedStore = Arc::new(MemoryStore::new());
+        let root = add_user(&store, "Root", "root", "notsecurepassword").await;
+        add_device(&store, &root, "356938035643809", "notsecurepassword", 24564).await;
+        add_user(&store, "Other", "other", "password").await;
+        let context = AppContext::with_store(Config::default(), store);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .app_data(verified())
+                .configure(app_config),
+        )
+        .await;
+        let req = get("/users").to_request();
+
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("root"));
+
+        let req = get("/users/root/devices").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["serial"], "356938035643809");
+        assert_eq!(body[0]["client_id"], 24564);
+        assert!(body[0].get("credentials").is_none());
+
+        let req = get("/users/other/devices").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = get("/users/nobody/devices").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_track() {
+        let store: SharedStore = Arc::new(MemoryStore::new());
+        let root = add_user(&store, "Root", "root", "notsecurepassword").await;
+        let device = add_device(&store, &root, "root", "notsecurepassword", 24564).await;
+        let timestamp = "2025-03-01T08:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        store
+            .insert_positions(&[CoordinatesData {
+                id: None,
+                device: device.id.clone().unwrap(),
+                latitude: 14.65,
+                longitude: 121.04,
+                timestamp: Datetime::from(timestamp),
+            }])
+            .await
+            .unwrap();
+        let context = AppContext::with_store(Config::default(), store);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .app_data(verified())
+                .configure(app_config),
+        )
+        .await;
+
+        let req = get("/devices/24564/track?from=2025-03-01T00:00:00Z&to=2025-03-02T00:00:00Z")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["lat"], 14.65);
+        assert_eq!(body[0]["device_id"], device.id.unwrap().to_string());
+
+        let req = get("/devices/24564/track?from=2025-03-02T00:00:00Z&to=2025-03-03T00:00:00Z")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(body.as_array().unwrap().is_empty());
+
+        let req = get("/devices/24564/track?from=2025-03-01T00:00:00Z").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = get("/positions?south=14&west=121&north=15&east=122&from=2025-03-01T00:00:00Z&to=2025-03-02T00:00:00Z")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
 
+        let req = get("/positions?south=15&west=121&north=14&east=122&from=2025-03-01T00:00:00Z&to=2025-03-02T00:00:00Z")
+            .to_request();
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
 
+        // Areas crossing the antimeridian are refused.
+        let req = get("/positions?south=14&west=179&north=15&east=-179&from=2025-03-01T00:00:00Z&to=2025-03-02T00:00:00Z")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_sessions() {
+        use gps_tracker::session::SessionData;
+        let store: SharedStore = Arc::new(MemoryStore::new());
+        let root = add_user(&store, "Root", "root", "notsecurepassword").await;
+        let device = add_device(&store, &root, "root", "notsecurepassword", 24564).await;
+        let other = add_device(&store, &root, "other", "otherkey", 24565).await;
+        let device_id = device.id.clone().unwrap();
+        let at = |time: &str| Datetime::from(time.parse::<DateTime<Utc>>().unwrap());
+        let positions: Vec<CoordinatesData> = ["2025-03-01T08:00:00Z", "2025-03-01T10:30:00Z"]
+            .iter()
+            .map(|time| CoordinatesData {
+                id: None,
+                device: device_id.clone(),
+                latitude: 14.65,
+                longitude: 121.04,
+                timestamp: at(time),
+            })
+            .collect();
+        store.insert_positions(&positions).await.unwrap();
+        let session = |device: &RecordId, login: &str, logout: &str| SessionData {
+            id: None,
+            device: device.clone(),
+            source_address: "127.0.0.1:5000".to_string(),
+            login: at(login),
+            logout: Some(at(logout)),
+            positions: 0,
+            heartbeats: 0,
+        };
+        let morning = store
+            .create_session(&session(
+                &device_id,
+                "2025-03-01T07:00:00Z",
+                "2025-03-01T09:00:00Z",
+            ))
+            .await
+            .unwrap();
+        store
+            .create_session(&session(
+                &device_id,
+                "2025-03-01T10:00:00Z",
+                "2025-03-01T11:00:00Z",
+            ))
+            .await
+            .unwrap();
+        let elsewhere = store
+            .create_session(&session(
+                other.id.as_ref().unwrap(),
+                "2025-03-01T07:00:00Z",
+                "2025-03-01T09:00:00Z",
+            ))
+            .await
+            .unwrap();
+        let context = AppContext::with_store(Config::default(), store);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .app_data(verified())
+                .configure(app_config),
+        )
+        .await;
+
+        let req = get("/devices/24564/sessions").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[1]["source_address"], "127.0.0.1:5000");
+
+        let req = get(&format!(
+            "/devices/24564/track?session={}",
+            morning.id.unwrap()
+        ))
+        .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(
+            body[0]["timestamp"],
+            serde_json::json!(at("2025-03-01T08:00:00Z"))
+        );
+
+        // A session of another device is not found.
+        let req = get(&format!(
+            "/devices/24564/track?session={}",
+            elsewhere.id.unwrap()
+        ))
+        .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = get("/devices/1/sessions").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_owner() {
+        let store: SharedStore = Arc::new(MemoryStore::new());
+        let alice = add_user(&store, "Alice", "alice", "alicepassword").await;
+        add_device(&store, &alice, "alice", "alicekey", 1).await;
+        let bob = add_user(&store, "Bob", "bob", "bobpassword").await;
+        let device = add_device(&store, &bob, "bob", "bobkey", 2).await;
+        let timestamp = "2025-03-01T08:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        store
+            .insert_positions(&[CoordinatesData {
+                id: None,
+                device: device.id.clone().unwrap(),
+                latitude: 14.65,
+                longitude: 121.04,
+                timestamp: Datetime::from(timestamp),
+            }])
+            .await
+            .unwrap();
+        let context = AppContext::with_store(Config::default(), store);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .app_data(verified())
+                .configure(app_config),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/devices").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().contains_key(header::WWW_AUTHENTICATE));
+        let req = get_as("/devices", "alice", "bobpassword").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // Alice cannot read the device of Bob.
+        let range = "from=2025-03-01T00:00:00Z&to=2025-03-02T00:00:00Z";
+        let track = format!("/devices/2/track?{}", range);
+        for uri in [&track, "/devices/2/sessions", "/users/bob/devices"] {
+            let req = get_as(uri, "alice", "alicepassword").to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+        let area = format!("/positions?south=14&west=121&north=15&east=122&{}", range);
+        let req = get_as(&area, "alice", "alicepassword").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(body.as_array().unwrap().is_empty());
+        let req = get_as("/users/alice/devices", "alice", "alicepassword").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["client_id"], 1);
+
+        // Bob can.
+        let req = get_as(&track, "bob", "bobpassword").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        let req = get_as(&area, "bob", "bobpassword").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_verified_credentials() {
+        let store: SharedStore = Arc::new(MemoryStore::new());
+        add_user(&store, "Alice", "alice", "alicepassword").await;
+        let context = AppContext::with_store(Config::default(), store.clone());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(context))
+                .app_data(verified())
+                .configure(app_config),
+        )
+        .await;
+        let status = |username: &str, password: &str| {
+            let req = get_as("/users", username, password).to_request();
+            test::call_service(&app, req)
+        };
+
+        assert_eq!(
+            status("alice", "alicepassword").await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status("alice", "alicepassword").await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status("alice", "wrongpassword").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // A reset or a disabled user applies to the next request.
+        let users = User::new(store);
+        users.set_password("alice", "newpassword").await.unwrap();
+        assert_eq!(
+            status("alice", "alicepassword").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status("alice", "newpassword").await.status(),
+            StatusCode::OK
+        );
+        users.set_disabled("alice", true).await.unwrap();
+        assert_eq!(
+            status("alice", "newpassword").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_metrics() {
+        let app = test::init_service(App::new().route("/metrics", web::get().to(metrics))).await;
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+
+        let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = test::read_body(resp).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("gps_websocket_subscribers"), "{}", body);
     }
 }
@@ -0,0 +1,119 @@
+//! Coordinates packets per second handled before and after the device cache
+//! and the write pipeline, against an in-memory store that answers every
+//! call after a fixed latency.
+//!
+//! ```sh
+//! cargo bench --bench pipeline
+//! ```
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use gps_tracker::actions::Coordinates;
+use gps_tracker::config::Config;
+use gps_tracker::context::AppContext;
+use gps_tracker::device::{DeviceData, DEFAULT_KIND, DEFAULT_PROTOCOL};
+use gps_tracker::handler::{HandlerContext, HandlerRegistry};
+use gps_tracker::payload::Payload;
+use gps_tracker::pipeline::{StoreBatchWriter, WritePipeline};
+use gps_tracker::request::RequestPacket;
+use gps_tracker::store::{MemoryStore, Store};
+use gps_tracker::user::UserData;
+use std::sync::Arc;
+use std::time::Duration;
+
+const PACKETS: u32 = 200;
+const CLIENT_ID: u32 = 24564;
+const ROUND_TRIP: Duration = Duration::from_millis(1);
+
+/// A context on a store holding one device, caching devices for
+/// `device_cache_ttl_secs`.
+async fn memory_context(device_cache_ttl_secs: u64) -> AppContext {
+    let store = MemoryStore::new().with_latency(ROUND_TRIP);
+    let owner = store
+        .create_user(&UserData {
+            id: None,
+            name: "Root".to_string(),
+            username: "root".to_string(),
+            password: String::new(),
+            disabled: false,
+        })
+        .await
+        .unwrap();
+    store
+        .create_device(&DeviceData {
+            id: None,
+            serial: "root".to_string(),
+            name: "Root".to_string(),
+            kind: DEFAULT_KIND.to_string(),
+            owner: owner.id.unwrap(),
+            protocol: DEFAULT_PROTOCOL.to_string(),
+            credentials: String::new(),
+            client_id: CLIENT_ID,
+            disabled: false,
+        })
+        .await
+        .unwrap();
+    let mut config = Config::default();
+    config.server.device_cache_ttl_secs = device_cache_ttl_secs;
+    AppContext::with_store(config, Arc::new(store))
+}
+
+async fn packets() -> Vec<RequestPacket> {
+    let mut packets = Vec::new();
+    for index in 0..PACKETS {
+        let payload =
+            Coordinates::generate_payload(CLIENT_ID, 10.00001 + index as f64 * 0.0001, -127.000001)
+                .await
+                .unwrap();
+        let data = Payload::to_binary(&payload).unwrap();
+        packets.push(RequestPacket::parse(&data).unwrap());
+    }
+    packets
+}
+
+async fn handle_all(registry: &HandlerRegistry, context: &AppContext, packets: &[RequestPacket]) {
+    let ctx = HandlerContext {
+        context,
+        source_address: "127.0.0.1:5000".parse().unwrap(),
+    };
+    for packet in packets {
+        registry.dispatch(&ctx, packet).await.unwrap().unwrap();
+    }
+}
+
+fn coordinates_ingest(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let registry = HandlerRegistry::with_defaults();
+    let packets = runtime.block_on(packets());
+    let mut group = c.benchmark_group("coordinates_ingest");
+    group.throughput(Throughput::Elements(PACKETS as u64));
+    group.sample_size(10);
+
+    // A device lookup and an insert round trip per packet.
+    let context = runtime.block_on(memory_context(0));
+    group.bench_function("before/lookup_and_insert_per_packet", |b| {
+        b.iter(|| runtime.block_on(handle_all(&registry, &context, &packets)))
+    });
+
+    // Devices resolved from the cache, positions written in batches.
+    let context = runtime.block_on(memory_context(60));
+    group.bench_function("after/device_cache_and_pipeline", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let (pipeline, task) = WritePipeline::spawn(
+                    &context.config.server.pipeline,
+                    StoreBatchWriter::new(context.store.clone()),
+                    None,
+                );
+                let mut context = context.clone();
+                context.pipeline = Some(pipeline);
+                handle_all(&registry, &context, &packets).await;
+                // Dropping the last sender flushes the queued batches.
+                drop(context);
+                task.await.unwrap();
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, coordinates_ingest);
+criterion_main!(benches);
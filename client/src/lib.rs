@@ -29,7 +29,7 @@ mod tests {
                 println!("Length: {}", data.len());
             }
             Err(error) => {
-                assert!(false, "{:?}", error.to_string());
+                panic!("{:?}", error.to_string());
             }
         }
     }
@@ -58,6 +58,8 @@ impl UdpClient {
     ) -> Result<(), String> {
         if status == &ResponseType::Error.to_value() {
             Err(format!("ERROR: {}", error_message))
+        } else if status == &ResponseType::SlowDown.to_value() {
+            Err("SLOW DOWN: the server is rate limiting this client".to_string())
         } else {
             Ok(())
         }
@@ -110,7 +112,7 @@ impl UdpClient {
                                 .await?;
                         let payload_data = Payload::to_binary(&payload_generator)?;
                         if let Err(error) = socket.send(&payload_data).await {
-                            return Err(format!("LOGIN REQUEST ERROR: {}", error.to_string()));
+                            return Err(format!("LOGIN REQUEST ERROR: {}", error));
                         }
                     }
                     RequestType::HeartBeat => {
@@ -119,7 +121,7 @@ impl UdpClient {
                         let payload_generator = Heartbeat::generate_payload(client_id).await?;
                         let payload_data = Payload::to_binary(&payload_generator)?;
                         if let Err(error) = socket.send(&payload_data).await {
-                            return Err(format!("HEARTBEAT REQUEST ERROR: {}", error.to_string()));
+                            return Err(format!("HEARTBEAT REQUEST ERROR: {}", error));
                         }
                     }
                     RequestType::Logout => {
@@ -128,7 +130,7 @@ impl UdpClient {
                         let payload_generator = Logout::generate_payload(client_id).await?;
                         let payload_data = Payload::to_binary(&payload_generator)?;
                         if let Err(error) = socket.send(&payload_data).await {
-                            return Err(format!("LOGOUT REQUEST ERROR: {}", error.to_string()));
+                            return Err(format!("LOGOUT REQUEST ERROR: {}", error));
                         }
                     }
                     RequestType::Coordinates => {
@@ -152,10 +154,7 @@ impl UdpClient {
                             Coordinates::generate_payload(client_id, lat, lon).await?;
                         let payload_data = Payload::to_binary(&payload_generator)?;
                         if let Err(error) = socket.send(&payload_data).await {
-                            return Err(format!(
-                                "COORDINATES REQUEST ERROR: {}",
-                                error.to_string()
-                            ));
+                            return Err(format!("COORDINATES REQUEST ERROR: {}", error));
                         }
                     }
                     _ => {
@@ -164,15 +163,23 @@ impl UdpClient {
                 }
 
                 // Response Data Receiving
-                let mut buf = [0; 64];
+                // One spare byte tells an oversized response apart from one that
+                // exactly fits, since recv silently truncates.
+                let max_datagram_size = self.server_config.server.max_datagram_size;
+                let mut buf = vec![0; max_datagram_size + 1];
                 let (size, _) = socket.recv_from(&mut buf).await.unwrap();
+                if size > max_datagram_size {
+                    return Err(format!(
+                        "response exceeds the maximum datagram size of {} bytes",
+                        max_datagram_size
+                    ));
+                }
                 if size > 0 {
                     let filled = &mut buf[..size];
-                    match filled.get(0) {
+                    match filled.first() {
                         Some(_) => {
                             if let Some(status) = filled.get(1) {
-                                let _ = self
-                                    .check_response_status(status, "login failed".to_string())
+                                self.check_response_status(status, "login failed".to_string())
                                     .await?;
                             }
                             let client_id_filled: &[u8] = if let Some(id) = filled.get(2..) {
@@ -182,14 +189,12 @@ impl UdpClient {
                             };
                             let client_id = String::from_utf8_lossy(client_id_filled).to_string();
                             println!("Response received for {}", client_id);
-                            return Ok(client_id);
-                        }
-                        None => {
-                            return Err("nothing to do".to_string());
+                            Ok(client_id)
                         }
+                        None => Err("nothing to do".to_string()),
                     }
                 } else {
-                    return Err("nothing is received".to_string());
+                    Err("nothing is received".to_string())
                 }
             }
             Err(error) => Err(error.to_string()),
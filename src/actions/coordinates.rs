@@ -1,18 +1,36 @@
-use crate::db::Db;
+use crate::device::DeviceCache;
+use crate::handler::{HandlerContext, HandlerResponse, RequestHandler};
+use crate::metrics::Metrics;
 use crate::payload::Payload;
-use crate::request::RequestType;
+use crate::pipeline::WriteRecord;
+use crate::request::{read_client_id, RequestPacket, RequestType};
 use crate::response::ResponseType;
-use crate::user::{User, UserData};
-use crate::validation::ValidationError;
+use crate::spool::{Spool, SpoolEntry, SpoolSink};
+use crate::store::SharedStore;
+use crate::validation::{RequestError, ValidationError};
+use async_trait::async_trait;
 use chrono::Utc;
 use ieee_754::IEEE754;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use surrealdb::sql::Datetime;
 use surrealdb::RecordId;
+use tracing::{debug, warn};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CoordinatesData {
-    pub user: RecordId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RecordId>,
+    pub device: RecordId,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub timestamp: Datetime,
+}
+
+/// A decoded position fix whose device has not been looked up yet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CoordinatesFix {
+    pub client_id: u32,
     pub latitude: f64,
     pub longitude: f64,
     pub timestamp: Datetime,
@@ -31,14 +49,22 @@ pub struct CoordinatesData {
 
 #[derive(Debug)]
 pub struct Coordinates {
-    db: Db,
+    store: SharedStore,
+    devices: DeviceCache,
 }
 
 impl Coordinates {
-    /// Initializes Heartbeat instance including database connections.
-    pub async fn new() -> Result<Self, String> {
-        let db = Db::connect().await?;
-        Ok(Self { db })
+    /// Initializes Coordinates instance on top of the store, looking every
+    /// device up.
+    pub fn new(store: SharedStore) -> Self {
+        let devices = DeviceCache::new(store.clone(), Duration::ZERO);
+        Self { store, devices }
+    }
+
+    /// Resolves devices through `devices` instead.
+    pub fn with_devices(mut self, devices: DeviceCache) -> Self {
+        self.devices = devices;
+        self
     }
 
     /// Generate Payload
@@ -84,121 +110,244 @@ impl Coordinates {
         Ok(with_spacing)
     }
 
-    pub async fn parse(payload_length: usize, data: &[u8]) -> Result<CoordinatesData, String> {
+    pub async fn parse(
+        devices: &DeviceCache,
+        payload_length: usize,
+        data: &[u8],
+    ) -> Result<CoordinatesData, RequestError> {
+        let fix: CoordinatesFix = Self::parse_fix(payload_length, data)?;
+        Self::resolve(devices, fix).await
+    }
+
+    /// Decodes the client id and position of a packet without touching the
+    /// store, so the fix can be spooled while the store is unavailable.
+    pub fn parse_fix(
+        payload_length: usize,
+        data: &[u8],
+    ) -> Result<CoordinatesFix, ValidationError> {
         if data.len() < payload_length {
-            return Err(ValidationError::InvalidCoordinatesPayload.to_string());
+            return Err(ValidationError::InvalidCoordinatesPayload);
         }
-        match data.get(0..4) {
-            Some(client_id_binary) => {
-                let client_id_hex: Vec<String> = client_id_binary
-                    .iter()
-                    .map(|x| format!("{:x}", x))
-                    .collect();
-                match u32::from_str_radix(&client_id_hex.concat(), 16) {
-                    Ok(client_id) => {
-                        let user: User = User::new().await?;
-                        println!("Client Id:{:?}", client_id);
-                        let user_data: UserData = user.get_by_client_id(client_id).await?;
-                        let user_id = if let Some(user_id) = user_data.id {
-                            user_id
-                        } else {
-                            return Err("invalid user id".to_string());
-                        };
-
-                        let mut coordinates_data: CoordinatesData = CoordinatesData {
-                            longitude: 0.0,
-                            latitude: 0.0,
-                            user: user_id,
-                            timestamp: Datetime::from(Utc::now()),
-                        };
-
-                        match data.get(4..12) {
-                            Some(value) => {
-                                let latitude: Result<f64, _> = IEEE754::to_64bit_float(
-                                    value
-                                        .iter()
-                                        .map(|x| x.to_owned() as u32)
-                                        .collect::<Vec<u32>>(),
-                                );
-                                match latitude {
-                                    Ok(v) => {
-                                        coordinates_data.latitude = v;
-                                        println!("Latitude: {}", coordinates_data.latitude);
-                                    }
-                                    Err(_) => {
-                                        return Err(
-                                            ValidationError::UnableToParseLatitude.to_string()
-                                        );
-                                    }
-                                }
-                            }
-                            None => {
-                                return Err(ValidationError::InvalidLatitude.to_string());
-                            }
-                        }
-
-                        match data.get(12..20) {
-                            Some(value) => {
-                                let longitude: Result<f64, _> = IEEE754::to_64bit_float(
-                                    value
-                                        .iter()
-                                        .map(|x| x.to_owned() as u32)
-                                        .collect::<Vec<u32>>(),
-                                );
-                                match longitude {
-                                    Ok(v) => {
-                                        coordinates_data.longitude = v;
-                                        println!("Longitude: {}", coordinates_data.longitude);
-                                    }
-                                    Err(_) => {
-                                        return Err(
-                                            ValidationError::UnableToParseLongitude.to_string()
-                                        );
-                                    }
-                                }
-                            }
-                            None => {
-                                return Err(ValidationError::InvalidLongitude.to_string());
-                            }
-                        }
-                        Ok(coordinates_data.to_owned())
+        let client_id = match read_client_id(data) {
+            Some(client_id) => client_id,
+            None => return Err(ValidationError::ClientIdEmpty),
+        };
+        debug!(client_id, "coordinates client id");
+        let mut fix: CoordinatesFix = CoordinatesFix {
+            client_id,
+            longitude: 0.0,
+            latitude: 0.0,
+            timestamp: Datetime::from(Utc::now()),
+        };
+
+        match data.get(4..12) {
+            Some(value) => {
+                let latitude: Result<f64, _> = IEEE754::to_64bit_float(
+                    value
+                        .iter()
+                        .map(|x| x.to_owned() as u32)
+                        .collect::<Vec<u32>>(),
+                );
+                match latitude {
+                    Ok(v) => {
+                        fix.latitude = v;
+                        debug!(latitude = fix.latitude, "latitude parsed");
+                    }
+                    Err(_) => {
+                        return Err(ValidationError::UnableToParseLatitude);
+                    }
+                }
+            }
+            None => {
+                return Err(ValidationError::InvalidLatitude);
+            }
+        }
+
+        match data.get(12..20) {
+            Some(value) => {
+                let longitude: Result<f64, _> = IEEE754::to_64bit_float(
+                    value
+                        .iter()
+                        .map(|x| x.to_owned() as u32)
+                        .collect::<Vec<u32>>(),
+                );
+                match longitude {
+                    Ok(v) => {
+                        fix.longitude = v;
+                        debug!(longitude = fix.longitude, "longitude parsed");
+                    }
+                    Err(_) => {
+                        return Err(ValidationError::UnableToParseLongitude);
                     }
-                    Err(_) => Err(ValidationError::InvalidClientId.to_string()),
                 }
             }
-            None => Err(ValidationError::ClientIdEmpty.to_string()),
+            None => {
+                return Err(ValidationError::InvalidLongitude);
+            }
         }
+        Ok(fix)
     }
 
-    /// Returns the table name.
-    fn get_table(&self) -> String {
-        String::from("coordinates")
+    /// Finds the device with the client id of `fix`.
+    pub async fn resolve(
+        devices: &DeviceCache,
+        fix: CoordinatesFix,
+    ) -> Result<CoordinatesData, RequestError> {
+        let device_id = devices.resolve(fix.client_id).await?;
+        Ok(CoordinatesData {
+            id: None,
+            device: device_id,
+            latitude: fix.latitude,
+            longitude: fix.longitude,
+            timestamp: fix.timestamp,
+        })
     }
 
     /// Create a coordinates record
-    pub async fn create(&self, data: CoordinatesData) -> Result<CoordinatesData, String> {
-        match self
-            .db
-            .client
-            .insert::<Vec<CoordinatesData>>(self.get_table())
-            .content(data.clone())
-            .await
-        {
-            Ok(response) => {
-                if let Some(record) = response.get(0) {
-                    return Ok(record.to_owned());
+    pub async fn create(&self, data: CoordinatesData) -> Result<(), String> {
+        let _timer = Metrics::global().start_db_timer("coordinates.create");
+        self.store.insert_positions(&[data]).await
+    }
+}
+
+/// Stores a position fix and answers with the client id of its user.
+#[derive(Debug)]
+pub struct CoordinatesHandler;
+
+#[async_trait]
+impl RequestHandler for CoordinatesHandler {
+    type Request = CoordinatesFix;
+
+    fn request_type(&self) -> u8 {
+        RequestType::Coordinates.to_value()
+    }
+
+    fn name(&self) -> &'static str {
+        RequestType::Coordinates.name()
+    }
+
+    fn client_id(&self, packet: &RequestPacket) -> Option<u32> {
+        read_client_id(&packet.payload)
+    }
+
+    async fn decode(
+        &self,
+        _ctx: &HandlerContext<'_>,
+        packet: &RequestPacket,
+    ) -> Result<CoordinatesFix, RequestError> {
+        Ok(Coordinates::parse_fix(
+            packet.payload_length,
+            &packet.payload,
+        )?)
+    }
+
+    async fn handle(
+        &self,
+        ctx: &HandlerContext<'_>,
+        request: CoordinatesFix,
+    ) -> Result<HandlerResponse, String> {
+        let store = &ctx.context.store;
+        if let Some(spool) = &ctx.context.spool {
+            // Fixes queue behind the spooled ones until the replay catches up,
+            // so they reach the database in the order they were received.
+            if spool.pending() > 0 {
+                return Self::spool(ctx, spool, request).await;
+            }
+        }
+        let stored = match Coordinates::resolve(&ctx.context.devices, request.clone()).await {
+            Ok(coordinates_data) => Self::store(ctx, request.clone(), coordinates_data).await,
+            Err(error) => Err(error.to_string()),
+        };
+        if let Err(error) = stored {
+            match &ctx.context.spool {
+                Some(spool) if !store.is_available().await => {
+                    warn!(%error, "database unreachable, spooling coordinates");
+                    return Self::spool(ctx, spool, request).await;
+                }
+                _ => {
+                    warn!(%error, "coordinates failed");
+                    return Ok(HandlerResponse::error("000000000".to_string()));
                 }
-                return Err("coordinates error: no record found".to_string());
             }
-            Err(error) => Err(format!("coordinates error: {:?}", error)),
         }
+        ctx.context.sessions.position(
+            request.client_id,
+            ctx.source_address,
+            request.latitude,
+            request.longitude,
+        );
+        Ok(HandlerResponse::success(request.client_id.to_string()))
+    }
+
+    async fn encode(&self, response: HandlerResponse) -> Result<String, String> {
+        Coordinates::generate_response(response.client_id, response.is_error).await
+    }
+}
+
+impl CoordinatesHandler {
+    /// Hands the fix to the write pipeline, or stores it right away when the
+    /// context has none.
+    async fn store(
+        ctx: &HandlerContext<'_>,
+        fix: CoordinatesFix,
+        data: CoordinatesData,
+    ) -> Result<(), String> {
+        match &ctx.context.pipeline {
+            Some(pipeline) => {
+                pipeline
+                    .submit(WriteRecord::Coordinates { fix, data })
+                    .await
+            }
+            None => {
+                debug!(coordinates_data = ?data, "storing coordinates");
+                Coordinates::new(ctx.context.store.clone())
+                    .create(data)
+                    .await
+            }
+        }
+    }
+
+    /// Accepts a fix into the spool, to be stored once the database recovers.
+    async fn spool(
+        ctx: &HandlerContext<'_>,
+        spool: &Spool,
+        fix: CoordinatesFix,
+    ) -> Result<HandlerResponse, String> {
+        let entry = spool.append(fix).await?;
+        debug!(key = %entry.key, pending = spool.pending(), "coordinates spooled");
+        let fix = entry.fix;
+        ctx.context.sessions.position(
+            fix.client_id,
+            ctx.source_address,
+            fix.latitude,
+            fix.longitude,
+        );
+        Ok(HandlerResponse::success(fix.client_id.to_string()))
+    }
+}
+
+/// Replays spooled fixes under their spool key, so a fix replayed twice is
+/// stored once.
+#[async_trait]
+impl SpoolSink for Coordinates {
+    async fn is_available(&self) -> bool {
+        self.store.is_available().await
+    }
+
+    async fn write(&self, entry: &SpoolEntry) -> Result<(), String> {
+        let mut data = Coordinates::resolve(&self.devices, entry.fix.clone()).await?;
+        data.id = Some(RecordId::from_table_key("coordinates", entry.key.as_str()));
+        let _timer = Metrics::global().start_db_timer("coordinates.replay");
+        self.store.insert_positions(&[data]).await
     }
 }
 
 #[cfg(test)]
 mod test_coordinates {
     use super::*;
-    use std::str::FromStr;
+    use crate::context::AppContext;
+    use crate::store::{add_device, add_user, MemoryStore};
+    use std::sync::Arc;
 
     #[tokio::test]
     pub async fn test_generate_payload() {
@@ -214,23 +363,120 @@ mod test_coordinates {
         assert!(payload.is_ok(), "{:?}", payload.err());
     }
 
+    #[tokio::test]
+    pub async fn test_parse_fix() {
+        let payload = Coordinates::generate_payload(24564, 10.00001, -127.000001)
+            .await
+            .unwrap();
+        let data = Payload::to_binary(payload.as_str()).unwrap();
+        let fix = Coordinates::parse_fix(data.len() - 3, &data[3..]);
+        assert!(fix.is_ok(), "{:?}", fix.err());
+        let fix = fix.unwrap();
+        assert_eq!(fix.client_id, 24564);
+        assert_eq!(fix.latitude, 10.00001);
+        assert_eq!(fix.longitude, -127.000001);
+
+        assert!(Coordinates::parse_fix(20, &data[3..10]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parse_max_datagram_size() {
+        let payload = Coordinates::generate_payload(0x0100_0005, 10.00001, -127.000001)
+            .await
+            .unwrap();
+        let mut data = Payload::to_binary(payload.as_str()).unwrap();
+        data.resize(crate::config::DEFAULT_MAX_DATAGRAM_SIZE, 0xAB);
+        let fix = Coordinates::parse_fix(data.len() - 3, &data[3..]);
+        assert!(fix.is_ok(), "{:?}", fix.err());
+        let fix = fix.unwrap();
+        // Zero bytes inside the client id are kept.
+        assert_eq!(fix.client_id, 0x0100_0005);
+        assert_eq!(fix.latitude, 10.00001);
+        assert_eq!(fix.longitude, -127.000001);
+    }
+
     #[tokio::test]
     pub async fn test_create() {
-        let coords = Coordinates::new().await;
-        assert!(coords.is_ok(), "{:?}", coords.err());
-        let coords = coords.unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let user = add_user(&*store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        let device = add_device(&*store, &user, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        let coords = Coordinates::new(store.clone());
 
         for _ in 0..10 {
             let coords_data = coords
                 .create(CoordinatesData {
+                    id: None,
                     longitude: -127.000001,
                     latitude: 10.00001,
-                    user: RecordId::from_str("users:0dgt5u58j2jh3oq4xzbt").unwrap(),
+                    device: device.id.clone().unwrap(),
                     timestamp: Datetime::from(Utc::now()),
                 })
                 .await;
             assert!(coords_data.is_ok(), "{:?}", coords_data.err());
-            std::thread::sleep(std::time::Duration::from_millis(500));
         }
+        assert_eq!(store.positions().len(), 10);
+    }
+
+    #[tokio::test]
+    pub async fn test_handle_error_response() {
+        let store = Arc::new(MemoryStore::new());
+        let user = add_user(&*store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        add_device(&*store, &user, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        let context = AppContext::with_store(Default::default(), store.clone());
+        let ctx = HandlerContext {
+            context: &context,
+            source_address: "127.0.0.1:5000".parse().unwrap(),
+        };
+        let fix = |client_id| CoordinatesFix {
+            client_id,
+            latitude: 10.00001,
+            longitude: -127.000001,
+            timestamp: Datetime::from(Utc::now()),
+        };
+        let handler = CoordinatesHandler;
+        // An unknown device is rejected rather than left without an answer.
+        let response = handler.handle(&ctx, fix(24565)).await.unwrap();
+        assert_eq!(response, HandlerResponse::error("000000000".to_string()));
+        assert_eq!(
+            handler.encode(response).await.unwrap(),
+            "02 07 30 30 30 30 30 30 30 30 30"
+        );
+        // So is a fix that cannot be stored and has no spool to go to.
+        store.set_available(false);
+        let response = handler.handle(&ctx, fix(24564)).await.unwrap();
+        assert!(response.is_error);
+        store.set_available(true);
+        let response = handler.handle(&ctx, fix(24564)).await.unwrap();
+        assert_eq!(response, HandlerResponse::success("24564".to_string()));
+        assert_eq!(store.positions().len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_replay_spooled_fix_once() {
+        let store = Arc::new(MemoryStore::new());
+        let user = add_user(&*store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        add_device(&*store, &user, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        let coordinates = Coordinates::new(store.clone());
+        let entry = SpoolEntry::new(CoordinatesFix {
+            client_id: 24564,
+            latitude: 10.00001,
+            longitude: -127.000001,
+            timestamp: Datetime::from(Utc::now()),
+        });
+        coordinates.write(&entry).await.unwrap();
+        coordinates.write(&entry).await.unwrap();
+        assert_eq!(store.positions().len(), 1);
     }
 }
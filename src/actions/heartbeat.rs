@@ -1,25 +1,30 @@
-use crate::db::Db;
+use crate::device::DeviceCache;
+use crate::handler::{HandlerContext, HandlerResponse, RequestHandler};
+use crate::metrics::Metrics;
 use crate::payload::Payload;
-use crate::request::RequestType;
+use crate::pipeline::WriteRecord;
+use crate::request::{read_client_id, RequestPacket, RequestType};
 use crate::response::ResponseType;
-use crate::user::{User, UserData};
-use crate::validation::ValidationError;
+use crate::store::SharedStore;
+use crate::validation::{RequestError, ValidationError};
+use async_trait::async_trait;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use surrealdb::sql::Datetime;
 use surrealdb::RecordId;
+use tracing::{debug, warn};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct HeartbeatData {
     pub timestamp: Datetime,
     pub id: Option<RecordId>,
     pub source_address: String,
-    pub user: RecordId,
+    pub device: RecordId,
 }
 
 #[derive(Debug)]
 pub struct Heartbeat {
-    db: Db,
+    store: SharedStore,
 }
 // Format:
 // Type: 0x03
@@ -58,70 +63,114 @@ impl Heartbeat {
     }
 
     /// Initializes Heartbeat instance including database connections.
-    pub async fn new() -> Result<Self, String> {
-        let db = Db::connect().await?;
-        Ok(Self { db })
+    pub fn new(store: SharedStore) -> Self {
+        Self { store }
     }
 
-    /// Parse a heartbeat from a packet
+    /// Parse a heartbeat from a packet, returning the client id it was sent
+    /// with along with it.
     pub async fn parse(
+        devices: &DeviceCache,
         source_address: String,
         payload_length: usize,
         data: &[u8],
-    ) -> Result<HeartbeatData, String> {
+    ) -> Result<(u32, HeartbeatData), RequestError> {
         if data.len() < payload_length {
-            return Err(ValidationError::InvalidHeartbeatPayload.to_string());
-        }
-        let client_id_hex: Vec<String> = data.iter().map(|x| format!("{:x}", x)).collect();
-        println!("Client Id: {:?}", client_id_hex);
-        match u32::from_str_radix(&client_id_hex.concat(), 16) {
-            Ok(client_id) => {
-                let user: User = User::new().await?;
-                let user_data: UserData = user.get_by_client_id(client_id).await?;
-                if let Some(user_id) = user_data.id {
-                    Ok(HeartbeatData {
-                        source_address,
-                        id: None,
-                        user: user_id,
-                        timestamp: Datetime::from(Utc::now()),
-                    })
-                } else {
-                    return Err(ValidationError::InvalidUserId.to_string());
-                }
-            }
-            Err(_) => Err(ValidationError::InvalidClientId.to_string()),
+            return Err(ValidationError::InvalidHeartbeatPayload.into());
         }
+        let client_id = match read_client_id(data) {
+            Some(client_id) => client_id,
+            None => return Err(ValidationError::InvalidClientId.into()),
+        };
+        debug!(client_id, "heartbeat client id");
+        let device_id = devices.resolve(client_id).await?;
+        Ok((
+            client_id,
+            HeartbeatData {
+                source_address,
+                id: None,
+                device: device_id,
+                timestamp: Datetime::from(Utc::now()),
+            },
+        ))
     }
 
-    /// Returns the table name.
-    fn get_table(&self) -> String {
-        String::from("heartbeat")
+    /// Create a heartbeat record
+    pub async fn create(&self, data: HeartbeatData) -> Result<(), String> {
+        let _timer = Metrics::global().start_db_timer("heartbeat.create");
+        self.store.insert_heartbeats(&[data]).await
     }
+}
 
-    /// Create a heartbeat record
-    pub async fn create(&self, data: HeartbeatData) -> Result<HeartbeatData, String> {
-        match self
-            .db
-            .client
-            .insert::<Vec<HeartbeatData>>(self.get_table())
-            .content(data.clone())
-            .await
-        {
-            Ok(response) => {
-                if let Some(record) = response.get(0) {
-                    return Ok(record.to_owned());
-                }
-                return Err("heartbeat error: no record found".to_string());
+/// Records a heartbeat and answers with the client id of its user.
+#[derive(Debug)]
+pub struct HeartbeatHandler;
+
+#[async_trait]
+impl RequestHandler for HeartbeatHandler {
+    /// Client id and heartbeat.
+    type Request = (u32, HeartbeatData);
+
+    fn request_type(&self) -> u8 {
+        RequestType::HeartBeat.to_value()
+    }
+
+    fn name(&self) -> &'static str {
+        RequestType::HeartBeat.name()
+    }
+
+    fn client_id(&self, packet: &RequestPacket) -> Option<u32> {
+        read_client_id(&packet.payload)
+    }
+
+    async fn decode(
+        &self,
+        ctx: &HandlerContext<'_>,
+        packet: &RequestPacket,
+    ) -> Result<(u32, HeartbeatData), RequestError> {
+        let (client_id, heartbeat_data) = Heartbeat::parse(
+            &ctx.context.devices,
+            ctx.source_address.to_string(),
+            packet.payload_length,
+            &packet.payload,
+        )
+        .await?;
+        debug!(?heartbeat_data, "heartbeat parsed");
+        Ok((client_id, heartbeat_data))
+    }
+
+    async fn handle(
+        &self,
+        ctx: &HandlerContext<'_>,
+        (client_id, request): (u32, HeartbeatData),
+    ) -> Result<HandlerResponse, String> {
+        let stored = match &ctx.context.pipeline {
+            Some(pipeline) => pipeline.submit(WriteRecord::Heartbeat(request)).await,
+            None => {
+                let hb: Heartbeat = Heartbeat::new(ctx.context.store.clone());
+                hb.create(request).await
             }
-            Err(error) => Err(format!("heartbeat error: {:?}", error)),
+        };
+        if let Err(error) = stored {
+            warn!(%error, "heartbeat failed");
+            return Ok(HandlerResponse::error("000000000".to_string()));
         }
+        ctx.context
+            .sessions
+            .heartbeat(client_id, ctx.source_address);
+        Ok(HandlerResponse::success(client_id.to_string()))
+    }
+
+    async fn encode(&self, response: HandlerResponse) -> Result<String, String> {
+        Heartbeat::generate_response(response.client_id, response.is_error).await
     }
 }
 
 #[cfg(test)]
 mod test_heartbeat {
     use super::*;
-    use std::str::FromStr;
+    use crate::store::{add_device, add_user, MemoryStore};
+    use std::sync::Arc;
 
     #[tokio::test]
     pub async fn test_generate_payload() {
@@ -135,23 +184,61 @@ mod test_heartbeat {
         assert!(payload.is_ok(), "{:?}", payload.err());
     }
 
+    #[tokio::test]
+    async fn test_parse_max_datagram_size() {
+        let memory = Arc::new(MemoryStore::new());
+        let user = add_user(&*memory, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        // Zero bytes inside the client id are kept.
+        let device = add_device(&*memory, &user, "root", "notsecurepassword", 0x0100_0005)
+            .await
+            .unwrap();
+        let devices = DeviceCache::new(memory, std::time::Duration::ZERO);
+        let payload_length = crate::config::DEFAULT_MAX_DATAGRAM_SIZE - 3;
+        let mut data = 0x0100_0005u32.to_be_bytes().to_vec();
+        data.resize(payload_length, 0xAB);
+
+        let heartbeat = Heartbeat::parse(
+            &devices,
+            "127.0.0.1:5000".to_string(),
+            payload_length,
+            &data,
+        )
+        .await;
+        assert!(heartbeat.is_ok(), "{:?}", heartbeat.err());
+        let (client_id, heartbeat) = heartbeat.unwrap();
+        assert_eq!(client_id, 0x0100_0005);
+        assert_eq!(heartbeat.device, device.id.unwrap());
+        assert!(
+            Heartbeat::parse(&devices, "127.0.0.1:5000".to_string(), 3, &data[..3])
+                .await
+                .is_err()
+        );
+    }
+
     #[tokio::test]
     pub async fn test_create() {
-        let hb = Heartbeat::new().await;
-        assert!(hb.is_ok(), "{:?}", hb.err());
-        let hb = hb.unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let user = add_user(&*store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        let device = add_device(&*store, &user, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        let hb = Heartbeat::new(store.clone());
 
         for _ in 0..10 {
             let hb_data = hb
                 .create(HeartbeatData {
                     id: None,
                     source_address: "127.0.0.1".to_string(),
-                    user: RecordId::from_str("users:0dgt5u58j2jh3oq4xzbt").unwrap(),
+                    device: device.id.clone().unwrap(),
                     timestamp: Datetime::from(Utc::now()),
                 })
                 .await;
             assert!(hb_data.is_ok(), "{:?}", hb_data.err());
-            std::thread::sleep(std::time::Duration::from_millis(500));
         }
+        assert_eq!(store.heartbeats().len(), 10);
     }
 }
@@ -1,14 +1,28 @@
+use crate::device::{Device, DeviceData};
+use crate::handler::{HandlerContext, HandlerResponse, RequestHandler};
+use crate::logging::Redacted;
+use crate::metrics::Metrics;
 use crate::payload::Payload;
-use crate::request::RequestType;
+use crate::request::{RequestPacket, RequestType};
 use crate::response::ResponseType;
-use crate::user::{User, UserData};
-use crate::validation::ValidationError;
+use crate::store::SharedStore;
+use crate::validation::{RequestError, ValidationError};
+use async_trait::async_trait;
+use tracing::{debug, info, warn};
 
-#[derive(Debug)]
 pub struct Login {
     username: String,
     password: String,
 }
+
+impl std::fmt::Debug for Login {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Login")
+            .field("username", &self.username)
+            .field("password", &Redacted(&self.password))
+            .finish()
+    }
+}
 // Format:
 // Type: 0x01
 // Payload Length: 0x0014 (20 bytes, 10 bytes for username and 10 bytes for password)
@@ -50,9 +64,9 @@ impl Login {
     }
 
     /// Parse the login packet
-    pub async fn parse(payload_length: usize, credentials: &[u8]) -> Result<Self, String> {
+    pub async fn parse(payload_length: usize, credentials: &[u8]) -> Result<Self, ValidationError> {
         if credentials.len() < payload_length {
-            return Err(ValidationError::InvalidLoginPayload.to_string());
+            return Err(ValidationError::InvalidLoginPayload);
         }
         let mut username: Vec<u8> = Vec::new();
         let mut password: Vec<u8> = Vec::new();
@@ -70,25 +84,96 @@ impl Login {
             }
         }
 
-        Ok(Self {
-            username: String::from_utf8(username).unwrap(),
-            password: String::from_utf8(password).unwrap(),
-        })
+        match (String::from_utf8(username), String::from_utf8(password)) {
+            (Ok(username), Ok(password)) => Ok(Self { username, password }),
+            _ => Err(ValidationError::InvalidLoginPayload),
+        }
     }
 
-    /// authenticate a user
-    pub async fn authenticate(credential: Login) -> Result<UserData, String> {
-        let user: User = User::new().await?;
-        let data: UserData = user
-            .get_by_username_and_password(&credential.username, &credential.password)
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Authenticates a device; the username of the packet is its serial and
+    /// the password its device key.
+    pub async fn authenticate(
+        store: &SharedStore,
+        credential: Login,
+    ) -> Result<DeviceData, String> {
+        let device: Device = Device::new(store.clone());
+        let data: DeviceData = device
+            .authenticate(&credential.username, &credential.password)
             .await?;
         Ok(data)
     }
 }
 
+/// Authenticates a device and answers with its client id.
+#[derive(Debug)]
+pub struct LoginHandler;
+
+#[async_trait]
+impl RequestHandler for LoginHandler {
+    type Request = Login;
+
+    fn request_type(&self) -> u8 {
+        RequestType::Login.to_value()
+    }
+
+    fn name(&self) -> &'static str {
+        RequestType::Login.name()
+    }
+
+    async fn decode(
+        &self,
+        _ctx: &HandlerContext<'_>,
+        packet: &RequestPacket,
+    ) -> Result<Login, RequestError> {
+        let login_data = Login::parse(packet.payload_length, &packet.payload).await?;
+        debug!(?login_data, "login parsed");
+        Ok(login_data)
+    }
+
+    async fn handle(
+        &self,
+        ctx: &HandlerContext<'_>,
+        request: Login,
+    ) -> Result<HandlerResponse, String> {
+        match Login::authenticate(&ctx.context.store, request).await {
+            Ok(device_data) => {
+                tracing::Span::current().record("client_id", device_data.client_id);
+                let device = Device::new(ctx.context.store.clone());
+                if let Err(error) = device.start_session(&device_data, ctx.source_address).await {
+                    warn!(%error, "login failed");
+                    return Ok(HandlerResponse::error("0".to_string()));
+                }
+                info!("login succeeded");
+                if let Some(id) = device_data.id.clone() {
+                    ctx.context.devices.insert(device_data.client_id, id);
+                }
+                ctx.context
+                    .sessions
+                    .login(device_data.client_id, ctx.source_address);
+                Ok(HandlerResponse::success(device_data.client_id.to_string()))
+            }
+            Err(error) => {
+                warn!(%error, "login failed");
+                Metrics::global().auth_failures.inc();
+                Ok(HandlerResponse::error("0".to_string()))
+            }
+        }
+    }
+
+    async fn encode(&self, response: HandlerResponse) -> Result<String, String> {
+        Login::generate_response(response.client_id, response.is_error).await
+    }
+}
+
 #[cfg(test)]
 mod test_login {
     use super::*;
+    use crate::store::{add_device, add_user, MemoryStore};
+    use std::sync::Arc;
 
     #[tokio::test]
     async fn test_payload_generator() {
@@ -108,13 +193,54 @@ mod test_login {
         assert_eq!(test_value_binary, payload.unwrap());
     }
 
+    #[tokio::test]
+    async fn test_parse_max_datagram_size() {
+        // Header is 3 bytes and the username/password separator 1 byte.
+        let credentials_length = crate::config::DEFAULT_MAX_DATAGRAM_SIZE - 4;
+        let username = "u".repeat(credentials_length / 2);
+        let password = "p".repeat(credentials_length - username.len());
+        let payload = Login::generate_payload(username.clone(), password.clone()).await;
+        assert!(payload.is_ok(), "{:?}", payload.err());
+        let data = Payload::to_binary(payload.unwrap().as_str()).unwrap();
+        assert_eq!(data.len(), crate::config::DEFAULT_MAX_DATAGRAM_SIZE);
+
+        let login = Login::parse(data.len() - 3, &data[3..]).await;
+        assert!(login.is_ok(), "{:?}", login.err());
+        let login = login.unwrap();
+        assert_eq!(login.username, username);
+        assert_eq!(login.password, password);
+    }
+
+    #[tokio::test]
+    async fn test_debug_redacts_password() {
+        let login = Login::parse(20, b"root\0notsecurepassword").await.unwrap();
+        let output = format!("{:?}", login);
+        assert!(!output.contains("notsecurepassword"), "{}", output);
+        assert!(output.contains("root"), "{}", output);
+    }
+
     #[tokio::test]
     async fn test_authentication() {
         let username = "root".to_string();
         let password = "notsecurepassword".to_string();
 
-        let user_data: Result<UserData, String> =
-            Login::authenticate(Login { username, password }).await;
-        assert!(user_data.is_ok(), "{:?}", user_data.err());
+        let store = MemoryStore::new();
+        let user = add_user(&store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        add_device(&store, &user, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        let store: SharedStore = Arc::new(store);
+
+        let device_data: Result<DeviceData, String> =
+            Login::authenticate(&store, Login { username, password }).await;
+        assert!(device_data.is_ok(), "{:?}", device_data.err());
+
+        let wrong = Login {
+            username: "root".to_string(),
+            password: "wrong".to_string(),
+        };
+        assert!(Login::authenticate(&store, wrong).await.is_err());
     }
 }
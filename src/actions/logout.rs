@@ -1,12 +1,18 @@
-use crate::db::Db;
+use crate::device::Device;
+use crate::handler::{HandlerContext, HandlerResponse, RequestHandler};
+use crate::metrics::Metrics;
 use crate::payload::Payload;
-use crate::request::RequestType;
+use crate::request::{read_client_id, RequestPacket, RequestType};
 use crate::response::ResponseType;
-use crate::validation::ValidationError;
+use crate::session::SessionData;
+use crate::store::SharedStore;
+use crate::validation::{RequestError, ValidationError};
+use async_trait::async_trait;
+use tracing::{debug, warn};
 
 #[derive(Debug)]
 pub struct Logout {
-    db: Db,
+    store: SharedStore,
 }
 // Format:
 // Type: 0x04
@@ -44,48 +50,88 @@ impl Logout {
         Ok(with_spacing)
     }
 
-    /// Initializes Heartbeat instance including database connections.
-    pub async fn new() -> Result<Self, String> {
-        let db = Db::connect().await?;
-        Ok(Self { db })
+    /// Initializes Logout instance on top of the store.
+    pub fn new(store: SharedStore) -> Self {
+        Self { store }
     }
 
-    pub async fn parse(payload_length: usize, data: &[u8]) -> Result<u32, String> {
+    pub async fn parse(payload_length: usize, data: &[u8]) -> Result<u32, ValidationError> {
         if data.len() > payload_length {
-            return Err(ValidationError::InvalidLogoutPayload.to_string());
+            return Err(ValidationError::InvalidLogoutPayload);
         }
 
-        let client_id_hex: Vec<String> = data.iter().map(|x| format!("{:x}", x)).collect();
-        match u32::from_str_radix(&client_id_hex.concat(), 16) {
-            Ok(client_id) => Ok(client_id),
-            Err(_) => Err(ValidationError::InvalidClientId.to_string()),
-        }
+        read_client_id(data).ok_or(ValidationError::InvalidClientId)
     }
 
-    pub async fn logout(&self, client_id: u32) -> Result<bool, String> {
-        if let Err(error) = self
-            .db
-            .client
-            .query(
-                r#"
-                    DELETE FROM type::table($coordinates_table) WHERE `user`.`client_id`=$client_id;
-                    DELETE FROM type::table($heartbeat_table) WHERE `user`.`client_id`=$client_id;
-                "#,
-            )
-            .bind(("coordinates_table", "coordinates"))
-            .bind(("heartbeat_table", "heartbeat"))
-            .bind(("client_id", client_id))
-            .await
-        {
-            return Err(error.to_string());
+    /// Closes the session of the device, keeping its positions and
+    /// heartbeats. Returns the closed session, or `None` when none was open.
+    pub async fn logout(&self, client_id: u32) -> Result<Option<SessionData>, String> {
+        let _timer = Metrics::global().start_db_timer("logout.logout");
+        Device::new(self.store.clone()).end_session(client_id).await
+    }
+}
+
+/// Logs a device out and echoes its client id.
+#[derive(Debug)]
+pub struct LogoutHandler;
+
+#[async_trait]
+impl RequestHandler for LogoutHandler {
+    type Request = u32;
+
+    fn request_type(&self) -> u8 {
+        RequestType::Logout.to_value()
+    }
+
+    fn name(&self) -> &'static str {
+        RequestType::Logout.name()
+    }
+
+    fn client_id(&self, packet: &RequestPacket) -> Option<u32> {
+        read_client_id(&packet.payload)
+    }
+
+    async fn decode(
+        &self,
+        _ctx: &HandlerContext<'_>,
+        packet: &RequestPacket,
+    ) -> Result<u32, RequestError> {
+        let client_id = Logout::parse(packet.payload_length, &packet.payload).await?;
+        debug!(client_id, "logout parsed");
+        Ok(client_id)
+    }
+
+    async fn handle(
+        &self,
+        ctx: &HandlerContext<'_>,
+        client_id: u32,
+    ) -> Result<HandlerResponse, String> {
+        let logout = Logout::new(ctx.context.store.clone());
+        match logout.logout(client_id).await {
+            Ok(_) => {
+                ctx.context.sessions.logout(client_id);
+                Ok(HandlerResponse::success(client_id.to_string()))
+            }
+            Err(error) => {
+                warn!(%error, "logout failed");
+                Ok(HandlerResponse::error(client_id.to_string()))
+            }
         }
-        Ok(true)
+    }
+
+    async fn encode(&self, response: HandlerResponse) -> Result<String, String> {
+        Logout::generate_response(response.client_id, response.is_error).await
     }
 }
 
 #[cfg(test)]
 mod test_logout {
     use super::*;
+    use crate::actions::CoordinatesData;
+    use crate::store::{add_device, add_user, MemoryStore, Store};
+    use chrono::Utc;
+    use std::sync::Arc;
+    use surrealdb::sql::Datetime;
 
     #[tokio::test]
     pub async fn test_generate_payload() {
@@ -99,12 +145,53 @@ mod test_logout {
         assert!(payload.is_ok(), "{:?}", payload.err());
     }
 
+    #[tokio::test]
+    async fn test_parse_max_datagram_size() {
+        let payload_length = crate::config::DEFAULT_MAX_DATAGRAM_SIZE - 3;
+        let mut data = 0x0100_0005u32.to_be_bytes().to_vec();
+        data.resize(payload_length, 0xAB);
+        assert_eq!(Logout::parse(payload_length, &data).await, Ok(0x0100_0005));
+        assert_eq!(Logout::parse(4, &[0x00, 0x00, 0x5F, 0xF4]).await, Ok(24564));
+        assert!(Logout::parse(3, &data[..3]).await.is_err());
+    }
+
     #[tokio::test]
     pub async fn test_logout() {
         let client_id: u32 = 24564;
-        let logout: Result<Logout, String> = Logout::new().await;
-        assert!(logout.is_ok(), "{:?}", logout.err());
-        let result: Result<bool, String> = logout.unwrap().logout(client_id).await;
+        let store = Arc::new(MemoryStore::new());
+        let user = add_user(&*store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        let device = add_device(&*store, &user, "root", "notsecurepassword", client_id)
+            .await
+            .unwrap();
+        let logout: Logout = Logout::new(store.clone());
+        let result = logout.logout(client_id).await;
         assert!(result.is_ok(), "{:?}", result.err());
+        assert!(result.unwrap().is_none());
+
+        Device::new(store.clone())
+            .start_session(&device, "127.0.0.1:5000".parse().unwrap())
+            .await
+            .unwrap();
+        store
+            .insert_positions(&[CoordinatesData {
+                id: None,
+                device: device.id.clone().unwrap(),
+                latitude: 14.65,
+                longitude: 121.04,
+                timestamp: Datetime::from(Utc::now()),
+            }])
+            .await
+            .unwrap();
+        let session = logout.logout(client_id).await.unwrap().unwrap();
+        assert!(session.logout.is_some());
+        assert_eq!(session.positions, 1);
+        assert_eq!(session.source_address, "127.0.0.1:5000");
+        // Logging out keeps the history.
+        assert_eq!(store.positions().len(), 1);
+
+        store.set_available(false);
+        assert!(logout.logout(client_id).await.is_err());
     }
 }
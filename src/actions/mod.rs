@@ -3,7 +3,7 @@ pub mod heartbeat;
 pub mod login;
 pub mod logout;
 
-pub use coordinates::{Coordinates, CoordinatesData};
-pub use heartbeat::{Heartbeat, HeartbeatData};
-pub use login::Login;
-pub use logout::Logout;
+pub use coordinates::{Coordinates, CoordinatesData, CoordinatesFix, CoordinatesHandler};
+pub use heartbeat::{Heartbeat, HeartbeatData, HeartbeatHandler};
+pub use login::{Login, LoginHandler};
+pub use logout::{Logout, LogoutHandler};
@@ -0,0 +1,473 @@
+use crate::device::{Device, DeviceData, DEFAULT_KIND, DEFAULT_PROTOCOL};
+use crate::password;
+use crate::user::{User, UserData};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// A user as exported and imported by `gps-admin`. Passwords are only
+/// exported as their Argon2 hashes, and only when asked; an imported user
+/// with neither gets a generated password.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub name: String,
+    pub username: String,
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    /// Argon2 PHC hash of the password, stored as it is on import.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl From<&UserData> for UserRecord {
+    fn from(data: &UserData) -> Self {
+        Self {
+            name: data.name.clone(),
+            username: data.username.clone(),
+            password: None,
+            password_hash: None,
+            disabled: data.disabled,
+        }
+    }
+}
+
+/// A device as exported and imported by `gps-admin`, owned by the user with
+/// the username `owner`. Device keys are only exported as their Argon2
+/// hashes, and only when asked; an imported device with neither gets a
+/// generated key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceRecord {
+    pub serial: String,
+    pub name: String,
+    #[serde(default = "DeviceRecord::default_kind")]
+    pub kind: String,
+    pub owner: String,
+    #[serde(default = "DeviceRecord::default_protocol")]
+    pub protocol: String,
+    #[serde(default, skip_serializing)]
+    pub key: Option<String>,
+    /// Argon2 PHC hash of the device key, stored as it is on import.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_hash: Option<String>,
+    /// Allocated on import when missing.
+    #[serde(default)]
+    pub client_id: Option<u32>,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl DeviceRecord {
+    fn default_kind() -> String {
+        DEFAULT_KIND.to_string()
+    }
+
+    fn default_protocol() -> String {
+        DEFAULT_PROTOCOL.to_string()
+    }
+}
+
+/// What importing one record did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Imported {
+    Created {
+        /// Username or serial of the record.
+        name: String,
+        /// Client id of an imported device.
+        client_id: Option<u32>,
+        /// Password or device key generated for a record without one.
+        key: Option<String>,
+    },
+    /// The username or serial already exists; the stored record is left as
+    /// it is.
+    Skipped { name: String },
+}
+
+pub fn read_csv<T: DeserializeOwned>(data: &str) -> Result<Vec<T>, String> {
+    csv::Reader::from_reader(data.as_bytes())
+        .deserialize()
+        .collect::<Result<Vec<T>, _>>()
+        .map_err(|error| format!("csv error: {:?}", error))
+}
+
+pub fn write_csv<T: Serialize>(records: &[T]) -> Result<String, String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for record in records {
+        if let Err(error) = writer.serialize(record) {
+            return Err(format!("csv error: {:?}", error));
+        }
+    }
+    match writer.into_inner() {
+        Ok(data) => String::from_utf8(data).map_err(|error| format!("csv error: {:?}", error)),
+        Err(error) => Err(format!("csv error: {:?}", error)),
+    }
+}
+
+pub fn read_json<T: DeserializeOwned>(data: &str) -> Result<Vec<T>, String> {
+    serde_json::from_str(data).map_err(|error| format!("json error: {:?}", error))
+}
+
+pub fn write_json<T: Serialize>(records: &[T]) -> Result<String, String> {
+    serde_json::to_string_pretty(records).map_err(|error| format!("json error: {:?}", error))
+}
+
+/// The given secret, or a generated one that is returned to be shown once.
+pub fn secret_or_generated(secret: Option<String>) -> (String, Option<String>) {
+    match secret {
+        Some(secret) => (secret, None),
+        None => {
+            let key = password::generate_key();
+            (key.clone(), Some(key))
+        }
+    }
+}
+
+/// The stored hash of `name`, for exports that include them. Every record
+/// of a CSV export needs the same columns, so a missing hash fails it.
+fn exported_hash(name: &str, stored: &str, with_hashes: bool) -> Result<Option<String>, String> {
+    match with_hashes {
+        true if password::is_hashed(stored) => Ok(Some(stored.to_string())),
+        true => Err(format!("export error: {} has no hash", name)),
+        false => Ok(None),
+    }
+}
+
+/// Every user, ordered by username, with their password hashes when
+/// `with_hashes` is set.
+pub async fn export_users(user: &User, with_hashes: bool) -> Result<Vec<UserRecord>, String> {
+    let mut users = user.get_users().await?;
+    users.sort_by(|a, b| a.username.cmp(&b.username));
+    let mut records = Vec::new();
+    for data in &users {
+        records.push(UserRecord {
+            password_hash: exported_hash(&data.username, &data.password, with_hashes)?,
+            ..UserRecord::from(data)
+        });
+    }
+    Ok(records)
+}
+
+/// Every device, ordered by client id, with their key hashes when
+/// `with_hashes` is set.
+pub async fn export_devices(
+    device: &Device,
+    user: &User,
+    with_hashes: bool,
+) -> Result<Vec<DeviceRecord>, String> {
+    let users = user.get_users().await?;
+    let mut records = Vec::new();
+    for data in device.get_devices(None).await? {
+        let owner = match users
+            .iter()
+            .find(|user| user.id.as_ref() == Some(&data.owner))
+        {
+            Some(owner) => owner.username.clone(),
+            None => return Err(format!("device {} has no owner", data.serial)),
+        };
+        records.push(DeviceRecord {
+            key_hash: exported_hash(&data.serial, &data.credentials, with_hashes)?,
+            serial: data.serial,
+            name: data.name,
+            kind: data.kind,
+            owner,
+            protocol: data.protocol,
+            key: None,
+            client_id: Some(data.client_id),
+            disabled: data.disabled,
+        });
+    }
+    Ok(records)
+}
+
+/// Creates the users of `records` whose username is not taken. A record
+/// may give a password or a password hash, not both.
+pub async fn import_users(user: &User, records: Vec<UserRecord>) -> Result<Vec<Imported>, String> {
+    let existing: Vec<String> = user
+        .get_users()
+        .await?
+        .into_iter()
+        .map(|data| data.username)
+        .collect();
+    let mut imported = Vec::new();
+    for record in records {
+        if existing.contains(&record.username) {
+            imported.push(Imported::Skipped {
+                name: record.username,
+            });
+            continue;
+        }
+        let failed = |error: String| format!("import of {} failed: {}", record.username, error);
+        let (created, key) = match (record.password, record.password_hash) {
+            (Some(_), Some(_)) => {
+                return Err(failed("both a password and a hash given".to_string()))
+            }
+            (None, Some(hash)) => (
+                user.create_hashed(&record.name, &record.username, &hash)
+                    .await,
+                None,
+            ),
+            (password, None) => {
+                let (secret, key) = secret_or_generated(password);
+                (
+                    user.create(&record.name, &record.username, &secret).await,
+                    key,
+                )
+            }
+        };
+        let data = created.map_err(failed)?;
+        if record.disabled {
+            user.set_disabled(&data.username, true).await?;
+        }
+        imported.push(Imported::Created {
+            name: data.username,
+            client_id: None,
+            key,
+        });
+    }
+    Ok(imported)
+}
+
+/// Creates the devices of `records` whose serial is not taken. Owners must
+/// exist already, and a record may give a key or a key hash, not both. Records with a client id go first, so the ids allocated
+/// for the others never clash with them.
+pub async fn import_devices(
+    device: &Device,
+    user: &User,
+    records: Vec<DeviceRecord>,
+) -> Result<Vec<Imported>, String> {
+    let existing: Vec<String> = device
+        .get_devices(None)
+        .await?
+        .into_iter()
+        .map(|data| data.serial)
+        .collect();
+    let (mut ordered, allocated): (Vec<DeviceRecord>, Vec<DeviceRecord>) = records
+        .into_iter()
+        .partition(|record| record.client_id.is_some());
+    ordered.extend(allocated);
+
+    let mut imported = Vec::new();
+    for record in ordered {
+        if existing.contains(&record.serial) {
+            imported.push(Imported::Skipped {
+                name: record.serial,
+            });
+            continue;
+        }
+        let failed = |error: String| format!("import of {} failed: {}", record.serial, error);
+        let owner = user.get_by_username(&record.owner).await.map_err(failed)?;
+        let data = DeviceData {
+            id: None,
+            serial: record.serial.clone(),
+            name: record.name.clone(),
+            kind: record.kind.clone(),
+            owner: owner
+                .id
+                .ok_or_else(|| failed("owner has no id".to_string()))?,
+            protocol: record.protocol.clone(),
+            credentials: String::new(),
+            client_id: record.client_id.unwrap_or_default(),
+            disabled: record.disabled,
+        };
+        let (created, key) = match (record.key.clone(), record.key_hash.clone()) {
+            (Some(_), Some(_)) => return Err(failed("both a key and a hash given".to_string())),
+            (None, Some(hash)) => (
+                device
+                    .create_hashed(DeviceData {
+                        credentials: hash,
+                        ..data
+                    })
+                    .await,
+                None,
+            ),
+            (secret, None) => {
+                let (secret, key) = secret_or_generated(secret);
+                (device.create(data, &secret).await, key)
+            }
+        };
+        let data = created.map_err(failed)?;
+        imported.push(Imported::Created {
+            name: data.serial,
+            client_id: Some(data.client_id),
+            key,
+        });
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod test_admin {
+    use super::*;
+    use crate::store::{add_device, add_user, MemoryStore, SharedStore};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_csv() {
+        let records: Vec<DeviceRecord> = read_csv(
+            "serial,name,owner,key,client_id,disabled\n\
+             root,Root,root,notsecurepassword,24564,false\n\
+             356938035643809,Van,root,,,true\n",
+        )
+        .unwrap();
+        assert_eq!(records[0].key.as_deref(), Some("notsecurepassword"));
+        assert_eq!(records[0].client_id, Some(24564));
+        assert_eq!(records[0].kind, DEFAULT_KIND);
+        assert_eq!(records[1].key, None);
+        assert_eq!(records[1].client_id, None);
+        assert!(records[1].disabled);
+
+        let output = write_csv(&records).unwrap();
+        assert_eq!(
+            output,
+            "serial,name,kind,owner,protocol,client_id,disabled\n\
+             root,Root,tracker,root,udp,24564,false\n\
+             356938035643809,Van,tracker,root,udp,,true\n"
+        );
+        assert!(read_csv::<UserRecord>("name\nRoot\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_import_and_export() {
+        let store = MemoryStore::new();
+        let root = add_user(&store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        add_device(&store, &root, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        let store: SharedStore = Arc::new(store);
+        let user = User::new(store.clone());
+        let device = Device::new(store.clone());
+
+        let users: Vec<UserRecord> = read_json(
+            r#"[
+                {"name": "Root", "username": "root", "password": "other"},
+                {"name": "Fleet", "username": "fleet"}
+            ]"#,
+        )
+        .unwrap();
+        let imported = import_users(&user, users).await.unwrap();
+        assert_eq!(
+            imported[0],
+            Imported::Skipped {
+                name: "root".to_string()
+            }
+        );
+        let password = match &imported[1] {
+            Imported::Created { key, .. } => key.clone().unwrap(),
+            other => panic!("{:?}", other),
+        };
+        assert!(user
+            .get_by_username_and_password("fleet", &password)
+            .await
+            .is_ok());
+
+        let devices: Vec<DeviceRecord> = read_json(
+            r#"[
+                {"serial": "root", "name": "Root", "owner": "root", "key": "other"},
+                {"serial": "van", "name": "Van", "owner": "fleet", "disabled": true},
+                {"serial": "truck", "name": "Truck", "owner": "fleet", "key": "truckkey", "client_id": 24565}
+            ]"#,
+        )
+        .unwrap();
+        let imported = import_devices(&device, &user, devices).await.unwrap();
+        assert_eq!(
+            imported[0],
+            Imported::Created {
+                name: "truck".to_string(),
+                client_id: Some(24565),
+                key: None
+            }
+        );
+        assert_eq!(
+            imported[1],
+            Imported::Skipped {
+                name: "root".to_string()
+            }
+        );
+        let key = match &imported[2] {
+            Imported::Created { client_id, key, .. } => {
+                assert_eq!(*client_id, Some(24566));
+                key.clone().unwrap()
+            }
+            other => panic!("{:?}", other),
+        };
+        assert!(device.authenticate("truck", "truckkey").await.is_ok());
+        // The van is disabled, so even its generated key is refused.
+        let refused = device.authenticate("van", &key).await;
+        assert_eq!(refused.err(), Some("device is disabled".to_string()));
+
+        let orphan: Vec<DeviceRecord> =
+            read_json(r#"[{"serial": "bike", "name": "Bike", "owner": "nobody"}]"#).unwrap();
+        assert!(import_devices(&device, &user, orphan).await.is_err());
+
+        let exported = export_devices(&device, &user, false).await.unwrap();
+        let serials: Vec<&str> = exported
+            .iter()
+            .map(|record| record.serial.as_str())
+            .collect();
+        assert_eq!(serials, ["root", "truck", "van"]);
+        assert_eq!(exported[1].owner, "fleet");
+        let json = write_json(&exported).unwrap();
+        assert!(!json.contains("key"), "{}", json);
+        assert_eq!(read_json::<DeviceRecord>(&json).unwrap(), exported);
+
+        let exported = export_users(&user, false).await.unwrap();
+        let json = write_json(&exported).unwrap();
+        assert!(!json.contains("password"), "{}", json);
+        assert_eq!(read_json::<UserRecord>(&json).unwrap(), exported);
+    }
+
+    #[tokio::test]
+    async fn test_export_hashes() {
+        let source = MemoryStore::new();
+        let root = add_user(&source, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        add_device(&source, &root, "root", "rootkey", 24564)
+            .await
+            .unwrap();
+        let source: SharedStore = Arc::new(source);
+        let users = export_users(&User::new(source.clone()), true)
+            .await
+            .unwrap();
+        let devices = export_devices(&Device::new(source.clone()), &User::new(source), true)
+            .await
+            .unwrap();
+        assert!(password::is_hashed(
+            users[0].password_hash.as_ref().unwrap()
+        ));
+        let csv = write_csv(&devices).unwrap();
+        assert!(
+            csv.starts_with("serial,name,kind,owner,protocol,key_hash,"),
+            "{}",
+            csv
+        );
+
+        // Imported elsewhere, the same password and key still log in.
+        let target: SharedStore = Arc::new(MemoryStore::new());
+        let user = User::new(target.clone());
+        let device = Device::new(target);
+        let users = read_json::<UserRecord>(&write_json(&users).unwrap()).unwrap();
+        let imported = import_users(&user, users).await.unwrap();
+        assert!(matches!(&imported[0], Imported::Created { key: None, .. }));
+        let devices = read_csv::<DeviceRecord>(&csv).unwrap();
+        let imported = import_devices(&device, &user, devices).await.unwrap();
+        assert!(matches!(&imported[0], Imported::Created { key: None, .. }));
+        assert!(user
+            .get_by_username_and_password("root", "notsecurepassword")
+            .await
+            .is_ok());
+        assert!(device.authenticate("root", "rootkey").await.is_ok());
+
+        let both: Vec<UserRecord> = read_json(
+            r#"[{"name": "Fleet", "username": "fleet", "password": "fleet", "password_hash": "$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA"}]"#,
+        )
+        .unwrap();
+        assert!(import_users(&user, both).await.is_err());
+        let plain: Vec<UserRecord> =
+            read_json(r#"[{"name": "Fleet", "username": "fleet", "password_hash": "fleet"}]"#)
+                .unwrap();
+        assert!(import_users(&user, plain).await.is_err());
+    }
+}
@@ -0,0 +1,485 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use gps_tracker::admin::{self, DeviceRecord, Imported, UserRecord};
+use gps_tracker::config::Config;
+use gps_tracker::device::{Device, DeviceData, DEFAULT_KIND, DEFAULT_PROTOCOL};
+use gps_tracker::retention::Retention;
+use gps_tracker::store;
+use gps_tracker::user::User;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::BufRead;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    Csv,
+    Json,
+}
+
+/// Manages users and their devices on the configured database.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Config file, `config.toml` by default. `APP_CONFIG_PATH` takes precedence.
+    #[arg(short, long, global = true)]
+    config_path: Option<String>,
+    /// Overrides a config key, e.g. `--set database.engine=sqlite`.
+    /// Only accepted before the subcommand.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Manages the users owning devices.
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manages the trackers logging in to the server.
+    #[command(subcommand)]
+    Device(DeviceCommand),
+    /// Applies the `server.retention` policies once, even when the job is
+    /// disabled, and prints what was removed.
+    Prune,
+}
+
+#[derive(Debug, Subcommand)]
+enum UserCommand {
+    /// Adds a user. Without `--password-stdin` a password is generated and
+    /// printed.
+    Create {
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        username: String,
+        /// Reads the password from the first line of stdin.
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Lists every user.
+    List,
+    /// Stops a user's devices from logging in, keeping their records.
+    Disable { username: String },
+    /// Lets a disabled user's devices log in again.
+    Enable { username: String },
+    /// Deletes a user along with its devices and their positions, heartbeats
+    /// and sessions.
+    Delete { username: String },
+    /// Sets a new password, read from the first line of stdin.
+    ResetPassword { username: String },
+    /// Writes every user to stdout or `--output`.
+    Export {
+        #[arg(short, long, value_enum, default_value_t = Format::Csv)]
+        format: Format,
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Includes the password hashes, so the users keep their passwords
+        /// when imported elsewhere.
+        #[arg(long)]
+        with_hashes: bool,
+    },
+    /// Creates the users of a file whose usernames are not taken yet.
+    Import {
+        file: String,
+        #[arg(short, long, value_enum, default_value_t = Format::Csv)]
+        format: Format,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum DeviceCommand {
+    /// Adds a device. Without `--key-stdin` a device key is generated and
+    /// printed.
+    Create {
+        /// IMEI or serial number, sent as the username of the login packet.
+        #[arg(long)]
+        serial: String,
+        #[arg(long)]
+        name: String,
+        /// Username of the user owning the device.
+        #[arg(long)]
+        owner: String,
+        #[arg(long, default_value = DEFAULT_KIND)]
+        kind: String,
+        #[arg(long, default_value = DEFAULT_PROTOCOL)]
+        protocol: String,
+        /// Reads the device key from the first line of stdin.
+        #[arg(long)]
+        key_stdin: bool,
+        /// Allocated above every existing client id when omitted.
+        #[arg(long)]
+        client_id: Option<u32>,
+    },
+    /// Lists every device, or those of `--owner`.
+    List {
+        #[arg(long)]
+        owner: Option<String>,
+    },
+    /// Stops a device from logging in, keeping its records.
+    Disable { serial: String },
+    /// Lets a disabled device log in again.
+    Enable { serial: String },
+    /// Deletes a device along with its positions, heartbeats and sessions.
+    Delete { serial: String },
+    /// Replaces the device key with a new random one and prints it.
+    RotateKey { serial: String },
+    /// Deletes the positions, heartbeats and sessions of a device, keeping
+    /// the device. Logging out never deletes them.
+    Purge { serial: String },
+    /// Writes every device to stdout or `--output`.
+    Export {
+        #[arg(short, long, value_enum, default_value_t = Format::Csv)]
+        format: Format,
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Includes the device key hashes, so the devices keep their keys
+        /// when imported elsewhere.
+        #[arg(long)]
+        with_hashes: bool,
+    },
+    /// Creates the devices of a file whose serials are not taken yet.
+    Import {
+        file: String,
+        #[arg(short, long, value_enum, default_value_t = Format::Csv)]
+        format: Format,
+    },
+}
+
+/// Reads a password or device key from the first line of stdin, so it never
+/// shows up in the process list or the shell history.
+fn read_secret(what: &str) -> Result<String, String> {
+    let mut line = String::new();
+    match std::io::stdin().lock().read_line(&mut line) {
+        Ok(_) => match line.trim_end_matches(['\r', '\n']) {
+            "" => Err(format!("admin error: empty {}", what)),
+            secret => Ok(secret.to_string()),
+        },
+        Err(error) => Err(format!("admin error: {:?}", error)),
+    }
+}
+
+/// The secret read from stdin when `from_stdin` is set, or a generated one
+/// that is returned to be shown once.
+fn new_secret(what: &str, from_stdin: bool) -> Result<(String, Option<String>), String> {
+    let secret = if from_stdin {
+        Some(read_secret(what)?)
+    } else {
+        None
+    };
+    Ok(admin::secret_or_generated(secret))
+}
+
+fn export<T: Serialize>(
+    records: &[T],
+    format: Format,
+    output: Option<String>,
+) -> Result<(), String> {
+    let data = match format {
+        Format::Csv => admin::write_csv(records)?,
+        Format::Json => admin::write_json(records)? + "\n",
+    };
+    match output {
+        Some(path) => {
+            std::fs::write(&path, data).map_err(|error| format!("admin error: {:?}", error))
+        }
+        None => {
+            print!("{}", data);
+            Ok(())
+        }
+    }
+}
+
+fn read_records<T: DeserializeOwned>(file: &str, format: Format) -> Result<Vec<T>, String> {
+    let data =
+        std::fs::read_to_string(file).map_err(|error| format!("admin error: {:?}", error))?;
+    match format {
+        Format::Csv => admin::read_csv(&data),
+        Format::Json => admin::read_json(&data),
+    }
+}
+
+fn print_imported(imported: Vec<Imported>) {
+    for imported in imported {
+        match imported {
+            Imported::Created {
+                name,
+                client_id,
+                key,
+            } => {
+                let client_id = client_id
+                    .map(|client_id| format!(" with client id {}", client_id))
+                    .unwrap_or_default();
+                match key {
+                    Some(key) => {
+                        println!("created {}{}, generated secret {}", name, client_id, key)
+                    }
+                    None => println!("created {}{}", name, client_id),
+                }
+            }
+            Imported::Skipped { name } => println!("skipped {}, it is taken", name),
+        }
+    }
+}
+
+fn status(disabled: bool) -> &'static str {
+    if disabled {
+        "disabled"
+    } else {
+        "enabled"
+    }
+}
+
+async fn run_user(user: &User, command: UserCommand) -> Result<(), String> {
+    match command {
+        UserCommand::Create {
+            name,
+            username,
+            password_stdin,
+        } => {
+            let (secret, generated) = new_secret("password", password_stdin)?;
+            let data = user.create(&name, &username, &secret).await?;
+            println!("created {}", data.username);
+            if let Some(password) = generated {
+                println!("password {}", password);
+            }
+        }
+        UserCommand::List => {
+            let records = admin::export_users(user, false).await?;
+            println!("{:<20}  {:<20}  status", "username", "name");
+            for record in records {
+                println!(
+                    "{:<20}  {:<20}  {}",
+                    record.username,
+                    record.name,
+                    status(record.disabled)
+                );
+            }
+        }
+        UserCommand::Disable { username } => {
+            user.set_disabled(&username, true).await?;
+            println!("disabled {}", username);
+        }
+        UserCommand::Enable { username } => {
+            user.set_disabled(&username, false).await?;
+            println!("enabled {}", username);
+        }
+        UserCommand::Delete { username } => {
+            user.delete(&username).await?;
+            println!("deleted {}", username);
+        }
+        UserCommand::ResetPassword { username } => {
+            let password = read_secret("password")?;
+            user.set_password(&username, &password).await?;
+            println!("password of {} reset", username);
+        }
+        UserCommand::Export {
+            format,
+            output,
+            with_hashes,
+        } => {
+            export(
+                &admin::export_users(user, with_hashes).await?,
+                format,
+                output,
+            )?;
+        }
+        UserCommand::Import { file, format } => {
+            let records: Vec<UserRecord> = read_records(&file, format)?;
+            print_imported(admin::import_users(user, records).await?);
+        }
+    }
+    Ok(())
+}
+
+async fn run_device(device: &Device, user: &User, command: DeviceCommand) -> Result<(), String> {
+    match command {
+        DeviceCommand::Create {
+            serial,
+            name,
+            owner,
+            kind,
+            protocol,
+            key_stdin,
+            client_id,
+        } => {
+            let owner = user.get_by_username(&owner).await?;
+            let (secret, generated) = new_secret("device key", key_stdin)?;
+            let data = DeviceData {
+                id: None,
+                serial,
+                name,
+                kind,
+                owner: owner.id.ok_or("admin error: owner has no id")?,
+                protocol,
+                credentials: String::new(),
+                client_id: client_id.unwrap_or_default(),
+                disabled: false,
+            };
+            let data = device.create(data, &secret).await?;
+            println!("created {} with client id {}", data.serial, data.client_id);
+            if let Some(key) = generated {
+                println!("device key {}", key);
+            }
+        }
+        DeviceCommand::List { owner } => {
+            let records: Vec<DeviceRecord> = admin::export_devices(device, user, false)
+                .await?
+                .into_iter()
+                .filter(|record| owner.as_ref().is_none_or(|owner| &record.owner == owner))
+                .collect();
+            println!(
+                "{:>10}  {:<20}  {:<20}  {:<20}  status",
+                "client_id", "serial", "name", "owner"
+            );
+            for record in records {
+                println!(
+                    "{:>10}  {:<20}  {:<20}  {:<20}  {}",
+                    record.client_id.unwrap_or_default(),
+                    record.serial,
+                    record.name,
+                    record.owner,
+                    status(record.disabled)
+                );
+            }
+        }
+        DeviceCommand::Disable { serial } => {
+            device.set_disabled(&serial, true).await?;
+            println!("disabled {}", serial);
+        }
+        DeviceCommand::Enable { serial } => {
+            device.set_disabled(&serial, false).await?;
+            println!("enabled {}", serial);
+        }
+        DeviceCommand::Delete { serial } => {
+            device.delete(&serial).await?;
+            println!("deleted {}", serial);
+        }
+        DeviceCommand::RotateKey { serial } => {
+            let key = device.rotate_key(&serial).await?;
+            println!("device key {}", key);
+        }
+        DeviceCommand::Purge { serial } => {
+            device.purge_history(&serial).await?;
+            println!("purged the history of {}", serial);
+        }
+        DeviceCommand::Export {
+            format,
+            output,
+            with_hashes,
+        } => {
+            export(
+                &admin::export_devices(device, user, with_hashes).await?,
+                format,
+                output,
+            )?;
+        }
+        DeviceCommand::Import { file, format } => {
+            let records: Vec<DeviceRecord> = read_records(&file, format)?;
+            print_imported(admin::import_devices(device, user, records).await?);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    let args = Args::parse();
+    let config = Config::load_with(args.config_path, &args.overrides).await?;
+    let store = store::connect(&config.database).await?;
+    let user = User::new(store.clone());
+    match args.command {
+        Command::User(command) => run_user(&user, command).await,
+        Command::Device(command) => run_device(&Device::new(store), &user, command).await,
+        Command::Prune => {
+            let report = Retention::new(store, config.server.retention)
+                .run_once(chrono::Utc::now())
+                .await?;
+            println!(
+                "pruned {} positions, downsampled {} positions, pruned {} heartbeats",
+                report.positions_pruned, report.positions_downsampled, report.heartbeats_pruned
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_args {
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let args = Args::try_parse_from([
+            "gps-admin",
+            "--set",
+            "database.engine=sqlite",
+            "--set",
+            "database.path=gps.sqlite",
+            "user",
+            "list",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.overrides,
+            ["database.engine=sqlite", "database.path=gps.sqlite"]
+        );
+
+        // After the subcommand it is refused rather than replacing the others.
+        assert!(Args::try_parse_from([
+            "gps-admin",
+            "--set",
+            "database.engine=sqlite",
+            "user",
+            "list",
+            "--set",
+            "database.path=gps.sqlite",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_secrets_from_stdin() {
+        let args = Args::try_parse_from([
+            "gps-admin",
+            "device",
+            "create",
+            "--serial",
+            "root",
+            "--name",
+            "Root",
+            "--owner",
+            "root",
+            "--key-stdin",
+        ])
+        .unwrap();
+        assert!(matches!(
+            args.command,
+            Command::Device(DeviceCommand::Create {
+                key_stdin: true,
+                ..
+            })
+        ));
+        // Secrets are never taken as arguments.
+        for args in [
+            [
+                "user",
+                "create",
+                "--name",
+                "Root",
+                "--username",
+                "root",
+                "--password",
+                "x",
+            ]
+            .as_slice(),
+            ["user", "reset-password", "root", "--password", "x"].as_slice(),
+            [
+                "device", "create", "--serial", "root", "--name", "Root", "--owner", "root",
+                "--key", "x",
+            ]
+            .as_slice(),
+        ] {
+            let argv = std::iter::once("gps-admin").chain(args.iter().copied());
+            assert!(Args::try_parse_from(argv).is_err(), "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use clap::{Parser, Subcommand};
+use gps_tracker::config::{Config, DatabaseEngine};
+use gps_tracker::db::Db;
+use gps_tracker::migrations::{self, MIGRATIONS};
+
+/// Applies the versioned SurrealDB schema migrations.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Config file, `config.toml` by default. `APP_CONFIG_PATH` takes precedence.
+    #[arg(short, long, global = true)]
+    config_path: Option<String>,
+    /// Overrides a config key, e.g. `--set database.host=10.0.0.5:8080`.
+    /// Only accepted before the subcommand.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Prints the database version and the pending migrations.
+    Status,
+    /// Applies the pending migrations.
+    Up,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    let args = Args::parse();
+    let config = Config::load_with(args.config_path, &args.overrides).await?;
+    if matches!(
+        config.database.engine,
+        DatabaseEngine::Sqlite | DatabaseEngine::Postgres
+    ) {
+        return Err(format!(
+            "migrations apply to SurrealDB only; the {:?} store creates its tables on start",
+            config.database.engine
+        ));
+    }
+    let db = Db::connect(&config.database).await?;
+    match args.command {
+        Command::Status => {
+            let current = migrations::current_version(&db).await?;
+            println!(
+                "database version {}, latest {}",
+                current,
+                migrations::latest_version()
+            );
+            for migration in migrations::pending(current)? {
+                println!("pending {:04} {}", migration.version, migration.name);
+            }
+        }
+        Command::Up => {
+            let applied = migrations::migrate(&db).await?;
+            for version in &applied {
+                let migration = &MIGRATIONS[*version as usize - 1];
+                println!("applied {:04} {}", migration.version, migration.name);
+            }
+            if applied.is_empty() {
+                println!("database is up to date");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_args {
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let args = Args::try_parse_from([
+            "gps-migrate",
+            "--set",
+            "database.engine=sqlite",
+            "--set",
+            "database.path=gps.sqlite",
+            "status",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.overrides,
+            ["database.engine=sqlite", "database.path=gps.sqlite"]
+        );
+
+        // After the subcommand it is refused rather than replacing the others.
+        assert!(Args::try_parse_from([
+            "gps-migrate",
+            "--set",
+            "database.engine=sqlite",
+            "status",
+            "--set",
+            "database.path=gps.sqlite",
+        ])
+        .is_err());
+    }
+}
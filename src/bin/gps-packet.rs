@@ -0,0 +1,183 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use gps_tracker::actions::{Coordinates, Heartbeat, Login, Logout};
+use gps_tracker::config::DEFAULT_MAX_DATAGRAM_SIZE;
+use gps_tracker::decode::{self, Decoded};
+use gps_tracker::payload::Payload;
+use gps_tracker::RequestPacket;
+use std::io::Write;
+use std::path::Path;
+
+/// Builds tracker packets and decodes them with the server's codecs.
+#[derive(Debug, Parser)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Builds a request packet.
+    Encode {
+        #[command(subcommand)]
+        packet: Packet,
+        #[arg(short, long, value_enum, default_value_t = Format::Hex, global = true)]
+        format: Format,
+        /// File to write to instead of stdout.
+        #[arg(short, long, global = true)]
+        output: Option<String>,
+    },
+    /// Prints every field of a packet given as a file or a hex string.
+    Decode {
+        /// `.bin` file, or hex such as "03 00 04 00 00 5F F4".
+        input: String,
+        /// Decodes a response sent by the server instead of a request.
+        #[arg(short, long)]
+        response: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum Packet {
+    Login {
+        #[arg(short, long)]
+        username: String,
+        #[arg(short, long)]
+        password: String,
+    },
+    Heartbeat {
+        #[arg(short, long)]
+        client_id: u32,
+    },
+    Coordinates {
+        #[arg(short, long)]
+        client_id: u32,
+        #[arg(long, allow_negative_numbers = true)]
+        latitude: f64,
+        #[arg(long, allow_negative_numbers = true)]
+        longitude: f64,
+    },
+    Logout {
+        #[arg(short, long)]
+        client_id: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    /// Space separated hex, as used in the README.
+    Hex,
+    /// Raw bytes, ready for `nc -u`.
+    Binary,
+}
+
+async fn encode(packet: Packet) -> Result<String, String> {
+    match packet {
+        Packet::Login { username, password } => Login::generate_payload(username, password).await,
+        Packet::Heartbeat { client_id } => Heartbeat::generate_payload(client_id).await,
+        Packet::Coordinates {
+            client_id,
+            latitude,
+            longitude,
+        } => Coordinates::generate_payload(client_id, latitude, longitude).await,
+        Packet::Logout { client_id } => Logout::generate_payload(client_id).await,
+    }
+}
+
+/// Reads `input` as a file when it exists, otherwise as hex.
+fn read_input(input: &str) -> Result<Vec<u8>, String> {
+    if Path::new(input).is_file() {
+        return std::fs::read(input).map_err(|error| format!("input error: {:?}", error));
+    }
+    let compact: String = input.split_whitespace().collect();
+    hex::decode(compact.trim_start_matches("0x"))
+        .map_err(|error| format!("input is neither a file nor hex: {:?}", error))
+}
+
+fn print_field(name: &str, value: impl std::fmt::Display) {
+    println!("{:<16}{}", format!("{}:", name), value);
+}
+
+async fn print_decoded(data: &[u8], response: bool) {
+    print_field("bytes", Payload::apply_spacing(&hex::encode(data)));
+    print_field("size", data.len());
+    if data.len() > DEFAULT_MAX_DATAGRAM_SIZE {
+        print_field(
+            "warning",
+            format!(
+                "larger than the default max_datagram_size of {}",
+                DEFAULT_MAX_DATAGRAM_SIZE
+            ),
+        );
+    }
+    if let Some(value) = data.first() {
+        print_field("type", format!("0x{:02x}", value));
+    }
+    let decoded = if response {
+        Decoded::Response(decode::decode_response(data))
+    } else {
+        if let Ok(packet) = RequestPacket::parse(data) {
+            print_field(
+                "payload length",
+                format!(
+                    "{} declared, {} received",
+                    packet.payload_length,
+                    packet.payload.len()
+                ),
+            );
+        }
+        Decoded::Request(decode::decode_request(data).await)
+    };
+    match decoded {
+        Decoded::Request(request) => {
+            print_field("request type", request.request_type);
+            if let Some(client_id) = request.client_id {
+                print_field("client id", client_id);
+            }
+            if let Some(username) = request.username {
+                print_field("username", username);
+                print_field("password", "[REDACTED]");
+            }
+            if let Some(latitude) = request.latitude {
+                print_field("latitude", latitude);
+            }
+            if let Some(longitude) = request.longitude {
+                print_field("longitude", longitude);
+            }
+            print_field("error", request.error.unwrap_or("none".to_string()));
+        }
+        Decoded::Response(response) => {
+            print_field("request type", response.request_type);
+            print_field("status", response.status);
+            if let Some(client_id) = response.client_id {
+                print_field("client id", client_id);
+            }
+            print_field("error", response.error.unwrap_or("none".to_string()));
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    match Args::parse().command {
+        Command::Encode {
+            packet,
+            format,
+            output,
+        } => {
+            let packet = encode(packet).await?;
+            let bytes = match format {
+                Format::Hex => format!("{}\n", packet).into_bytes(),
+                Format::Binary => Payload::to_binary(&packet)?,
+            };
+            let result = match output {
+                Some(path) => std::fs::write(path, bytes),
+                None => std::io::stdout().write_all(&bytes),
+            };
+            result.map_err(|error| format!("output error: {:?}", error))
+        }
+        Command::Decode { input, response } => {
+            print_decoded(&read_input(&input)?, response).await;
+            Ok(())
+        }
+    }
+}
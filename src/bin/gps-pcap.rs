@@ -0,0 +1,70 @@
+use clap::{Parser, ValueEnum};
+use gps_tracker::decode::{self, Decoded};
+use gps_tracker::pcap;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+/// Decodes the tracker datagrams of a pcap or pcapng file into a timeline.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Capture written by tcpdump, Wireshark or dumpcap.
+    file: String,
+    /// UDP port of the server; datagrams to it are requests, from it responses.
+    #[arg(short, long, default_value_t = 34256)]
+    port: u16,
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+#[derive(Debug, Serialize)]
+struct TimelineEntry {
+    timestamp: String,
+    source: String,
+    destination: String,
+    #[serde(flatten)]
+    packet: Decoded,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    let args = Args::parse();
+    let data = std::fs::read(&args.file).map_err(|error| format!("pcap error: {:?}", error))?;
+    let mut timeline: Vec<TimelineEntry> = Vec::new();
+    for datagram in pcap::read_datagrams(&data)? {
+        let packet = if datagram.destination.port() == args.port {
+            Decoded::Request(decode::decode_request(&datagram.payload).await)
+        } else if datagram.source.port() == args.port {
+            Decoded::Response(decode::decode_response(&datagram.payload))
+        } else {
+            continue;
+        };
+        timeline.push(TimelineEntry {
+            timestamp: datagram
+                .timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            source: datagram.source.to_string(),
+            destination: datagram.destination.to_string(),
+            packet,
+        });
+    }
+    match args.format {
+        Format::Text => {
+            for entry in timeline.iter() {
+                println!(
+                    "{} {} -> {} {}",
+                    entry.timestamp, entry.source, entry.destination, entry.packet
+                );
+            }
+        }
+        Format::Json => match serde_json::to_string_pretty(&timeline) {
+            Ok(output) => println!("{}", output),
+            Err(error) => return Err(format!("json error: {:?}", error)),
+        },
+    }
+    Ok(())
+}
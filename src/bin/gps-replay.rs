@@ -0,0 +1,53 @@
+use clap::Parser;
+use gps_tracker::capture::{self, ReplayOptions};
+use std::time::Duration;
+
+/// Resends the datagrams of a capture file to a server and compares the
+/// responses with the recorded ones.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Capture file written by the server with `[server.capture]` enabled.
+    capture: String,
+    /// Server to send the datagrams to.
+    #[arg(short, long, default_value = "127.0.0.1:34256")]
+    target: String,
+    /// Timing scale: 1 keeps the recorded timing, 2 is twice as fast, 0 sends
+    /// without delays.
+    #[arg(short, long, default_value_t = 1.0)]
+    speed: f64,
+    /// Milliseconds waited for each expected response.
+    #[arg(long, default_value_t = 1000)]
+    timeout_ms: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), String> {
+    let args = Args::parse();
+    if !args.speed.is_finite() || args.speed < 0.0 {
+        return Err(format!("speed must be 0 or more, got {}", args.speed));
+    }
+    let records = capture::read(&args.capture)?;
+    let exchanges = capture::exchanges(&records)?;
+    let options = ReplayOptions {
+        target: args.target,
+        speed: args.speed,
+        response_timeout: Duration::from_millis(args.timeout_ms),
+    };
+    let results = capture::replay(exchanges, &options).await?;
+    let mut differing: usize = 0;
+    for (index, result) in results.iter().enumerate() {
+        if let Some(diff) = result.diff() {
+            differing += 1;
+            println!("#{} {}", index, diff);
+        }
+    }
+    println!(
+        "replayed {} requests, {} with different responses",
+        results.len(),
+        differing
+    );
+    if differing > 0 {
+        return Err(format!("{} responses differ from the capture", differing));
+    }
+    Ok(())
+}
@@ -0,0 +1,498 @@
+use crate::config::CaptureConfig;
+use crate::request::RequestType;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Datagram received by the server.
+    Received,
+    /// Response sent by the server.
+    Sent,
+}
+
+/// A single datagram in a capture file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    /// RFC 3339 time with microseconds.
+    pub timestamp: String,
+    pub direction: Direction,
+    /// Address of the device the datagram came from or went to.
+    pub peer: String,
+    /// Datagram bytes, hex encoded.
+    pub data: String,
+}
+
+impl CaptureRecord {
+    pub fn new(direction: Direction, peer: SocketAddr, data: &[u8]) -> Self {
+        Self {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            direction,
+            peer: peer.to_string(),
+            data: hex::encode(data),
+        }
+    }
+
+    pub fn bytes(&self) -> Result<Vec<u8>, String> {
+        hex::decode(&self.data).map_err(|error| format!("capture data error: {:?}", error))
+    }
+
+    pub fn time(&self) -> Result<DateTime<Utc>, String> {
+        match DateTime::parse_from_rfc3339(&self.timestamp) {
+            Ok(time) => Ok(time.with_timezone(&Utc)),
+            Err(error) => Err(format!("capture timestamp error: {:?}", error)),
+        }
+    }
+}
+
+/// Records waiting for the capture writer. Once it falls this far behind,
+/// new records are dropped rather than slowing requests down.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Appends every datagram the server receives and sends to a capture file.
+/// Records are written by a separate task, so request handling never waits
+/// on the disk.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    path: PathBuf,
+    sender: mpsc::Sender<String>,
+    /// Whether login keys are masked before they are written.
+    redact: bool,
+}
+
+impl Recorder {
+    /// Opens the capture file for appending, creating it readable by its
+    /// owner only and its directory if needed, and spawns the task writing
+    /// to it. The task ends once every `Recorder` clone is dropped and the
+    /// queued records are written.
+    pub fn spawn(config: &CaptureConfig) -> Result<(Self, JoinHandle<()>), String> {
+        let path = PathBuf::from(&config.path);
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            if let Err(error) = fs::create_dir_all(parent) {
+                return Err(format!("capture error: {:?}", error));
+            }
+        }
+        let mut options = File::options();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = match options.open(&path) {
+            Ok(file) => tokio::fs::File::from_std(file),
+            Err(error) => return Err(format!("capture error: {:?}", error)),
+        };
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let task = tokio::spawn(Self::write(path.clone(), file, receiver));
+        Ok((
+            Self {
+                path,
+                sender,
+                redact: !config.include_credentials,
+            },
+            task,
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queues a datagram. Failures are logged, never returned, so recording
+    /// cannot break request handling.
+    pub fn record(&self, direction: Direction, peer: SocketAddr, data: &[u8]) {
+        let record = match direction {
+            Direction::Received if self.redact => {
+                CaptureRecord::new(direction, peer, &redact_login(data))
+            }
+            _ => CaptureRecord::new(direction, peer, data),
+        };
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(error) => {
+                warn!(%error, "unable to encode capture record");
+                return;
+            }
+        };
+        line.push('\n');
+        if let Err(error) = self.sender.try_send(line) {
+            warn!(%error, path = %self.path.display(), "capture record dropped");
+        }
+    }
+
+    async fn write(path: PathBuf, mut file: tokio::fs::File, mut receiver: mpsc::Receiver<String>) {
+        while let Some(line) = receiver.recv().await {
+            if let Err(error) = file.write_all(line.as_bytes()).await {
+                warn!(%error, path = %path.display(), "unable to write capture record");
+            }
+        }
+        if let Err(error) = file.flush().await {
+            warn!(%error, path = %path.display(), "unable to write capture record");
+        }
+    }
+}
+
+/// Masks the device key of a login packet with `*`, keeping its length so
+/// the packet still parses. Other packets are returned unchanged.
+fn redact_login(data: &[u8]) -> Cow<'_, [u8]> {
+    if data.first() != Some(&RequestType::Login.to_value()) {
+        return Cow::Borrowed(data);
+    }
+    let mut redacted = data.to_vec();
+    if let Some(payload) = redacted.get_mut(3..) {
+        if let Some(separator) = payload.iter().position(|value| *value == 0) {
+            payload[separator + 1..].fill(b'*');
+        }
+    }
+    Cow::Owned(redacted)
+}
+
+/// Reads every record of a capture file.
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<CaptureRecord>, String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) => return Err(format!("capture error: {:?}", error)),
+    };
+    let mut records: Vec<CaptureRecord> = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|error| format!("capture error: {:?}", error))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<CaptureRecord>(&line) {
+            Ok(record) => records.push(record),
+            Err(error) => {
+                return Err(format!(
+                    "capture error: line {}: {:?}",
+                    number + 1,
+                    error.to_string()
+                ))
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// A received datagram and the responses the server sent back for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exchange {
+    /// Time since the first received datagram of the capture.
+    pub offset: Duration,
+    pub peer: String,
+    pub request: Vec<u8>,
+    pub expected: Vec<Vec<u8>>,
+}
+
+/// Pairs each received datagram with the responses sent to the same peer
+/// before its next datagram, which is how the server handles them.
+pub fn exchanges(records: &[CaptureRecord]) -> Result<Vec<Exchange>, String> {
+    let mut exchanges: Vec<Exchange> = Vec::new();
+    let mut last_by_peer: HashMap<&str, usize> = HashMap::new();
+    let mut start: Option<DateTime<Utc>> = None;
+    for record in records {
+        match record.direction {
+            Direction::Received => {
+                let time = record.time()?;
+                let start = *start.get_or_insert(time);
+                last_by_peer.insert(record.peer.as_str(), exchanges.len());
+                exchanges.push(Exchange {
+                    offset: (time - start).to_std().unwrap_or_default(),
+                    peer: record.peer.clone(),
+                    request: record.bytes()?,
+                    expected: Vec::new(),
+                });
+            }
+            Direction::Sent => match last_by_peer.get(record.peer.as_str()) {
+                Some(index) => exchanges[*index].expected.push(record.bytes()?),
+                None => warn!(peer = %record.peer, "response without a request, skipped"),
+            },
+        }
+    }
+    Ok(exchanges)
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// Server the datagrams are sent to.
+    pub target: String,
+    /// Timing scale: `1` keeps the original timing, `2` halves the delays,
+    /// `0` sends as fast as responses come back.
+    pub speed: f64,
+    /// Time waited for each expected response.
+    pub response_timeout: Duration,
+}
+
+/// Outcome of replaying one exchange.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayResult {
+    pub exchange: Exchange,
+    pub actual: Vec<Vec<u8>>,
+}
+
+impl ReplayResult {
+    pub fn matches(&self) -> bool {
+        self.exchange.expected == self.actual
+    }
+
+    /// Describes how the responses differ from the recorded ones, or `None`
+    /// when they match.
+    pub fn diff(&self) -> Option<String> {
+        if self.matches() {
+            return None;
+        }
+        let mut lines: Vec<String> = vec![format!(
+            "request from {} at {:?}: {}",
+            self.exchange.peer,
+            self.exchange.offset,
+            hex::encode(&self.exchange.request)
+        )];
+        let count = self.exchange.expected.len().max(self.actual.len());
+        for index in 0..count {
+            let expected = self.exchange.expected.get(index);
+            let actual = self.actual.get(index);
+            if expected == actual {
+                continue;
+            }
+            let show = |bytes: Option<&Vec<u8>>| match bytes {
+                Some(bytes) => hex::encode(bytes),
+                None => "<none>".to_string(),
+            };
+            let offset = match (expected, actual) {
+                (Some(expected), Some(actual)) => expected
+                    .iter()
+                    .zip(actual.iter())
+                    .position(|(left, right)| left != right)
+                    .unwrap_or(expected.len().min(actual.len())),
+                _ => 0,
+            };
+            lines.push(format!(
+                "  response {} differs at byte {}\n    expected: {}\n    actual:   {}",
+                index,
+                offset,
+                show(expected),
+                show(actual)
+            ));
+        }
+        Some(lines.join("\n"))
+    }
+}
+
+/// Resends the received datagrams of a capture to `options.target`, one
+/// local socket per recorded peer, and collects the responses.
+pub async fn replay(
+    exchanges: Vec<Exchange>,
+    options: &ReplayOptions,
+) -> Result<Vec<ReplayResult>, String> {
+    let target = match tokio::net::lookup_host(options.target.as_str()).await {
+        Ok(mut addresses) => match addresses.next() {
+            Some(address) => address,
+            None => {
+                return Err(format!(
+                    "replay error: unable to resolve {}",
+                    options.target
+                ))
+            }
+        },
+        Err(error) => return Err(format!("replay error: {:?}", error)),
+    };
+    let local = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let mut sockets: HashMap<String, UdpSocket> = HashMap::new();
+    let mut last_by_peer: HashMap<String, usize> = HashMap::new();
+    let mut results: Vec<ReplayResult> = Vec::with_capacity(exchanges.len());
+    let mut buf = vec![0; u16::MAX as usize];
+    let start = Instant::now();
+    for exchange in exchanges {
+        if options.speed > 0.0 {
+            tokio::time::sleep_until(start + exchange.offset.div_f64(options.speed)).await;
+        }
+        if !sockets.contains_key(&exchange.peer) {
+            let socket = UdpSocket::bind(local)
+                .await
+                .map_err(|error| format!("replay error: {:?}", error))?;
+            socket
+                .connect(target)
+                .await
+                .map_err(|error| format!("replay error: {:?}", error))?;
+            sockets.insert(exchange.peer.clone(), socket);
+        }
+        let socket = &sockets[&exchange.peer];
+        // Late responses belong to the previous request from this peer.
+        if let Some(previous) = last_by_peer.get(&exchange.peer) {
+            while let Ok(size) = socket.try_recv(&mut buf) {
+                results[*previous].actual.push(buf[..size].to_vec());
+            }
+        }
+        if let Err(error) = socket.send(&exchange.request).await {
+            return Err(format!("replay error: {:?}", error));
+        }
+        let mut actual: Vec<Vec<u8>> = Vec::new();
+        while actual.len() < exchange.expected.len() {
+            match tokio::time::timeout(options.response_timeout, socket.recv(&mut buf)).await {
+                Ok(Ok(size)) => actual.push(buf[..size].to_vec()),
+                Ok(Err(error)) => return Err(format!("replay error: {:?}", error)),
+                Err(_) => break,
+            }
+        }
+        last_by_peer.insert(exchange.peer.clone(), results.len());
+        results.push(ReplayResult { exchange, actual });
+    }
+    tokio::time::sleep(options.response_timeout.min(Duration::from_millis(100))).await;
+    for (peer, index) in last_by_peer {
+        while let Ok(size) = sockets[&peer].try_recv(&mut buf) {
+            results[index].actual.push(buf[..size].to_vec());
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod test_capture {
+    use super::*;
+
+    /// Answers every datagram with its bytes reversed, except `0xff` which
+    /// gets no answer.
+    async fn reverse_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            while let Ok((size, peer)) = socket.recv_from(&mut buf).await {
+                if buf[..size] == [0xff] {
+                    continue;
+                }
+                let response: Vec<u8> = buf[..size].iter().rev().copied().collect();
+                let _ = socket.send_to(&response, peer).await;
+            }
+        });
+        address
+    }
+
+    fn path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("gps-tracker-{}-{}.jsonl", name, std::process::id()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_record_and_read() {
+        let path = path("capture");
+        let _ = fs::remove_file(&path);
+        let (recorder, task) = Recorder::spawn(&CaptureConfig {
+            enabled: true,
+            path: path.clone(),
+            include_credentials: false,
+        })
+        .unwrap();
+        let first: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        recorder.record(Direction::Received, first, &[1, 2]);
+        recorder.record(Direction::Received, second, &[3]);
+        recorder.record(Direction::Sent, first, &[2, 1]);
+        recorder.record(Direction::Sent, second, &[3]);
+        recorder.record(Direction::Received, first, &[0xff]);
+        recorder.record(Direction::Received, first, b"\x01\x00\x08root\0key");
+        drop(recorder);
+        task.await.unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let records = read(&path).unwrap();
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[0].bytes().unwrap(), vec![1, 2]);
+        // The key of the login is masked, its username kept.
+        assert_eq!(
+            records[5].bytes().unwrap(),
+            b"\x01\x00\x08root\0***".to_vec()
+        );
+        let exchanges = exchanges(&records).unwrap();
+        assert_eq!(exchanges.len(), 4);
+        assert_eq!(exchanges[0].expected, vec![vec![2, 1]]);
+        assert_eq!(exchanges[1].expected, vec![vec![3]]);
+        assert!(exchanges[2].expected.is_empty());
+        assert!(exchanges[2].offset >= exchanges[1].offset);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_record_credentials() {
+        let path = path("capture-credentials");
+        let _ = fs::remove_file(&path);
+        let (recorder, task) = Recorder::spawn(&CaptureConfig {
+            enabled: true,
+            path: path.clone(),
+            include_credentials: true,
+        })
+        .unwrap();
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        recorder.record(Direction::Received, peer, b"\x01\x00\x08root\0key");
+        drop(recorder);
+        task.await.unwrap();
+
+        let records = read(&path).unwrap();
+        assert_eq!(
+            records[0].bytes().unwrap(),
+            b"\x01\x00\x08root\0key".to_vec()
+        );
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_replay_diffs_responses() {
+        let target = reverse_server().await;
+        let exchange = |request: Vec<u8>, expected: Vec<Vec<u8>>| Exchange {
+            offset: Duration::ZERO,
+            peer: "127.0.0.1:5000".to_string(),
+            request,
+            expected,
+        };
+        let exchanges = vec![
+            exchange(vec![1, 2, 3], vec![vec![3, 2, 1]]),
+            exchange(vec![4, 5], vec![vec![4, 5]]),
+            exchange(vec![0xff], vec![]),
+            exchange(vec![6], vec![]),
+        ];
+        let options = ReplayOptions {
+            target: target.to_string(),
+            speed: 0.0,
+            response_timeout: Duration::from_millis(500),
+        };
+        let results = replay(exchanges, &options).await.unwrap();
+        assert!(results[0].matches());
+        assert!(results[0].diff().is_none());
+        assert!(!results[1].matches());
+        assert!(results[1]
+            .diff()
+            .unwrap()
+            .contains("differs at byte 0\n    expected: 0405\n    actual:   0504"));
+        assert!(results[2].matches());
+        // An unexpected response is reported as well.
+        assert_eq!(results[3].actual, vec![vec![6]]);
+        assert!(!results[3].matches());
+    }
+}
@@ -1,56 +1,1020 @@
-use serde::Deserialize;
+use crate::logging::{self, Redacted};
+use serde::{Deserialize, Serialize};
 use std::env;
-use std::{fs::File, io::Read};
-#[derive(Debug, Clone, Deserialize)]
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use toml::{Table, Value};
+/// Largest datagram accepted by default, header included.
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServerConfig {
+    #[serde(default = "ServerConfig::default_host")]
     pub host: String,
+    /// Largest datagram accepted, header included. Longer ones are rejected.
+    #[serde(default = "default_max_datagram_size")]
+    pub max_datagram_size: usize,
+    /// Time allowed to drain queued packets after a shutdown signal.
+    #[serde(default = "default_shutdown_grace_period_secs")]
+    pub shutdown_grace_period_secs: u64,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub spool: SpoolConfig,
+    #[serde(default)]
+    pub pipeline: PipelineConfig,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    /// Address serving `/metrics` from the UDP server process, if set. This is
+    /// the scrape target for packet, validation and rate limit metrics; the
+    /// API's `/metrics` only has the API process's own.
+    #[serde(default)]
+    pub metrics_host: Option<String>,
+    /// How often the config file is checked for changes; `0` only reloads on SIGHUP.
+    #[serde(default = "default_config_reload_interval_ms")]
+    pub config_reload_interval_ms: u64,
+    /// How long a client id keeps resolving to its device without a lookup;
+    /// `0` looks every packet up.
+    #[serde(default = "ServerConfig::default_device_cache_ttl_secs")]
+    pub device_cache_ttl_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: Self::default_host(),
+            max_datagram_size: default_max_datagram_size(),
+            shutdown_grace_period_secs: default_shutdown_grace_period_secs(),
+            rate_limit: RateLimitConfig::default(),
+            session: SessionConfig::default(),
+            spool: SpoolConfig::default(),
+            pipeline: PipelineConfig::default(),
+            capture: CaptureConfig::default(),
+            retention: RetentionConfig::default(),
+            metrics_host: None,
+            config_reload_interval_ms: default_config_reload_interval_ms(),
+            device_cache_ttl_secs: Self::default_device_cache_ttl_secs(),
+        }
+    }
+}
+
+impl ServerConfig {
+    fn default_host() -> String {
+        "127.0.0.1:34256".to_string()
+    }
+
+    fn default_device_cache_ttl_secs() -> u64 {
+        60
+    }
+}
+
+/// Token-bucket limits applied to incoming datagrams.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default = "RateLimitConfig::default_enabled")]
+    pub enabled: bool,
+    /// Sustained packets per second allowed for a single client id.
+    #[serde(default = "RateLimitConfig::default_client_rate_per_sec")]
+    pub client_rate_per_sec: f64,
+    #[serde(default = "RateLimitConfig::default_client_burst")]
+    pub client_burst: u32,
+    /// Sustained packets per second allowed for a single source IP.
+    #[serde(default = "RateLimitConfig::default_source_rate_per_sec")]
+    pub source_rate_per_sec: f64,
+    #[serde(default = "RateLimitConfig::default_source_burst")]
+    pub source_burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            client_rate_per_sec: Self::default_client_rate_per_sec(),
+            client_burst: Self::default_client_burst(),
+            source_rate_per_sec: Self::default_source_rate_per_sec(),
+            source_burst: Self::default_source_burst(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+
+    fn default_client_rate_per_sec() -> f64 {
+        5.0
+    }
+
+    fn default_client_burst() -> u32 {
+        10
+    }
+
+    fn default_source_rate_per_sec() -> f64 {
+        20.0
+    }
+
+    fn default_source_burst() -> u32 {
+        40
+    }
+}
+
+/// Device session tracking.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionConfig {
+    /// Seconds without a heartbeat or position after which a device is offline.
+    #[serde(default = "SessionConfig::default_heartbeat_timeout_secs")]
+    pub heartbeat_timeout_secs: u64,
+    /// Session events buffered for slow subscribers before they start lagging.
+    #[serde(default = "SessionConfig::default_event_capacity")]
+    pub event_capacity: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_timeout_secs: Self::default_heartbeat_timeout_secs(),
+            event_capacity: Self::default_event_capacity(),
+        }
+    }
+}
+
+impl SessionConfig {
+    fn default_heartbeat_timeout_secs() -> u64 {
+        30
+    }
+
+    fn default_event_capacity() -> usize {
+        1024
+    }
+}
+
+/// Local store-and-forward spool for fixes accepted while SurrealDB is down.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpoolConfig {
+    #[serde(default = "SpoolConfig::default_enabled")]
+    pub enabled: bool,
+    /// Append-only file holding the fixes waiting to be replayed.
+    #[serde(default = "SpoolConfig::default_path")]
+    pub path: String,
+    /// Size above which new fixes are rejected instead of spooled.
+    #[serde(default = "SpoolConfig::default_max_bytes")]
+    pub max_bytes: u64,
+    /// Fixes written per second while replaying; `0` disables the limit.
+    #[serde(default = "SpoolConfig::default_replay_rate_per_sec")]
+    pub replay_rate_per_sec: f64,
+    /// How often the database is checked for recovery while fixes are pending.
+    #[serde(default = "SpoolConfig::default_replay_interval_ms")]
+    pub replay_interval_ms: u64,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            path: Self::default_path(),
+            max_bytes: Self::default_max_bytes(),
+            replay_rate_per_sec: Self::default_replay_rate_per_sec(),
+            replay_interval_ms: Self::default_replay_interval_ms(),
+        }
+    }
+}
+
+impl SpoolConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+
+    fn default_path() -> String {
+        "spool/coordinates.jsonl".to_string()
+    }
+
+    fn default_max_bytes() -> u64 {
+        64 * 1024 * 1024
+    }
+
+    fn default_replay_rate_per_sec() -> f64 {
+        100.0
+    }
+
+    fn default_replay_interval_ms() -> u64 {
+        1000
+    }
+}
+
+/// Batching of the coordinates and heartbeat writes. Only used while the
+/// spool is enabled; otherwise every record is stored before its answer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PipelineConfig {
+    /// Records written in a single insert at most.
+    #[serde(default = "PipelineConfig::default_batch_size")]
+    pub batch_size: usize,
+    /// Longest time a record waits for its batch to fill up.
+    #[serde(default = "PipelineConfig::default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+    /// Records queued before handlers wait for the writer to catch up.
+    #[serde(default = "PipelineConfig::default_channel_capacity")]
+    pub channel_capacity: usize,
+    /// Attempts made to write a batch before spooling or dropping it.
+    #[serde(default = "PipelineConfig::default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: Self::default_batch_size(),
+            flush_interval_ms: Self::default_flush_interval_ms(),
+            channel_capacity: Self::default_channel_capacity(),
+            max_attempts: Self::default_max_attempts(),
+        }
+    }
+}
+
+impl PipelineConfig {
+    fn default_batch_size() -> usize {
+        100
+    }
+
+    fn default_flush_interval_ms() -> u64 {
+        50
+    }
+
+    fn default_channel_capacity() -> usize {
+        10_000
+    }
+
+    fn default_max_attempts() -> u32 {
+        3
+    }
+}
+
+/// Recording of every datagram received and sent, for replaying later.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CaptureConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// File the packets are appended to, one JSON record per line, readable
+    /// by its owner only.
+    #[serde(default = "CaptureConfig::default_path")]
+    pub path: String,
+    /// Keeps the device keys of login packets, which are masked otherwise.
+    /// Replaying a masked login fails to authenticate.
+    #[serde(default)]
+    pub include_credentials: bool,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: Self::default_path(),
+            include_credentials: false,
+        }
+    }
+}
+
+impl CaptureConfig {
+    fn default_path() -> String {
+        "captures/packets.jsonl".to_string()
+    }
+}
+
+/// How long positions and heartbeats are kept. `0` keeps them forever.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Days raw positions are kept before being downsampled, or deleted
+    /// when `downsample_interval_secs` is `0`.
+    #[serde(default)]
+    pub positions_days: u64,
+    /// Older positions keep the first one of every interval of this length.
+    #[serde(default)]
+    pub downsample_interval_secs: u64,
+    /// Days after which downsampled positions are deleted too.
+    #[serde(default)]
+    pub downsample_days: u64,
+    #[serde(default)]
+    pub heartbeats_days: u64,
+}
+
+/// Devices following their own retention policy: those of any of `kinds`,
+/// owned by any of `owners` (usernames), or whose serial is in `serials`.
+/// The policy replaces the default one as a whole.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetentionGroup {
+    pub name: String,
+    #[serde(default)]
+    pub kinds: Vec<String>,
+    #[serde(default)]
+    pub owners: Vec<String>,
+    #[serde(default)]
+    pub serials: Vec<String>,
+    #[serde(flatten)]
+    pub policy: RetentionPolicy,
+}
+
+/// Background pruning and downsampling of old positions and heartbeats.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Time between two runs of the job.
+    #[serde(default = "RetentionConfig::default_interval_secs")]
+    pub interval_secs: u64,
+    /// Rows deleted by a single statement at most.
+    #[serde(default = "RetentionConfig::default_batch_size")]
+    pub batch_size: usize,
+    /// Policy of the devices in no group.
+    #[serde(default)]
+    pub default: RetentionPolicy,
+    /// Checked in order; a device follows the first group it belongs to.
+    #[serde(default)]
+    pub groups: Vec<RetentionGroup>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: Self::default_interval_secs(),
+            batch_size: Self::default_batch_size(),
+            default: RetentionPolicy::default(),
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl RetentionConfig {
+    fn default_interval_secs() -> u64 {
+        3600
+    }
+
+    fn default_batch_size() -> usize {
+        1000
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct DatabaseConfig {
+    #[serde(default = "DatabaseConfig::default_engine")]
+    pub engine: DatabaseEngine,
+    /// Directory of the embedded `rocksdb` and `surrealkv` engines, or the
+    /// `sqlite` database file.
+    #[serde(default = "DatabaseConfig::default_path")]
+    pub path: String,
+    #[serde(default = "DatabaseConfig::default_username")]
     pub username: String,
+    /// Has no default; set it here, through `GPS_DATABASE__PASSWORD` or read
+    /// it from a file with `password_file`.
+    #[serde(default)]
     pub password: String,
+    #[serde(default = "DatabaseConfig::default_host")]
     pub host: String,
+    #[serde(default = "DatabaseConfig::default_namespace")]
     pub namespace: String,
+    #[serde(default = "DatabaseConfig::default_database")]
     pub database: String,
+    /// Number of connections kept open and shared by every handler.
+    #[serde(default = "DatabaseConfig::default_pool_size")]
+    pub pool_size: usize,
+    /// Attempts made when opening a connection at startup.
+    #[serde(default = "DatabaseConfig::default_connect_attempts")]
+    pub connect_attempts: u32,
+    #[serde(default = "DatabaseConfig::default_health_check_interval_ms")]
+    pub health_check_interval_ms: u64,
+    #[serde(default = "DatabaseConfig::default_reconnect_initial_backoff_ms")]
+    pub reconnect_initial_backoff_ms: u64,
+    #[serde(default = "DatabaseConfig::default_reconnect_max_backoff_ms")]
+    pub reconnect_max_backoff_ms: u64,
+    /// Applies pending SurrealDB migrations when connecting. When off, the
+    /// server refuses to start until `gps-migrate up` has been run.
+    #[serde(default = "DatabaseConfig::default_migrate_on_start")]
+    pub migrate_on_start: bool,
+}
+
+impl fmt::Debug for DatabaseConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DatabaseConfig")
+            .field("engine", &self.engine)
+            .field("path", &self.path)
+            .field("username", &self.username)
+            .field("password", &Redacted(&self.password))
+            .field("host", &self.host)
+            .field("namespace", &self.namespace)
+            .field("database", &self.database)
+            .field("pool_size", &self.pool_size)
+            .field("connect_attempts", &self.connect_attempts)
+            .field("health_check_interval_ms", &self.health_check_interval_ms)
+            .field(
+                "reconnect_initial_backoff_ms",
+                &self.reconnect_initial_backoff_ms,
+            )
+            .field("reconnect_max_backoff_ms", &self.reconnect_max_backoff_ms)
+            .field("migrate_on_start", &self.migrate_on_start)
+            .finish()
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            engine: Self::default_engine(),
+            path: Self::default_path(),
+            username: Self::default_username(),
+            password: String::new(),
+            host: Self::default_host(),
+            namespace: Self::default_namespace(),
+            database: Self::default_database(),
+            pool_size: Self::default_pool_size(),
+            connect_attempts: Self::default_connect_attempts(),
+            health_check_interval_ms: Self::default_health_check_interval_ms(),
+            reconnect_initial_backoff_ms: Self::default_reconnect_initial_backoff_ms(),
+            reconnect_max_backoff_ms: Self::default_reconnect_max_backoff_ms(),
+            migrate_on_start: Self::default_migrate_on_start(),
+        }
+    }
+}
+
+impl DatabaseConfig {
+    /// Address handed to SurrealDB, e.g. `ws://127.0.0.1:8080` or
+    /// `rocksdb://gps.db`.
+    pub fn endpoint(&self) -> String {
+        match self.engine {
+            DatabaseEngine::Ws => format!("ws://{}", self.host),
+            DatabaseEngine::Wss => format!("wss://{}", self.host),
+            DatabaseEngine::Rocksdb => format!("rocksdb://{}", self.path),
+            DatabaseEngine::Surrealkv => format!("surrealkv://{}", self.path),
+            DatabaseEngine::Memory => "mem://".to_string(),
+            DatabaseEngine::Sqlite => format!("sqlite://{}", self.path),
+            DatabaseEngine::Postgres => format!("postgres://{}/{}", self.host, self.database),
+        }
+    }
+
+    fn default_engine() -> DatabaseEngine {
+        DatabaseEngine::Ws
+    }
+
+    fn default_path() -> String {
+        "gps.db".to_string()
+    }
+
+    fn default_username() -> String {
+        "root".to_string()
+    }
+
+    fn default_host() -> String {
+        "127.0.0.1:8080".to_string()
+    }
+
+    fn default_namespace() -> String {
+        "dev".to_string()
+    }
+
+    fn default_database() -> String {
+        "gps".to_string()
+    }
+
+    fn default_pool_size() -> usize {
+        4
+    }
+
+    fn default_connect_attempts() -> u32 {
+        5
+    }
+
+    fn default_health_check_interval_ms() -> u64 {
+        5000
+    }
+
+    fn default_reconnect_initial_backoff_ms() -> u64 {
+        250
+    }
+
+    fn default_reconnect_max_backoff_ms() -> u64 {
+        30000
+    }
+
+    fn default_migrate_on_start() -> bool {
+        true
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WebConfig {
+    #[serde(default = "WebConfig::default_host")]
     pub host: String,
+    #[serde(default = "WebConfig::default_port")]
     pub port: u32,
+    /// Time allowed for open connections to finish after a shutdown signal.
+    #[serde(default = "default_shutdown_grace_period_secs")]
+    pub shutdown_grace_period_secs: u64,
+    /// How long a password that passed the Argon2 check is accepted again
+    /// without one; `0` checks every request.
+    #[serde(default = "WebConfig::default_credential_cache_ttl_secs")]
+    pub credential_cache_ttl_secs: u64,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        Self {
+            host: Self::default_host(),
+            port: Self::default_port(),
+            shutdown_grace_period_secs: default_shutdown_grace_period_secs(),
+            credential_cache_ttl_secs: Self::default_credential_cache_ttl_secs(),
+        }
+    }
+}
+
+impl WebConfig {
+    fn default_host() -> String {
+        "127.0.0.1".to_string()
+    }
+
+    fn default_port() -> u32 {
+        4090
+    }
+
+    fn default_credential_cache_ttl_secs() -> u64 {
+        60
+    }
+}
+
+fn default_shutdown_grace_period_secs() -> u64 {
+    10
+}
+
+fn default_config_reload_interval_ms() -> u64 {
+    2000
+}
+
+fn default_max_datagram_size() -> usize {
+    DEFAULT_MAX_DATAGRAM_SIZE
+}
+
+/// Storage backend. The embedded SurrealDB engines are compiled in with the
+/// matching crate feature, e.g. `--features kv-rocksdb`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseEngine {
+    /// A `surreal start` process reached at `host`.
+    Ws,
+    /// Same as `ws`, over TLS.
+    Wss,
+    /// Embedded, stored in RocksDB under `path`.
+    Rocksdb,
+    /// Embedded, stored in SurrealKV under `path`.
+    Surrealkv,
+    /// Embedded and kept in memory; nothing survives a restart.
+    Memory,
+    /// SQLite file at `path` instead of SurrealDB.
+    Sqlite,
+    /// PostgreSQL with PostGIS at `host`, using `database` as the database
+    /// name.
+    Postgres,
+}
+
+impl DatabaseEngine {
+    /// Whether the database runs inside this process, so there is a single
+    /// connection and no credentials.
+    pub fn is_embedded(self) -> bool {
+        !matches!(
+            self,
+            DatabaseEngine::Ws | DatabaseEngine::Wss | DatabaseEngine::Postgres
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogConfig {
+    /// Filter directive, e.g. `info` or `gps_tracker=debug,surrealdb=warn`.
+    #[serde(default = "LogConfig::default_level")]
+    pub level: String,
+    #[serde(default = "LogConfig::default_format")]
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: Self::default_level(),
+            format: Self::default_format(),
+        }
+    }
+}
+
+impl LogConfig {
+    fn default_level() -> String {
+        "info".to_string()
+    }
+
+    fn default_format() -> LogFormat {
+        LogFormat::Pretty
+    }
+}
+
+/// Settings are layered, each layer overriding the previous one: built-in
+/// defaults, the TOML file, `GPS_` environment variables such as
+/// `GPS_DATABASE__PASSWORD` (`__` separates the sections), then
+/// `--set key=value` flags. A key ending in `_file`, such as
+/// `database.password_file`, reads the value of the key without the suffix
+/// from that file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(default)]
     pub database: DatabaseConfig,
+    #[serde(default)]
     pub server: ServerConfig,
+    #[serde(default)]
     pub web: WebConfig,
+    #[serde(default)]
+    pub log: LogConfig,
 }
 
+/// Prefix of the environment variables overriding config keys.
+const ENV_PREFIX: &str = "GPS_";
+
+/// Suffix of the keys whose value is read from the file they name.
+const FILE_SUFFIX: &str = "_file";
+
 impl Config {
-    pub async fn load(file_path: Option<String>) -> Result<Config, String> {
-        let file_path: String = if let Ok(value) = env::var("APP_CONFIG_PATH") {
+    /// Returns the config file to read: `APP_CONFIG_PATH`, then `file_path`,
+    /// then `config.toml`.
+    pub fn resolve_path(file_path: Option<String>) -> String {
+        if let Ok(value) = env::var("APP_CONFIG_PATH") {
             value
         } else if let Some(value) = file_path {
             value
         } else {
             "config.toml".to_string()
+        }
+    }
+
+    pub async fn load(file_path: Option<String>) -> Result<Config, String> {
+        Self::load_with(file_path, &[]).await
+    }
+
+    /// Loads every layer, `overrides` holding the `key=value` pairs given on
+    /// the command line. Only a file chosen through `APP_CONFIG_PATH` or
+    /// `file_path` has to exist.
+    pub async fn load_with(
+        file_path: Option<String>,
+        overrides: &[String],
+    ) -> Result<Config, String> {
+        let required = file_path.is_some() || env::var("APP_CONFIG_PATH").is_ok();
+        let file_path: String = Self::resolve_path(file_path);
+        let content = match fs::read_to_string(&file_path) {
+            Ok(content) => Some(content),
+            Err(error) if required || error.kind() != std::io::ErrorKind::NotFound => {
+                return Err(format!("config error: {:?}", error.to_string()));
+            }
+            Err(_) => None,
+        };
+        Self::from_layers(content.as_deref(), env::vars(), overrides)
+    }
+
+    /// Parses and validates a TOML document on top of the defaults.
+    pub fn parse(content: &str) -> Result<Config, String> {
+        Self::from_layers(Some(content), std::iter::empty(), &[])
+    }
+
+    /// Builds the config from the defaults, the `content` of the file, the
+    /// `GPS_` variables of `env` and the `key=value` `overrides`, then
+    /// validates it. Every problem found is reported at once.
+    pub fn from_layers(
+        content: Option<&str>,
+        env: impl IntoIterator<Item = (String, String)>,
+        overrides: &[String],
+    ) -> Result<Config, String> {
+        let mut problems: Vec<String> = Vec::new();
+        let mut table = match Table::try_from(Config::default()) {
+            Ok(table) => table,
+            Err(error) => return Err(format!("config error: {:?}", error.to_string())),
         };
-        match File::options().read(true).open(file_path) {
-            Ok(mut file) => {
-                let mut content = String::new();
-                if let Err(error) = file.read_to_string(&mut content) {
-                    return Err(format!("config error: {:?}", error.to_string()));
+        if let Some(content) = content {
+            match toml::from_str::<Table>(content) {
+                Ok(mut file) => {
+                    resolve_secret_files(&mut file, "", &mut problems);
+                    merge(&mut table, file);
                 }
+                Err(error) => return Err(format!("config error: {:?}", error.to_string())),
+            }
+        }
+        let mut env: Vec<(String, String)> = env
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let key = name.strip_prefix(ENV_PREFIX)?;
+                // Only names with a section separator are config keys.
+                key.contains("__")
+                    .then(|| (key.to_lowercase().replace("__", "."), value))
+            })
+            .collect();
+        env.sort();
+        for (key, value) in env {
+            set_key(&mut table, &key, &value, &mut problems);
+        }
+        for item in overrides {
+            match item.split_once('=') {
+                Some((key, value)) => set_key(&mut table, key.trim(), value, &mut problems),
+                None => problems.push(format!("{}: expected key=value", item)),
+            }
+        }
+        match Value::Table(table).try_into::<Config>() {
+            Ok(config) => {
+                if let Err(error) = config.validate() {
+                    problems.push(error.trim_start_matches("config error: ").to_string());
+                }
+                if problems.is_empty() {
+                    Ok(config)
+                } else {
+                    Err(format!("config error: {}", problems.join("; ")))
+                }
+            }
+            Err(error) => {
+                problems.push(error.to_string().trim().to_string());
+                Err(format!("config error: {}", problems.join("; ")))
+            }
+        }
+    }
+
+    /// Checks the values serde cannot, reporting every problem at once.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems: Vec<String> = Vec::new();
+        if let Err(error) = logging::parse_filter(&self.log.level) {
+            problems.push(format!("log.level: {}", error));
+        }
+        let rate_limit = &self.server.rate_limit;
+        for (key, value) in [
+            ("client_rate_per_sec", rate_limit.client_rate_per_sec),
+            ("source_rate_per_sec", rate_limit.source_rate_per_sec),
+        ] {
+            if !value.is_finite() || value <= 0.0 {
+                problems.push(format!(
+                    "server.rate_limit.{}: must be greater than 0, got {}",
+                    key, value
+                ));
+            }
+        }
+        check_address("server.host", &self.server.host, &mut problems);
+        if let Some(metrics_host) = &self.server.metrics_host {
+            check_address("server.metrics_host", metrics_host, &mut problems);
+        }
+        if !self.database.engine.is_embedded() {
+            check_address("database.host", &self.database.host, &mut problems);
+        }
+        if !(1..=MAX_UDP_PAYLOAD).contains(&self.server.max_datagram_size) {
+            problems.push(format!(
+                "server.max_datagram_size: must be between 1 and {}, got {}",
+                MAX_UDP_PAYLOAD, self.server.max_datagram_size
+            ));
+        }
+        if !(1..=u16::MAX as u32).contains(&self.web.port) {
+            problems.push(format!(
+                "web.port: must be between 1 and {}, got {}",
+                u16::MAX,
+                self.web.port
+            ));
+        }
+        let mut required = vec![
+            ("web.host", &self.web.host),
+            ("database.namespace", &self.database.namespace),
+            ("database.database", &self.database.database),
+        ];
+        match self.database.engine {
+            DatabaseEngine::Ws | DatabaseEngine::Wss | DatabaseEngine::Postgres => {
+                required.push(("database.username", &self.database.username));
+                required.push(("database.password", &self.database.password));
+            }
+            DatabaseEngine::Rocksdb | DatabaseEngine::Surrealkv | DatabaseEngine::Sqlite => {
+                required.push(("database.path", &self.database.path));
+            }
+            DatabaseEngine::Memory => {}
+        }
+        for (key, value) in required {
+            if value.trim().is_empty() {
+                problems.push(format!("{}: must not be empty", key));
+            }
+        }
+        if self.server.spool.enabled && self.server.spool.path.trim().is_empty() {
+            problems.push("server.spool.path: must not be empty".to_string());
+        }
+        if self.server.capture.enabled && self.server.capture.path.trim().is_empty() {
+            problems.push("server.capture.path: must not be empty".to_string());
+        }
+        let replay_rate = self.server.spool.replay_rate_per_sec;
+        if !replay_rate.is_finite() || replay_rate < 0.0 {
+            problems.push(format!(
+                "server.spool.replay_rate_per_sec: must be 0 or more, got {}",
+                replay_rate
+            ));
+        }
+        for (key, value) in [
+            (
+                "server.session.heartbeat_timeout_secs",
+                self.server.session.heartbeat_timeout_secs,
+            ),
+            (
+                "server.session.event_capacity",
+                self.server.session.event_capacity as u64,
+            ),
+            (
+                "server.pipeline.batch_size",
+                self.server.pipeline.batch_size as u64,
+            ),
+            (
+                "server.pipeline.channel_capacity",
+                self.server.pipeline.channel_capacity as u64,
+            ),
+            (
+                "server.pipeline.max_attempts",
+                self.server.pipeline.max_attempts as u64,
+            ),
+            (
+                "server.retention.interval_secs",
+                self.server.retention.interval_secs,
+            ),
+            (
+                "server.retention.batch_size",
+                self.server.retention.batch_size as u64,
+            ),
+            ("database.pool_size", self.database.pool_size as u64),
+            (
+                "database.connect_attempts",
+                self.database.connect_attempts as u64,
+            ),
+        ] {
+            if value == 0 {
+                problems.push(format!("{}: must be greater than 0", key));
+            }
+        }
+        let retention = &self.server.retention;
+        let policies =
+            std::iter::once(("server.retention.default".to_string(), &retention.default)).chain(
+                retention.groups.iter().map(|group| {
+                    (
+                        format!("server.retention.groups.{}", group.name),
+                        &group.policy,
+                    )
+                }),
+            );
+        for (key, policy) in policies {
+            if policy.downsample_interval_secs > 0
+                && policy.downsample_days > 0
+                && policy.downsample_days <= policy.positions_days
+            {
+                problems.push(format!(
+                    "{}.downsample_days: must be greater than positions_days, got {}",
+                    key, policy.downsample_days
+                ));
+            }
+        }
+        for group in &retention.groups {
+            if group.name.trim().is_empty() {
+                problems.push("server.retention.groups.name: must not be empty".to_string());
+            } else if group.kinds.is_empty() && group.owners.is_empty() && group.serials.is_empty()
+            {
+                problems.push(format!(
+                    "server.retention.groups.{}: must list kinds, owners or serials",
+                    group.name
+                ));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("config error: {}", problems.join("; ")))
+        }
+    }
+}
+
+/// Largest payload a UDP datagram can carry over IPv4.
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// Accepts `ip:port` as well as `hostname:port`, without resolving the name.
+fn check_address(key: &str, value: &str, problems: &mut Vec<String>) {
+    if value.parse::<SocketAddr>().is_ok() {
+        return;
+    }
+    let valid = match value.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty() && !host.contains(':') && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    };
+    if !valid {
+        problems.push(format!("{}: expected host:port, got {:?}", key, value));
+    }
+}
 
-                match toml::from_str::<Config>(&content) {
-                    Ok(config) => Ok(config),
-                    Err(error) => Err(format!("config error: {:?}", error.to_string())),
+/// Overlays `layer` onto `base`, merging the tables both have.
+fn merge(base: &mut Table, layer: Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(layer)) => merge(base, layer),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Replaces every `<key>_file` entry of a file layer with `<key>` holding
+/// the content of the file it names.
+fn resolve_secret_files(table: &mut Table, prefix: &str, problems: &mut Vec<String>) {
+    let keys: Vec<String> = table.keys().cloned().collect();
+    for key in keys {
+        let path = format!("{}{}", prefix, key);
+        match table.get_mut(&key) {
+            Some(Value::Table(inner)) => {
+                resolve_secret_files(inner, &format!("{}.", path), problems)
+            }
+            Some(Value::String(file)) if key.ends_with(FILE_SUFFIX) => {
+                let file = file.clone();
+                table.remove(&key);
+                if let Some(value) = read_secret(&path, &file, problems) {
+                    let key = key.trim_end_matches(FILE_SUFFIX).to_string();
+                    table.insert(key, Value::String(value));
                 }
             }
-            Err(error) => Err(format!("config error: {:?}", error.to_string())),
+            _ => {}
+        }
+    }
+}
+
+fn read_secret(key: &str, file: &str, problems: &mut Vec<String>) -> Option<String> {
+    match fs::read_to_string(file) {
+        Ok(content) => Some(content.trim_end_matches(['\r', '\n']).to_string()),
+        Err(error) => {
+            problems.push(format!("{}: unable to read {:?}: {}", key, file, error));
+            None
+        }
+    }
+}
+
+/// Sets the dotted `key` to `raw`, converted to the type the key already has.
+/// Keys unknown to the defaults, such as `server.metrics_host`, take the
+/// TOML value `raw` spells, or a string.
+fn set_key(table: &mut Table, key: &str, raw: &str, problems: &mut Vec<String>) {
+    let (parents, name) = match key.rsplit_once('.') {
+        Some((parents, name)) => (parents.split('.').collect::<Vec<&str>>(), name),
+        None => (Vec::new(), key),
+    };
+    let mut section = table;
+    for parent in parents {
+        match section.get_mut(parent) {
+            Some(Value::Table(inner)) => section = inner,
+            _ => {
+                problems.push(format!("{}: unknown section {:?}", key, parent));
+                return;
+            }
+        }
+    }
+    if !section.contains_key(name) && name.ends_with(FILE_SUFFIX) {
+        if let Some(value) = read_secret(key, raw, problems) {
+            let name = name.trim_end_matches(FILE_SUFFIX).to_string();
+            section.insert(name, Value::String(value));
+        }
+        return;
+    }
+    let value = match section.get(name) {
+        Some(Value::String(_)) => Ok(Value::String(raw.to_string())),
+        Some(Value::Integer(_)) => raw
+            .trim()
+            .parse::<i64>()
+            .map(Value::Integer)
+            .map_err(|_| "an integer"),
+        Some(Value::Float(_)) => raw
+            .trim()
+            .parse::<f64>()
+            .map(Value::Float)
+            .map_err(|_| "a number"),
+        Some(Value::Boolean(_)) => raw
+            .trim()
+            .parse::<bool>()
+            .map(Value::Boolean)
+            .map_err(|_| "true or false"),
+        Some(_) => Err("a section, not a single value"),
+        None => Ok(toml::from_str::<Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"))
+            .unwrap_or_else(|| Value::String(raw.to_string()))),
+    };
+    match value {
+        Ok(value) => {
+            section.insert(name.to_string(), value);
         }
+        Err(expected) => problems.push(format!("{}: expected {}, got {:?}", key, expected, raw)),
     }
 }
 
@@ -59,3 +1023,206 @@ async fn test_config_load() {
     let config = Config::load(None).await;
     assert!(config.is_ok());
 }
+
+#[tokio::test]
+async fn test_config_debug_redacts_password() {
+    let config = Config::load(None).await.unwrap();
+    let output = format!("{:?}", config);
+    assert!(output.contains("password: [REDACTED]"), "{}", output);
+}
+
+#[test]
+fn test_config_validate() {
+    let content = std::fs::read_to_string("config.toml").unwrap();
+    let mut config = Config::parse(&content).unwrap();
+    config.log.level = "gps_tracker=loud".to_string();
+    config.server.rate_limit.client_rate_per_sec = 0.0;
+    config.server.session.heartbeat_timeout_secs = 0;
+    let error = config.validate().unwrap_err();
+    assert!(error.contains("log.level"), "{}", error);
+    assert!(
+        error.contains("server.rate_limit.client_rate_per_sec"),
+        "{}",
+        error
+    );
+    assert!(
+        error.contains("server.session.heartbeat_timeout_secs"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_config_defaults() {
+    let error = Config::from_layers(None, std::iter::empty(), &[]).unwrap_err();
+    assert_eq!(error, "config error: database.password: must not be empty");
+
+    let overrides = ["database.password=root".to_string()];
+    let config = Config::from_layers(None, std::iter::empty(), &overrides).unwrap();
+    assert_eq!(config.server, ServerConfig::default());
+    assert_eq!(config.web.port, 4090);
+    assert_eq!(config.database.password, "root");
+}
+
+#[test]
+fn test_config_layers() {
+    let content = std::fs::read_to_string("config.toml").unwrap();
+    let env = [
+        ("GPS_DATABASE__PASSWORD".to_string(), "1234".to_string()),
+        (
+            "GPS_SERVER__RATE_LIMIT__CLIENT_BURST".to_string(),
+            "3".to_string(),
+        ),
+        ("GPS_WEB__PORT".to_string(), "5000".to_string()),
+        ("GPS_UNRELATED".to_string(), "ignored".to_string()),
+        ("HOME".to_string(), "/root".to_string()),
+    ];
+    let overrides = [
+        "web.port=6000".to_string(),
+        "server.metrics_host=0.0.0.0:9000".to_string(),
+    ];
+    let config = Config::from_layers(Some(&content), env, &overrides).unwrap();
+    // Numeric-looking values stay strings when the key is a string.
+    assert_eq!(config.database.password, "1234");
+    assert_eq!(config.database.namespace, "dev");
+    assert_eq!(config.server.rate_limit.client_burst, 3);
+    assert_eq!(config.web.port, 6000);
+    assert_eq!(config.server.metrics_host, Some("0.0.0.0:9000".to_string()));
+}
+
+#[test]
+fn test_config_retention() {
+    let content = r#"
+        [database]
+        password = "root"
+
+        [server.retention]
+        enabled = true
+
+        [server.retention.default]
+        positions_days = 30
+        downsample_interval_secs = 60
+        downsample_days = 365
+        heartbeats_days = 7
+
+        [[server.retention.groups]]
+        name = "obd"
+        kinds = ["obd"]
+        positions_days = 7
+    "#;
+    let config = Config::parse(content).unwrap();
+    let retention = &config.server.retention;
+    assert!(retention.enabled);
+    assert_eq!(retention.batch_size, 1000);
+    assert_eq!(retention.default.downsample_days, 365);
+    assert_eq!(retention.groups[0].kinds, ["obd"]);
+    assert_eq!(retention.groups[0].policy.positions_days, 7);
+    assert_eq!(retention.groups[0].policy.heartbeats_days, 0);
+
+    let overrides = ["server.retention.default.downsample_days=30".to_string()];
+    let error = Config::from_layers(Some(content), std::iter::empty(), &overrides).unwrap_err();
+    assert!(
+        error.contains("server.retention.default.downsample_days"),
+        "{}",
+        error
+    );
+    let content = content.replace("kinds = [\"obd\"]", "");
+    let error = Config::parse(&content).unwrap_err();
+    assert!(
+        error.contains("server.retention.groups.obd: must list kinds, owners or serials"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_config_secret_file() {
+    let path = std::env::temp_dir().join(format!("gps-tracker-secret-{}", std::process::id()));
+    std::fs::write(&path, "from-file\n").unwrap();
+    let path = path.to_string_lossy().to_string();
+
+    let content = std::fs::read_to_string("config.toml").unwrap().replace(
+        "password = \"root\"",
+        &format!("password_file = {:?}", path),
+    );
+    let config = Config::parse(&content).unwrap();
+    assert_eq!(config.database.password, "from-file");
+
+    let env = [("GPS_DATABASE__PASSWORD_FILE".to_string(), path.clone())];
+    let config = Config::from_layers(None, env, &[]).unwrap();
+    assert_eq!(config.database.password, "from-file");
+
+    let overrides = ["database.password_file=/nonexistent/secret".to_string()];
+    let error = Config::from_layers(None, std::iter::empty(), &overrides).unwrap_err();
+    assert!(
+        error.contains("database.password_file: unable to read"),
+        "{}",
+        error
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_config_reports_every_problem() {
+    let content = std::fs::read_to_string("config.toml").unwrap();
+    let overrides = [
+        "server.host=localhost".to_string(),
+        "web.port=70000".to_string(),
+        "database.namespace=".to_string(),
+        "server.session.event_capacity=many".to_string(),
+        "missing.key=1".to_string(),
+        "no-separator".to_string(),
+    ];
+    let error = Config::from_layers(Some(&content), std::iter::empty(), &overrides).unwrap_err();
+    for expected in [
+        "server.host: expected host:port",
+        "web.port: must be between 1 and 65535, got 70000",
+        "database.namespace: must not be empty",
+        "server.session.event_capacity: expected an integer",
+        "missing.key: unknown section",
+        "no-separator: expected key=value",
+    ] {
+        assert!(error.contains(expected), "{}", error);
+    }
+}
+
+#[test]
+fn test_config_database_engine() {
+    let config = Config::from_layers(
+        None,
+        std::iter::empty(),
+        &["database.password=root".to_string()],
+    )
+    .unwrap();
+    assert_eq!(config.database.endpoint(), "ws://127.0.0.1:8080");
+
+    // Embedded engines need neither a host nor credentials.
+    let overrides = [
+        "database.engine=memory".to_string(),
+        "database.host=".to_string(),
+    ];
+    let config = Config::from_layers(None, std::iter::empty(), &overrides).unwrap();
+    assert_eq!(config.database.engine, DatabaseEngine::Memory);
+    assert_eq!(config.database.endpoint(), "mem://");
+
+    let overrides = [
+        "database.engine=rocksdb".to_string(),
+        "database.path=/var/lib/gps".to_string(),
+    ];
+    let config = Config::from_layers(None, std::iter::empty(), &overrides).unwrap();
+    assert_eq!(config.database.endpoint(), "rocksdb:///var/lib/gps");
+
+    let overrides = [
+        "database.engine=surrealkv".to_string(),
+        "database.path=".to_string(),
+    ];
+    let error = Config::from_layers(None, std::iter::empty(), &overrides).unwrap_err();
+    assert_eq!(error, "config error: database.path: must not be empty");
+
+    let overrides = ["database.engine=sqlite".to_string()];
+    let config = Config::from_layers(None, std::iter::empty(), &overrides).unwrap();
+    assert_eq!(config.database.engine, DatabaseEngine::Sqlite);
+
+    let overrides = ["database.engine=oracle".to_string()];
+    assert!(Config::from_layers(None, std::iter::empty(), &overrides).is_err());
+}
@@ -0,0 +1,63 @@
+use crate::config::Config;
+use crate::device::DeviceCache;
+use crate::pipeline::WritePipeline;
+use crate::session::SessionManager;
+use crate::shutdown::Shutdown;
+use crate::spool::Spool;
+use crate::store::{self, SharedStore};
+use std::time::Duration;
+
+/// Application state created once at startup and shared by every handler.
+#[derive(Debug, Clone)]
+pub struct AppContext {
+    pub config: Config,
+    pub store: SharedStore,
+    pub shutdown: Shutdown,
+    pub sessions: SessionManager,
+    /// Devices by client id, for the packets sent after a login.
+    pub devices: DeviceCache,
+    /// Fixes accepted while the database is unreachable, when enabled.
+    pub spool: Option<Spool>,
+    /// Batches coordinates and heartbeat writes; handlers write directly without it.
+    pub pipeline: Option<WritePipeline>,
+}
+
+impl AppContext {
+    /// Opens the store described by `config.database`.
+    pub async fn init(config: Config) -> Result<Self, String> {
+        let store = store::connect(&config.database).await?;
+        Ok(Self::with_store(config, store))
+    }
+
+    /// Creates the context around an already opened store.
+    pub fn with_store(config: Config, store: SharedStore) -> Self {
+        let sessions = SessionManager::new(&config.server.session);
+        let devices = DeviceCache::new(
+            store.clone(),
+            Duration::from_secs(config.server.device_cache_ttl_secs),
+        );
+        Self {
+            config,
+            store,
+            shutdown: Shutdown::new(),
+            sessions,
+            devices,
+            spool: None,
+            pipeline: None,
+        }
+    }
+
+    /// Opens the spool configured under `[server.spool]`, if enabled.
+    pub fn with_spool(mut self) -> Result<Self, String> {
+        if self.config.server.spool.enabled {
+            self.spool = Some(Spool::open(&self.config.server.spool)?);
+        }
+        Ok(self)
+    }
+
+    /// Loads the configuration file and connects the database pool.
+    pub async fn load(file_path: Option<String>) -> Result<Self, String> {
+        let config: Config = Config::load(file_path).await?;
+        Self::init(config).await
+    }
+}
@@ -1,51 +1,191 @@
-use crate::config::{Config, DatabaseConfig};
-use surrealdb::engine::remote::ws::{Client, Ws};
+use crate::config::DatabaseConfig;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+use surrealdb::engine::any::{self, Any};
 use surrealdb::opt::auth::Root;
 use surrealdb::Surreal;
+use tracing::{info, warn};
 
-#[derive(Debug)]
+/// Shared, pooled SurrealDB handle.
+///
+/// Cloning a `Db` is cheap: every clone points to the same pool of connections.
+/// A background task per connection health-checks it and replaces it once it
+/// stopped responding, backing off exponentially while SurrealDB is down.
+/// Embedded engines get a single connection and no supervisor, since opening
+/// the engine again would start a second, separate database.
+#[derive(Debug, Clone)]
 pub struct Db {
-    pub client: Surreal<Client>,
+    pool: Arc<Vec<RwLock<Surreal<Any>>>>,
+    next: Arc<AtomicUsize>,
+    config: DatabaseConfig,
 }
 
 impl Db {
-    pub async fn connect() -> Result<Self, String> {
-        let config: Config = Config::load(None).await?;
-        let db_config: DatabaseConfig = config.database;
-        let client: Surreal<Client> = Surreal::init();
-        match client.connect::<Ws>(db_config.host).await {
-            Ok(_) => {
-                match client
-                    .signin(Root {
-                        username: &db_config.username,
-                        password: &db_config.password,
-                    })
-                    .await
-                {
-                    Ok(_) => match client
-                        .use_ns(db_config.namespace)
-                        .use_db(&db_config.database)
-                        .await
-                    {
-                        Ok(_) => Ok(Self { client }),
-                        Err(error) => Err(format!("database error: {:?}", error)),
-                    },
-                    Err(error) => Err(error.to_string()),
-                }
+    /// Opens `pool_size` connections and starts their reconnect supervisors.
+    pub async fn connect(db_config: &DatabaseConfig) -> Result<Self, String> {
+        let embedded = db_config.engine.is_embedded();
+        let pool_size = if embedded {
+            1
+        } else {
+            db_config.pool_size.max(1)
+        };
+        let mut pool: Vec<RwLock<Surreal<Any>>> = Vec::new();
+        for _ in 0..pool_size {
+            let client = Self::connect_with_backoff(db_config).await?;
+            pool.push(RwLock::new(client));
+        }
+        let db = Self {
+            pool: Arc::new(pool),
+            next: Arc::new(AtomicUsize::new(0)),
+            config: db_config.clone(),
+        };
+        if !embedded {
+            db.supervise();
+        }
+        Ok(db)
+    }
+
+    /// Returns a connection from the pool in round-robin order.
+    pub fn client(&self) -> Surreal<Any> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.pool.len();
+        match self.pool[index].read() {
+            Ok(client) => client.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Whether SurrealDB answers a health check within a second.
+    pub async fn is_reachable(&self) -> bool {
+        matches!(
+            tokio::time::timeout(Duration::from_secs(1), self.client().health()).await,
+            Ok(Ok(_))
+        )
+    }
+
+    /// Opens a single connection, signs in to remote engines and selects the
+    /// namespace and database.
+    async fn open(db_config: &DatabaseConfig) -> Result<Surreal<Any>, String> {
+        let client = match any::connect(db_config.endpoint()).await {
+            Ok(client) => client,
+            Err(error) => return Err(format!("database error: {:?}", error)),
+        };
+        if !db_config.engine.is_embedded() {
+            if let Err(error) = client
+                .signin(Root {
+                    username: &db_config.username,
+                    password: &db_config.password,
+                })
+                .await
+            {
+                return Err(error.to_string());
             }
+        }
+        match client
+            .use_ns(db_config.namespace.as_str())
+            .use_db(db_config.database.as_str())
+            .await
+        {
+            Ok(_) => Ok(client),
             Err(error) => Err(format!("database error: {:?}", error)),
         }
     }
+
+    /// Retries `open` with exponential backoff up to `connect_attempts` times.
+    async fn connect_with_backoff(db_config: &DatabaseConfig) -> Result<Surreal<Any>, String> {
+        let mut backoff = Duration::from_millis(db_config.reconnect_initial_backoff_ms);
+        let max_backoff = Duration::from_millis(db_config.reconnect_max_backoff_ms);
+        let mut attempt: u32 = 1;
+        loop {
+            match Self::open(db_config).await {
+                Ok(client) => return Ok(client),
+                Err(error) => {
+                    if attempt >= db_config.connect_attempts {
+                        return Err(error);
+                    }
+                    warn!(attempt, ?backoff, %error, "database connection failed, retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Spawns one task per connection that health-checks it and reconnects
+    /// it when it stops responding, so a connection backing off never holds
+    /// up the checks of the others.
+    fn supervise(&self) {
+        for index in 0..self.pool.len() {
+            let pool = Arc::downgrade(&self.pool);
+            tokio::spawn(Self::supervise_slot(pool, index, self.config.clone()));
+        }
+    }
+
+    /// Health-checks the connection at `index` until every `Db` clone has
+    /// been dropped.
+    async fn supervise_slot(
+        pool: Weak<Vec<RwLock<Surreal<Any>>>>,
+        index: usize,
+        config: DatabaseConfig,
+    ) {
+        let interval = Duration::from_millis(config.health_check_interval_ms);
+        let initial_backoff = Duration::from_millis(config.reconnect_initial_backoff_ms);
+        let max_backoff = Duration::from_millis(config.reconnect_max_backoff_ms);
+        loop {
+            tokio::time::sleep(interval).await;
+            let client = match pool.upgrade() {
+                Some(pool) => match pool[index].read() {
+                    Ok(client) => client.clone(),
+                    Err(poisoned) => poisoned.into_inner().clone(),
+                },
+                None => return,
+            };
+            let healthy = matches!(
+                tokio::time::timeout(interval, client.health()).await,
+                Ok(Ok(_))
+            );
+            if healthy {
+                continue;
+            }
+            warn!(slot = index, "database connection unhealthy, reconnecting");
+            let mut backoff = initial_backoff;
+            let client = loop {
+                match Self::open(&config).await {
+                    Ok(client) => break client,
+                    Err(error) => {
+                        warn!(slot = index, ?backoff, %error, "database reconnect failed, retrying");
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(max_backoff);
+                        if pool.strong_count() == 0 {
+                            return;
+                        }
+                    }
+                }
+            };
+            let Some(pool) = pool.upgrade() else {
+                return;
+            };
+            match pool[index].write() {
+                Ok(mut current) => *current = client,
+                Err(poisoned) => *poisoned.into_inner() = client,
+            }
+            info!(slot = index, "database connection restored");
+        }
+    }
 }
 
 #[cfg(test)]
 mod db_tests {
 
     use super::*;
+    use crate::config::Config;
 
     #[tokio::test]
+    #[ignore = "needs a SurrealDB at 127.0.0.1:8080"]
     async fn test_connection() {
-        let db = Db::connect().await;
+        let config = Config::load(None).await.unwrap();
+        let db = Db::connect(&config.database).await;
         assert!(db.is_ok(), "{:?}", db.err());
     }
 }
@@ -0,0 +1,210 @@
+use crate::actions::{Coordinates, Login, Logout};
+use crate::response::ResponseType;
+use crate::{RequestPacket, RequestType};
+use serde::Serialize;
+use std::fmt;
+
+/// A request datagram decoded the way the server reads it, without touching
+/// the database.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DecodedRequest {
+    pub request_type: String,
+    pub payload_length: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<u32>,
+    /// Login username; the password is never decoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+    /// Why the server would reject the datagram.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A response datagram: request type, status and the client id as text.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DecodedResponse {
+    pub request_type: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "direction", rename_all = "lowercase")]
+pub enum Decoded {
+    Request(DecodedRequest),
+    Response(DecodedResponse),
+}
+
+fn request_type_name(value: u8) -> String {
+    match RequestType::get_by_value(value) {
+        RequestType::Invalid => format!("Unknown(0x{:02x})", value),
+        request_type => request_type.name().to_string(),
+    }
+}
+
+/// Decodes a request with `RequestPacket::parse` and the action parsers.
+pub async fn decode_request(data: &[u8]) -> DecodedRequest {
+    let mut decoded = DecodedRequest {
+        request_type: request_type_name(data.first().copied().unwrap_or_default()),
+        payload_length: 0,
+        client_id: None,
+        username: None,
+        latitude: None,
+        longitude: None,
+        error: None,
+    };
+    let packet = match RequestPacket::parse(data) {
+        Ok(packet) => packet,
+        Err(error) => {
+            decoded.error = Some(error.to_string());
+            return decoded;
+        }
+    };
+    decoded.payload_length = packet.payload_length;
+    let result = match packet.request_type {
+        RequestType::Login => Login::parse(packet.payload_length, &packet.payload)
+            .await
+            .map(|login| decoded.username = Some(login.username().to_string()))
+            .map_err(|error| error.to_string()),
+        RequestType::Coordinates => Coordinates::parse_fix(packet.payload_length, &packet.payload)
+            .map(|fix| {
+                decoded.client_id = Some(fix.client_id);
+                decoded.latitude = Some(fix.latitude);
+                decoded.longitude = Some(fix.longitude);
+            })
+            .map_err(|error| error.to_string()),
+        // The heartbeat parser looks the client up, so only its id is read.
+        RequestType::HeartBeat => {
+            decoded.client_id = packet.client_id();
+            Ok(())
+        }
+        RequestType::Logout => Logout::parse(packet.payload_length, &packet.payload)
+            .await
+            .map(|client_id| decoded.client_id = Some(client_id))
+            .map_err(|error| error.to_string()),
+        RequestType::Invalid => Err("unknown request type".to_string()),
+    };
+    if let Err(error) = result {
+        decoded.error = Some(error);
+    }
+    decoded
+}
+
+/// Decodes a response sent by the server.
+pub fn decode_response(data: &[u8]) -> DecodedResponse {
+    let mut decoded = DecodedResponse {
+        request_type: request_type_name(data.first().copied().unwrap_or_default()),
+        status: String::new(),
+        client_id: None,
+        error: None,
+    };
+    let status = match data.get(1) {
+        Some(status) => *status,
+        None => {
+            decoded.error = Some("response too short".to_string());
+            return decoded;
+        }
+    };
+    decoded.status = match ResponseType::get_by_value(status) {
+        Some(response_type) => format!("{:?}", response_type),
+        None => format!("Unknown(0x{:02x})", status),
+    };
+    match String::from_utf8(data[2..].to_vec()) {
+        Ok(client_id) if !client_id.is_empty() => decoded.client_id = Some(client_id),
+        Ok(_) => {}
+        Err(_) => decoded.error = Some("client id is not text".to_string()),
+    }
+    decoded
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let error = match self {
+            Decoded::Request(request) => {
+                write!(f, "request {}", request.request_type)?;
+                if let Some(client_id) = request.client_id {
+                    write!(f, " client_id={}", client_id)?;
+                }
+                if let Some(username) = &request.username {
+                    write!(f, " username={}", username)?;
+                }
+                if let (Some(latitude), Some(longitude)) = (request.latitude, request.longitude) {
+                    write!(f, " latitude={} longitude={}", latitude, longitude)?;
+                }
+                &request.error
+            }
+            Decoded::Response(response) => {
+                write!(f, "response {} {}", response.request_type, response.status)?;
+                if let Some(client_id) = &response.client_id {
+                    write!(f, " client_id={}", client_id)?;
+                }
+                &response.error
+            }
+        };
+        match error {
+            Some(error) => write!(f, " error={:?}", error),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_decode {
+    use super::*;
+    use crate::payload::Payload;
+
+    #[tokio::test]
+    async fn test_decode_request() {
+        let login = Payload::to_binary(
+            "01 00 14 72 6F 6F 74 00 6E 6F 74 73 65 63 75 72 65 70 61 73 73 77 6F 72 64",
+        )
+        .unwrap();
+        let decoded = Decoded::Request(decode_request(&login).await);
+        assert_eq!(decoded.to_string(), "request Login username=root");
+        // Usernames that are not UTF-8 are rejected instead of panicking.
+        let decoded = decode_request(&[0x01, 0x00, 0x02, 0xFF, 0x00]).await;
+        assert_eq!(decoded.error, Some("Invalid login payload".to_string()));
+
+        let coordinates = Coordinates::generate_payload(24564, 14.5995, 120.9842)
+            .await
+            .unwrap();
+        let coordinates = Payload::to_binary(&coordinates).unwrap();
+        let decoded = decode_request(&coordinates).await;
+        assert_eq!(decoded.client_id, Some(24564));
+        assert!((decoded.latitude.unwrap() - 14.5995).abs() < 1e-9);
+        assert!((decoded.longitude.unwrap() - 120.9842).abs() < 1e-9);
+
+        let decoded = decode_request(&[0x03, 0x00, 0x04, 0x00, 0x00, 0x5F, 0xF4]).await;
+        assert_eq!(decoded.client_id, Some(24564));
+        let decoded = decode_request(&[0x09, 0x00]).await;
+        assert_eq!(decoded.request_type, "Unknown(0x09)");
+        assert!(decoded.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_decode_response() {
+        let decoded = Decoded::Response(decode_response(&[0x03, 0x06, 0x32, 0x34]));
+        assert_eq!(
+            decoded.to_string(),
+            "response HeartBeat Success client_id=24"
+        );
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::json!({
+                "direction": "response",
+                "request_type": "HeartBeat",
+                "status": "Success",
+                "client_id": "24",
+            })
+        );
+        assert_eq!(decode_response(&[0x02, 0x08]).status, "SlowDown");
+        assert!(decode_response(&[0x02]).error.is_some());
+    }
+}
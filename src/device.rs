@@ -0,0 +1,423 @@
+use crate::logging::Redacted;
+use crate::metrics::Metrics;
+use crate::password;
+use crate::session::SessionData;
+use crate::store::SharedStore;
+use crate::validation::{RequestError, ValidationError};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use surrealdb::sql::Datetime;
+use surrealdb::RecordId;
+
+/// Kind given to devices created without one, and to the devices migrated
+/// from users that used to carry a client id.
+pub const DEFAULT_KIND: &str = "tracker";
+
+/// Protocol of the packets this server decodes.
+pub const DEFAULT_PROTOCOL: &str = "udp";
+
+/// A tracker. It logs in with its serial and device key, and its positions
+/// and heartbeats are visible to the user owning it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeviceData {
+    pub id: Option<RecordId>,
+    /// IMEI or serial number, sent as the username of the login packet.
+    pub serial: String,
+    pub name: String,
+    /// Hardware type, e.g. `tracker` or `obd`.
+    pub kind: String,
+    /// User the device belongs to.
+    pub owner: RecordId,
+    pub protocol: String,
+    /// Argon2id hash of the device key. Never serialized, so no API can leak it.
+    #[serde(skip_serializing)]
+    pub credentials: String,
+    /// Id the device sends in every packet after logging in. 0 is never
+    /// allocated: failed logins answer with it.
+    pub client_id: u32,
+    /// Disabled devices keep their records but cannot log in.
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl std::fmt::Debug for DeviceData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeviceData")
+            .field("id", &self.id)
+            .field("serial", &self.serial)
+            .field("name", &self.name)
+            .field("kind", &self.kind)
+            .field("owner", &self.owner)
+            .field("protocol", &self.protocol)
+            .field("credentials", &Redacted(&self.credentials))
+            .field("client_id", &self.client_id)
+            .field("disabled", &self.disabled)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Device {
+    store: SharedStore,
+}
+
+impl Device {
+    pub fn new(store: SharedStore) -> Self {
+        Self { store }
+    }
+
+    /// Every device, or those of `owner`, ordered by client id.
+    pub async fn get_devices(&self, owner: Option<&RecordId>) -> Result<Vec<DeviceData>, String> {
+        let _timer = Metrics::global().start_db_timer("devices.get_devices");
+        self.store.devices(owner).await
+    }
+
+    pub async fn get_by_client_id(&self, client_id: u32) -> Result<DeviceData, String> {
+        let _timer = Metrics::global().start_db_timer("devices.get_by_client_id");
+        self.store.device_by_client_id(client_id).await
+    }
+
+    pub async fn get_by_serial(&self, serial: &str) -> Result<DeviceData, String> {
+        let _timer = Metrics::global().start_db_timer("devices.get_by_serial");
+        match self.store.device_by_serial(serial).await? {
+            Some(data) => Ok(data),
+            None => Err(format!("device {} not found", serial)),
+        }
+    }
+
+    /// Checks a device key. Disabled devices, and devices of a disabled user,
+    /// are refused.
+    pub async fn authenticate(&self, serial: &str, key: &str) -> Result<DeviceData, String> {
+        let _timer = Metrics::global().start_db_timer("devices.authenticate");
+        let data = self.store.device_by_serial(serial).await?;
+        let stored = data.as_ref().map(|data| data.credentials.clone());
+        let data = match (data, password::verify_async(key, stored).await?) {
+            (Some(data), true) => data,
+            _ => return Err("invalid serial or device key".to_string()),
+        };
+        if data.disabled {
+            return Err("device is disabled".to_string());
+        }
+        if self.store.user_by_id(&data.owner).await?.disabled {
+            return Err("owner is disabled".to_string());
+        }
+        Ok(data)
+    }
+
+    /// Lowest client id above every allocated one.
+    pub async fn next_client_id(&self) -> Result<u32, String> {
+        let devices = self.get_devices(None).await?;
+        match devices.iter().map(|data| data.client_id).max() {
+            Some(u32::MAX) => Err("device.next_client_id error: client ids exhausted".to_string()),
+            Some(client_id) => Ok(client_id + 1),
+            None => Ok(1),
+        }
+    }
+
+    /// Adds `data` with the hash of `key` as its credentials. A client id of
+    /// 0 is replaced by the next free one.
+    pub async fn create(&self, mut data: DeviceData, key: &str) -> Result<DeviceData, String> {
+        data.credentials = password::hash_async(key).await?;
+        self.create_hashed(data).await
+    }
+
+    /// Adds `data`, whose credentials are already an Argon2 hash, e.g. one
+    /// exported from another installation. A client id of 0 is replaced by
+    /// the next free one.
+    pub async fn create_hashed(&self, mut data: DeviceData) -> Result<DeviceData, String> {
+        let _timer = Metrics::global().start_db_timer("devices.create");
+        if !password::is_hashed(&data.credentials) {
+            return Err(format!("device {} has no key hash", data.serial));
+        }
+        if data.client_id == 0 {
+            data.client_id = self.next_client_id().await?;
+        }
+        self.store.create_device(&data).await
+    }
+
+    pub async fn set_key(&self, serial: &str, key: &str) -> Result<DeviceData, String> {
+        let mut data = self.get_by_serial(serial).await?;
+        data.credentials = password::hash_async(key).await?;
+        self.store.update_device(&data).await?;
+        Ok(data)
+    }
+
+    /// Replaces the device key with a new random one and returns it. Only its
+    /// hash is stored.
+    pub async fn rotate_key(&self, serial: &str) -> Result<String, String> {
+        let key = password::generate_key();
+        self.set_key(serial, &key).await?;
+        Ok(key)
+    }
+
+    pub async fn set_disabled(&self, serial: &str, disabled: bool) -> Result<DeviceData, String> {
+        let mut data = self.get_by_serial(serial).await?;
+        data.disabled = disabled;
+        self.store.update_device(&data).await?;
+        Ok(data)
+    }
+
+    /// Deletes a device along with its positions, heartbeats and sessions.
+    pub async fn delete(&self, serial: &str) -> Result<(), String> {
+        let data = self.get_by_serial(serial).await?;
+        match data.id {
+            Some(id) => self.store.delete_device(&id).await,
+            None => Err(format!("device {} has no id", serial)),
+        }
+    }
+
+    /// Deletes the positions, heartbeats and sessions of a device, keeping
+    /// the device itself. Logging out never does this.
+    pub async fn purge_history(&self, serial: &str) -> Result<(), String> {
+        let _timer = Metrics::global().start_db_timer("devices.purge_history");
+        let data = self.get_by_serial(serial).await?;
+        self.store.delete_device_history(data.client_id).await
+    }
+
+    /// Opens a session for a device that logged in from `source_address`.
+    /// A session left open by a previous login is closed first.
+    pub async fn start_session(
+        &self,
+        data: &DeviceData,
+        source_address: SocketAddr,
+    ) -> Result<SessionData, String> {
+        let _timer = Metrics::global().start_db_timer("devices.start_session");
+        let device = match &data.id {
+            Some(id) => id.clone(),
+            None => return Err(format!("device {} has no id", data.serial)),
+        };
+        let login = Datetime::from(Utc::now());
+        self.store.close_session(&device, login.clone()).await?;
+        self.store
+            .create_session(&SessionData {
+                id: None,
+                device,
+                source_address: source_address.to_string(),
+                login,
+                logout: None,
+                positions: 0,
+                heartbeats: 0,
+            })
+            .await
+    }
+
+    /// Closes the open session of the device with `client_id`, leaving its
+    /// positions and heartbeats stored. Returns `None` when it had none.
+    pub async fn end_session(&self, client_id: u32) -> Result<Option<SessionData>, String> {
+        let _timer = Metrics::global().start_db_timer("devices.end_session");
+        let data = self.get_by_client_id(client_id).await?;
+        match data.id {
+            Some(id) => {
+                self.store
+                    .close_session(&id, Datetime::from(Utc::now()))
+                    .await
+            }
+            None => Err(format!("device {} has no id", data.serial)),
+        }
+    }
+
+    /// Sessions of the device with `client_id`, newest first.
+    pub async fn get_sessions(&self, client_id: u32) -> Result<Vec<SessionData>, String> {
+        let _timer = Metrics::global().start_db_timer("devices.get_sessions");
+        let data = self.get_by_client_id(client_id).await?;
+        match data.id {
+            Some(id) => self.store.sessions(&id).await,
+            None => Err(format!("device {} has no id", data.serial)),
+        }
+    }
+
+    /// Session `id`, which must belong to the device with `client_id`.
+    pub async fn get_session(&self, client_id: u32, id: &RecordId) -> Result<SessionData, String> {
+        let _timer = Metrics::global().start_db_timer("devices.get_session");
+        let data = self.get_by_client_id(client_id).await?;
+        if id.table() != "sessions" {
+            return Err(format!("not a session id: {}", id));
+        }
+        let session = self.store.session_by_id(id).await?;
+        if data.id.as_ref() != Some(&session.device) {
+            return Err("session not found".to_string());
+        }
+        Ok(session)
+    }
+}
+
+/// Ids of the devices sending packets, by client id, so coordinates and
+/// heartbeats find their device without a database round trip. Entries
+/// expire after `ttl`, which bounds how long a deleted device keeps
+/// resolving; a `ttl` of zero looks every packet up.
+#[derive(Debug, Clone)]
+pub struct DeviceCache {
+    store: SharedStore,
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<u32, (RecordId, Instant)>>>,
+}
+
+impl DeviceCache {
+    pub fn new(store: SharedStore, ttl: Duration) -> Self {
+        Self {
+            store,
+            ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Id of the device with `client_id`, looked up when it is not cached or
+    /// its entry expired.
+    pub async fn resolve(&self, client_id: u32) -> Result<RecordId, RequestError> {
+        if let Some((id, cached_at)) = self.lock().get(&client_id) {
+            if cached_at.elapsed() < self.ttl {
+                return Ok(id.clone());
+            }
+        }
+        let data = Device::new(self.store.clone())
+            .get_by_client_id(client_id)
+            .await?;
+        let Some(id) = data.id else {
+            return Err(ValidationError::InvalidDeviceId.into());
+        };
+        self.insert(client_id, id.clone());
+        Ok(id)
+    }
+
+    /// Caches a device just loaded, e.g. by a login.
+    pub fn insert(&self, client_id: u32, id: RecordId) {
+        if !self.ttl.is_zero() {
+            self.lock().insert(client_id, (id, Instant::now()));
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u32, (RecordId, Instant)>> {
+        match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_device {
+    use super::*;
+    use crate::store::{add_device, add_user, MemoryStore};
+    use crate::user::User;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let store = MemoryStore::new();
+        let root = add_user(&store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        add_device(&store, &root, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        let store: SharedStore = Arc::new(store);
+        let device = Device::new(store.clone());
+
+        let data = device.authenticate("root", "notsecurepassword").await;
+        assert_eq!(data.unwrap().client_id, 24564);
+        assert!(device.authenticate("root", "wrong").await.is_err());
+        let unknown = device.authenticate("nobody", "notsecurepassword").await;
+        assert_eq!(
+            unknown.err(),
+            Some("invalid serial or device key".to_string())
+        );
+
+        device.set_disabled("root", true).await.unwrap();
+        let refused = device.authenticate("root", "notsecurepassword").await;
+        assert_eq!(refused.err(), Some("device is disabled".to_string()));
+        device.set_disabled("root", false).await.unwrap();
+
+        User::new(store.clone())
+            .set_disabled("root", true)
+            .await
+            .unwrap();
+        let refused = device.authenticate("root", "notsecurepassword").await;
+        assert_eq!(refused.err(), Some("owner is disabled".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_manage_devices() {
+        let store = MemoryStore::new();
+        let root = add_user(&store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        add_device(&store, &root, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        let other = add_user(&store, "Other", "other", "password")
+            .await
+            .unwrap();
+        let device = Device::new(Arc::new(store));
+
+        let new_device = |serial: &str, owner: &RecordId| DeviceData {
+            id: None,
+            serial: serial.to_string(),
+            name: "Van".to_string(),
+            kind: DEFAULT_KIND.to_string(),
+            owner: owner.clone(),
+            protocol: DEFAULT_PROTOCOL.to_string(),
+            credentials: String::new(),
+            client_id: 0,
+            disabled: false,
+        };
+        let root_id = root.id.clone().unwrap();
+        let other_id = other.id.clone().unwrap();
+        let van = device
+            .create(new_device("356938035643809", &root_id), "vankey")
+            .await
+            .unwrap();
+        assert_eq!(van.client_id, 24565);
+        assert!(password::is_hashed(&van.credentials));
+        assert!(device
+            .create(new_device("356938035643809", &root_id), "x")
+            .await
+            .is_err());
+        let truck = device
+            .create(new_device("truck", &other_id), "truckkey")
+            .await
+            .unwrap();
+        assert_eq!(truck.client_id, 24566);
+        assert_eq!(device.get_devices(Some(&root_id)).await.unwrap().len(), 2);
+        assert_eq!(device.get_devices(None).await.unwrap().len(), 3);
+
+        let key = device.rotate_key("356938035643809").await.unwrap();
+        assert!(device
+            .authenticate("356938035643809", "vankey")
+            .await
+            .is_err());
+        assert!(device.authenticate("356938035643809", &key).await.is_ok());
+
+        device.delete("356938035643809").await.unwrap();
+        assert!(device.get_by_serial("356938035643809").await.is_err());
+        assert_eq!(
+            device.get_by_client_id(24566).await.unwrap().serial,
+            "truck"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_device_cache() {
+        let memory = Arc::new(MemoryStore::new());
+        let root = add_user(&*memory, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        let tracker = add_device(&*memory, &root, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        let cache = DeviceCache::new(memory.clone(), Duration::from_secs(60));
+        let uncached = DeviceCache::new(memory.clone(), Duration::ZERO);
+        assert_eq!(cache.resolve(24564).await, Ok(tracker.id.clone().unwrap()));
+        assert!(cache.resolve(24565).await.is_err());
+
+        // Cached devices resolve without the store.
+        memory.set_available(false);
+        assert_eq!(cache.resolve(24564).await, Ok(tracker.id.clone().unwrap()));
+        assert!(uncached.resolve(24564).await.is_err());
+        uncached.insert(24564, tracker.id.clone().unwrap());
+        assert!(uncached.resolve(24564).await.is_err());
+    }
+}
@@ -0,0 +1,307 @@
+use crate::actions::{CoordinatesHandler, HeartbeatHandler, LoginHandler, LogoutHandler};
+use crate::context::AppContext;
+use crate::request::RequestPacket;
+use crate::response::{generate_status_response, ResponseType};
+use crate::validation::RequestError;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// What a handler knows about the datagram it is processing.
+#[derive(Debug)]
+pub struct HandlerContext<'a> {
+    pub context: &'a AppContext,
+    pub source_address: SocketAddr,
+}
+
+/// Outcome of a handled request, encoded into the response datagram.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandlerResponse {
+    pub client_id: String,
+    pub is_error: bool,
+}
+
+impl HandlerResponse {
+    pub fn success(client_id: String) -> Self {
+        Self {
+            client_id,
+            is_error: false,
+        }
+    }
+
+    pub fn error(client_id: String) -> Self {
+        Self {
+            client_id,
+            is_error: true,
+        }
+    }
+}
+
+/// Decodes, handles and answers one request type.
+///
+/// Implement this and add it to a `HandlerRegistry` to support a new packet
+/// type without touching `UdpServer`.
+#[async_trait]
+pub trait RequestHandler: Send + Sync + 'static {
+    type Request: Send;
+
+    /// Type byte of the packets this handler accepts.
+    fn request_type(&self) -> u8;
+
+    /// Name used in logs and metrics labels.
+    fn name(&self) -> &'static str;
+
+    /// Client id the packet was sent with, used for per-client rate limiting
+    /// and logs. `None` by default, for packets that carry none.
+    fn client_id(&self, _packet: &RequestPacket) -> Option<u32> {
+        None
+    }
+
+    /// Turns the packet payload into a request. Malformed packets fail with
+    /// `RequestError::Invalid`, which the server counts by variant.
+    async fn decode(
+        &self,
+        ctx: &HandlerContext<'_>,
+        packet: &RequestPacket,
+    ) -> Result<Self::Request, RequestError>;
+
+    /// Runs the request. An `Err` means no response is sent.
+    async fn handle(
+        &self,
+        ctx: &HandlerContext<'_>,
+        request: Self::Request,
+    ) -> Result<HandlerResponse, String>;
+
+    /// Encodes the response as spaced hex, `<type> <status> <client id>` by default.
+    async fn encode(&self, response: HandlerResponse) -> Result<String, String> {
+        let response_type = if response.is_error {
+            ResponseType::Error
+        } else {
+            ResponseType::Success
+        };
+        Ok(generate_status_response(
+            self.request_type(),
+            response_type,
+            response.client_id,
+        ))
+    }
+}
+
+/// Object-safe view of a `RequestHandler`, so handlers with different
+/// request types can share the registry.
+#[async_trait]
+trait DynRequestHandler: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn client_id(&self, packet: &RequestPacket) -> Option<u32>;
+
+    async fn dispatch(
+        &self,
+        ctx: &HandlerContext<'_>,
+        packet: &RequestPacket,
+    ) -> Result<String, RequestError>;
+}
+
+#[async_trait]
+impl<H: RequestHandler> DynRequestHandler for H {
+    fn name(&self) -> &'static str {
+        RequestHandler::name(self)
+    }
+
+    fn client_id(&self, packet: &RequestPacket) -> Option<u32> {
+        RequestHandler::client_id(self, packet)
+    }
+
+    async fn dispatch(
+        &self,
+        ctx: &HandlerContext<'_>,
+        packet: &RequestPacket,
+    ) -> Result<String, RequestError> {
+        let request = self.decode(ctx, packet).await?;
+        let response = self.handle(ctx, request).await?;
+        Ok(self.encode(response).await?)
+    }
+}
+
+/// Request handlers keyed by their type byte.
+#[derive(Clone, Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<u8, Arc<dyn DynRequestHandler>>,
+}
+
+impl std::fmt::Debug for HandlerRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut handlers: Vec<(&u8, &'static str)> = self
+            .handlers
+            .iter()
+            .map(|(request_type, handler)| (request_type, handler.name()))
+            .collect();
+        handlers.sort();
+        f.debug_struct("HandlerRegistry")
+            .field("handlers", &handlers)
+            .finish()
+    }
+}
+
+impl HandlerRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a registry with the login, coordinates, heartbeat and logout handlers.
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        registry.register(LoginHandler);
+        registry.register(CoordinatesHandler);
+        registry.register(HeartbeatHandler);
+        registry.register(LogoutHandler);
+        registry
+    }
+
+    /// Registers `handler` for its type byte, returning the name of the
+    /// handler it replaced, if any.
+    pub fn register<H: RequestHandler>(&mut self, handler: H) -> Option<&'static str> {
+        self.handlers
+            .insert(handler.request_type(), Arc::new(handler))
+            .map(|previous| previous.name())
+    }
+
+    /// Returns the name of the handler registered for `request_type`.
+    pub fn name(&self, request_type: u8) -> Option<&'static str> {
+        self.handlers
+            .get(&request_type)
+            .map(|handler| handler.name())
+    }
+
+    /// Returns the client id the packet was sent with, as read by the handler
+    /// registered for its type.
+    pub fn client_id(&self, packet: &RequestPacket) -> Option<u32> {
+        self.handlers
+            .get(&packet.request_type_value)?
+            .client_id(packet)
+    }
+
+    /// Runs the handler registered for the packet type and returns the
+    /// encoded response, or `None` when no handler is registered.
+    pub async fn dispatch(
+        &self,
+        ctx: &HandlerContext<'_>,
+        packet: &RequestPacket,
+    ) -> Option<Result<String, RequestError>> {
+        let handler = self.handlers.get(&packet.request_type_value)?.clone();
+        Some(handler.dispatch(ctx, packet).await)
+    }
+}
+
+#[cfg(test)]
+mod test_handler {
+    use super::*;
+
+    struct EchoHandler;
+
+    #[async_trait]
+    impl RequestHandler for EchoHandler {
+        type Request = Vec<u8>;
+
+        fn request_type(&self) -> u8 {
+            0x05
+        }
+
+        fn name(&self) -> &'static str {
+            "Echo"
+        }
+
+        fn client_id(&self, packet: &RequestPacket) -> Option<u32> {
+            crate::request::read_client_id(&packet.payload)
+        }
+
+        async fn decode(
+            &self,
+            _ctx: &HandlerContext<'_>,
+            packet: &RequestPacket,
+        ) -> Result<Self::Request, RequestError> {
+            Ok(packet.payload.clone())
+        }
+
+        async fn handle(
+            &self,
+            _ctx: &HandlerContext<'_>,
+            request: Self::Request,
+        ) -> Result<HandlerResponse, String> {
+            Ok(HandlerResponse::success(
+                String::from_utf8_lossy(&request).to_string(),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_defaults() {
+        let registry = HandlerRegistry::with_defaults();
+        assert_eq!(registry.name(0x01), Some("Login"));
+        assert_eq!(registry.name(0x02), Some("Coordinates"));
+        assert_eq!(registry.name(0x03), Some("HeartBeat"));
+        assert_eq!(registry.name(0x04), Some("Logout"));
+        assert_eq!(registry.name(0x05), None);
+    }
+
+    #[test]
+    fn test_register_custom_handler() {
+        let mut registry = HandlerRegistry::with_defaults();
+        assert_eq!(registry.register(EchoHandler), None);
+        assert_eq!(registry.name(0x05), Some("Echo"));
+        assert_eq!(registry.register(EchoHandler), Some("Echo"));
+    }
+
+    #[test]
+    fn test_client_id() {
+        let mut registry = HandlerRegistry::with_defaults();
+        let echo = RequestPacket::parse(&[0x05, 0x00, 0x04, 0x00, 0x00, 0x5F, 0xF4]).unwrap();
+        assert_eq!(registry.client_id(&echo), None);
+        registry.register(EchoHandler);
+        assert_eq!(registry.client_id(&echo), Some(24564));
+        let heartbeat = RequestPacket::parse(&[0x03, 0x00, 0x04, 0x00, 0x00, 0x5F, 0xF4]).unwrap();
+        assert_eq!(registry.client_id(&heartbeat), Some(24564));
+        let login = RequestPacket::parse(&[0x01, 0x00, 0x04, b'r', b'o', b'o', b't']).unwrap();
+        assert_eq!(registry.client_id(&login), None);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_invalid_packet() {
+        use crate::config::Config;
+        use crate::store::MemoryStore;
+        use crate::validation::ValidationError;
+
+        let context = AppContext::with_store(Config::default(), Arc::new(MemoryStore::new()));
+        let ctx = HandlerContext {
+            context: &context,
+            source_address: "127.0.0.1:5000".parse().unwrap(),
+        };
+        let registry = HandlerRegistry::with_defaults();
+        // A coordinates packet holding only a client id.
+        let packet = RequestPacket::parse(&[0x02, 0x00, 0x04, 0x00, 0x00, 0x5F, 0xF4]).unwrap();
+        assert_eq!(
+            registry.dispatch(&ctx, &packet).await,
+            Some(Err(RequestError::Invalid(ValidationError::InvalidLatitude)))
+        );
+        // A heartbeat of an unknown device fails in the store instead.
+        let packet = RequestPacket::parse(&[0x03, 0x00, 0x04, 0x00, 0x00, 0x5F, 0xF4]).unwrap();
+        assert!(matches!(
+            registry.dispatch(&ctx, &packet).await,
+            Some(Err(RequestError::Failed(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_default_encode() {
+        let response = EchoHandler
+            .encode(HandlerResponse::success("42".to_string()))
+            .await;
+        assert_eq!(response, Ok("05 06 34 32".to_string()));
+        let response = EchoHandler
+            .encode(HandlerResponse::error("0".to_string()))
+            .await;
+        assert_eq!(response, Ok("05 07 30".to_string()));
+    }
+}
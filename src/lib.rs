@@ -1,9 +1,28 @@
 pub mod actions;
+pub mod admin;
+pub mod capture;
 pub mod config;
+pub mod context;
 pub mod db;
+pub mod decode;
+pub mod device;
+pub mod handler;
+pub mod logging;
+pub mod metrics;
+pub mod migrations;
+pub mod password;
 pub mod payload;
+pub mod pcap;
+pub mod pipeline;
+pub mod rate_limit;
+pub mod reload;
 pub mod request;
 pub mod response;
+pub mod retention;
+pub mod session;
+pub mod shutdown;
+pub mod spool;
+pub mod store;
 pub mod udp_server;
 pub mod user;
 pub mod validation;
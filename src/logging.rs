@@ -0,0 +1,91 @@
+use crate::config::{LogConfig, LogFormat};
+use std::fmt;
+use std::sync::OnceLock;
+use tracing::warn;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// Wraps a secret so that `Debug` and `Display` never print its value.
+#[derive(Clone, PartialEq)]
+pub struct Redacted<T>(pub T);
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[REDACTED]")
+    }
+}
+
+impl<T> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[REDACTED]")
+    }
+}
+
+/// Handle swapping the filter of the installed subscriber, and whether
+/// `RUST_LOG` chose that filter.
+static FILTER: OnceLock<(reload::Handle<EnvFilter, Registry>, bool)> = OnceLock::new();
+
+/// Parses a filter directive such as `info` or `gps_tracker=debug`.
+pub fn parse_filter(level: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(level).map_err(|error| error.to_string())
+}
+
+/// Installs the global tracing subscriber. `RUST_LOG`, when set, takes
+/// precedence over the configured level.
+pub fn init(config: &LogConfig) -> Result<(), String> {
+    let (filter, from_env) = match EnvFilter::try_from_default_env() {
+        Ok(filter) => (filter, true),
+        Err(_) => match parse_filter(config.level.as_str()) {
+            Ok(filter) => (filter, false),
+            Err(error) => return Err(format!("log config error: {:?}", error)),
+        },
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let registry = tracing_subscriber::registry().with(filter);
+    let result = match config.format {
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json().flatten_event(true))
+            .try_init(),
+        LogFormat::Pretty => registry.with(tracing_subscriber::fmt::layer()).try_init(),
+    };
+    result.map_err(|error| format!("log config error: {:?}", error.to_string()))?;
+    let _ = FILTER.set((handle, from_env));
+    Ok(())
+}
+
+/// Applies a new level to the installed subscriber. Does nothing when
+/// `RUST_LOG` chose the filter or no subscriber was installed by `init`.
+pub fn reload_level(level: &str) -> Result<(), String> {
+    let filter = parse_filter(level).map_err(|error| format!("log config error: {:?}", error))?;
+    match FILTER.get() {
+        Some((_, true)) => {
+            warn!("RUST_LOG is set, ignoring the configured log level");
+            Ok(())
+        }
+        Some((handle, false)) => handle
+            .reload(filter)
+            .map_err(|error| format!("log config error: {:?}", error.to_string())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test_logging {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        let secret = Redacted("notsecurepassword".to_string());
+        assert_eq!(format!("{:?}", secret), "[REDACTED]");
+        assert_eq!(format!("{}", secret), "[REDACTED]");
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        assert!(parse_filter("info").is_ok());
+        assert!(parse_filter("gps_tracker=debug,surrealdb=warn").is_ok());
+        assert!(parse_filter("gps_tracker=loud").is_err());
+        assert!(reload_level("gps_tracker=loud").is_err());
+    }
+}
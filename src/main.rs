@@ -1,7 +1,118 @@
+use clap::Parser;
+use gps_tracker::actions::Coordinates;
+use gps_tracker::capture::Recorder;
+use gps_tracker::config::Config;
+use gps_tracker::context::AppContext;
+use gps_tracker::logging;
+use gps_tracker::metrics::Metrics;
+use gps_tracker::pipeline::{StoreBatchWriter, WritePipeline};
+use gps_tracker::reload::ConfigReloader;
+use gps_tracker::retention::Retention;
 use gps_tracker::udp_server::UdpServer;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// Config file, `config.toml` by default. `APP_CONFIG_PATH` takes precedence.
+    #[arg(short, long)]
+    config_path: Option<String>,
+    /// Overrides a config key, e.g. `--set server.rate_limit.client_burst=20`.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), String> {
-    UdpServer::launch().await?;
+    let args = Args::parse();
+    let config_path = Config::resolve_path(args.config_path.clone());
+    let config = Config::load_with(args.config_path, &args.overrides).await?;
+    logging::init(&config.log)?;
+    let mut context = AppContext::init(config).await?.with_spool()?;
+    // Handlers answer as soon as a record is queued, so the pipeline only
+    // runs with a spool to catch the batches it fails to write. Without one,
+    // every record is stored before its answer.
+    let pipeline_task = match context.spool.clone() {
+        Some(spool) => {
+            let (pipeline, task) = WritePipeline::spawn(
+                &context.config.server.pipeline,
+                StoreBatchWriter::new(context.store.clone()),
+                Some(spool),
+            );
+            context.pipeline = Some(pipeline);
+            Some(task)
+        }
+        None => {
+            warn!("spool disabled, storing every record before answering");
+            None
+        }
+    };
+    let shutdown = context.shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(error) = shutdown.listen_for_signals().await {
+            error!(%error, "unable to listen for shutdown signals");
+        }
+    });
+    if let Some(metrics_host) = context.config.server.metrics_host.clone() {
+        let shutdown = context.shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(error) = Metrics::serve(metrics_host, shutdown).await {
+                error!(%error, "metrics server failed");
+            }
+        });
+    }
+    let sessions = context.sessions.clone();
+    let shutdown = context.shutdown.clone();
+    tokio::spawn(async move { sessions.run(shutdown).await });
+    let sessions = context.sessions.clone();
+    let (store, shutdown) = (context.store.clone(), context.shutdown.clone());
+    tokio::spawn(async move { sessions.persist(store, shutdown).await });
+    if let Some(spool) = context.spool.clone() {
+        let coordinates =
+            Coordinates::new(context.store.clone()).with_devices(context.devices.clone());
+        let shutdown = context.shutdown.clone();
+        tokio::spawn(async move { spool.run(coordinates, shutdown).await });
+    }
+    if context.config.server.retention.enabled {
+        let retention = Retention::new(
+            context.store.clone(),
+            context.config.server.retention.clone(),
+        );
+        let shutdown = context.shutdown.clone();
+        tokio::spawn(async move { retention.run(shutdown).await });
+    }
+    let mut server = UdpServer::new(context);
+    let mut recorder_task = None;
+    if server.context().config.server.capture.enabled {
+        let (recorder, task) = Recorder::spawn(&server.context().config.server.capture)?;
+        info!(path = %recorder.path().display(), "recording packets");
+        server = server.with_recorder(recorder);
+        recorder_task = Some(task);
+    }
+    let reloader = ConfigReloader::new(
+        config_path,
+        args.overrides,
+        server.context().config.clone(),
+        server.rate_limiter().clone(),
+        server.context().sessions.clone(),
+    );
+    let poll_interval =
+        Duration::from_millis(server.context().config.server.config_reload_interval_ms);
+    let shutdown = server.context().shutdown.clone();
+    tokio::spawn(async move { reloader.run(poll_interval, shutdown).await });
+    server.launch().await?;
+    // Dropping the server drops the last pipeline and recorder senders, so
+    // the writers flush the queued records and stop.
+    drop(server);
+    if let Some(pipeline_task) = pipeline_task {
+        if let Err(error) = pipeline_task.await {
+            error!(%error, "write pipeline failed");
+        }
+    }
+    if let Some(recorder_task) = recorder_task {
+        if let Err(error) = recorder_task.await {
+            error!(%error, "capture writer failed");
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,246 @@
+use crate::shutdown::Shutdown;
+use crate::validation::ValidationError;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+/// Process-wide Prometheus registry updated by the UDP server, the actions
+/// and the API. Each process only holds its own metrics: the UDP server's
+/// are served on `server.metrics_host`, the API's on its `/metrics` route.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub packets_received: IntCounterVec,
+    pub request_errors: IntCounterVec,
+    pub validation_errors: IntCounterVec,
+    pub auth_failures: IntCounter,
+    pub rate_limited: IntCounterVec,
+    pub request_duration: HistogramVec,
+    pub db_query_duration: HistogramVec,
+    pub websocket_subscribers: IntGauge,
+    pub spool_pending: IntGauge,
+    pub pipeline_dropped: IntCounterVec,
+    pub retention_removed: IntCounterVec,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+impl Metrics {
+    /// Returns the registry shared by the whole process.
+    pub fn global() -> &'static Metrics {
+        METRICS.get_or_init(|| Metrics::new().expect("metrics registration failed"))
+    }
+
+    pub fn new() -> Result<Self, String> {
+        let registry = Registry::new_custom(Some("gps".to_string()), None)
+            .map_err(|error| format!("metrics error: {:?}", error))?;
+        let metrics = Self {
+            packets_received: IntCounterVec::new(
+                Opts::new(
+                    "packets_received_total",
+                    "Datagrams received by request type",
+                ),
+                &["request_type"],
+            )
+            .map_err(|error| format!("metrics error: {:?}", error))?,
+            request_errors: IntCounterVec::new(
+                Opts::new(
+                    "request_errors_total",
+                    "Requests that failed to be handled, by request type",
+                ),
+                &["request_type"],
+            )
+            .map_err(|error| format!("metrics error: {:?}", error))?,
+            validation_errors: IntCounterVec::new(
+                Opts::new(
+                    "validation_errors_total",
+                    "Packets rejected by validation, by ValidationError variant",
+                ),
+                &["error"],
+            )
+            .map_err(|error| format!("metrics error: {:?}", error))?,
+            auth_failures: IntCounter::new("auth_failures_total", "Failed login attempts")
+                .map_err(|error| format!("metrics error: {:?}", error))?,
+            rate_limited: IntCounterVec::new(
+                Opts::new(
+                    "rate_limited_total",
+                    "Datagrams answered with slow down, by limit key",
+                ),
+                &["key"],
+            )
+            .map_err(|error| format!("metrics error: {:?}", error))?,
+            request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "request_duration_seconds",
+                    "Time spent handling a datagram, by request type",
+                ),
+                &["request_type"],
+            )
+            .map_err(|error| format!("metrics error: {:?}", error))?,
+            db_query_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "db_query_duration_seconds",
+                    "Database round trip latency, by operation",
+                ),
+                &["operation"],
+            )
+            .map_err(|error| format!("metrics error: {:?}", error))?,
+            websocket_subscribers: IntGauge::new(
+                "websocket_subscribers",
+                "Websocket sessions subscribed to live coordinates",
+            )
+            .map_err(|error| format!("metrics error: {:?}", error))?,
+            spool_pending: IntGauge::new(
+                "spool_pending",
+                "Fixes spooled while the database was unreachable, awaiting replay",
+            )
+            .map_err(|error| format!("metrics error: {:?}", error))?,
+            pipeline_dropped: IntCounterVec::new(
+                Opts::new(
+                    "pipeline_dropped_total",
+                    "Records the write pipeline could neither store nor spool, by table",
+                ),
+                &["table"],
+            )
+            .map_err(|error| format!("metrics error: {:?}", error))?,
+            retention_removed: IntCounterVec::new(
+                Opts::new(
+                    "retention_removed_total",
+                    "Records removed by the retention job, by table and reason",
+                ),
+                &["table", "reason"],
+            )
+            .map_err(|error| format!("metrics error: {:?}", error))?,
+            registry,
+        };
+        metrics.register()?;
+        Ok(metrics)
+    }
+
+    fn register(&self) -> Result<(), String> {
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(self.packets_received.clone()),
+            Box::new(self.request_errors.clone()),
+            Box::new(self.validation_errors.clone()),
+            Box::new(self.auth_failures.clone()),
+            Box::new(self.rate_limited.clone()),
+            Box::new(self.request_duration.clone()),
+            Box::new(self.db_query_duration.clone()),
+            Box::new(self.websocket_subscribers.clone()),
+            Box::new(self.spool_pending.clone()),
+            Box::new(self.pipeline_dropped.clone()),
+            Box::new(self.retention_removed.clone()),
+        ];
+        for collector in collectors {
+            if let Err(error) = self.registry.register(collector) {
+                return Err(format!("metrics error: {:?}", error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a datagram under the name of the handler for its request type.
+    pub fn record_packet(&self, request_type: &str) {
+        self.packets_received
+            .with_label_values(&[request_type])
+            .inc();
+    }
+
+    pub fn record_request_error(&self, request_type: &str) {
+        self.request_errors.with_label_values(&[request_type]).inc();
+    }
+
+    pub fn record_validation_error(&self, error: &ValidationError) {
+        self.validation_errors
+            .with_label_values(&[error.name()])
+            .inc();
+    }
+
+    /// Starts a timer that records the database latency of `operation` when dropped.
+    pub fn start_db_timer(&self, operation: &str) -> HistogramTimer {
+        self.db_query_duration
+            .with_label_values(&[operation])
+            .start_timer()
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> Result<String, String> {
+        let mut buffer: Vec<u8> = Vec::new();
+        if let Err(error) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            return Err(format!("metrics error: {:?}", error));
+        }
+        String::from_utf8(buffer).map_err(|error| format!("metrics error: {:?}", error))
+    }
+
+    /// Serves `/metrics` over plain HTTP for binaries without a web server,
+    /// until `shutdown` is triggered.
+    pub async fn serve(host: String, shutdown: Shutdown) -> Result<(), String> {
+        let listener = match TcpListener::bind(host.as_str()).await {
+            Ok(listener) => listener,
+            Err(error) => return Err(format!("unable to bind metrics server, reason: {}", error)),
+        };
+        info!(%host, "metrics server listening");
+        loop {
+            let (mut stream, _) = tokio::select! {
+                _ = shutdown.wait() => return Ok(()),
+                accepted = listener.accept() => match accepted {
+                    Ok(value) => value,
+                    Err(error) => {
+                        warn!(%error, "unable to accept metrics connection");
+                        continue;
+                    }
+                },
+            };
+            tokio::spawn(async move {
+                // The request itself is irrelevant: every path returns the metrics.
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let response = match Self::global().render() {
+                    Ok(body) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        prometheus::TEXT_FORMAT,
+                        body.len(),
+                        body
+                    ),
+                    Err(error) => format!(
+                        "HTTP/1.1 500 Internal Server Error\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        error.len(),
+                        error
+                    ),
+                };
+                if let Err(error) = stream.write_all(response.as_bytes()).await {
+                    warn!(%error, "unable to send metrics response");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use super::*;
+    use crate::request::RequestType;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new().unwrap();
+        metrics.record_packet(RequestType::HeartBeat.name());
+        metrics.record_validation_error(&ValidationError::InvalidClientId);
+        metrics.rate_limited.with_label_values(&["client"]).inc();
+        drop(metrics.start_db_timer("users.get_by_client_id"));
+
+        let output = metrics.render().unwrap();
+        assert!(output.contains("gps_packets_received_total{request_type=\"HeartBeat\"} 1"));
+        assert!(output.contains("gps_validation_errors_total{error=\"InvalidClientId\"} 1"));
+        assert!(output.contains("gps_rate_limited_total{key=\"client\"} 1"));
+        assert!(output.contains(
+            "gps_db_query_duration_seconds_count{operation=\"users.get_by_client_id\"} 1"
+        ));
+        assert!(output.contains("gps_websocket_subscribers 0"));
+    }
+}
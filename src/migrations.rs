@@ -0,0 +1,244 @@
+use crate::db::Db;
+use serde::Deserialize;
+use tracing::info;
+
+/// A versioned SurrealQL script. Versions start at 1 and increase by one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub script: &'static str,
+}
+
+/// Every migration this build knows, oldest first.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        script: include_str!("../migrations/surrealdb/0001_initial.surql"),
+    },
+    Migration {
+        version: 2,
+        name: "hash_passwords",
+        script: include_str!("../migrations/surrealdb/0002_hash_passwords.surql"),
+    },
+    Migration {
+        version: 3,
+        name: "disable_users",
+        script: include_str!("../migrations/surrealdb/0003_disable_users.surql"),
+    },
+    Migration {
+        version: 4,
+        name: "devices",
+        script: include_str!("../migrations/surrealdb/0004_devices.surql"),
+    },
+    Migration {
+        version: 5,
+        name: "sessions",
+        script: include_str!("../migrations/surrealdb/0005_sessions.surql"),
+    },
+];
+
+/// Version of the newest migration in this build.
+pub fn latest_version() -> u32 {
+    MIGRATIONS
+        .last()
+        .map(|migration| migration.version)
+        .unwrap_or(0)
+}
+
+/// Migrations to apply on a database at `current`. Fails when the database
+/// was migrated by a newer build, since this one would not know its schema.
+pub fn pending(current: u32) -> Result<&'static [Migration], String> {
+    let latest = latest_version();
+    if current > latest {
+        return Err(format!(
+            "migration error: database schema is at version {} but this build only knows up to {}",
+            current, latest
+        ));
+    }
+    Ok(&MIGRATIONS[current as usize..])
+}
+
+#[derive(Debug, Deserialize)]
+struct Applied {
+    version: u32,
+}
+
+/// Highest version recorded in the `migration` table, 0 for a new database.
+pub async fn current_version(db: &Db) -> Result<u32, String> {
+    match db
+        .client()
+        .query("SELECT version FROM migration ORDER BY version DESC LIMIT 1")
+        .await
+    {
+        Ok(mut response) => match response.take::<Option<Applied>>(0) {
+            Ok(applied) => Ok(applied.map(|applied| applied.version).unwrap_or(0)),
+            Err(error) => Err(format!("migration error: {:?}", error)),
+        },
+        Err(error) => Err(format!("migration error: {:?}", error)),
+    }
+}
+
+/// Fails unless the database is at the version of this build.
+pub async fn check(db: &Db) -> Result<(), String> {
+    let current = current_version(db).await?;
+    match pending(current)?.len() {
+        0 => Ok(()),
+        count => Err(format!(
+            "migration error: {} migration(s) pending, run `gps-migrate up`",
+            count
+        )),
+    }
+}
+
+/// Applies the pending migrations, each in its own transaction along with the
+/// record of its version, and returns the versions applied.
+pub async fn migrate(db: &Db) -> Result<Vec<u32>, String> {
+    migrate_to(db, latest_version()).await
+}
+
+/// Applies the pending migrations up to `target` included, like `migrate`.
+pub async fn migrate_to(db: &Db, target: u32) -> Result<Vec<u32>, String> {
+    let mut applied = Vec::new();
+    let pending = pending(current_version(db).await?)?;
+    for migration in pending
+        .iter()
+        .filter(|migration| migration.version <= target)
+    {
+        let result = db
+            .client()
+            .query(format!(
+                "BEGIN TRANSACTION;\n{}\n\
+                 CREATE type::thing('migration', $version) \
+                 SET version = $version, name = $name, applied_at = time::now();\n\
+                 COMMIT TRANSACTION;",
+                migration.script
+            ))
+            .bind(("version", migration.version))
+            .bind(("name", migration.name))
+            .await;
+        let result = match result {
+            Ok(response) => response.check().map(|_| ()),
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            // Another process may have applied it first.
+            if current_version(db).await? >= migration.version {
+                continue;
+            }
+            return Err(format!(
+                "migration error: version {} ({}) failed: {:?}",
+                migration.version, migration.name, error
+            ));
+        }
+        info!(
+            version = migration.version,
+            name = migration.name,
+            "migration applied"
+        );
+        applied.push(migration.version);
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod test_migrations {
+    use super::*;
+    use crate::config::{DatabaseConfig, DatabaseEngine};
+    use crate::password;
+    use crate::store::{Store, SurrealStore};
+    use surrealdb::sql::Datetime;
+    use surrealdb::RecordId;
+
+    #[test]
+    fn test_versions() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, index + 1, "{}", migration.name);
+        }
+        assert_eq!(pending(0).unwrap().len(), MIGRATIONS.len());
+        assert!(pending(latest_version()).unwrap().is_empty());
+        let error = pending(latest_version() + 1).unwrap_err();
+        assert!(error.contains("only knows up to"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let config = DatabaseConfig {
+            engine: DatabaseEngine::Memory,
+            migrate_on_start: false,
+            ..DatabaseConfig::default()
+        };
+        let db = Db::connect(&config).await.unwrap();
+        assert!(check(&db).await.is_err());
+        assert_eq!(migrate_to(&db, 1).await.unwrap(), [1]);
+
+        // Records as written before passwords were hashed and devices existed.
+        let client = db.client();
+        client
+            .query(
+                "CREATE users:root SET name = 'Root', username = 'root', \
+                 password = 'notsecurepassword', client_id = 24564; \
+                 CREATE coordinates:fix SET user = users:root, latitude = 14.65, \
+                 longitude = 121.04, timestamp = d'2024-01-01T00:00:00Z'; \
+                 CREATE heartbeat:beat SET user = users:root, \
+                 source_address = '127.0.0.1:5000', timestamp = d'2024-01-01T00:00:00Z'",
+            )
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+        assert_eq!(migrate(&db).await.unwrap().len(), MIGRATIONS.len() - 1);
+        assert!(migrate(&db).await.unwrap().is_empty());
+        check(&db).await.unwrap();
+
+        // The user became a device keyed like it, holding its client id and
+        // its password as the device key, and its history moved along.
+        let store = SurrealStore::new(db.clone());
+        let root = store.user_by_username("root").await.unwrap().unwrap();
+        assert!(password::verify("notsecurepassword", &root.password));
+        assert!(!root.disabled);
+        let device = store.device_by_client_id(24564).await.unwrap();
+        assert_eq!(device.id, Some(RecordId::from_table_key("devices", "root")));
+        assert_eq!(device.serial, "root");
+        assert_eq!(device.owner, root.id.clone().unwrap());
+        assert!(password::verify("notsecurepassword", &device.credentials));
+        let track = store
+            .track(
+                24564,
+                Datetime::from(chrono::DateTime::UNIX_EPOCH),
+                Datetime::from(chrono::Utc::now()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(track.len(), 1);
+        assert_eq!(track[0].device, device.id.clone().unwrap());
+        let mut response = client
+            .query("SELECT VALUE device FROM heartbeat; SELECT VALUE user FROM coordinates")
+            .await
+            .unwrap();
+        let heartbeats: Vec<RecordId> = response.take(0).unwrap();
+        assert_eq!(heartbeats, [device.id.clone().unwrap()]);
+        let users: Vec<Option<RecordId>> = response.take(1).unwrap();
+        assert_eq!(users, [None]);
+
+        let plaintext = "CREATE users SET name = 'Other', username = 'other', \
+                         password = 'notsecurepassword'";
+        assert!(client.query(plaintext).await.unwrap().check().is_err());
+        let create = "CREATE users SET name = 'Root', username = 'root', \
+                      password = crypto::argon2::generate('notsecurepassword')";
+        // The unique index on username rejects a second root.
+        assert!(client.query(create).await.unwrap().check().is_err());
+        let plaintext = "CREATE devices SET serial = 'other', name = 'Other', \
+                         owner = users:root, credentials = 'key', client_id = 1";
+        assert!(client.query(plaintext).await.unwrap().check().is_err());
+
+        client
+            .query("CREATE migration:99 SET version = 99")
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+        assert!(migrate(&db).await.is_err());
+    }
+}
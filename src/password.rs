@@ -0,0 +1,101 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::sync::OnceLock;
+
+/// Hashes `password` as an Argon2id PHC string with a random salt.
+pub fn hash(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(error) => Err(format!("password.hash error: {:?}", error)),
+    }
+}
+
+/// Whether a stored password is a PHC hash rather than legacy plaintext.
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with("$argon2")
+}
+
+/// Checks `password` against a stored hash. The digests are compared in
+/// constant time; anything that is not a valid hash never matches.
+pub fn verify(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Random device key: 24 hex characters, short enough for a login packet.
+pub fn generate_key() -> String {
+    let mut key = [0u8; 12];
+    OsRng.fill_bytes(&mut key);
+    hex::encode(key)
+}
+
+/// Burns the time of one verification, so an unknown username takes as long
+/// to reject as a wrong password.
+pub fn verify_dummy(password: &str) {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    let hash = DUMMY.get_or_init(|| hash("dummy password").unwrap_or_default());
+    verify(password, hash);
+}
+
+/// `hash` on the blocking pool; Argon2 is slow on purpose.
+pub async fn hash_async(password: &str) -> Result<String, String> {
+    let password = password.to_string();
+    match tokio::task::spawn_blocking(move || hash(&password)).await {
+        Ok(result) => result,
+        Err(error) => Err(format!("password.hash error: {:?}", error)),
+    }
+}
+
+/// `verify` on the blocking pool. Without a stored hash the check still
+/// takes as long, and fails.
+pub async fn verify_async(password: &str, stored: Option<String>) -> Result<bool, String> {
+    let password = password.to_string();
+    match tokio::task::spawn_blocking(move || match stored {
+        Some(stored) => verify(&password, &stored),
+        None => {
+            verify_dummy(&password);
+            false
+        }
+    })
+    .await
+    {
+        Ok(verified) => Ok(verified),
+        Err(error) => Err(format!("password.verify error: {:?}", error)),
+    }
+}
+
+#[cfg(test)]
+mod test_password {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hash = hash("notsecurepassword").unwrap();
+        assert!(hash.starts_with("$argon2id$"), "{}", hash);
+        assert!(is_hashed(&hash));
+        assert!(verify("notsecurepassword", &hash));
+        assert!(!verify("wrong", &hash));
+        // Salts are random, so the same password hashes differently.
+        assert_ne!(hash, super::hash("notsecurepassword").unwrap());
+    }
+
+    #[test]
+    fn test_plaintext_never_matches() {
+        assert!(!is_hashed("notsecurepassword"));
+        assert!(!verify("notsecurepassword", "notsecurepassword"));
+        assert!(!verify("", ""));
+    }
+
+    #[test]
+    fn test_generate_key() {
+        let key = generate_key();
+        assert_eq!(key.len(), 24);
+        assert_ne!(key, generate_key());
+    }
+}
@@ -22,7 +22,7 @@ impl Payload {
             with_spacing.push(c);
             counter += 1;
             if counter == 2 {
-                with_spacing.push_str(&" ");
+                with_spacing.push(' ');
                 counter = 0;
             }
         }
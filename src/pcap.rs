@@ -0,0 +1,397 @@
+use chrono::{DateTime, Utc};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// A UDP datagram extracted from a capture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Datagram {
+    pub timestamp: DateTime<Utc>,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+/// Value some platforms write for raw IP instead of `LINKTYPE_RAW`.
+const DLT_RAW: [u32; 2] = [12, 14];
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: [u16; 2] = [0x8100, 0x88A8];
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// Reads integers in the byte order of the capture.
+#[derive(Debug, Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(self, data: &[u8], offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(self, data: &[u8], offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+}
+
+/// Extracts the UDP datagrams of a pcap or pcapng file. Packets of other
+/// protocols, IP fragments and a truncated last record are skipped.
+pub fn read_datagrams(data: &[u8]) -> Result<Vec<Datagram>, String> {
+    match data.get(0..4) {
+        Some([0x0A, 0x0D, 0x0D, 0x0A]) => read_pcapng(data),
+        Some(magic) => read_pcap(data, magic),
+        None => Err("pcap error: file too short".to_string()),
+    }
+}
+
+fn read_pcap(data: &[u8], magic: &[u8]) -> Result<Vec<Datagram>, String> {
+    let (big, units_per_sec) = match magic {
+        [0xA1, 0xB2, 0xC3, 0xD4] => (true, 1_000_000),
+        [0xD4, 0xC3, 0xB2, 0xA1] => (false, 1_000_000),
+        [0xA1, 0xB2, 0x3C, 0x4D] => (true, 1_000_000_000),
+        [0x4D, 0x3C, 0xB2, 0xA1] => (false, 1_000_000_000),
+        _ => return Err(format!("pcap error: unknown magic {}", hex::encode(magic))),
+    };
+    let endian = Endian { big };
+    let linktype = match endian.u32(data, 20) {
+        Some(linktype) => linktype & 0xFFFF,
+        None => return Err("pcap error: truncated file header".to_string()),
+    };
+    let mut datagrams: Vec<Datagram> = Vec::new();
+    let mut offset: usize = 24;
+    while let (Some(seconds), Some(fraction), Some(length)) = (
+        endian.u32(data, offset),
+        endian.u32(data, offset + 4),
+        endian.u32(data, offset + 8),
+    ) {
+        let start = offset + 16;
+        let Some(frame) = data.get(start..start + length as usize) else {
+            break;
+        };
+        let units = seconds as u64 * units_per_sec + fraction as u64;
+        if let Some(datagram) = udp_datagram(linktype, frame, timestamp(units, units_per_sec)) {
+            datagrams.push(datagram);
+        }
+        offset = start + length as usize;
+    }
+    Ok(datagrams)
+}
+
+/// Interface described by a pcapng interface description block.
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    linktype: u32,
+    units_per_sec: u64,
+}
+
+fn read_pcapng(data: &[u8]) -> Result<Vec<Datagram>, String> {
+    let mut datagrams: Vec<Datagram> = Vec::new();
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut endian = Endian { big: false };
+    let mut offset: usize = 0;
+    while offset + 12 <= data.len() {
+        // The section header sets the byte order of the blocks that follow.
+        if data[offset..offset + 4] == [0x0A, 0x0D, 0x0D, 0x0A] {
+            endian.big = match data.get(offset + 8..offset + 12) {
+                Some([0x1A, 0x2B, 0x3C, 0x4D]) => true,
+                Some([0x4D, 0x3C, 0x2B, 0x1A]) => false,
+                _ => return Err("pcapng error: invalid byte order magic".to_string()),
+            };
+            interfaces.clear();
+        }
+        let (Some(block_type), Some(length)) =
+            (endian.u32(data, offset), endian.u32(data, offset + 4))
+        else {
+            break;
+        };
+        let length = length as usize;
+        if length < 12 || !length.is_multiple_of(4) {
+            return Err(format!("pcapng error: invalid block length {}", length));
+        }
+        let Some(block) = data.get(offset..offset + length) else {
+            break;
+        };
+        match block_type {
+            // Interface description.
+            1 => interfaces.push(Interface {
+                linktype: endian.u16(block, 8).unwrap_or_default() as u32,
+                units_per_sec: timestamp_resolution(endian, &block[..length - 4]),
+            }),
+            // Enhanced packet.
+            6 => {
+                let interface = endian
+                    .u32(block, 8)
+                    .and_then(|index| interfaces.get(index as usize));
+                if let (Some(interface), Some(high), Some(low), Some(captured)) = (
+                    interface,
+                    endian.u32(block, 12),
+                    endian.u32(block, 16),
+                    endian.u32(block, 20),
+                ) {
+                    if let Some(frame) = block.get(28..28 + captured as usize) {
+                        let units = ((high as u64) << 32) | low as u64;
+                        let time = timestamp(units, interface.units_per_sec);
+                        if let Some(datagram) = udp_datagram(interface.linktype, frame, time) {
+                            datagrams.push(datagram);
+                        }
+                    }
+                }
+            }
+            // Simple packet, which has no timestamp.
+            3 => {
+                if let (Some(interface), Some(original)) =
+                    (interfaces.first(), endian.u32(block, 8))
+                {
+                    let end = (12 + original as usize).min(length - 4);
+                    if let Some(frame) = block.get(12..end) {
+                        if let Some(datagram) =
+                            udp_datagram(interface.linktype, frame, DateTime::UNIX_EPOCH)
+                        {
+                            datagrams.push(datagram);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+    Ok(datagrams)
+}
+
+/// Reads the `if_tsresol` option of an interface description block, which
+/// defaults to microseconds.
+fn timestamp_resolution(endian: Endian, block: &[u8]) -> u64 {
+    let mut offset: usize = 16;
+    while let (Some(code), Some(length)) =
+        (endian.u16(block, offset), endian.u16(block, offset + 2))
+    {
+        if code == 0 {
+            break;
+        }
+        if code == 9 && length == 1 {
+            if let Some(value) = block.get(offset + 4) {
+                let exponent = (value & 0x7F) as u32;
+                let base: u64 = if value & 0x80 == 0 { 10 } else { 2 };
+                return base.checked_pow(exponent).unwrap_or(1_000_000);
+            }
+        }
+        offset += 4 + (length as usize).div_ceil(4) * 4;
+    }
+    1_000_000
+}
+
+fn timestamp(units: u64, units_per_sec: u64) -> DateTime<Utc> {
+    let seconds = (units / units_per_sec) as i64;
+    let nanos = ((units % units_per_sec) as u128 * 1_000_000_000 / units_per_sec as u128) as u32;
+    DateTime::from_timestamp(seconds, nanos).unwrap_or_default()
+}
+
+/// Strips the link layer header, returning the IP packet.
+fn ip_packet(linktype: u32, frame: &[u8]) -> Option<&[u8]> {
+    match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut offset: usize = 12;
+            let mut ethertype = u16::from_be_bytes(frame.get(offset..offset + 2)?.try_into().ok()?);
+            while ETHERTYPE_VLAN.contains(&ethertype) {
+                offset += 4;
+                ethertype = u16::from_be_bytes(frame.get(offset..offset + 2)?.try_into().ok()?);
+            }
+            match ethertype {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => frame.get(offset + 2..),
+                _ => None,
+            }
+        }
+        // The IP version is read from the packet rather than the address
+        // family, which is in the byte order of the capturing host.
+        LINKTYPE_NULL => frame.get(4..),
+        LINKTYPE_LINUX_SLL => frame.get(16..),
+        LINKTYPE_LINUX_SLL2 => frame.get(20..),
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(frame),
+        value if DLT_RAW.contains(&value) => Some(frame),
+        _ => None,
+    }
+}
+
+fn udp_datagram(linktype: u32, frame: &[u8], timestamp: DateTime<Utc>) -> Option<Datagram> {
+    let packet = ip_packet(linktype, frame)?;
+    let (source, destination, segment) = match packet.first()? >> 4 {
+        4 => {
+            let header_length = ((packet[0] & 0x0F) as usize) * 4;
+            let total_length = u16::from_be_bytes(packet.get(2..4)?.try_into().ok()?) as usize;
+            let fragment = u16::from_be_bytes(packet.get(6..8)?.try_into().ok()?);
+            // More fragments flag or a fragment offset.
+            if fragment & 0x3FFF != 0 || *packet.get(9)? != IP_PROTOCOL_UDP {
+                return None;
+            }
+            let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            let end = total_length.min(packet.len());
+            (
+                IpAddr::V4(Ipv4Addr::from(source)),
+                IpAddr::V4(Ipv4Addr::from(destination)),
+                packet.get(header_length..end)?,
+            )
+        }
+        6 => {
+            let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            let mut next_header = *packet.get(6)?;
+            let mut offset: usize = 40;
+            // Hop-by-hop, routing and destination options headers.
+            while [0, 43, 60].contains(&next_header) {
+                next_header = *packet.get(offset)?;
+                offset += (*packet.get(offset + 1)? as usize + 1) * 8;
+            }
+            if next_header != IP_PROTOCOL_UDP {
+                return None;
+            }
+            (
+                IpAddr::V6(Ipv6Addr::from(source)),
+                IpAddr::V6(Ipv6Addr::from(destination)),
+                packet.get(offset..)?,
+            )
+        }
+        _ => return None,
+    };
+    let source_port = u16::from_be_bytes(segment.get(0..2)?.try_into().ok()?);
+    let destination_port = u16::from_be_bytes(segment.get(2..4)?.try_into().ok()?);
+    let length = u16::from_be_bytes(segment.get(4..6)?.try_into().ok()?) as usize;
+    let payload = segment.get(8..length.clamp(8, segment.len()))?;
+    Some(Datagram {
+        timestamp,
+        source: SocketAddr::new(source, source_port),
+        destination: SocketAddr::new(destination, destination_port),
+        payload: payload.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod test_pcap {
+    use super::*;
+
+    fn udp(source_port: u16, destination_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut segment: Vec<u8> = Vec::new();
+        segment.extend_from_slice(&source_port.to_be_bytes());
+        segment.extend_from_slice(&destination_port.to_be_bytes());
+        segment.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        segment.extend_from_slice(&[0, 0]);
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn ipv4_ethernet_frame(segment: &[u8]) -> Vec<u8> {
+        let mut frame: Vec<u8> = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&(20 + segment.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0x40, 0, 64, IP_PROTOCOL_UDP, 0, 0]);
+        frame.extend_from_slice(&[10, 0, 0, 5]);
+        frame.extend_from_slice(&[10, 0, 0, 1]);
+        frame.extend_from_slice(segment);
+        frame
+    }
+
+    fn ipv6_sll_frame(segment: &[u8]) -> Vec<u8> {
+        let mut frame: Vec<u8> = vec![0; 14];
+        frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        frame.extend_from_slice(&[0x60, 0, 0, 0]);
+        frame.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&[IP_PROTOCOL_UDP, 64]);
+        frame.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        frame.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        frame.extend_from_slice(segment);
+        frame
+    }
+
+    #[test]
+    fn test_read_pcap() {
+        let mut file: Vec<u8> = vec![0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0];
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&65535u32.to_le_bytes());
+        file.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        let frame = ipv4_ethernet_frame(&udp(5000, 34256, &[0x03, 0x00, 0x04, 0, 0, 0x5F, 0xF4]));
+        file.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+        file.extend_from_slice(&250_000u32.to_le_bytes());
+        file.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        file.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        file.extend_from_slice(&frame);
+        // Truncated record at the end of the file.
+        file.extend_from_slice(&[0; 10]);
+
+        let datagrams = read_datagrams(&file).unwrap();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].source, "10.0.0.5:5000".parse().unwrap());
+        assert_eq!(datagrams[0].destination, "10.0.0.1:34256".parse().unwrap());
+        assert_eq!(
+            datagrams[0].payload,
+            vec![0x03, 0x00, 0x04, 0, 0, 0x5F, 0xF4]
+        );
+        assert_eq!(
+            datagrams[0].timestamp.to_rfc3339(),
+            "2023-11-14T22:13:20.250+00:00"
+        );
+    }
+
+    #[test]
+    fn test_read_pcapng() {
+        let block = |block_type: u32, body: &[u8]| {
+            let length = 12 + body.len().div_ceil(4) * 4;
+            let mut block: Vec<u8> = Vec::new();
+            block.extend_from_slice(&block_type.to_be_bytes());
+            block.extend_from_slice(&(length as u32).to_be_bytes());
+            block.extend_from_slice(body);
+            block.resize(length - 4, 0);
+            block.extend_from_slice(&(length as u32).to_be_bytes());
+            block
+        };
+        let mut file: Vec<u8> = Vec::new();
+        let mut section: Vec<u8> = vec![0x1A, 0x2B, 0x3C, 0x4D, 0, 1, 0, 0];
+        section.extend_from_slice(&[0xFF; 8]);
+        file.extend(block(0x0A0D0D0A, &section));
+        // Nanosecond timestamps through the if_tsresol option.
+        let mut interface: Vec<u8> = Vec::new();
+        interface.extend_from_slice(&(LINKTYPE_LINUX_SLL as u16).to_be_bytes());
+        interface.extend_from_slice(&[0, 0, 0, 0, 0xFF, 0xFF]);
+        interface.extend_from_slice(&[0, 9, 0, 1, 9, 0, 0, 0, 0, 0, 0, 0]);
+        file.extend(block(1, &interface));
+        let frame = ipv6_sll_frame(&udp(34256, 5000, &[0x03, 0x06, 0x32]));
+        let units: u64 = 1_700_000_000_000_000_123;
+        let mut packet: Vec<u8> = vec![0; 4];
+        packet.extend_from_slice(&((units >> 32) as u32).to_be_bytes());
+        packet.extend_from_slice(&(units as u32).to_be_bytes());
+        packet.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        packet.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        packet.extend_from_slice(&frame);
+        file.extend(block(6, &packet));
+
+        let datagrams = read_datagrams(&file).unwrap();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].source, "[::1]:34256".parse().unwrap());
+        assert_eq!(datagrams[0].payload, vec![0x03, 0x06, 0x32]);
+        assert_eq!(datagrams[0].timestamp.timestamp_subsec_nanos(), 123);
+    }
+
+    #[test]
+    fn test_read_invalid_file() {
+        assert!(read_datagrams(&[0x00, 0x01]).is_err());
+        assert!(read_datagrams(&[0x00, 0x01, 0x02, 0x03, 0x04]).is_err());
+    }
+}
@@ -0,0 +1,506 @@
+use crate::actions::{CoordinatesData, CoordinatesFix, HeartbeatData};
+use crate::config::PipelineConfig;
+use crate::metrics::Metrics;
+use crate::spool::{Spool, SpoolEntry};
+use crate::store::SharedStore;
+use async_trait::async_trait;
+use chrono::Utc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use surrealdb::RecordId;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, error, warn};
+
+/// A decoded record waiting to be written.
+#[derive(Debug, Clone)]
+pub enum WriteRecord {
+    /// A fix and its resolved device; the fix is what gets spooled if the
+    /// batch cannot be written.
+    Coordinates {
+        fix: CoordinatesFix,
+        data: CoordinatesData,
+    },
+    /// Heartbeats are lossy: a batch that cannot be written is dropped
+    /// rather than spooled, since the next heartbeat proves the device alive
+    /// again and sessions are tracked in memory.
+    Heartbeat(HeartbeatData),
+}
+
+/// Destination of the batches.
+#[async_trait]
+pub trait BatchWriter: Send + Sync {
+    /// Whether writes are expected to succeed right now.
+    async fn is_available(&self) -> bool;
+
+    /// Stores `rows`, skipping those whose id is already stored.
+    async fn write_coordinates(&self, rows: &[CoordinatesData]) -> Result<(), String>;
+
+    /// Stores `rows`, skipping those whose id is already stored.
+    async fn write_heartbeats(&self, rows: &[HeartbeatData]) -> Result<(), String>;
+}
+
+/// Writes batches to the store, which skips rows whose id is already stored,
+/// so retrying a batch that was stored before its response got lost does not
+/// store it twice.
+#[derive(Debug, Clone)]
+pub struct StoreBatchWriter {
+    store: SharedStore,
+}
+
+impl StoreBatchWriter {
+    pub fn new(store: SharedStore) -> Self {
+        Self { store }
+    }
+}
+
+#[async_trait]
+impl BatchWriter for StoreBatchWriter {
+    async fn is_available(&self) -> bool {
+        self.store.is_available().await
+    }
+
+    async fn write_coordinates(&self, rows: &[CoordinatesData]) -> Result<(), String> {
+        let _timer = Metrics::global().start_db_timer("coordinates.insert_batch");
+        self.store
+            .insert_positions(rows)
+            .await
+            .map_err(|error| format!("pipeline error: {}", error))
+    }
+
+    async fn write_heartbeats(&self, rows: &[HeartbeatData]) -> Result<(), String> {
+        let _timer = Metrics::global().start_db_timer("heartbeat.insert_batch");
+        self.store
+            .insert_heartbeats(rows)
+            .await
+            .map_err(|error| format!("pipeline error: {}", error))
+    }
+}
+
+static HEARTBEAT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Sending half of the write pipeline. Handlers submit records and answer
+/// right away; a background task writes them in batches bounded by
+/// `batch_size` and `flush_interval_ms`.
+///
+/// Every record gets its id when it is submitted. Retries and spool replays
+/// reuse that id, so a fix is never stored twice. Since handlers answer before
+/// the write, a fix is only sure to be stored when a spool catches the batches
+/// that fail: without one, or once it is full, they are dropped and counted
+/// in `pipeline_dropped_total`. The server therefore runs the pipeline only
+/// when the spool is enabled.
+#[derive(Debug, Clone)]
+pub struct WritePipeline {
+    sender: mpsc::Sender<WriteRecord>,
+}
+
+impl WritePipeline {
+    /// Starts the batching task. It flushes what is queued and stops once
+    /// every `WritePipeline` clone has been dropped.
+    pub fn spawn(
+        config: &PipelineConfig,
+        writer: impl BatchWriter + 'static,
+        spool: Option<Spool>,
+    ) -> (Self, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel(config.channel_capacity.max(1));
+        let worker = Worker {
+            writer,
+            spool,
+            batch_size: config.batch_size.max(1),
+            flush_interval: Duration::from_millis(config.flush_interval_ms),
+            max_attempts: config.max_attempts.max(1),
+        };
+        let task = tokio::spawn(worker.run(receiver));
+        (Self { sender }, task)
+    }
+
+    /// Queues a record, waiting while the queue is full.
+    pub async fn submit(&self, record: WriteRecord) -> Result<(), String> {
+        let record = match record {
+            WriteRecord::Coordinates { fix, mut data } => {
+                if data.id.is_none() {
+                    let entry = SpoolEntry::new(fix.clone());
+                    data.id = Some(RecordId::from_table_key("coordinates", entry.key));
+                }
+                WriteRecord::Coordinates { fix, data }
+            }
+            WriteRecord::Heartbeat(mut data) => {
+                if data.id.is_none() {
+                    let key = format!(
+                        "{}_{}",
+                        Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+                        HEARTBEAT_SEQUENCE.fetch_add(1, Ordering::Relaxed)
+                    );
+                    data.id = Some(RecordId::from_table_key("heartbeat", key));
+                }
+                WriteRecord::Heartbeat(data)
+            }
+        };
+        self.sender
+            .send(record)
+            .await
+            .map_err(|_| "pipeline error: writer stopped".to_string())
+    }
+}
+
+struct Worker<W> {
+    writer: W,
+    spool: Option<Spool>,
+    batch_size: usize,
+    flush_interval: Duration,
+    max_attempts: u32,
+}
+
+impl<W: BatchWriter> Worker<W> {
+    async fn run(self, mut receiver: mpsc::Receiver<WriteRecord>) {
+        while let Some(first) = receiver.recv().await {
+            let mut batch: Vec<WriteRecord> = vec![first];
+            let deadline = Instant::now() + self.flush_interval;
+            while batch.len() < self.batch_size {
+                match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(record)) => batch.push(record),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            self.flush(batch).await;
+        }
+    }
+
+    async fn flush(&self, batch: Vec<WriteRecord>) {
+        let mut fixes: Vec<CoordinatesFix> = Vec::new();
+        let mut coordinates: Vec<CoordinatesData> = Vec::new();
+        let mut heartbeats: Vec<HeartbeatData> = Vec::new();
+        for record in batch {
+            match record {
+                WriteRecord::Coordinates { fix, data } => {
+                    fixes.push(fix);
+                    coordinates.push(data);
+                }
+                WriteRecord::Heartbeat(data) => heartbeats.push(data),
+            }
+        }
+        if !coordinates.is_empty() {
+            debug!(rows = coordinates.len(), "writing coordinates batch");
+            if let Err(error) = self
+                .write(|| self.writer.write_coordinates(&coordinates))
+                .await
+            {
+                self.spool_coordinates(fixes, coordinates, error).await;
+            }
+        }
+        if !heartbeats.is_empty() {
+            debug!(rows = heartbeats.len(), "writing heartbeat batch");
+            if let Err(error) = self
+                .write(|| self.writer.write_heartbeats(&heartbeats))
+                .await
+            {
+                // Not spooled; see `WriteRecord::Heartbeat`.
+                error!(rows = heartbeats.len(), %error, "dropping heartbeat batch");
+                Metrics::global()
+                    .pipeline_dropped
+                    .with_label_values(&["heartbeat"])
+                    .inc_by(heartbeats.len() as u64);
+            }
+        }
+    }
+
+    /// Runs `write` up to `max_attempts` times, backing off exponentially, and
+    /// gives up early once the writer is unavailable.
+    async fn write<'a, F, Fut>(&'a self, write: F) -> Result<(), String>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<(), String>> + 'a,
+    {
+        let mut backoff = Duration::from_millis(100);
+        let mut attempt: u32 = 1;
+        loop {
+            match write().await {
+                Ok(_) => return Ok(()),
+                Err(error) => {
+                    // Retrying only helps while the database is reachable.
+                    if attempt >= self.max_attempts || !self.writer.is_available().await {
+                        return Err(error);
+                    }
+                    warn!(attempt, ?backoff, %error, "batch write failed, retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Moves the fixes of a failed batch to the spool under the ids they were
+    /// submitted with, or drops them when there is no spool.
+    async fn spool_coordinates(
+        &self,
+        fixes: Vec<CoordinatesFix>,
+        coordinates: Vec<CoordinatesData>,
+        error: String,
+    ) {
+        let dropped = &Metrics::global().pipeline_dropped;
+        let Some(spool) = &self.spool else {
+            error!(rows = coordinates.len(), %error, "dropping coordinates batch");
+            dropped
+                .with_label_values(&["coordinates"])
+                .inc_by(coordinates.len() as u64);
+            return;
+        };
+        warn!(rows = coordinates.len(), %error, "spooling coordinates batch");
+        for (fix, data) in fixes.into_iter().zip(coordinates) {
+            let entry = match data.id {
+                Some(id) => SpoolEntry {
+                    key: id.key().to_string(),
+                    fix,
+                },
+                None => SpoolEntry::new(fix),
+            };
+            if let Err(error) = spool.push(&entry).await {
+                error!(key = %entry.key, %error, "dropping coordinates");
+                dropped.with_label_values(&["coordinates"]).inc();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_pipeline {
+    use super::*;
+    use crate::config::SpoolConfig;
+    use crate::spool::SpoolSink;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+    use surrealdb::sql::Datetime;
+
+    /// Stands in for SurrealDB, ignoring rows whose id is already stored like
+    /// `INSERT IGNORE`.
+    #[derive(Default)]
+    struct StandInDatabase {
+        available: AtomicBool,
+        /// Stores the next batch but reports a failure, like a lost response.
+        lose_next_response: AtomicBool,
+        batches: Mutex<Vec<usize>>,
+        coordinates: Mutex<HashMap<String, CoordinatesData>>,
+        heartbeats: Mutex<HashMap<String, HeartbeatData>>,
+    }
+
+    impl StandInDatabase {
+        fn up() -> Arc<Self> {
+            let database = Self::default();
+            database.available.store(true, Ordering::Relaxed);
+            Arc::new(database)
+        }
+    }
+
+    #[async_trait]
+    impl BatchWriter for Arc<StandInDatabase> {
+        async fn is_available(&self) -> bool {
+            self.available.load(Ordering::Relaxed)
+        }
+
+        async fn write_coordinates(&self, rows: &[CoordinatesData]) -> Result<(), String> {
+            if !self.available.load(Ordering::Relaxed) {
+                return Err("connection refused".to_string());
+            }
+            self.batches.lock().unwrap().push(rows.len());
+            let mut coordinates = self.coordinates.lock().unwrap();
+            for row in rows {
+                let key = row.id.as_ref().unwrap().key().to_string();
+                coordinates.entry(key).or_insert_with(|| row.clone());
+            }
+            if self.lose_next_response.swap(false, Ordering::Relaxed) {
+                return Err("connection reset".to_string());
+            }
+            Ok(())
+        }
+
+        async fn write_heartbeats(&self, rows: &[HeartbeatData]) -> Result<(), String> {
+            if !self.available.load(Ordering::Relaxed) {
+                return Err("connection refused".to_string());
+            }
+            self.batches.lock().unwrap().push(rows.len());
+            let mut heartbeats = self.heartbeats.lock().unwrap();
+            for row in rows {
+                let key = row.id.as_ref().unwrap().key().to_string();
+                heartbeats.entry(key).or_insert_with(|| row.clone());
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl SpoolSink for Arc<StandInDatabase> {
+        async fn is_available(&self) -> bool {
+            self.available.load(Ordering::Relaxed)
+        }
+
+        async fn write(&self, entry: &SpoolEntry) -> Result<(), String> {
+            if !self.available.load(Ordering::Relaxed) {
+                return Err("connection refused".to_string());
+            }
+            self.coordinates
+                .lock()
+                .unwrap()
+                .insert(entry.key.clone(), coordinates(entry.fix.clone()));
+            Ok(())
+        }
+    }
+
+    fn config(batch_size: usize, max_attempts: u32) -> PipelineConfig {
+        PipelineConfig {
+            batch_size,
+            flush_interval_ms: 20,
+            channel_capacity: 100,
+            max_attempts,
+        }
+    }
+
+    fn fix(client_id: u32) -> CoordinatesFix {
+        CoordinatesFix {
+            client_id,
+            latitude: 10.00001,
+            longitude: -127.000001,
+            timestamp: Datetime::from(Utc::now()),
+        }
+    }
+
+    fn coordinates(fix: CoordinatesFix) -> CoordinatesData {
+        CoordinatesData {
+            id: None,
+            device: RecordId::from_str("devices:0dgt5u58j2jh3oq4xzbt").unwrap(),
+            latitude: fix.latitude,
+            longitude: fix.longitude,
+            timestamp: fix.timestamp,
+        }
+    }
+
+    fn heartbeat() -> WriteRecord {
+        WriteRecord::Heartbeat(HeartbeatData {
+            id: None,
+            source_address: "127.0.0.1:5000".to_string(),
+            device: RecordId::from_str("devices:0dgt5u58j2jh3oq4xzbt").unwrap(),
+            timestamp: Datetime::from(Utc::now()),
+        })
+    }
+
+    fn record(client_id: u32) -> WriteRecord {
+        let fix = fix(client_id);
+        WriteRecord::Coordinates {
+            data: coordinates(fix.clone()),
+            fix,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_by_size_and_time() {
+        let database = StandInDatabase::up();
+        let (pipeline, task) = WritePipeline::spawn(&config(3, 1), database.clone(), None);
+        for client_id in 0..7 {
+            pipeline.submit(record(client_id)).await.unwrap();
+        }
+        pipeline.submit(heartbeat()).await.unwrap();
+        // The last partial batch is flushed by the timer.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(database.coordinates.lock().unwrap().len(), 7);
+        assert_eq!(database.heartbeats.lock().unwrap().len(), 1);
+        assert_eq!(*database.batches.lock().unwrap(), vec![3, 3, 1, 1]);
+
+        drop(pipeline);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flushes_on_close() {
+        let database = StandInDatabase::up();
+        let mut config = config(100, 1);
+        config.flush_interval_ms = 60_000;
+        let (pipeline, task) = WritePipeline::spawn(&config, database.clone(), None);
+        for client_id in 0..5 {
+            pipeline.submit(record(client_id)).await.unwrap();
+        }
+        drop(pipeline);
+        task.await.unwrap();
+        assert_eq!(database.coordinates.lock().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_retry_stores_each_fix_once() {
+        let database = StandInDatabase::up();
+        database.lose_next_response.store(true, Ordering::Relaxed);
+        let (pipeline, task) = WritePipeline::spawn(&config(4, 3), database.clone(), None);
+        for client_id in 0..4 {
+            pipeline.submit(record(client_id)).await.unwrap();
+        }
+        drop(pipeline);
+        task.await.unwrap();
+        // The batch was written twice but every fix is stored once.
+        assert_eq!(*database.batches.lock().unwrap(), vec![4, 4]);
+        assert_eq!(database.coordinates.lock().unwrap().len(), 4);
+    }
+
+    fn spool(path: &std::path::Path) -> Spool {
+        let _ = std::fs::remove_file(path);
+        Spool::open(&SpoolConfig {
+            enabled: true,
+            path: path.to_string_lossy().to_string(),
+            max_bytes: 1024 * 1024,
+            replay_rate_per_sec: 0.0,
+            replay_interval_ms: 10,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_spools_failed_batch() {
+        let path =
+            std::env::temp_dir().join(format!("gps-tracker-pipeline-{}.jsonl", std::process::id()));
+        let spool = spool(&path);
+        let database = Arc::new(StandInDatabase::default());
+        let (pipeline, task) =
+            WritePipeline::spawn(&config(10, 1), database.clone(), Some(spool.clone()));
+        for client_id in 0..3 {
+            pipeline.submit(record(client_id)).await.unwrap();
+        }
+        drop(pipeline);
+        task.await.unwrap();
+        assert_eq!(spool.pending(), 3);
+
+        database.available.store(true, Ordering::Relaxed);
+        assert_eq!(spool.replay(&database).await, Ok(3));
+        assert_eq!(database.coordinates.lock().unwrap().len(), 3);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_drops_failed_heartbeat_batch() {
+        let path = std::env::temp_dir().join(format!(
+            "gps-tracker-pipeline-heartbeat-{}.jsonl",
+            std::process::id()
+        ));
+        let spool = spool(&path);
+        let dropped = Metrics::global()
+            .pipeline_dropped
+            .with_label_values(&["heartbeat"]);
+        let before = dropped.get();
+        let database = Arc::new(StandInDatabase::default());
+        let (pipeline, task) =
+            WritePipeline::spawn(&config(10, 3), database.clone(), Some(spool.clone()));
+        pipeline.submit(record(0)).await.unwrap();
+        pipeline.submit(heartbeat()).await.unwrap();
+        pipeline.submit(heartbeat()).await.unwrap();
+        drop(pipeline);
+        task.await.unwrap();
+        // Only the fix waits in the spool; the heartbeats were dropped.
+        assert_eq!(spool.pending(), 1);
+        assert!(dropped.get() >= before + 2);
+
+        database.available.store(true, Ordering::Relaxed);
+        assert_eq!(spool.replay(&database).await, Ok(1));
+        assert_eq!(database.coordinates.lock().unwrap().len(), 1);
+        assert!(database.heartbeats.lock().unwrap().is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
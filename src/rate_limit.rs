@@ -0,0 +1,348 @@
+use crate::config::RateLimitConfig;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+
+/// Maximum number of buckets kept per key type. Past it, the bucket seen
+/// least recently is evicted, so a flood of spoofed sources cannot grow the
+/// map.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Time between two sweeps of the buckets that refilled completely.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitDecision {
+    Allowed,
+    ThrottledBySource,
+    ThrottledByClient,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// Position in `Tracked::seen`.
+    seen: u64,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, now: Instant, seen: u64) -> Self {
+        Self {
+            tokens: capacity,
+            updated_at: now,
+            seen,
+        }
+    }
+
+    fn refill(&mut self, rate_per_sec: f64, capacity: f64, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate_per_sec).min(capacity);
+        self.updated_at = now;
+    }
+
+    fn try_take(&mut self) -> bool {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Tracked<K> {
+    buckets: HashMap<K, TokenBucket>,
+    /// Keys by the order they were last seen in, oldest first.
+    seen: BTreeMap<u64, K>,
+    next_seen: u64,
+    swept_at: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets<K> {
+    tracked: Mutex<Tracked<K>>,
+    throttled: AtomicU64,
+}
+
+impl<K: Eq + Hash + Copy> Buckets<K> {
+    fn new() -> Self {
+        Self {
+            tracked: Mutex::new(Tracked {
+                buckets: HashMap::new(),
+                seen: BTreeMap::new(),
+                next_seen: 0,
+                swept_at: Instant::now(),
+            }),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Tracked<K>> {
+        match self.tracked.lock() {
+            Ok(tracked) => tracked,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn check(&self, key: K, rate_per_sec: f64, burst: u32, now: Instant) -> bool {
+        let capacity = f64::from(burst.max(1));
+        let mut tracked = self.lock();
+        let tracked = &mut *tracked;
+        if now.saturating_duration_since(tracked.swept_at) >= SWEEP_INTERVAL {
+            // Buckets that refilled completely carry no state worth keeping.
+            let seen = &mut tracked.seen;
+            tracked.buckets.retain(|_, bucket| {
+                bucket.refill(rate_per_sec, capacity, now);
+                let keep = bucket.tokens < capacity;
+                if !keep {
+                    seen.remove(&bucket.seen);
+                }
+                keep
+            });
+            tracked.swept_at = now;
+        }
+        let seen = tracked.next_seen;
+        tracked.next_seen += 1;
+        let bucket = match tracked.buckets.get_mut(&key) {
+            Some(bucket) => {
+                tracked.seen.remove(&bucket.seen);
+                bucket.seen = seen;
+                bucket
+            }
+            None => {
+                if tracked.buckets.len() >= MAX_TRACKED_KEYS {
+                    if let Some((_, oldest)) = tracked.seen.pop_first() {
+                        tracked.buckets.remove(&oldest);
+                    }
+                }
+                tracked
+                    .buckets
+                    .entry(key)
+                    .or_insert_with(|| TokenBucket::new(capacity, now, seen))
+            }
+        };
+        tracked.seen.insert(seen, key);
+        bucket.refill(rate_per_sec, capacity, now);
+        let allowed = bucket.try_take();
+        if !allowed {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.lock().buckets.len()
+    }
+}
+
+/// Token-bucket rate limiter keyed by source IP and by client id.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RwLock<RateLimitConfig>,
+    by_source: Buckets<IpAddr>,
+    by_client: Buckets<u32>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            config: RwLock::new(config.clone()),
+            by_source: Buckets::new(),
+            by_client: Buckets::new(),
+        }
+    }
+
+    /// Replaces the limits. Existing buckets keep their tokens, capped to the
+    /// new burst size on their next refill.
+    pub fn reconfigure(&self, config: &RateLimitConfig) {
+        match self.config.write() {
+            Ok(mut current) => *current = config.clone(),
+            Err(poisoned) => *poisoned.into_inner() = config.clone(),
+        }
+    }
+
+    fn config(&self) -> RateLimitConfig {
+        match self.config.read() {
+            Ok(config) => config.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Takes a token for the source address of a datagram.
+    pub fn check_source(&self, source: IpAddr) -> RateLimitDecision {
+        self.check_source_at(source, Instant::now())
+    }
+
+    /// Takes a token for the client id carried by a request.
+    pub fn check_client(&self, client_id: u32) -> RateLimitDecision {
+        self.check_client_at(client_id, Instant::now())
+    }
+
+    fn check_source_at(&self, source: IpAddr, now: Instant) -> RateLimitDecision {
+        let config = self.config();
+        if !config.enabled
+            || self
+                .by_source
+                .check(source, config.source_rate_per_sec, config.source_burst, now)
+        {
+            RateLimitDecision::Allowed
+        } else {
+            RateLimitDecision::ThrottledBySource
+        }
+    }
+
+    fn check_client_at(&self, client_id: u32, now: Instant) -> RateLimitDecision {
+        let config = self.config();
+        if !config.enabled
+            || self.by_client.check(
+                client_id,
+                config.client_rate_per_sec,
+                config.client_burst,
+                now,
+            )
+        {
+            RateLimitDecision::Allowed
+        } else {
+            RateLimitDecision::ThrottledByClient
+        }
+    }
+
+    /// Number of datagrams rejected because their source IP exceeded its limit.
+    pub fn throttled_by_source(&self) -> u64 {
+        self.by_source.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Number of datagrams rejected because their client id exceeded its limit.
+    pub fn throttled_by_client(&self) -> u64 {
+        self.by_client.throttled.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test_rate_limit {
+    use super::*;
+
+    fn limiter(enabled: bool) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            enabled,
+            client_rate_per_sec: 1.0,
+            client_burst: 2,
+            source_rate_per_sec: 10.0,
+            source_burst: 3,
+        })
+    }
+
+    #[test]
+    fn test_burst_then_throttle() {
+        let limiter = limiter(true);
+        let now = Instant::now();
+        let source: IpAddr = "127.0.0.1".parse().unwrap();
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.check_source_at(source, now),
+                RateLimitDecision::Allowed
+            );
+        }
+        assert_eq!(
+            limiter.check_source_at(source, now),
+            RateLimitDecision::ThrottledBySource
+        );
+        assert_eq!(limiter.throttled_by_source(), 1);
+    }
+
+    #[test]
+    fn test_refill() {
+        let limiter = limiter(true);
+        let now = Instant::now();
+        assert_eq!(limiter.check_client_at(7, now), RateLimitDecision::Allowed);
+        assert_eq!(limiter.check_client_at(7, now), RateLimitDecision::Allowed);
+        assert_eq!(
+            limiter.check_client_at(7, now),
+            RateLimitDecision::ThrottledByClient
+        );
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            limiter.check_client_at(7, later),
+            RateLimitDecision::Allowed
+        );
+        assert_eq!(limiter.throttled_by_client(), 1);
+    }
+
+    #[test]
+    fn test_keys_are_independent() {
+        let limiter = limiter(true);
+        let now = Instant::now();
+        assert_eq!(limiter.check_client_at(1, now), RateLimitDecision::Allowed);
+        assert_eq!(limiter.check_client_at(1, now), RateLimitDecision::Allowed);
+        assert_eq!(limiter.check_client_at(2, now), RateLimitDecision::Allowed);
+    }
+
+    #[test]
+    fn test_tracked_keys_are_bounded() {
+        let limiter = limiter(true);
+        let now = Instant::now();
+        // Every source stays throttled-free but keeps a partly used bucket,
+        // as under a flood of spoofed sources.
+        for index in 0..MAX_TRACKED_KEYS as u32 + 500 {
+            let source = IpAddr::from(index.to_be_bytes());
+            assert_eq!(
+                limiter.check_source_at(source, now),
+                RateLimitDecision::Allowed
+            );
+        }
+        assert_eq!(limiter.by_source.len(), MAX_TRACKED_KEYS);
+
+        // The least recently seen source went first, so it starts over.
+        let first = IpAddr::from(0u32.to_be_bytes());
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.check_source_at(first, now),
+                RateLimitDecision::Allowed
+            );
+        }
+        assert_eq!(limiter.by_source.len(), MAX_TRACKED_KEYS);
+
+        // Idle buckets are swept once they refilled.
+        let later = now + SWEEP_INTERVAL;
+        assert_eq!(
+            limiter.check_client_at(1, later),
+            RateLimitDecision::Allowed
+        );
+        limiter.check_source_at(first, later);
+        assert_eq!(limiter.by_source.len(), 1);
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let limiter = limiter(true);
+        let now = Instant::now();
+        assert_eq!(limiter.check_client_at(1, now), RateLimitDecision::Allowed);
+        assert_eq!(limiter.check_client_at(1, now), RateLimitDecision::Allowed);
+        assert_eq!(
+            limiter.check_client_at(1, now),
+            RateLimitDecision::ThrottledByClient
+        );
+
+        limiter.reconfigure(&RateLimitConfig {
+            enabled: false,
+            ..RateLimitConfig::default()
+        });
+        assert_eq!(limiter.check_client_at(1, now), RateLimitDecision::Allowed);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let limiter = limiter(false);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(limiter.check_client_at(1, now), RateLimitDecision::Allowed);
+        }
+        assert_eq!(limiter.throttled_by_client(), 0);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::config::Config;
+use crate::logging;
+use crate::rate_limit::RateLimiter;
+use crate::session::SessionManager;
+use crate::shutdown::Shutdown;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+/// Names the settings that differ between `current` and `new` but are only
+/// read at startup.
+fn restart_required(current: &Config, new: &Config) -> Vec<&'static str> {
+    let (server, new_server) = (&current.server, &new.server);
+    let mut changed: Vec<&'static str> = Vec::new();
+    for (key, differs) in [
+        ("server.host", server.host != new_server.host),
+        (
+            "server.max_datagram_size",
+            server.max_datagram_size != new_server.max_datagram_size,
+        ),
+        (
+            "server.shutdown_grace_period_secs",
+            server.shutdown_grace_period_secs != new_server.shutdown_grace_period_secs,
+        ),
+        (
+            "server.metrics_host",
+            server.metrics_host != new_server.metrics_host,
+        ),
+        (
+            "server.config_reload_interval_ms",
+            server.config_reload_interval_ms != new_server.config_reload_interval_ms,
+        ),
+        (
+            "server.device_cache_ttl_secs",
+            server.device_cache_ttl_secs != new_server.device_cache_ttl_secs,
+        ),
+        (
+            "server.session.event_capacity",
+            server.session.event_capacity != new_server.session.event_capacity,
+        ),
+        ("server.spool", server.spool != new_server.spool),
+        ("server.pipeline", server.pipeline != new_server.pipeline),
+        ("server.capture", server.capture != new_server.capture),
+        ("server.retention", server.retention != new_server.retention),
+        ("database", current.database != new.database),
+        ("web", current.web != new.web),
+        ("log.format", current.log.format != new.log.format),
+    ] {
+        if differs {
+            changed.push(key);
+        }
+    }
+    changed
+}
+
+/// Re-reads the config file on SIGHUP or when it changes and applies the
+/// settings that can change at runtime: rate limits, the session heartbeat
+/// timeout and the log level.
+#[derive(Debug)]
+pub struct ConfigReloader {
+    path: String,
+    /// `key=value` pairs from the command line, applied on every reload.
+    overrides: Vec<String>,
+    /// Config in effect: runtime settings from the last reload, the others
+    /// from startup.
+    current: Mutex<Config>,
+    rate_limiter: Arc<RateLimiter>,
+    sessions: SessionManager,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        path: String,
+        overrides: Vec<String>,
+        config: Config,
+        rate_limiter: Arc<RateLimiter>,
+        sessions: SessionManager,
+    ) -> Self {
+        Self {
+            path,
+            overrides,
+            current: Mutex::new(config),
+            rate_limiter,
+            sessions,
+        }
+    }
+
+    /// Returns the config in effect.
+    pub fn current(&self) -> Config {
+        match self.current.lock() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Reads the config file and the environment again and applies them.
+    pub async fn reload(&self) -> Result<Vec<&'static str>, String> {
+        let config = Config::load_with(Some(self.path.clone()), &self.overrides).await?;
+        self.apply(config)
+    }
+
+    /// Applies the runtime settings of `config` once it is valid, and returns
+    /// the changed settings that need a restart. An invalid config changes
+    /// nothing.
+    pub fn apply(&self, config: Config) -> Result<Vec<&'static str>, String> {
+        config.validate()?;
+        let mut current = match self.current.lock() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let restart = restart_required(&current, &config);
+        for setting in restart.iter() {
+            warn!(setting, "setting changed but only applies after a restart");
+        }
+        if current.log.level != config.log.level {
+            logging::reload_level(&config.log.level)?;
+        }
+        self.rate_limiter.reconfigure(&config.server.rate_limit);
+        self.sessions.set_heartbeat_timeout(Duration::from_secs(
+            config.server.session.heartbeat_timeout_secs,
+        ));
+        current.log.level = config.log.level;
+        current.server.rate_limit = config.server.rate_limit;
+        current.server.session.heartbeat_timeout_secs =
+            config.server.session.heartbeat_timeout_secs;
+        info!("configuration reloaded");
+        Ok(restart)
+    }
+
+    /// Reloads on SIGHUP and, every `poll_interval` unless it is zero, when
+    /// the file modification time changed, until shutdown is triggered.
+    pub async fn run(&self, poll_interval: Duration, shutdown: Shutdown) {
+        let hangup = Arc::new(Notify::new());
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::hangup()) {
+                Ok(mut signal) => {
+                    let hangup = hangup.clone();
+                    tokio::spawn(async move {
+                        while signal.recv().await.is_some() {
+                            hangup.notify_one();
+                        }
+                    });
+                }
+                Err(error) => warn!(?error, "unable to listen for SIGHUP"),
+            }
+        }
+        let mut modified = Self::modified(&self.path);
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => return,
+                _ = hangup.notified() => {
+                    info!(path = %self.path, "SIGHUP received, reloading configuration");
+                }
+                _ = tokio::time::sleep(poll_interval), if !poll_interval.is_zero() => {
+                    let current = Self::modified(&self.path);
+                    if current == modified {
+                        continue;
+                    }
+                    modified = current;
+                    info!(path = %self.path, "configuration file changed, reloading");
+                }
+            }
+            if let Err(error) = self.reload().await {
+                error!(%error, "configuration rejected, keeping the current one");
+            }
+        }
+    }
+
+    fn modified(path: &str) -> Option<SystemTime> {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test_reload {
+    use super::*;
+    use crate::config::SessionConfig;
+    use crate::rate_limit::RateLimitDecision;
+
+    fn reloader(path: &str) -> (ConfigReloader, Arc<RateLimiter>, SessionManager) {
+        let config = Config::parse(&fs::read_to_string("config.toml").unwrap()).unwrap();
+        let rate_limiter = Arc::new(RateLimiter::new(&config.server.rate_limit));
+        let sessions = SessionManager::new(&SessionConfig::default());
+        let reloader = ConfigReloader::new(
+            path.to_string(),
+            Vec::new(),
+            config,
+            rate_limiter.clone(),
+            sessions.clone(),
+        );
+        (reloader, rate_limiter, sessions)
+    }
+
+    #[test]
+    fn test_apply_runtime_settings() {
+        let (reloader, rate_limiter, sessions) = reloader("config.toml");
+        let mut config = reloader.current();
+        config.server.rate_limit.client_burst = 1;
+        config.server.session.heartbeat_timeout_secs = 5;
+        assert_eq!(reloader.apply(config), Ok(vec![]));
+
+        assert_eq!(rate_limiter.check_client(7), RateLimitDecision::Allowed);
+        assert_eq!(
+            rate_limiter.check_client(7),
+            RateLimitDecision::ThrottledByClient
+        );
+        assert_eq!(sessions.heartbeat_timeout(), Duration::from_secs(5));
+        assert_eq!(reloader.current().server.rate_limit.client_burst, 1);
+    }
+
+    #[test]
+    fn test_restart_required() {
+        let (reloader, _, _) = reloader("config.toml");
+        let mut config = reloader.current();
+        config.server.host = "0.0.0.0:34256".to_string();
+        config.web.port = 4091;
+        assert_eq!(
+            reloader.apply(config.clone()),
+            Ok(vec!["server.host", "web"])
+        );
+        // Still reported until a restart applies them.
+        assert_eq!(reloader.apply(config), Ok(vec!["server.host", "web"]));
+        assert_eq!(reloader.current().server.host, "127.0.0.1:34256");
+    }
+
+    #[test]
+    fn test_invalid_config_is_rejected() {
+        let (reloader, rate_limiter, _) = reloader("config.toml");
+        let mut config = reloader.current();
+        config.server.rate_limit.client_burst = 1;
+        config.server.rate_limit.source_rate_per_sec = -1.0;
+        assert!(reloader.apply(config).is_err());
+
+        let current = reloader.current();
+        assert_eq!(current.server.rate_limit.client_burst, 10);
+        for _ in 0..10 {
+            assert_eq!(rate_limiter.check_client(7), RateLimitDecision::Allowed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload_from_file() {
+        let path =
+            std::env::temp_dir().join(format!("gps-tracker-reload-{}.toml", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let content = fs::read_to_string("config.toml").unwrap();
+        let (reloader, _, sessions) = reloader(&path);
+
+        fs::write(
+            &path,
+            content.replace("heartbeat_timeout_secs = 30", "heartbeat_timeout_secs = 7"),
+        )
+        .unwrap();
+        assert_eq!(reloader.reload().await, Ok(vec![]));
+        assert_eq!(sessions.heartbeat_timeout(), Duration::from_secs(7));
+
+        fs::write(&path, content.replace("[database]", "[database")).unwrap();
+        assert!(reloader.reload().await.is_err());
+        assert_eq!(sessions.heartbeat_timeout(), Duration::from_secs(7));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -30,6 +30,17 @@ impl RequestType {
         }
     }
 
+    /// Returns the variant name, used as a metrics label.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Login => "Login",
+            Self::Coordinates => "Coordinates",
+            Self::HeartBeat => "HeartBeat",
+            Self::Logout => "Logout",
+            Self::Invalid => "Invalid",
+        }
+    }
+
     pub fn get_by_value(value: u8) -> RequestType {
         match value {
             0x01 => RequestType::Login,
@@ -41,30 +52,53 @@ impl RequestType {
     }
 }
 
+/// Client id in the first four bytes of a payload, big-endian.
+pub fn read_client_id(payload: &[u8]) -> Option<u32> {
+    payload
+        .get(0..4)
+        .map(|value| u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+}
+
 #[derive(Debug)]
 pub struct RequestPacket {
     pub request_type: RequestType,
+    /// Raw type byte, which also identifies request types unknown to `RequestType`.
+    pub request_type_value: u8,
     pub payload_length: usize,
     pub payload: Vec<u8>,
 }
 
 impl RequestPacket {
+    /// Returns the client id carried in the first four payload bytes, if the
+    /// request type has one.
+    pub fn client_id(&self) -> Option<u32> {
+        match self.request_type {
+            RequestType::Coordinates | RequestType::HeartBeat | RequestType::Logout => {
+                read_client_id(&self.payload)
+            }
+            _ => None,
+        }
+    }
+
     pub fn parse(data: &[u8]) -> Result<Self, ValidationError> {
         match data.first() {
-            Some(value) => {
-                let request_type: RequestType = RequestType::get_by_value(value.to_owned());
-                match data.get(2) {
-                    Some(value) => match format!("{}", value.to_owned()).parse() {
-                        Ok(payload_length) => match data.get(3..) {
+            Some(request_type_value) => {
+                let request_type_value: u8 = request_type_value.to_owned();
+                let request_type: RequestType = RequestType::get_by_value(request_type_value);
+                // The payload length is a big-endian u16 in bytes 1 and 2.
+                match data.get(1..3) {
+                    Some(value) => {
+                        let payload_length = usize::from(u16::from_be_bytes([value[0], value[1]]));
+                        match data.get(3..) {
                             Some(value) => Ok(Self {
                                 request_type,
+                                request_type_value,
                                 payload_length,
                                 payload: value.to_vec(),
                             }),
                             None => Err(ValidationError::InvalidRequestPacketPayload),
-                        },
-                        Err(_) => Err(ValidationError::UnableToParseRequestPayloadLength),
-                    },
+                        }
+                    }
                     None => Err(ValidationError::InvalidRequestPacketPayloadLength),
                 }
             }
@@ -72,3 +106,45 @@ impl RequestPacket {
         }
     }
 }
+
+#[cfg(test)]
+mod test_request {
+    use super::*;
+
+    #[test]
+    fn test_client_id() {
+        let packet = RequestPacket::parse(&[0x03, 0x00, 0x04, 0x00, 0x00, 0x5F, 0xF4]).unwrap();
+        assert_eq!(packet.client_id(), Some(24564));
+
+        let packet = RequestPacket::parse(&[0x01, 0x00, 0x14, 0x72, 0x6F, 0x6F, 0x74]).unwrap();
+        assert_eq!(packet.client_id(), None);
+    }
+
+    #[test]
+    fn test_parse_max_datagram_size() {
+        let max = crate::config::DEFAULT_MAX_DATAGRAM_SIZE;
+        let payload_length = max - 3;
+        let mut data: Vec<u8> = vec![0x02];
+        data.extend_from_slice(&(payload_length as u16).to_be_bytes());
+        data.extend(std::iter::repeat_n(0xAB, payload_length));
+        assert_eq!(data.len(), max);
+
+        let packet = RequestPacket::parse(&data).unwrap();
+        assert_eq!(packet.request_type, RequestType::Coordinates);
+        assert_eq!(packet.request_type_value, 0x02);
+        assert_eq!(packet.payload_length, payload_length);
+        assert_eq!(packet.payload.len(), payload_length);
+    }
+
+    #[test]
+    fn test_parse_short_packet() {
+        assert!(matches!(
+            RequestPacket::parse(&[]),
+            Err(ValidationError::InvalidRequestPacket)
+        ));
+        assert!(matches!(
+            RequestPacket::parse(&[0x03, 0x00]),
+            Err(ValidationError::InvalidRequestPacketPayloadLength)
+        ));
+    }
+}
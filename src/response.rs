@@ -1,7 +1,10 @@
+use crate::payload::Payload;
+
 #[derive(Debug)]
 pub enum ResponseType {
     Success = 0x06,
     Error = 0x07,
+    SlowDown = 0x08,
 }
 
 impl ResponseType {
@@ -9,6 +12,50 @@ impl ResponseType {
         match self {
             Self::Success => 0x06,
             Self::Error => 0x07,
+            Self::SlowDown => 0x08,
+        }
+    }
+
+    pub fn get_by_value(value: u8) -> Option<ResponseType> {
+        match value {
+            0x06 => Some(ResponseType::Success),
+            0x07 => Some(ResponseType::Error),
+            0x08 => Some(ResponseType::SlowDown),
+            _ => None,
         }
     }
 }
+
+/// Generates a response for a request rejected before reaching its action.
+pub fn generate_status_response(
+    request_type: u8,
+    response_type: ResponseType,
+    client_id: String,
+) -> String {
+    let hex_client_id: String = hex::encode_upper(client_id);
+    Payload::apply_spacing(
+        format!(
+            "{:02x}{:02x}{}",
+            request_type,
+            response_type.to_value(),
+            hex_client_id
+        )
+        .as_str(),
+    )
+}
+
+/// Generates the response sent instead of handling a rate limited request.
+pub fn generate_slow_down_response(request_type: u8, client_id: String) -> String {
+    generate_status_response(request_type, ResponseType::SlowDown, client_id)
+}
+
+#[cfg(test)]
+mod test_response {
+    use super::*;
+
+    #[test]
+    fn test_generate_slow_down_response() {
+        let response = generate_slow_down_response(0x03, "24564".to_string());
+        assert_eq!("03 08 32 34 35 36 34", response);
+    }
+}
@@ -0,0 +1,308 @@
+use crate::config::{RetentionConfig, RetentionPolicy};
+use crate::device::DeviceData;
+use crate::metrics::Metrics;
+use crate::shutdown::Shutdown;
+use crate::store::SharedStore;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use surrealdb::sql::Datetime;
+use tracing::{info, warn};
+
+/// Rows removed by one run of the retention job.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct RetentionReport {
+    /// Positions deleted for being older than their policy keeps them.
+    pub positions_pruned: u64,
+    /// Positions deleted for sharing their downsampling interval with an
+    /// older one.
+    pub positions_downsampled: u64,
+    pub heartbeats_pruned: u64,
+}
+
+/// Applies the retention policies of `RetentionConfig` to every device.
+#[derive(Debug)]
+pub struct Retention {
+    store: SharedStore,
+    config: RetentionConfig,
+    /// Per device, the time positions were already downsampled up to, so
+    /// later runs only go through the positions that aged since.
+    downsampled: Mutex<HashMap<String, Datetime>>,
+}
+
+impl Retention {
+    pub fn new(store: SharedStore, config: RetentionConfig) -> Self {
+        Self {
+            store,
+            config,
+            downsampled: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Policy of the first group `device` belongs to, or the default one.
+    pub fn policy(&self, device: &DeviceData, owner: Option<&str>) -> &RetentionPolicy {
+        self.config
+            .groups
+            .iter()
+            .find(|group| {
+                group.kinds.contains(&device.kind)
+                    || group.serials.contains(&device.serial)
+                    || owner.is_some_and(|owner| group.owners.iter().any(|name| name == owner))
+            })
+            .map_or(&self.config.default, |group| &group.policy)
+    }
+
+    /// Runs the job every `interval_secs` until shutdown.
+    pub async fn run(&self, shutdown: Shutdown) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => return,
+                _ = interval.tick() => {}
+            }
+            if !self.store.is_available().await {
+                continue;
+            }
+            match self.run_once(Utc::now()).await {
+                Ok(report) => info!(
+                    positions_pruned = report.positions_pruned,
+                    positions_downsampled = report.positions_downsampled,
+                    heartbeats_pruned = report.heartbeats_pruned,
+                    "retention run finished"
+                ),
+                Err(error) => warn!(%error, "retention run failed"),
+            }
+        }
+    }
+
+    /// Prunes and downsamples the history of every device as of `now`.
+    pub async fn run_once(&self, now: DateTime<Utc>) -> Result<RetentionReport, String> {
+        let owners: HashMap<String, String> = self
+            .store
+            .users()
+            .await?
+            .into_iter()
+            .filter_map(|user| Some((user.id?.to_string(), user.username)))
+            .collect();
+        let mut report = RetentionReport::default();
+        for device in self.store.devices(None).await? {
+            let owner = owners.get(&device.owner.to_string()).map(String::as_str);
+            let policy = self.policy(&device, owner).clone();
+            self.apply(&device, &policy, now, &mut report).await?;
+        }
+        let removed = &Metrics::global().retention_removed;
+        removed
+            .with_label_values(&["coordinates", "pruned"])
+            .inc_by(report.positions_pruned);
+        removed
+            .with_label_values(&["coordinates", "downsampled"])
+            .inc_by(report.positions_downsampled);
+        removed
+            .with_label_values(&["heartbeat", "pruned"])
+            .inc_by(report.heartbeats_pruned);
+        Ok(report)
+    }
+
+    async fn apply(
+        &self,
+        device: &DeviceData,
+        policy: &RetentionPolicy,
+        now: DateTime<Utc>,
+        report: &mut RetentionReport,
+    ) -> Result<(), String> {
+        let Some(id) = device.id.clone() else {
+            return Ok(());
+        };
+        let store = &self.store;
+        if policy.heartbeats_days > 0 {
+            let before = days_ago(now, policy.heartbeats_days);
+            report.heartbeats_pruned += self
+                .batches(|limit| store.prune_heartbeats(&id, before.clone(), limit))
+                .await?;
+        }
+        if policy.positions_days == 0 {
+            return Ok(());
+        }
+        let raw = days_ago(now, policy.positions_days);
+        if policy.downsample_interval_secs == 0 {
+            report.positions_pruned += self
+                .batches(|limit| store.prune_positions(&id, raw.clone(), limit))
+                .await?;
+            return Ok(());
+        }
+        let mut from = Datetime::from(DateTime::UNIX_EPOCH);
+        if policy.downsample_days > 0 {
+            from = days_ago(now, policy.downsample_days);
+            report.positions_pruned += self
+                .batches(|limit| store.prune_positions(&id, from.clone(), limit))
+                .await?;
+        }
+        let key = id.to_string();
+        let done = self.lock().get(&key).cloned();
+        if let Some(done) = done {
+            // Back to the start of its interval, whose first position may be
+            // behind it.
+            let interval = policy.downsample_interval_secs as i64;
+            let start = done.timestamp() - done.timestamp().rem_euclid(interval);
+            if let Some(start) = DateTime::from_timestamp(start, 0) {
+                from = from.max(Datetime::from(start));
+            }
+        }
+        if from < raw {
+            report.positions_downsampled += self
+                .batches(|limit| {
+                    store.downsample_positions(
+                        &id,
+                        from.clone(),
+                        raw.clone(),
+                        policy.downsample_interval_secs,
+                        limit,
+                    )
+                })
+                .await?;
+        }
+        self.lock().insert(key, raw);
+        Ok(())
+    }
+
+    /// Calls `batch` with `batch_size` until it removes fewer rows, and
+    /// returns how many it removed in all.
+    async fn batches<F, Fut>(&self, mut batch: F) -> Result<u64, String>
+    where
+        F: FnMut(usize) -> Fut,
+        Fut: Future<Output = Result<u64, String>>,
+    {
+        let mut removed = 0;
+        loop {
+            let batch_removed = batch(self.config.batch_size).await?;
+            removed += batch_removed;
+            if batch_removed < self.config.batch_size as u64 {
+                return Ok(removed);
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Datetime>> {
+        match self.downsampled.lock() {
+            Ok(downsampled) => downsampled,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+fn days_ago(now: DateTime<Utc>, days: u64) -> Datetime {
+    Datetime::from(now - chrono::Duration::days(days as i64))
+}
+
+#[cfg(test)]
+mod test_retention {
+    use super::*;
+    use crate::actions::{CoordinatesData, HeartbeatData};
+    use crate::config::RetentionGroup;
+    use crate::store::{add_device, add_user, MemoryStore, Store};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_run_once() {
+        let store = Arc::new(MemoryStore::new());
+        let root = add_user(&*store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        let tracker = add_device(&*store, &root, "tracker", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        let obd = add_device(&*store, &root, "obd-1", "notsecurepassword", 24565)
+            .await
+            .unwrap();
+        let now = DateTime::from_timestamp(1_717_200_000, 0).unwrap();
+        let ago = |days: i64, seconds: i64| {
+            Datetime::from(now - chrono::Duration::days(days) + chrono::Duration::seconds(seconds))
+        };
+        let fix = |device: &DeviceData, timestamp| CoordinatesData {
+            id: None,
+            device: device.id.clone().unwrap(),
+            latitude: 14.65,
+            longitude: 121.04,
+            timestamp,
+        };
+        let heartbeat = |device: &DeviceData, timestamp| HeartbeatData {
+            id: None,
+            source_address: "127.0.0.1:5000".to_string(),
+            device: device.id.clone().unwrap(),
+            timestamp,
+        };
+        store
+            .insert_positions(&[
+                fix(&tracker, ago(40, 0)),
+                fix(&tracker, ago(2, 0)),
+                fix(&tracker, ago(2, 10)),
+                fix(&tracker, ago(2, 20)),
+                fix(&tracker, ago(0, -10)),
+                fix(&tracker, ago(0, -5)),
+                fix(&obd, ago(2, 0)),
+                fix(&obd, ago(0, -10)),
+            ])
+            .await
+            .unwrap();
+        store
+            .insert_heartbeats(&[
+                heartbeat(&tracker, ago(8, 0)),
+                heartbeat(&tracker, ago(1, 0)),
+            ])
+            .await
+            .unwrap();
+
+        let policy = RetentionPolicy {
+            positions_days: 1,
+            downsample_interval_secs: 60,
+            downsample_days: 30,
+            heartbeats_days: 7,
+        };
+        let config = RetentionConfig {
+            enabled: true,
+            batch_size: 1,
+            default: policy.clone(),
+            groups: vec![RetentionGroup {
+                name: "obd".to_string(),
+                kinds: Vec::new(),
+                owners: Vec::new(),
+                serials: vec!["obd-1".to_string()],
+                policy: RetentionPolicy {
+                    positions_days: 1,
+                    ..Default::default()
+                },
+            }],
+            ..Default::default()
+        };
+        let retention = Retention::new(store.clone(), config);
+        assert_eq!(retention.policy(&tracker, Some("root")), &policy);
+        assert_eq!(retention.policy(&obd, None).downsample_interval_secs, 0);
+
+        let report = retention.run_once(now).await.unwrap();
+        assert_eq!(
+            report,
+            RetentionReport {
+                positions_pruned: 2,
+                positions_downsampled: 2,
+                heartbeats_pruned: 1,
+            }
+        );
+        let positions = store.positions();
+        assert_eq!(positions.len(), 4);
+        assert!(positions.iter().any(|row| row.timestamp == ago(2, 0)));
+        assert_eq!(store.heartbeats().len(), 1);
+
+        // A day later the two fixes of the minute before the old cutoff share
+        // their interval, and the raw fix of the other device expired.
+        let later = now + chrono::Duration::days(1);
+        let report = retention.run_once(later).await.unwrap();
+        assert_eq!(report.positions_downsampled, 1);
+        assert_eq!(report.positions_pruned, 1);
+        assert_eq!(store.positions().len(), 2);
+        let report = retention.run_once(later).await.unwrap();
+        assert_eq!(report, RetentionReport::default());
+    }
+}
@@ -0,0 +1,461 @@
+use crate::config::SessionConfig;
+use crate::metrics::Metrics;
+use crate::shutdown::Shutdown;
+use crate::store::SharedStore;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use surrealdb::sql::Datetime;
+use surrealdb::RecordId;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+/// Last position reported by a device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub timestamp: Datetime,
+}
+
+/// State of a logged-in device, as stored in the `device_status` table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceSession {
+    pub client_id: u32,
+    pub online: bool,
+    pub source_address: String,
+    pub session_start: Datetime,
+    pub last_heartbeat: Option<Datetime>,
+    pub last_position: Option<Position>,
+    /// When the device was last heard from, used for the heartbeat timeout.
+    #[serde(skip, default = "Instant::now")]
+    last_seen: Instant,
+}
+
+impl DeviceSession {
+    fn new(client_id: u32, source_address: SocketAddr, now: Instant) -> Self {
+        Self {
+            client_id,
+            online: true,
+            source_address: source_address.to_string(),
+            session_start: Datetime::from(Utc::now()),
+            last_heartbeat: None,
+            last_position: None,
+            last_seen: now,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionEventKind {
+    Online,
+    Offline,
+    AddressChanged,
+}
+
+/// A device state transition, as stored in the `device_events` table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionEvent {
+    pub kind: SessionEventKind,
+    /// Address the device used before an `AddressChanged` event.
+    pub previous_address: Option<String>,
+    /// Device state right after the transition.
+    pub session: DeviceSession,
+    pub timestamp: Datetime,
+}
+
+impl SessionEvent {
+    fn new(kind: SessionEventKind, session: &DeviceSession) -> Self {
+        Self {
+            kind,
+            previous_address: None,
+            session: session.clone(),
+            timestamp: Datetime::from(Utc::now()),
+        }
+    }
+}
+
+/// A device's stay between a login and its logout, as stored in the
+/// `sessions` table. Positions and heartbeats are not linked to it: those
+/// of the device timestamped between `login` and `logout` belong to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionData {
+    pub id: Option<RecordId>,
+    pub device: RecordId,
+    /// Address the login packet came from.
+    pub source_address: String,
+    pub login: Datetime,
+    /// `None` while the session is open. Logging in again closes the
+    /// previous session of the device.
+    pub logout: Option<Datetime>,
+    /// Positions stored during the session, counted when it is closed.
+    #[serde(default)]
+    pub positions: u64,
+    /// Heartbeats stored during the session, counted when it is closed.
+    #[serde(default)]
+    pub heartbeats: u64,
+}
+
+impl SessionData {
+    /// End of the session's time range; open sessions run until now.
+    pub fn end(&self) -> Datetime {
+        self.logout
+            .clone()
+            .unwrap_or_else(|| Datetime::from(Utc::now()))
+    }
+}
+
+/// Tracks which devices are online from the packets they send.
+///
+/// Every transition is broadcast to the receivers returned by `subscribe`;
+/// `persist` writes them to the database so other processes can follow them.
+#[derive(Debug, Clone)]
+pub struct SessionManager {
+    sessions: Arc<Mutex<HashMap<u32, DeviceSession>>>,
+    events: broadcast::Sender<SessionEvent>,
+    /// Heartbeat timeout in milliseconds, changed by config reloads.
+    heartbeat_timeout_ms: Arc<AtomicU64>,
+}
+
+impl SessionManager {
+    pub fn new(config: &SessionConfig) -> Self {
+        let (events, _) = broadcast::channel(config.event_capacity.max(1));
+        Self {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            events,
+            heartbeat_timeout_ms: Arc::new(AtomicU64::new(
+                config.heartbeat_timeout_secs.saturating_mul(1000),
+            )),
+        }
+    }
+
+    /// Returns a receiver for the transitions emitted from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Returns the session of `client_id`, if it is logged in.
+    pub fn get(&self, client_id: u32) -> Option<DeviceSession> {
+        self.lock().get(&client_id).cloned()
+    }
+
+    /// Returns every tracked session, ordered by client id.
+    pub fn sessions(&self) -> Vec<DeviceSession> {
+        let mut sessions: Vec<DeviceSession> = self.lock().values().cloned().collect();
+        sessions.sort_by_key(|session| session.client_id);
+        sessions
+    }
+
+    /// Starts a new session for a device that logged in.
+    pub fn login(&self, client_id: u32, source_address: SocketAddr) {
+        self.login_at(client_id, source_address, Instant::now())
+    }
+
+    /// Records a heartbeat, bringing the device online if needed.
+    pub fn heartbeat(&self, client_id: u32, source_address: SocketAddr) {
+        self.heartbeat_at(client_id, source_address, Instant::now())
+    }
+
+    /// Records a position, bringing the device online if needed.
+    pub fn position(
+        &self,
+        client_id: u32,
+        source_address: SocketAddr,
+        latitude: f64,
+        longitude: f64,
+    ) {
+        self.position_at(
+            client_id,
+            source_address,
+            latitude,
+            longitude,
+            Instant::now(),
+        )
+    }
+
+    /// Ends the session of a device that logged out.
+    pub fn logout(&self, client_id: u32) {
+        let removed = self.lock().remove(&client_id);
+        if let Some(mut session) = removed {
+            if session.online {
+                session.online = false;
+                self.emit(SessionEvent::new(SessionEventKind::Offline, &session));
+            }
+        }
+    }
+
+    /// Marks the devices silent for longer than the heartbeat timeout offline.
+    pub fn expire(&self) -> usize {
+        self.expire_at(Instant::now())
+    }
+
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_timeout_ms.load(Ordering::Relaxed))
+    }
+
+    /// Changes the heartbeat timeout used from the next expiry check on.
+    pub fn set_heartbeat_timeout(&self, heartbeat_timeout: Duration) {
+        self.heartbeat_timeout_ms
+            .store(heartbeat_timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Expires sessions periodically until shutdown is triggered.
+    pub async fn run(&self, shutdown: Shutdown) {
+        loop {
+            let period = (self.heartbeat_timeout() / 4).max(Duration::from_millis(100));
+            tokio::select! {
+                _ = shutdown.wait() => return,
+                _ = tokio::time::sleep(period) => {
+                    let count = self.expire();
+                    if count > 0 {
+                        debug!(count, "sessions expired");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Saves every transition and the latest state of the device to the
+    /// store, until shutdown is triggered.
+    pub async fn persist(&self, store: SharedStore, shutdown: Shutdown) {
+        let mut receiver = self.subscribe();
+        loop {
+            let event = tokio::select! {
+                _ = shutdown.wait() => return,
+                received = receiver.recv() => match received {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "session events dropped before being persisted");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            };
+            if let Err(error) = Self::store(&store, event).await {
+                warn!(%error, "unable to persist session event");
+            }
+        }
+    }
+
+    async fn store(store: &SharedStore, event: SessionEvent) -> Result<(), String> {
+        let _timer = Metrics::global().start_db_timer("device_events.insert");
+        store.save_session_event(&event).await
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, DeviceSession>> {
+        match self.sessions.lock() {
+            Ok(sessions) => sessions,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn emit(&self, event: SessionEvent) {
+        info!(
+            client_id = event.session.client_id,
+            kind = ?event.kind,
+            source_address = %event.session.source_address,
+            "session state changed"
+        );
+        // Sending only fails when nobody is subscribed.
+        let _ = self.events.send(event);
+    }
+
+    fn login_at(&self, client_id: u32, source_address: SocketAddr, now: Instant) {
+        let mut sessions = self.lock();
+        let session = DeviceSession::new(client_id, source_address, now);
+        let event = match sessions.insert(client_id, session.clone()) {
+            Some(previous) if previous.online => {
+                if previous.source_address == session.source_address {
+                    None
+                } else {
+                    let mut event = SessionEvent::new(SessionEventKind::AddressChanged, &session);
+                    event.previous_address = Some(previous.source_address);
+                    Some(event)
+                }
+            }
+            _ => Some(SessionEvent::new(SessionEventKind::Online, &session)),
+        };
+        drop(sessions);
+        if let Some(event) = event {
+            self.emit(event);
+        }
+    }
+
+    fn heartbeat_at(&self, client_id: u32, source_address: SocketAddr, now: Instant) {
+        self.touch(client_id, source_address, now, |session| {
+            session.last_heartbeat = Some(Datetime::from(Utc::now()));
+        })
+    }
+
+    fn position_at(
+        &self,
+        client_id: u32,
+        source_address: SocketAddr,
+        latitude: f64,
+        longitude: f64,
+        now: Instant,
+    ) {
+        self.touch(client_id, source_address, now, |session| {
+            session.last_position = Some(Position {
+                latitude,
+                longitude,
+                timestamp: Datetime::from(Utc::now()),
+            });
+        })
+    }
+
+    /// Updates the session of a device that was heard from, creating it when
+    /// the device is unknown (e.g. it logged in before a restart).
+    fn touch(
+        &self,
+        client_id: u32,
+        source_address: SocketAddr,
+        now: Instant,
+        update: impl FnOnce(&mut DeviceSession),
+    ) {
+        let mut sessions = self.lock();
+        let session = sessions.entry(client_id).or_insert_with(|| {
+            let mut session = DeviceSession::new(client_id, source_address, now);
+            session.online = false;
+            session
+        });
+        update(session);
+        session.last_seen = now;
+        let source_address = source_address.to_string();
+        let event = if !session.online {
+            session.online = true;
+            session.source_address = source_address;
+            Some(SessionEvent::new(SessionEventKind::Online, session))
+        } else if session.source_address != source_address {
+            let previous_address = std::mem::replace(&mut session.source_address, source_address);
+            let mut event = SessionEvent::new(SessionEventKind::AddressChanged, session);
+            event.previous_address = Some(previous_address);
+            Some(event)
+        } else {
+            None
+        };
+        drop(sessions);
+        if let Some(event) = event {
+            self.emit(event);
+        }
+    }
+
+    fn expire_at(&self, now: Instant) -> usize {
+        let heartbeat_timeout = self.heartbeat_timeout();
+        let mut sessions = self.lock();
+        let mut events: Vec<SessionEvent> = Vec::new();
+        for session in sessions.values_mut() {
+            if session.online && now.duration_since(session.last_seen) >= heartbeat_timeout {
+                session.online = false;
+                events.push(SessionEvent::new(SessionEventKind::Offline, session));
+            }
+        }
+        drop(sessions);
+        let count = events.len();
+        for event in events {
+            self.emit(event);
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod test_session {
+    use super::*;
+
+    fn manager(heartbeat_timeout_secs: u64) -> SessionManager {
+        SessionManager::new(&SessionConfig {
+            heartbeat_timeout_secs,
+            event_capacity: 16,
+        })
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    #[test]
+    fn test_login_and_logout() {
+        let sessions = manager(30);
+        let mut events = sessions.subscribe();
+
+        sessions.login(24564, address(5000));
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, SessionEventKind::Online);
+        assert_eq!(event.session.client_id, 24564);
+        assert_eq!(event.session.source_address, "127.0.0.1:5000");
+        assert!(sessions.get(24564).unwrap().online);
+
+        sessions.logout(24564);
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, SessionEventKind::Offline);
+        assert!(!event.session.online);
+        assert!(sessions.get(24564).is_none());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_heartbeat_and_position() {
+        let sessions = manager(30);
+        let mut events = sessions.subscribe();
+
+        // Devices that logged in before a restart come back online on their
+        // next packet.
+        sessions.heartbeat(24564, address(5000));
+        assert_eq!(events.try_recv().unwrap().kind, SessionEventKind::Online);
+        sessions.position(24564, address(5000), 10.0, -127.0);
+        assert!(events.try_recv().is_err());
+
+        let session = sessions.get(24564).unwrap();
+        assert!(session.last_heartbeat.is_some());
+        let position = session.last_position.unwrap();
+        assert_eq!((position.latitude, position.longitude), (10.0, -127.0));
+    }
+
+    #[test]
+    fn test_address_changed() {
+        let sessions = manager(30);
+        let mut events = sessions.subscribe();
+        sessions.login(24564, address(5000));
+        let _ = events.try_recv();
+
+        sessions.heartbeat(24564, address(5001));
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, SessionEventKind::AddressChanged);
+        assert_eq!(event.previous_address.as_deref(), Some("127.0.0.1:5000"));
+        assert_eq!(event.session.source_address, "127.0.0.1:5001");
+    }
+
+    #[test]
+    fn test_heartbeat_timeout() {
+        let sessions = manager(30);
+        let mut events = sessions.subscribe();
+        let start = Instant::now();
+        sessions.login_at(24564, address(5000), start);
+        sessions.login_at(24565, address(5001), start);
+        let _ = events.try_recv();
+        let _ = events.try_recv();
+
+        sessions.heartbeat_at(24565, address(5001), start + Duration::from_secs(20));
+        assert_eq!(sessions.expire_at(start + Duration::from_secs(29)), 0);
+        assert_eq!(sessions.expire_at(start + Duration::from_secs(30)), 1);
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, SessionEventKind::Offline);
+        assert_eq!(event.session.client_id, 24564);
+        assert!(!sessions.get(24564).unwrap().online);
+        assert!(sessions.get(24565).unwrap().online);
+
+        // Already offline devices are not reported again.
+        assert_eq!(sessions.expire_at(start + Duration::from_secs(50)), 1);
+        assert_eq!(sessions.expire_at(start + Duration::from_secs(60)), 0);
+
+        sessions.heartbeat_at(24564, address(5000), start + Duration::from_secs(61));
+        let _ = events.try_recv();
+        assert_eq!(events.try_recv().unwrap().kind, SessionEventKind::Online);
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Coordinates shutdown between the listeners and the tasks they spawned.
+///
+/// Every clone observes the same flag, so triggering it once notifies the UDP
+/// loop, the websocket sessions and anything else waiting on `wait`.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+        }
+    }
+
+    /// Asks every listener to stop.
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Resolves once `trigger` has been called.
+    pub async fn wait(&self) {
+        let mut receiver = self.sender.subscribe();
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Triggers the shutdown when the process receives SIGINT or SIGTERM.
+    pub async fn listen_for_signals(&self) -> Result<(), String> {
+        wait_for_signal().await?;
+        self.trigger();
+        Ok(())
+    }
+}
+
+/// Waits for SIGINT (Ctrl+C) or, on unix, SIGTERM.
+pub async fn wait_for_signal() -> Result<(), String> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(value) => value,
+            Err(error) => return Err(format!("signal error: {:?}", error)),
+        };
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                if let Err(error) = result {
+                    return Err(format!("signal error: {:?}", error));
+                }
+            }
+            _ = terminate.recv() => {}
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        match tokio::signal::ctrl_c().await {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("signal error: {:?}", error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_shutdown {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trigger_wakes_waiters() {
+        let shutdown = Shutdown::new();
+        let waiter = shutdown.clone();
+        let handle = tokio::spawn(async move { waiter.wait().await });
+        assert!(!shutdown.is_triggered());
+        shutdown.trigger();
+        let result = tokio::time::timeout(std::time::Duration::from_secs(1), handle).await;
+        assert!(result.is_ok(), "waiter was not notified");
+        assert!(shutdown.is_triggered());
+    }
+
+    #[tokio::test]
+    async fn test_wait_after_trigger() {
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        let result = tokio::time::timeout(std::time::Duration::from_secs(1), shutdown.wait()).await;
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,429 @@
+use crate::actions::CoordinatesFix;
+use crate::config::SpoolConfig;
+use crate::metrics::Metrics;
+use crate::shutdown::Shutdown;
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// A fix waiting in the spool.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpoolEntry {
+    /// Unique id of the fix, used as its record id so a replay that is
+    /// interrupted and restarted never stores it twice.
+    pub key: String,
+    pub fix: CoordinatesFix,
+}
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+impl SpoolEntry {
+    /// Gives `fix` a key that is unique across restarts.
+    pub fn new(fix: CoordinatesFix) -> Self {
+        let key = format!(
+            "{}_{}_{}",
+            fix.client_id,
+            Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+            SEQUENCE.fetch_add(1, Ordering::Relaxed)
+        );
+        Self { key, fix }
+    }
+}
+
+/// Destination of replayed fixes.
+#[async_trait]
+pub trait SpoolSink: Send + Sync {
+    /// Whether writes are expected to succeed right now.
+    async fn is_available(&self) -> bool;
+
+    /// Stores `entry`. Writing the same key twice must store it once.
+    async fn write(&self, entry: &SpoolEntry) -> Result<(), String>;
+}
+
+#[async_trait]
+impl<T: SpoolSink + ?Sized> SpoolSink for Arc<T> {
+    async fn is_available(&self) -> bool {
+        (**self).is_available().await
+    }
+
+    async fn write(&self, entry: &SpoolEntry) -> Result<(), String> {
+        (**self).write(entry).await
+    }
+}
+
+/// Append-only file of fixes accepted while the database was unreachable,
+/// one JSON entry per line, replayed in order once it recovers.
+#[derive(Debug, Clone)]
+pub struct Spool {
+    path: PathBuf,
+    max_bytes: u64,
+    replay_rate_per_sec: f64,
+    replay_interval: Duration,
+    /// Serializes appends with the compaction that follows a replay.
+    lock: Arc<Mutex<()>>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl Spool {
+    /// Opens the spool file, creating its directory if needed, and counts the
+    /// fixes left over from a previous run.
+    pub fn open(config: &SpoolConfig) -> Result<Self, String> {
+        let path = PathBuf::from(&config.path);
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            if let Err(error) = fs::create_dir_all(parent) {
+                return Err(format!("spool error: {:?}", error));
+            }
+        }
+        let pending = Self::read_lines(&path)?.len();
+        if pending > 0 {
+            info!(pending, path = %path.display(), "spooled fixes waiting for replay");
+        }
+        Metrics::global().spool_pending.set(pending as i64);
+        Ok(Self {
+            path,
+            max_bytes: config.max_bytes,
+            replay_rate_per_sec: config.replay_rate_per_sec,
+            replay_interval: Duration::from_millis(config.replay_interval_ms),
+            lock: Arc::new(Mutex::new(())),
+            pending: Arc::new(AtomicUsize::new(pending)),
+        })
+    }
+
+    /// Number of fixes waiting to be replayed.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Appends `fix` to the spool, failing when the spool is full.
+    pub async fn append(&self, fix: CoordinatesFix) -> Result<SpoolEntry, String> {
+        let entry = SpoolEntry::new(fix);
+        self.push(&entry).await?;
+        Ok(entry)
+    }
+
+    /// Replays the pending fixes into `sink` in the order they were spooled and
+    /// removes the ones that were written, returning how many were written.
+    ///
+    /// The replay stops at the first failure while the sink is unavailable;
+    /// an entry the sink rejects while available is dropped.
+    pub async fn replay(&self, sink: &dyn SpoolSink) -> Result<usize, String> {
+        let lines = {
+            let _lock = self.lock.lock().await;
+            Self::read_lines(&self.path)?
+        };
+        let delay = if self.replay_rate_per_sec > 0.0 {
+            Some(Duration::from_secs_f64(1.0 / self.replay_rate_per_sec))
+        } else {
+            None
+        };
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut done: usize = 0;
+        let mut written: usize = 0;
+        for line in lines.iter() {
+            let entry = match serde_json::from_str::<SpoolEntry>(line) {
+                Ok(entry) => entry,
+                Err(error) => {
+                    warn!(%error, "dropping unreadable spool entry");
+                    done += 1;
+                    continue;
+                }
+            };
+            if !seen.insert(entry.key.clone()) {
+                done += 1;
+                continue;
+            }
+            match sink.write(&entry).await {
+                Ok(_) => written += 1,
+                Err(error) => {
+                    if !sink.is_available().await {
+                        warn!(%error, "replay interrupted, keeping the remaining fixes");
+                        break;
+                    }
+                    warn!(key = %entry.key, %error, "dropping spooled fix rejected by the database");
+                }
+            }
+            done += 1;
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        self.compact(done).await?;
+        Ok(written)
+    }
+
+    /// Replays pending fixes whenever `sink` is available, until shutdown is
+    /// triggered.
+    pub async fn run(&self, sink: impl SpoolSink, shutdown: Shutdown) {
+        let mut interval = tokio::time::interval(self.replay_interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => return,
+                _ = interval.tick() => {}
+            }
+            if self.pending() == 0 || !sink.is_available().await {
+                continue;
+            }
+            match self.replay(&sink).await {
+                Ok(written) => info!(written, pending = self.pending(), "spool replayed"),
+                Err(error) => warn!(%error, "spool replay failed"),
+            }
+        }
+    }
+
+    /// Appends an entry that already has a key, failing when the spool is full.
+    pub async fn push(&self, entry: &SpoolEntry) -> Result<(), String> {
+        let mut line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(error) => return Err(format!("spool error: {:?}", error)),
+        };
+        line.push('\n');
+        let _lock = self.lock.lock().await;
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(error) if error.kind() == ErrorKind::NotFound => 0,
+            Err(error) => return Err(format!("spool error: {:?}", error)),
+        };
+        if size + line.len() as u64 > self.max_bytes {
+            return Err(format!(
+                "spool error: spool is full ({} of {} bytes)",
+                size, self.max_bytes
+            ));
+        }
+        let result = File::options()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                file.write_all(line.as_bytes())?;
+                file.sync_data()
+            });
+        if let Err(error) = result {
+            return Err(format!("spool error: {:?}", error));
+        }
+        let pending = self.pending.fetch_add(1, Ordering::Relaxed) + 1;
+        Metrics::global().spool_pending.set(pending as i64);
+        Ok(())
+    }
+
+    /// Removes the first `done` lines. Appends only add lines at the end, so
+    /// they are still the ones that were replayed.
+    async fn compact(&self, done: usize) -> Result<(), String> {
+        if done == 0 {
+            return Ok(());
+        }
+        let _lock = self.lock.lock().await;
+        let lines = Self::read_lines(&self.path)?;
+        let remaining = lines.get(done..).unwrap_or_default();
+        let temporary = self.path.with_extension("tmp");
+        let result = File::create(&temporary)
+            .and_then(|mut file| {
+                for line in remaining {
+                    file.write_all(line.as_bytes())?;
+                    file.write_all(b"\n")?;
+                }
+                file.sync_data()
+            })
+            .and_then(|_| fs::rename(&temporary, &self.path));
+        if let Err(error) = result {
+            return Err(format!("spool error: {:?}", error));
+        }
+        self.pending.store(remaining.len(), Ordering::Relaxed);
+        Metrics::global().spool_pending.set(remaining.len() as i64);
+        Ok(())
+    }
+
+    fn read_lines(path: &Path) -> Result<Vec<String>, String> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(format!("spool error: {:?}", error)),
+        };
+        let mut lines: Vec<String> = Vec::new();
+        for line in BufReader::new(file).lines() {
+            match line {
+                Ok(line) if line.trim().is_empty() => {}
+                Ok(line) => lines.push(line),
+                Err(error) => return Err(format!("spool error: {:?}", error)),
+            }
+        }
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod test_spool {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicBool;
+    use surrealdb::sql::Datetime;
+
+    /// Stands in for SurrealDB: stores entries by key like an upsert and can
+    /// go down after a number of writes.
+    #[derive(Default)]
+    struct StandInDatabase {
+        available: AtomicBool,
+        writes_before_outage: std::sync::Mutex<Option<usize>>,
+        records: std::sync::Mutex<HashMap<String, CoordinatesFix>>,
+        order: std::sync::Mutex<Vec<u32>>,
+    }
+
+    impl StandInDatabase {
+        fn up() -> Self {
+            let database = Self::default();
+            database.available.store(true, Ordering::Relaxed);
+            database
+        }
+    }
+
+    #[async_trait]
+    impl SpoolSink for StandInDatabase {
+        async fn is_available(&self) -> bool {
+            self.available.load(Ordering::Relaxed)
+        }
+
+        async fn write(&self, entry: &SpoolEntry) -> Result<(), String> {
+            let mut writes_before_outage = self.writes_before_outage.lock().unwrap();
+            if let Some(remaining) = writes_before_outage.as_mut() {
+                if *remaining == 0 {
+                    self.available.store(false, Ordering::Relaxed);
+                }
+                *remaining = remaining.saturating_sub(1);
+            }
+            if !self.available.load(Ordering::Relaxed) {
+                return Err("connection refused".to_string());
+            }
+            self.order.lock().unwrap().push(entry.fix.client_id);
+            self.records
+                .lock()
+                .unwrap()
+                .insert(entry.key.clone(), entry.fix.clone());
+            Ok(())
+        }
+    }
+
+    fn spool(name: &str, max_bytes: u64) -> Spool {
+        let path = std::env::temp_dir().join(format!(
+            "gps-tracker-spool-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        Spool::open(&SpoolConfig {
+            enabled: true,
+            path: path.to_string_lossy().to_string(),
+            max_bytes,
+            replay_rate_per_sec: 0.0,
+            replay_interval_ms: 10,
+        })
+        .unwrap()
+    }
+
+    fn fix(client_id: u32) -> CoordinatesFix {
+        CoordinatesFix {
+            client_id,
+            latitude: 10.00001,
+            longitude: -127.000001,
+            timestamp: Datetime::from(Utc::now()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_after_outage() {
+        let spool = spool("outage", 1024 * 1024);
+        let database = StandInDatabase::default();
+        for client_id in 1..=5 {
+            spool.append(fix(client_id)).await.unwrap();
+        }
+        assert_eq!(spool.pending(), 5);
+
+        assert_eq!(spool.replay(&database).await, Ok(0));
+        assert_eq!(spool.pending(), 5);
+
+        // The database recovers, then goes down again after two writes.
+        database.available.store(true, Ordering::Relaxed);
+        *database.writes_before_outage.lock().unwrap() = Some(2);
+        assert_eq!(spool.replay(&database).await, Ok(2));
+        assert_eq!(spool.pending(), 3);
+
+        // Reopening the file after a restart picks up where the replay stopped.
+        let spool = Spool::open(&SpoolConfig {
+            enabled: true,
+            path: spool.path.to_string_lossy().to_string(),
+            max_bytes: 1024 * 1024,
+            replay_rate_per_sec: 0.0,
+            replay_interval_ms: 10,
+        })
+        .unwrap();
+        assert_eq!(spool.pending(), 3);
+        *database.writes_before_outage.lock().unwrap() = None;
+        database.available.store(true, Ordering::Relaxed);
+        assert_eq!(spool.replay(&database).await, Ok(3));
+        assert_eq!(spool.pending(), 0);
+        assert_eq!(*database.order.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+        let _ = fs::remove_file(&spool.path);
+    }
+
+    #[tokio::test]
+    async fn test_replay_deduplicates() {
+        let spool = spool("dedup", 1024 * 1024);
+        let database = StandInDatabase::up();
+        let entry = spool.append(fix(1)).await.unwrap();
+        spool.push(&entry).await.unwrap();
+        spool.append(fix(2)).await.unwrap();
+        assert_eq!(spool.pending(), 3);
+
+        assert_eq!(spool.replay(&database).await, Ok(2));
+        assert_eq!(spool.pending(), 0);
+        assert_eq!(*database.order.lock().unwrap(), vec![1, 2]);
+
+        // Replaying an entry the database already holds stores it once.
+        spool.push(&entry).await.unwrap();
+        assert_eq!(spool.replay(&database).await, Ok(1));
+        assert_eq!(database.records.lock().unwrap().len(), 2);
+        let _ = fs::remove_file(&spool.path);
+    }
+
+    #[tokio::test]
+    async fn test_spool_full() {
+        let spool = spool("full", 200);
+        assert!(spool.append(fix(1)).await.is_ok());
+        let result = spool.append(fix(2)).await;
+        assert!(result.is_err(), "{:?}", result);
+        assert_eq!(spool.pending(), 1);
+        let _ = fs::remove_file(&spool.path);
+    }
+
+    #[tokio::test]
+    async fn test_run_replays_on_recovery() {
+        let spool = spool("run", 1024 * 1024);
+        let database = Arc::new(StandInDatabase::default());
+        spool.append(fix(1)).await.unwrap();
+
+        let shutdown = Shutdown::new();
+        let task = {
+            let (spool, database, shutdown) = (spool.clone(), database.clone(), shutdown.clone());
+            tokio::spawn(async move { spool.run(database, shutdown).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(spool.pending(), 1);
+
+        database.available.store(true, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(spool.pending(), 0);
+        shutdown.trigger();
+        task.await.unwrap();
+        let _ = fs::remove_file(&spool.path);
+    }
+}
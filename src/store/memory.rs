@@ -0,0 +1,672 @@
+use crate::actions::{CoordinatesData, HeartbeatData};
+use crate::device::DeviceData;
+use crate::session::{DeviceSession, SessionData, SessionEvent};
+use crate::store::{thinned, BoundingBox, Store};
+use crate::user::UserData;
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use surrealdb::sql::Datetime;
+use surrealdb::RecordId;
+use tokio::sync::broadcast;
+
+/// Records buffered for slow watchers before they start skipping some.
+const WATCH_CAPACITY: usize = 1024;
+
+#[derive(Debug, Default)]
+struct State {
+    users: Vec<UserData>,
+    devices: Vec<DeviceData>,
+    positions: Vec<CoordinatesData>,
+    heartbeats: Vec<HeartbeatData>,
+    sessions: Vec<SessionData>,
+    /// Ids of the stored positions and heartbeats, as `table:key`.
+    ids: HashSet<String>,
+    device_status: BTreeMap<u32, DeviceSession>,
+    device_events: Vec<SessionEvent>,
+}
+
+impl State {
+    /// Removes the devices matching `matches` along with their positions,
+    /// heartbeats and sessions.
+    fn delete_devices(&mut self, matches: impl Fn(&DeviceData) -> bool) {
+        let deleted: Vec<RecordId> = self
+            .devices
+            .iter()
+            .filter(|device| matches(device))
+            .filter_map(|device| device.id.clone())
+            .collect();
+        self.devices.retain(|device| !matches(device));
+        self.positions.retain(|row| !deleted.contains(&row.device));
+        self.heartbeats.retain(|row| !deleted.contains(&row.device));
+        self.sessions.retain(|row| !deleted.contains(&row.device));
+        self.rebuild_ids();
+    }
+
+    /// Recomputes `ids` after rows were deleted.
+    fn rebuild_ids(&mut self) {
+        self.ids = self
+            .positions
+            .iter()
+            .filter_map(|row| row.id.as_ref())
+            .chain(self.heartbeats.iter().filter_map(|row| row.id.as_ref()))
+            .map(|id| id.to_string())
+            .collect();
+    }
+}
+
+/// Store keeping everything in memory, for tests and trying the server out
+/// without a database. Nothing survives a restart.
+#[derive(Debug)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+    available: AtomicBool,
+    next_id: AtomicU64,
+    positions: broadcast::Sender<CoordinatesData>,
+    session_events: broadcast::Sender<SessionEvent>,
+    /// Time every call waits before answering.
+    latency: Duration,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            available: AtomicBool::new(true),
+            next_id: AtomicU64::new(1),
+            positions: broadcast::channel(WATCH_CAPACITY).0,
+            session_events: broadcast::channel(WATCH_CAPACITY).0,
+            latency: Duration::ZERO,
+        }
+    }
+
+    /// Makes every call wait `latency` first, like the round trip to a
+    /// database server. Used by the benchmarks.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Fails when another user than `user` has its username, like the unique
+    /// indexes of the other backends.
+    fn check_unique_user(users: &[UserData], user: &UserData) -> Result<(), String> {
+        match users
+            .iter()
+            .find(|other| other.id != user.id && other.username == user.username)
+        {
+            Some(other) => Err(format!(
+                "user error: username {} already taken",
+                other.username
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails when another device than `device` has its serial or client id.
+    fn check_unique_device(devices: &[DeviceData], device: &DeviceData) -> Result<(), String> {
+        match devices.iter().find(|other| {
+            other.id != device.id
+                && (other.serial == device.serial || other.client_id == device.client_id)
+        }) {
+            Some(other) => Err(format!(
+                "device error: serial {} or client id {} already taken",
+                other.serial, other.client_id
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes every call fail while `false`, as if the database were down.
+    pub fn set_available(&self, available: bool) {
+        self.available.store(available, Ordering::Relaxed);
+    }
+
+    pub fn positions(&self) -> Vec<CoordinatesData> {
+        self.lock().positions.clone()
+    }
+
+    pub fn heartbeats(&self) -> Vec<HeartbeatData> {
+        self.lock().heartbeats.clone()
+    }
+
+    pub fn session_events(&self) -> Vec<SessionEvent> {
+        self.lock().device_events.clone()
+    }
+
+    fn new_id(&self, table: &str) -> RecordId {
+        let key = self.next_id.fetch_add(1, Ordering::Relaxed) as i64;
+        RecordId::from_table_key(table, key)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    async fn check_available(&self) -> Result<(), String> {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        if self.available.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err("store error: unavailable".to_string())
+        }
+    }
+
+    async fn find_user(&self, matches: impl Fn(&UserData) -> bool) -> Result<UserData, String> {
+        self.check_available().await?;
+        match self.lock().users.iter().find(|user| matches(user)) {
+            Some(user) => Ok(user.clone()),
+            None => Err("user not found".to_string()),
+        }
+    }
+
+    async fn find_device(
+        &self,
+        matches: impl Fn(&DeviceData) -> bool,
+    ) -> Result<DeviceData, String> {
+        self.check_available().await?;
+        match self.lock().devices.iter().find(|device| matches(device)) {
+            Some(device) => Ok(device.clone()),
+            None => Err("device not found".to_string()),
+        }
+    }
+
+    fn watch<T: Clone + Send + 'static>(sender: &broadcast::Sender<T>) -> BoxStream<'static, T> {
+        stream::unfold(sender.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(item) => return Some((item, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn is_available(&self) -> bool {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    async fn users(&self) -> Result<Vec<UserData>, String> {
+        self.check_available().await?;
+        Ok(self.lock().users.clone())
+    }
+
+    async fn user_by_id(&self, id: &RecordId) -> Result<UserData, String> {
+        self.find_user(|user| user.id.as_ref() == Some(id)).await
+    }
+
+    async fn user_by_username(&self, username: &str) -> Result<Option<UserData>, String> {
+        self.check_available().await?;
+        Ok(self
+            .lock()
+            .users
+            .iter()
+            .find(|user| user.username == username)
+            .cloned())
+    }
+
+    async fn create_user(&self, user: &UserData) -> Result<UserData, String> {
+        self.check_available().await?;
+        let mut user = user.clone();
+        user.id = Some(self.new_id("users"));
+        let mut state = self.lock();
+        Self::check_unique_user(&state.users, &user)?;
+        state.users.push(user.clone());
+        Ok(user)
+    }
+
+    async fn update_user(&self, user: &UserData) -> Result<(), String> {
+        self.check_available().await?;
+        let mut state = self.lock();
+        Self::check_unique_user(&state.users, user)?;
+        match state.users.iter_mut().find(|other| other.id == user.id) {
+            Some(stored) => {
+                *stored = user.clone();
+                Ok(())
+            }
+            None => Err("user not found".to_string()),
+        }
+    }
+
+    async fn delete_user(&self, id: &RecordId) -> Result<(), String> {
+        self.check_available().await?;
+        let mut state = self.lock();
+        state.users.retain(|user| user.id.as_ref() != Some(id));
+        state.delete_devices(|device| &device.owner == id);
+        Ok(())
+    }
+
+    async fn devices(&self, owner: Option<&RecordId>) -> Result<Vec<DeviceData>, String> {
+        self.check_available().await?;
+        let mut devices: Vec<DeviceData> = self
+            .lock()
+            .devices
+            .iter()
+            .filter(|device| owner.is_none_or(|owner| &device.owner == owner))
+            .cloned()
+            .collect();
+        devices.sort_by_key(|device| device.client_id);
+        Ok(devices)
+    }
+
+    async fn device_by_id(&self, id: &RecordId) -> Result<DeviceData, String> {
+        self.find_device(|device| device.id.as_ref() == Some(id))
+            .await
+    }
+
+    async fn device_by_client_id(&self, client_id: u32) -> Result<DeviceData, String> {
+        self.find_device(|device| device.client_id == client_id)
+            .await
+    }
+
+    async fn device_by_serial(&self, serial: &str) -> Result<Option<DeviceData>, String> {
+        self.check_available().await?;
+        Ok(self
+            .lock()
+            .devices
+            .iter()
+            .find(|device| device.serial == serial)
+            .cloned())
+    }
+
+    async fn create_device(&self, device: &DeviceData) -> Result<DeviceData, String> {
+        self.check_available().await?;
+        let mut device = device.clone();
+        device.id = Some(self.new_id("devices"));
+        let mut state = self.lock();
+        Self::check_unique_device(&state.devices, &device)?;
+        state.devices.push(device.clone());
+        Ok(device)
+    }
+
+    async fn update_device(&self, device: &DeviceData) -> Result<(), String> {
+        self.check_available().await?;
+        let mut state = self.lock();
+        let serial = match state.devices.iter().find(|other| other.id == device.id) {
+            Some(stored) => stored.serial.clone(),
+            None => return Err("device not found".to_string()),
+        };
+        let device = DeviceData {
+            serial,
+            ..device.clone()
+        };
+        Self::check_unique_device(&state.devices, &device)?;
+        if let Some(stored) = state.devices.iter_mut().find(|other| other.id == device.id) {
+            *stored = device;
+        }
+        Ok(())
+    }
+
+    async fn delete_device(&self, id: &RecordId) -> Result<(), String> {
+        self.check_available().await?;
+        self.lock()
+            .delete_devices(|device| device.id.as_ref() == Some(id));
+        Ok(())
+    }
+
+    async fn insert_positions(&self, rows: &[CoordinatesData]) -> Result<(), String> {
+        self.check_available().await?;
+        let mut state = self.lock();
+        for row in rows {
+            let mut row = row.clone();
+            let id = row
+                .id
+                .get_or_insert_with(|| self.new_id("coordinates"))
+                .clone();
+            if state.ids.insert(id.to_string()) {
+                state.positions.push(row.clone());
+                let _ = self.positions.send(row);
+            }
+        }
+        Ok(())
+    }
+
+    async fn insert_heartbeats(&self, rows: &[HeartbeatData]) -> Result<(), String> {
+        self.check_available().await?;
+        let mut state = self.lock();
+        for row in rows {
+            let mut row = row.clone();
+            let id = row
+                .id
+                .get_or_insert_with(|| self.new_id("heartbeat"))
+                .clone();
+            if state.ids.insert(id.to_string()) {
+                state.heartbeats.push(row);
+            }
+        }
+        Ok(())
+    }
+
+    async fn track(
+        &self,
+        client_id: u32,
+        from: Datetime,
+        to: Datetime,
+    ) -> Result<Vec<CoordinatesData>, String> {
+        let device = self
+            .find_device(|device| device.client_id == client_id)
+            .await?;
+        let mut track: Vec<CoordinatesData> = self
+            .lock()
+            .positions
+            .iter()
+            .filter(|row| Some(&row.device) == device.id.as_ref())
+            .filter(|row| row.timestamp >= from && row.timestamp <= to)
+            .cloned()
+            .collect();
+        track.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(track)
+    }
+
+    async fn area(
+        &self,
+        area: BoundingBox,
+        owner: Option<&RecordId>,
+        from: Datetime,
+        to: Datetime,
+    ) -> Result<Vec<CoordinatesData>, String> {
+        self.check_available().await?;
+        let state = self.lock();
+        let owned: Vec<&RecordId> = state
+            .devices
+            .iter()
+            .filter(|device| owner.is_none_or(|owner| &device.owner == owner))
+            .filter_map(|device| device.id.as_ref())
+            .collect();
+        let mut positions: Vec<CoordinatesData> = state
+            .positions
+            .iter()
+            .filter(|row| owner.is_none() || owned.contains(&&row.device))
+            .filter(|row| area.contains(row.latitude, row.longitude))
+            .filter(|row| row.timestamp >= from && row.timestamp <= to)
+            .cloned()
+            .collect();
+        positions.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(positions)
+    }
+
+    async fn delete_device_history(&self, client_id: u32) -> Result<(), String> {
+        self.check_available().await?;
+        let mut state = self.lock();
+        let devices: Vec<RecordId> = state
+            .devices
+            .iter()
+            .filter(|device| device.client_id == client_id)
+            .filter_map(|device| device.id.clone())
+            .collect();
+        state.positions.retain(|row| !devices.contains(&row.device));
+        state
+            .heartbeats
+            .retain(|row| !devices.contains(&row.device));
+        state.sessions.retain(|row| !devices.contains(&row.device));
+        state.rebuild_ids();
+        Ok(())
+    }
+
+    async fn prune_positions(
+        &self,
+        device: &RecordId,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String> {
+        self.check_available().await?;
+        let mut state = self.lock();
+        let mut pruned = 0;
+        state.positions.retain(|row| {
+            let prune = pruned < limit && &row.device == device && row.timestamp < before;
+            pruned += prune as usize;
+            !prune
+        });
+        state.rebuild_ids();
+        Ok(pruned as u64)
+    }
+
+    async fn downsample_positions(
+        &self,
+        device: &RecordId,
+        from: Datetime,
+        to: Datetime,
+        interval_secs: u64,
+        limit: usize,
+    ) -> Result<u64, String> {
+        self.check_available().await?;
+        let mut state = self.lock();
+        let mut window: Vec<(usize, Datetime)> = state
+            .positions
+            .iter()
+            .enumerate()
+            .filter(|(_, row)| &row.device == device)
+            .filter(|(_, row)| row.timestamp >= from && row.timestamp < to)
+            .map(|(index, row)| (index, row.timestamp.clone()))
+            .collect();
+        window.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        let thinned: HashSet<usize> = thinned(window, interval_secs, limit).into_iter().collect();
+        let mut index = 0;
+        state.positions.retain(|_| {
+            index += 1;
+            !thinned.contains(&(index - 1))
+        });
+        state.rebuild_ids();
+        Ok(thinned.len() as u64)
+    }
+
+    async fn prune_heartbeats(
+        &self,
+        device: &RecordId,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String> {
+        self.check_available().await?;
+        let mut state = self.lock();
+        let mut pruned = 0;
+        state.heartbeats.retain(|row| {
+            let prune = pruned < limit && &row.device == device && row.timestamp < before;
+            pruned += prune as usize;
+            !prune
+        });
+        state.rebuild_ids();
+        Ok(pruned as u64)
+    }
+
+    async fn create_session(&self, session: &SessionData) -> Result<SessionData, String> {
+        self.check_available().await?;
+        let mut session = session.clone();
+        session.id = Some(self.new_id("sessions"));
+        self.lock().sessions.push(session.clone());
+        Ok(session)
+    }
+
+    async fn close_session(
+        &self,
+        device: &RecordId,
+        logout: Datetime,
+    ) -> Result<Option<SessionData>, String> {
+        self.check_available().await?;
+        let mut state = self.lock();
+        let index = match state
+            .sessions
+            .iter()
+            .position(|row| &row.device == device && row.logout.is_none())
+        {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let login = state.sessions[index].login.clone();
+        let during = |row_device: &RecordId, timestamp: &Datetime| {
+            row_device == device && timestamp >= &login && timestamp <= &logout
+        };
+        let positions = state
+            .positions
+            .iter()
+            .filter(|row| during(&row.device, &row.timestamp))
+            .count();
+        let heartbeats = state
+            .heartbeats
+            .iter()
+            .filter(|row| during(&row.device, &row.timestamp))
+            .count();
+        let session = &mut state.sessions[index];
+        session.logout = Some(logout.clone());
+        session.positions = positions as u64;
+        session.heartbeats = heartbeats as u64;
+        Ok(Some(session.clone()))
+    }
+
+    async fn sessions(&self, device: &RecordId) -> Result<Vec<SessionData>, String> {
+        self.check_available().await?;
+        let mut sessions: Vec<SessionData> = self
+            .lock()
+            .sessions
+            .iter()
+            .filter(|row| &row.device == device)
+            .cloned()
+            .rev()
+            .collect();
+        sessions.sort_by(|a, b| b.login.cmp(&a.login));
+        Ok(sessions)
+    }
+
+    async fn session_by_id(&self, id: &RecordId) -> Result<SessionData, String> {
+        self.check_available().await?;
+        match self
+            .lock()
+            .sessions
+            .iter()
+            .find(|row| row.id.as_ref() == Some(id))
+        {
+            Some(session) => Ok(session.clone()),
+            None => Err("session not found".to_string()),
+        }
+    }
+
+    async fn save_session_event(&self, event: &SessionEvent) -> Result<(), String> {
+        self.check_available().await?;
+        let mut state = self.lock();
+        state
+            .device_status
+            .insert(event.session.client_id, event.session.clone());
+        state.device_events.push(event.clone());
+        let _ = self.session_events.send(event.clone());
+        Ok(())
+    }
+
+    async fn device_statuses(&self) -> Result<Vec<DeviceSession>, String> {
+        self.check_available().await?;
+        Ok(self.lock().device_status.values().cloned().collect())
+    }
+
+    async fn watch_positions(&self) -> Result<BoxStream<'static, CoordinatesData>, String> {
+        self.check_available().await?;
+        Ok(Self::watch(&self.positions))
+    }
+
+    async fn watch_session_events(&self) -> Result<BoxStream<'static, SessionEvent>, String> {
+        self.check_available().await?;
+        Ok(Self::watch(&self.session_events))
+    }
+}
+
+#[cfg(test)]
+mod test_memory {
+    use super::*;
+    use crate::password;
+    use crate::store::{add_device, add_user, check_store};
+    use chrono::Utc;
+
+    fn position(device: &DeviceData, id: Option<RecordId>) -> CoordinatesData {
+        CoordinatesData {
+            id,
+            device: device.id.clone().unwrap(),
+            latitude: 14.65,
+            longitude: 121.04,
+            timestamp: Datetime::from(Utc::now()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let store = MemoryStore::new();
+        let root = add_user(&store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        let tracker = add_device(&store, &root, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        check_store(std::sync::Arc::new(store), &root, &tracker).await;
+    }
+
+    #[tokio::test]
+    async fn test_users() {
+        let store = MemoryStore::new();
+        let root = add_user(&store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        let other = add_user(&store, "Other", "other", "password")
+            .await
+            .unwrap();
+        add_device(&store, &other, "other", "otherkey", 1)
+            .await
+            .unwrap();
+
+        let user = store.user_by_username("root").await.unwrap().unwrap();
+        assert!(password::verify("notsecurepassword", &user.password));
+        assert!(store.user_by_username("wrong").await.unwrap().is_none());
+        let user = store.user_by_id(root.id.as_ref().unwrap()).await.unwrap();
+        assert_eq!(user.username, "root");
+        let device = store.device_by_client_id(1).await.unwrap();
+        assert_eq!(device.owner, other.id.unwrap());
+        assert!(password::verify("otherkey", &device.credentials));
+        assert_eq!(store.users().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_insert_ignores_stored_ids() {
+        let store = MemoryStore::new();
+        let root = add_user(&store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        let root = add_device(&store, &root, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        let mut watcher = store.watch_positions().await.unwrap();
+        let id = RecordId::from_table_key("coordinates", "fix");
+        store
+            .insert_positions(&[position(&root, Some(id.clone())), position(&root, None)])
+            .await
+            .unwrap();
+        store
+            .insert_positions(&[position(&root, Some(id.clone()))])
+            .await
+            .unwrap();
+        assert_eq!(store.positions().len(), 2);
+        assert_eq!(watcher.next().await.unwrap().id, Some(id));
+
+        store.delete_device_history(24564).await.unwrap();
+        assert!(store.positions().is_empty());
+
+        store.set_available(false);
+        assert!(!store.is_available().await);
+        assert!(store
+            .insert_positions(&[position(&root, None)])
+            .await
+            .is_err());
+    }
+}
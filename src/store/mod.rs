@@ -0,0 +1,758 @@
+pub mod memory;
+pub mod postgres;
+pub mod sqlite;
+pub mod surreal;
+
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+pub use surreal::SurrealStore;
+
+use crate::actions::{CoordinatesData, HeartbeatData};
+use crate::config::{DatabaseConfig, DatabaseEngine};
+use crate::device::DeviceData;
+use crate::session::{DeviceSession, SessionData, SessionEvent};
+use crate::user::UserData;
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use surrealdb::sql::Datetime;
+use surrealdb::{RecordId, Value};
+use tracing::warn;
+
+/// Area between two latitudes and two longitudes, in degrees. `validate`
+/// rejects areas crossing the antimeridian, whose west exceeds their east;
+/// query them as two boxes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub south: f64,
+    pub west: f64,
+    pub north: f64,
+    pub east: f64,
+}
+
+impl BoundingBox {
+    pub fn validate(&self) -> Result<(), String> {
+        let latitudes = -90.0..=90.0;
+        let longitudes = -180.0..=180.0;
+        if !latitudes.contains(&self.south) || !latitudes.contains(&self.north) {
+            return Err("bounding box error: latitudes must be within -90 and 90".to_string());
+        }
+        if !longitudes.contains(&self.west) || !longitudes.contains(&self.east) {
+            return Err("bounding box error: longitudes must be within -180 and 180".to_string());
+        }
+        if self.south > self.north || self.west > self.east {
+            return Err(
+                "bounding box error: south and west must not exceed north and east".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        (self.south..=self.north).contains(&latitude)
+            && (self.west..=self.east).contains(&longitude)
+    }
+}
+
+/// Persistence used by the actions, the write pipeline, session tracking and
+/// the API. Each backend owns its query language; callers only see records.
+#[async_trait]
+pub trait Store: Send + Sync + std::fmt::Debug {
+    /// Whether writes are expected to succeed right now.
+    async fn is_available(&self) -> bool;
+
+    async fn users(&self) -> Result<Vec<UserData>, String>;
+
+    async fn user_by_id(&self, id: &RecordId) -> Result<UserData, String>;
+
+    /// The user with `username`, hash included, or `None` when there is none.
+    /// Passwords are verified by the caller, never in a query.
+    async fn user_by_username(&self, username: &str) -> Result<Option<UserData>, String>;
+
+    /// Stores a user whose password is already hashed and returns it with
+    /// its new id. Fails when the username is taken.
+    async fn create_user(&self, user: &UserData) -> Result<UserData, String>;
+
+    /// Saves the name, password and disabled flag of the user with the id
+    /// of `user`.
+    async fn update_user(&self, user: &UserData) -> Result<(), String>;
+
+    /// Deletes a user along with its devices and their positions, heartbeats
+    /// and sessions.
+    async fn delete_user(&self, id: &RecordId) -> Result<(), String>;
+
+    /// Every device, or those of `owner`, ordered by client id.
+    async fn devices(&self, owner: Option<&RecordId>) -> Result<Vec<DeviceData>, String>;
+
+    async fn device_by_id(&self, id: &RecordId) -> Result<DeviceData, String>;
+
+    async fn device_by_client_id(&self, client_id: u32) -> Result<DeviceData, String>;
+
+    /// The device with `serial`, credentials included, or `None` when there
+    /// is none.
+    async fn device_by_serial(&self, serial: &str) -> Result<Option<DeviceData>, String>;
+
+    /// Stores a device whose credentials are already hashed and returns it
+    /// with its new id. Fails when the serial or client id is taken.
+    async fn create_device(&self, device: &DeviceData) -> Result<DeviceData, String>;
+
+    /// Saves every field but the serial of the device with the id of
+    /// `device`.
+    async fn update_device(&self, device: &DeviceData) -> Result<(), String>;
+
+    /// Deletes a device along with its positions, heartbeats and sessions.
+    async fn delete_device(&self, id: &RecordId) -> Result<(), String>;
+
+    /// Stores `rows`, skipping those whose id is already stored. Rows without
+    /// an id get a new one.
+    async fn insert_positions(&self, rows: &[CoordinatesData]) -> Result<(), String>;
+
+    /// Stores `rows`, skipping those whose id is already stored. Rows without
+    /// an id get a new one.
+    async fn insert_heartbeats(&self, rows: &[HeartbeatData]) -> Result<(), String>;
+
+    /// Positions of the device with `client_id` between `from` and `to`
+    /// inclusive, oldest first.
+    async fn track(
+        &self,
+        client_id: u32,
+        from: Datetime,
+        to: Datetime,
+    ) -> Result<Vec<CoordinatesData>, String>;
+
+    /// Positions inside `area` between `from` and `to` inclusive, oldest
+    /// first, of every device or those of `owner`.
+    async fn area(
+        &self,
+        area: BoundingBox,
+        owner: Option<&RecordId>,
+        from: Datetime,
+        to: Datetime,
+    ) -> Result<Vec<CoordinatesData>, String>;
+
+    /// Deletes the positions, heartbeats and sessions of the device with
+    /// `client_id`.
+    async fn delete_device_history(&self, client_id: u32) -> Result<(), String>;
+
+    /// Deletes up to `limit` positions of `device` older than `before` and
+    /// returns how many were deleted.
+    async fn prune_positions(
+        &self,
+        device: &RecordId,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String>;
+
+    /// Keeps only the first position of `device` in every `interval_secs`
+    /// bucket between `from` inclusive and `to` exclusive, deleting up to
+    /// `limit` of the others. Buckets are counted from the Unix epoch.
+    async fn downsample_positions(
+        &self,
+        device: &RecordId,
+        from: Datetime,
+        to: Datetime,
+        interval_secs: u64,
+        limit: usize,
+    ) -> Result<u64, String>;
+
+    /// Deletes up to `limit` heartbeats of `device` older than `before` and
+    /// returns how many were deleted.
+    async fn prune_heartbeats(
+        &self,
+        device: &RecordId,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String>;
+
+    /// Stores a new session and returns it with its id.
+    async fn create_session(&self, session: &SessionData) -> Result<SessionData, String>;
+
+    /// Closes the open session of `device` at `logout`, counting the positions
+    /// and heartbeats stored during it. Returns `None` when no session is
+    /// open.
+    async fn close_session(
+        &self,
+        device: &RecordId,
+        logout: Datetime,
+    ) -> Result<Option<SessionData>, String>;
+
+    /// Sessions of `device`, newest first.
+    async fn sessions(&self, device: &RecordId) -> Result<Vec<SessionData>, String>;
+
+    async fn session_by_id(&self, id: &RecordId) -> Result<SessionData, String>;
+
+    /// Stores the latest state of the device and appends the event.
+    async fn save_session_event(&self, event: &SessionEvent) -> Result<(), String>;
+
+    /// Latest state of every device, ordered by client id.
+    async fn device_statuses(&self) -> Result<Vec<DeviceSession>, String>;
+
+    /// Positions stored from now on, until the stream is dropped.
+    async fn watch_positions(&self) -> Result<BoxStream<'static, CoordinatesData>, String>;
+
+    /// Session events stored from now on, until the stream is dropped.
+    async fn watch_session_events(&self) -> Result<BoxStream<'static, SessionEvent>, String>;
+}
+
+/// Store shared by every handler.
+pub type SharedStore = Arc<dyn Store>;
+
+/// Opens the backend selected by `database.engine`.
+pub async fn connect(config: &DatabaseConfig) -> Result<SharedStore, String> {
+    match config.engine {
+        DatabaseEngine::Sqlite => Ok(Arc::new(SqliteStore::open(&config.path)?)),
+        DatabaseEngine::Postgres => Ok(Arc::new(PostgresStore::connect(config).await?)),
+        _ => Ok(Arc::new(SurrealStore::connect(config).await?)),
+    }
+}
+
+/// Rowid of a `table:N` record id, for the SQL backends.
+pub(crate) fn record_key(id: &RecordId, table: &str) -> Result<i64, String> {
+    let key = Value::from(id.key().clone()).to_string();
+    match key.parse::<i64>() {
+        Ok(key) if id.table() == table => Ok(key),
+        _ => Err(format!("not a {} id: {}", table, id)),
+    }
+}
+
+/// Keys of the rows to delete when keeping the first of every
+/// `interval_secs` bucket, given rows ordered by timestamp.
+pub(crate) fn thinned<K>(
+    rows: impl IntoIterator<Item = (K, Datetime)>,
+    interval_secs: u64,
+    limit: usize,
+) -> Vec<K> {
+    let interval = interval_secs.max(1) as i64;
+    let mut bucket = None;
+    let mut thinned = Vec::new();
+    for (key, timestamp) in rows {
+        let current = Some(timestamp.timestamp().div_euclid(interval));
+        if bucket == current {
+            if thinned.len() == limit {
+                break;
+            }
+            thinned.push(key);
+        }
+        bucket = current;
+    }
+    thinned
+}
+
+/// Streams rows whose sequence number is above `last` by calling `fetch`
+/// every `interval`, for backends without live queries. `fetch` returns the
+/// new rows with their sequence number, in order.
+pub(crate) fn poll<T, F, Fut>(interval: Duration, last: i64, fetch: F) -> BoxStream<'static, T>
+where
+    T: Send + 'static,
+    F: Fn(i64) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Vec<(i64, T)>, String>> + Send,
+{
+    stream::unfold((Arc::new(fetch), last), move |(fetch, last)| async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            match fetch(last).await {
+                Ok(rows) if rows.is_empty() => continue,
+                Ok(rows) => {
+                    let last = rows.last().map(|(seq, _)| *seq).unwrap_or(last);
+                    let items: Vec<T> = rows.into_iter().map(|(_, item)| item).collect();
+                    return Some((stream::iter(items), (fetch, last)));
+                }
+                Err(error) => warn!(%error, "unable to poll for new rows"),
+            }
+        }
+    })
+    .flatten()
+    .boxed()
+}
+
+/// Adds a user through `store`, hashing `password`, and returns it with its
+/// new id.
+#[cfg(test)]
+pub(crate) async fn add_user<S: Store + ?Sized>(
+    store: &S,
+    name: &str,
+    username: &str,
+    password: &str,
+) -> Result<UserData, String> {
+    store
+        .create_user(&UserData {
+            id: None,
+            name: name.to_string(),
+            username: username.to_string(),
+            password: crate::password::hash(password)?,
+            disabled: false,
+        })
+        .await
+}
+
+/// Adds a device of `owner` through `store`, hashing `key`, and returns it
+/// with its new id.
+#[cfg(test)]
+pub(crate) async fn add_device<S: Store + ?Sized>(
+    store: &S,
+    owner: &UserData,
+    serial: &str,
+    key: &str,
+    client_id: u32,
+) -> Result<DeviceData, String> {
+    store
+        .create_device(&DeviceData {
+            id: None,
+            serial: serial.to_string(),
+            name: owner.name.clone(),
+            kind: crate::device::DEFAULT_KIND.to_string(),
+            owner: owner.id.clone().ok_or("store error: owner has no id")?,
+            protocol: crate::device::DEFAULT_PROTOCOL.to_string(),
+            credentials: crate::password::hash(key)?,
+            client_id,
+            disabled: false,
+        })
+        .await
+}
+
+/// Checks every backend runs the same way; each backend's tests call this
+/// with a store holding only `root` and its device `tracker`.
+#[cfg(test)]
+pub(crate) async fn check_store(store: SharedStore, root: &UserData, tracker: &DeviceData) {
+    use chrono::{Duration, Utc};
+    use futures::StreamExt;
+
+    let root_id = root.id.clone().unwrap();
+    let tracker_id = tracker.id.clone().unwrap();
+    let user = store
+        .user_by_username(&root.username)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(user.id, root.id);
+    assert!(crate::password::is_hashed(&user.password));
+    assert_eq!(user.password, root.password);
+    assert!(store.user_by_username("nobody").await.unwrap().is_none());
+    assert_eq!(
+        store.user_by_id(&root_id).await.unwrap().username,
+        root.username
+    );
+    assert_eq!(store.users().await.unwrap().len(), 1);
+
+    let device = store
+        .device_by_serial(&tracker.serial)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(device.id, tracker.id);
+    assert_eq!(device.owner, root_id);
+    assert!(crate::password::is_hashed(&device.credentials));
+    assert_eq!(device.credentials, tracker.credentials);
+    assert!(store.device_by_serial("nothing").await.unwrap().is_none());
+    assert_eq!(
+        store
+            .device_by_client_id(tracker.client_id)
+            .await
+            .unwrap()
+            .serial,
+        tracker.serial
+    );
+    assert_eq!(
+        store.device_by_id(&tracker_id).await.unwrap().client_id,
+        tracker.client_id
+    );
+    assert!(store
+        .device_by_client_id(tracker.client_id + 1)
+        .await
+        .is_err());
+    assert_eq!(store.devices(Some(&root_id)).await.unwrap().len(), 1);
+
+    let mut positions = store.watch_positions().await.unwrap();
+    let start = Utc::now();
+    let rows: Vec<CoordinatesData> = (0..3)
+        .map(|minute| CoordinatesData {
+            id: Some(RecordId::from_table_key(
+                "coordinates",
+                format!("fix-{}", minute),
+            )),
+            device: tracker_id.clone(),
+            latitude: 14.65 + minute as f64,
+            longitude: 121.04,
+            timestamp: Datetime::from(start + Duration::minutes(minute)),
+        })
+        .collect();
+    store.insert_positions(&rows).await.unwrap();
+    // Retried rows are skipped, rows without an id get one.
+    let mut retried = rows[..1].to_vec();
+    retried.push(CoordinatesData {
+        id: None,
+        ..rows[0].clone()
+    });
+    store.insert_positions(&retried).await.unwrap();
+    let first = tokio::time::timeout(std::time::Duration::from_secs(5), positions.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(first.id, rows[0].id);
+
+    let track = store
+        .track(
+            tracker.client_id,
+            Datetime::from(start),
+            Datetime::from(start + Duration::minutes(1)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(track.len(), 3);
+    assert_eq!(track.last().unwrap().latitude, 15.65);
+    assert_eq!(track[0].device, tracker_id);
+    let track = store
+        .track(
+            tracker.client_id,
+            Datetime::from(start - Duration::minutes(1)),
+            Datetime::from(start + Duration::minutes(5)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(track.len(), 4);
+    let area = BoundingBox {
+        south: 15.0,
+        west: 121.0,
+        north: 17.0,
+        east: 122.0,
+    };
+    let inside = store
+        .area(
+            area,
+            None,
+            Datetime::from(start - Duration::minutes(1)),
+            Datetime::from(start + Duration::minutes(5)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(inside.len(), 2);
+    assert_eq!(inside[0].latitude, 15.65);
+    let inside = store
+        .area(
+            area,
+            None,
+            Datetime::from(start + Duration::minutes(2)),
+            Datetime::from(start + Duration::minutes(5)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(inside.len(), 1);
+
+    let heartbeat = HeartbeatData {
+        id: None,
+        source_address: "127.0.0.1:5000".to_string(),
+        device: tracker_id.clone(),
+        timestamp: Datetime::from(start),
+    };
+    store.insert_heartbeats(&[heartbeat]).await.unwrap();
+
+    let new_session = |device: &RecordId, login| SessionData {
+        id: None,
+        device: device.clone(),
+        source_address: "127.0.0.1:5000".to_string(),
+        login: Datetime::from(login),
+        logout: None,
+        positions: 0,
+        heartbeats: 0,
+    };
+    let session = store
+        .create_session(&new_session(&tracker_id, start - Duration::minutes(1)))
+        .await
+        .unwrap();
+    assert!(session.id.is_some());
+    let closed = store
+        .close_session(&tracker_id, Datetime::from(start + Duration::minutes(1)))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(closed.id, session.id);
+    assert!(closed.logout.is_some());
+    // The first two rows and the copy of the first one.
+    assert_eq!(closed.positions, 3);
+    assert_eq!(closed.heartbeats, 1);
+    assert!(store
+        .close_session(&tracker_id, Datetime::from(start + Duration::minutes(2)))
+        .await
+        .unwrap()
+        .is_none());
+    let open = store
+        .create_session(&new_session(&tracker_id, start + Duration::minutes(2)))
+        .await
+        .unwrap();
+    let sessions = store.sessions(&tracker_id).await.unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions[0].id, open.id);
+    assert!(sessions[0].logout.is_none());
+    assert_eq!(sessions[1].positions, 3);
+    let found = store
+        .session_by_id(session.id.as_ref().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(found.device, tracker_id);
+    assert_eq!(found.heartbeats, 1);
+    // Closing a session leaves the positions it covers.
+    let track = store
+        .track(
+            tracker.client_id,
+            Datetime::from(start - Duration::minutes(1)),
+            Datetime::from(start + Duration::minutes(5)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(track.len(), 4);
+
+    // Fixes at 0s, 10s, 20s and 70s past a minute long ago.
+    let old = chrono::DateTime::from_timestamp(946_684_800, 0).unwrap();
+    let old_rows: Vec<CoordinatesData> = [0, 10, 20, 70]
+        .into_iter()
+        .map(|seconds| CoordinatesData {
+            id: None,
+            timestamp: Datetime::from(old + Duration::seconds(seconds)),
+            ..rows[0].clone()
+        })
+        .collect();
+    store.insert_positions(&old_rows).await.unwrap();
+    store
+        .insert_heartbeats(&[HeartbeatData {
+            id: None,
+            source_address: "127.0.0.1:5000".to_string(),
+            device: tracker_id.clone(),
+            timestamp: Datetime::from(old),
+        }])
+        .await
+        .unwrap();
+    let window = |limit| {
+        store.downsample_positions(
+            &tracker_id,
+            Datetime::from(old - Duration::minutes(1)),
+            Datetime::from(old + Duration::minutes(5)),
+            60,
+            limit,
+        )
+    };
+    assert_eq!(window(1).await.unwrap(), 1);
+    assert_eq!(window(10).await.unwrap(), 1);
+    assert_eq!(window(10).await.unwrap(), 0);
+    let old_track = |from: chrono::DateTime<Utc>| {
+        store.track(
+            tracker.client_id,
+            Datetime::from(from),
+            Datetime::from(old + Duration::minutes(5)),
+        )
+    };
+    let kept = old_track(old).await.unwrap();
+    assert_eq!(kept.len(), 2);
+    assert_eq!(kept[0].timestamp, Datetime::from(old));
+    assert_eq!(
+        kept[1].timestamp,
+        Datetime::from(old + Duration::seconds(70))
+    );
+    let cutoff = Datetime::from(old + Duration::minutes(1));
+    assert_eq!(
+        store
+            .prune_positions(&tracker_id, cutoff.clone(), 10)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(old_track(old).await.unwrap().len(), 1);
+    assert_eq!(
+        store
+            .prune_heartbeats(&tracker_id, cutoff.clone(), 10)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        store
+            .prune_heartbeats(&tracker_id, cutoff, 10)
+            .await
+            .unwrap(),
+        0
+    );
+    // Recent positions are left alone.
+    let track = store
+        .track(
+            tracker.client_id,
+            Datetime::from(start - Duration::minutes(1)),
+            Datetime::from(start + Duration::minutes(5)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(track.len(), 4);
+
+    store
+        .delete_device_history(tracker.client_id)
+        .await
+        .unwrap();
+    assert!(store.sessions(&tracker_id).await.unwrap().is_empty());
+    let track = store
+        .track(
+            tracker.client_id,
+            Datetime::from(start - Duration::minutes(1)),
+            Datetime::from(start + Duration::minutes(5)),
+        )
+        .await
+        .unwrap();
+    assert!(track.is_empty());
+    // History can be written again after being deleted.
+    store.insert_positions(&rows[..1]).await.unwrap();
+    store
+        .create_session(&new_session(&tracker_id, start))
+        .await
+        .unwrap();
+
+    let sessions = crate::session::SessionManager::new(&Default::default());
+    let mut events = store.watch_session_events().await.unwrap();
+    let mut received = sessions.subscribe();
+    sessions.login(tracker.client_id, "127.0.0.1:5000".parse().unwrap());
+    let event = received.recv().await.unwrap();
+    store.save_session_event(&event).await.unwrap();
+    let watched = tokio::time::timeout(std::time::Duration::from_secs(5), events.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(watched.kind, event.kind);
+    let statuses = store.device_statuses().await.unwrap();
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0].client_id, tracker.client_id);
+    assert!(statuses[0].online);
+
+    let mut other = store
+        .create_user(&UserData {
+            id: None,
+            name: "Other".to_string(),
+            username: "other".to_string(),
+            password: crate::password::hash("password").unwrap(),
+            disabled: false,
+        })
+        .await
+        .unwrap();
+    assert!(other.id.is_some());
+    assert_ne!(other.id, root.id);
+    assert!(store.create_user(&other).await.is_err());
+    other.name = "Renamed".to_string();
+    other.disabled = true;
+    store.update_user(&other).await.unwrap();
+    let updated = store.user_by_username("other").await.unwrap().unwrap();
+    assert_eq!(updated.name, "Renamed");
+    assert!(updated.disabled);
+    let other_id = other.id.clone().unwrap();
+
+    let mut van = store
+        .create_device(&DeviceData {
+            id: None,
+            serial: "356938035643809".to_string(),
+            name: "Van".to_string(),
+            kind: crate::device::DEFAULT_KIND.to_string(),
+            owner: other_id.clone(),
+            protocol: crate::device::DEFAULT_PROTOCOL.to_string(),
+            credentials: crate::password::hash("vankey").unwrap(),
+            client_id: tracker.client_id + 1,
+            disabled: false,
+        })
+        .await
+        .unwrap();
+    assert!(van.id.is_some());
+    assert_ne!(van.id, tracker.id);
+    let mut taken = van.clone();
+    taken.serial = "other serial".to_string();
+    taken.client_id = tracker.client_id;
+    assert!(store.create_device(&taken).await.is_err());
+    assert_eq!(store.devices(None).await.unwrap().len(), 2);
+    assert_eq!(store.devices(Some(&other_id)).await.unwrap().len(), 1);
+    let van_id = van.id.clone().unwrap();
+    store
+        .insert_positions(&[CoordinatesData {
+            id: None,
+            device: van_id.clone(),
+            ..rows[0].clone()
+        }])
+        .await
+        .unwrap();
+    let world = BoundingBox {
+        south: -90.0,
+        west: -180.0,
+        north: 90.0,
+        east: 180.0,
+    };
+    let owned = store
+        .area(
+            world,
+            Some(&other_id),
+            Datetime::from(start - Duration::minutes(1)),
+            Datetime::from(start + Duration::minutes(5)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(owned.len(), 1);
+    assert_eq!(owned[0].device, van_id);
+    let owned = store
+        .area(
+            world,
+            Some(&root_id),
+            Datetime::from(start - Duration::minutes(1)),
+            Datetime::from(start + Duration::minutes(5)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(owned.len(), 1);
+    assert_eq!(owned[0].device, tracker_id);
+    van.name = "Truck".to_string();
+    van.owner = root_id.clone();
+    van.disabled = true;
+    store.update_device(&van).await.unwrap();
+    let updated = store.device_by_serial(&van.serial).await.unwrap().unwrap();
+    assert_eq!(updated.name, "Truck");
+    assert_eq!(updated.owner, root_id);
+    assert!(updated.disabled);
+    van.client_id = tracker.client_id;
+    assert!(store.update_device(&van).await.is_err());
+    store
+        .create_session(&new_session(&van_id, start))
+        .await
+        .unwrap();
+    store.delete_device(&van_id).await.unwrap();
+    assert!(store.device_by_serial(&van.serial).await.unwrap().is_none());
+    assert!(store.sessions(&van_id).await.unwrap().is_empty());
+    // Only the van's position went with it.
+    let left = store
+        .area(
+            world,
+            None,
+            Datetime::from(start - Duration::minutes(1)),
+            Datetime::from(start + Duration::minutes(5)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(left.len(), 1);
+    assert_eq!(left[0].device, tracker_id);
+
+    store.delete_user(&root_id).await.unwrap();
+    assert!(store.sessions(&tracker_id).await.unwrap().is_empty());
+    assert!(store
+        .user_by_username(&root.username)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(store
+        .device_by_serial(&tracker.serial)
+        .await
+        .unwrap()
+        .is_none());
+    let left = store
+        .area(
+            world,
+            None,
+            Datetime::from(start - Duration::minutes(1)),
+            Datetime::from(start + Duration::minutes(5)),
+        )
+        .await
+        .unwrap();
+    assert!(left.is_empty());
+    assert_eq!(store.users().await.unwrap().len(), 1);
+}
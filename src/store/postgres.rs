@@ -0,0 +1,1018 @@
+use crate::actions::{CoordinatesData, HeartbeatData};
+use crate::config::DatabaseConfig;
+use crate::device::DeviceData;
+use crate::password;
+use crate::session::{DeviceSession, SessionData, SessionEvent};
+use crate::store::{poll, record_key, BoundingBox, Store};
+use crate::user::UserData;
+use async_trait::async_trait;
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use futures::stream::BoxStream;
+use std::collections::{BTreeSet, HashSet};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use surrealdb::sql::Datetime;
+use surrealdb::RecordId;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, NoTls, Row};
+use tracing::warn;
+
+/// Positions are partitioned by day; `coordinates_pYYYYMMDD` partitions are
+/// created on the first insert of their day.
+///
+/// Databases whose users carried the client id get one device per user, with
+/// the same id, and their positions and heartbeats are pointed at it.
+const SCHEMA: &str = r#"
+    CREATE EXTENSION IF NOT EXISTS postgis;
+    CREATE TABLE IF NOT EXISTS users (
+        id BIGSERIAL PRIMARY KEY,
+        name TEXT NOT NULL,
+        username TEXT NOT NULL UNIQUE,
+        password TEXT NOT NULL,
+        disabled BOOLEAN NOT NULL DEFAULT FALSE
+    );
+    ALTER TABLE users ADD COLUMN IF NOT EXISTS disabled BOOLEAN NOT NULL DEFAULT FALSE;
+    CREATE TABLE IF NOT EXISTS devices (
+        id BIGSERIAL PRIMARY KEY,
+        serial TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        owner_id BIGINT NOT NULL REFERENCES users (id),
+        protocol TEXT NOT NULL,
+        credentials TEXT NOT NULL,
+        client_id BIGINT NOT NULL UNIQUE,
+        disabled BOOLEAN NOT NULL DEFAULT FALSE
+    );
+    CREATE INDEX IF NOT EXISTS devices_owner ON devices (owner_id);
+    DO $$
+    BEGIN
+        IF EXISTS (
+            SELECT 1 FROM information_schema.columns
+            WHERE table_schema = current_schema()
+                AND table_name = 'users' AND column_name = 'client_id'
+        ) THEN
+            INSERT INTO devices (id, serial, name, kind, owner_id, protocol, credentials, client_id)
+                SELECT id, username, name, 'tracker', id, 'udp', password, client_id FROM users;
+            PERFORM setval(
+                pg_get_serial_sequence('devices', 'id'),
+                (SELECT COALESCE(MAX(id), 0) + 1 FROM devices),
+                false
+            );
+            ALTER TABLE coordinates DROP CONSTRAINT coordinates_user_id_fkey;
+            ALTER TABLE coordinates RENAME COLUMN user_id TO device_id;
+            ALTER TABLE coordinates ADD FOREIGN KEY (device_id) REFERENCES devices (id);
+            ALTER INDEX coordinates_user_timestamp RENAME TO coordinates_device_timestamp;
+            ALTER TABLE heartbeat DROP CONSTRAINT heartbeat_user_id_fkey;
+            ALTER TABLE heartbeat RENAME COLUMN user_id TO device_id;
+            ALTER TABLE heartbeat ADD FOREIGN KEY (device_id) REFERENCES devices (id);
+            ALTER INDEX heartbeat_user_timestamp RENAME TO heartbeat_device_timestamp;
+            ALTER TABLE users DROP COLUMN client_id;
+        END IF;
+    END
+    $$;
+    CREATE TABLE IF NOT EXISTS coordinates (
+        seq BIGSERIAL,
+        id TEXT,
+        device_id BIGINT NOT NULL REFERENCES devices (id),
+        position geography(Point, 4326) NOT NULL,
+        timestamp TIMESTAMPTZ NOT NULL,
+        UNIQUE (id, timestamp)
+    ) PARTITION BY RANGE (timestamp);
+    CREATE INDEX IF NOT EXISTS coordinates_device_timestamp ON coordinates (device_id, timestamp);
+    CREATE INDEX IF NOT EXISTS coordinates_position ON coordinates USING GIST (position);
+    CREATE INDEX IF NOT EXISTS coordinates_seq ON coordinates (seq);
+    CREATE TABLE IF NOT EXISTS heartbeat (
+        seq BIGSERIAL PRIMARY KEY,
+        id TEXT UNIQUE,
+        device_id BIGINT NOT NULL REFERENCES devices (id),
+        source_address TEXT NOT NULL,
+        timestamp TIMESTAMPTZ NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS heartbeat_device_timestamp ON heartbeat (device_id, timestamp);
+    CREATE TABLE IF NOT EXISTS sessions (
+        id BIGSERIAL PRIMARY KEY,
+        device_id BIGINT NOT NULL REFERENCES devices (id),
+        source_address TEXT NOT NULL,
+        login TIMESTAMPTZ NOT NULL,
+        logout TIMESTAMPTZ,
+        positions BIGINT NOT NULL DEFAULT 0,
+        heartbeats BIGINT NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS sessions_device_login ON sessions (device_id, login);
+    CREATE TABLE IF NOT EXISTS device_status (
+        client_id BIGINT PRIMARY KEY,
+        session JSONB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS device_events (
+        seq BIGSERIAL PRIMARY KEY,
+        event JSONB NOT NULL
+    );
+"#;
+
+const POSITION_FIELDS: &str = "SELECT c.seq, c.id, c.device_id, \
+     ST_Y(c.position::geometry), ST_X(c.position::geometry), c.timestamp FROM coordinates c";
+
+const USER_FIELDS: &str = "SELECT id, name, username, password, disabled FROM users";
+
+const DEVICE_FIELDS: &str = "SELECT id, serial, name, kind, owner_id, protocol, credentials, \
+     client_id, disabled FROM devices";
+
+const SESSION_FIELDS: &str = "SELECT id, device_id, source_address, login, logout, positions, \
+     heartbeats FROM sessions";
+
+/// How often watchers look for rows written by this or another process.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Store on PostgreSQL with PostGIS, for fleets large enough to want spatial
+/// SQL over their positions. Record ids keep their SurrealDB form, e.g.
+/// `users:1`.
+#[derive(Debug, Clone)]
+pub struct PostgresStore {
+    pool: Arc<Vec<RwLock<Arc<Client>>>>,
+    next: Arc<AtomicUsize>,
+    config: tokio_postgres::Config,
+    /// Days whose partition is known to exist.
+    partitions: Arc<Mutex<HashSet<NaiveDate>>>,
+}
+
+impl PostgresStore {
+    /// Opens `pool_size` connections and creates the tables.
+    pub async fn connect(db_config: &DatabaseConfig) -> Result<Self, String> {
+        let config = Self::client_config(db_config)?;
+        let mut pool = Vec::new();
+        for _ in 0..db_config.pool_size.max(1) {
+            let client = Self::connect_with_backoff(&config, db_config).await?;
+            pool.push(RwLock::new(Arc::new(client)));
+        }
+        let store = Self {
+            pool: Arc::new(pool),
+            next: Arc::new(AtomicUsize::new(0)),
+            config,
+            partitions: Arc::new(Mutex::new(HashSet::new())),
+        };
+        store
+            .client()
+            .await?
+            .batch_execute(SCHEMA)
+            .await
+            .map_err(|error| format!("postgres error: {:?}", error))?;
+        store.rehash("users", "password").await?;
+        store.rehash("devices", "credentials").await?;
+        Ok(store)
+    }
+
+    /// Replaces the plaintext passwords or device keys of rows added by hand
+    /// or by older versions with their hash.
+    async fn rehash(&self, table: &str, column: &str) -> Result<(), String> {
+        let rows = self
+            .query(
+                &format!(
+                    "SELECT id, {} FROM {} WHERE {} NOT LIKE '$argon2%'",
+                    column, table, column
+                ),
+                &[],
+            )
+            .await?;
+        for row in rows {
+            let hash = password::hash(row.get::<_, &str>(1))?;
+            self.query(
+                &format!("UPDATE {} SET {} = $1 WHERE id = $2", table, column),
+                &[&hash, &row.get::<_, i64>(0)],
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    fn client_config(db_config: &DatabaseConfig) -> Result<tokio_postgres::Config, String> {
+        let (host, port) = match db_config.host.rsplit_once(':') {
+            Some((host, port)) => match port.parse::<u16>() {
+                Ok(port) => (host.trim_start_matches('[').trim_end_matches(']'), port),
+                Err(_) => {
+                    return Err(format!(
+                        "postgres error: invalid port in {}",
+                        db_config.host
+                    ))
+                }
+            },
+            None => {
+                return Err(format!(
+                    "postgres error: expected host:port, got {}",
+                    db_config.host
+                ))
+            }
+        };
+        let mut config = tokio_postgres::Config::new();
+        config
+            .host(host)
+            .port(port)
+            .user(&db_config.username)
+            .password(&db_config.password)
+            .dbname(&db_config.database)
+            .application_name("gps-tracker");
+        Ok(config)
+    }
+
+    async fn open(config: &tokio_postgres::Config) -> Result<Client, String> {
+        let (client, connection) = config
+            .connect(NoTls)
+            .await
+            .map_err(|error| format!("postgres error: {:?}", error))?;
+        tokio::spawn(async move {
+            if let Err(error) = connection.await {
+                warn!(%error, "postgres connection closed");
+            }
+        });
+        Ok(client)
+    }
+
+    /// Retries `open` with exponential backoff up to `connect_attempts` times.
+    async fn connect_with_backoff(
+        config: &tokio_postgres::Config,
+        db_config: &DatabaseConfig,
+    ) -> Result<Client, String> {
+        let mut backoff = Duration::from_millis(db_config.reconnect_initial_backoff_ms);
+        let max_backoff = Duration::from_millis(db_config.reconnect_max_backoff_ms);
+        let mut attempt: u32 = 1;
+        loop {
+            match Self::open(config).await {
+                Ok(client) => return Ok(client),
+                Err(error) => {
+                    if attempt >= db_config.connect_attempts {
+                        return Err(error);
+                    }
+                    warn!(attempt, ?backoff, %error, "postgres connection failed, retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Returns a connection from the pool in round-robin order, replacing it
+    /// first if it was closed.
+    async fn client(&self) -> Result<Arc<Client>, String> {
+        let slot = &self.pool[self.next.fetch_add(1, Ordering::Relaxed) % self.pool.len()];
+        let client = match slot.read() {
+            Ok(client) => client.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        if !client.is_closed() {
+            return Ok(client);
+        }
+        let client = Arc::new(Self::open(&self.config).await?);
+        match slot.write() {
+            Ok(mut current) => *current = client.clone(),
+            Err(poisoned) => *poisoned.into_inner() = client.clone(),
+        }
+        Ok(client)
+    }
+
+    async fn query(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, String> {
+        self.client()
+            .await?
+            .query(query, params)
+            .await
+            .map_err(|error| format!("postgres error: {:?}", error))
+    }
+
+    async fn query_one(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Row, String> {
+        self.client()
+            .await?
+            .query_one(query, params)
+            .await
+            .map_err(|error| format!("postgres error: {:?}", error))
+    }
+
+    /// Runs `query` and returns the number of rows it changed.
+    async fn execute(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, String> {
+        self.client()
+            .await?
+            .execute(query, params)
+            .await
+            .map_err(|error| format!("postgres error: {:?}", error))
+    }
+
+    async fn user(&self, condition: &str, value: &(dyn ToSql + Sync)) -> Result<UserData, String> {
+        let rows = self
+            .query(&format!("{} WHERE {}", USER_FIELDS, condition), &[value])
+            .await?;
+        match rows.first() {
+            Some(row) => read_user(row),
+            None => Err("user not found".to_string()),
+        }
+    }
+
+    async fn device(
+        &self,
+        condition: &str,
+        value: &(dyn ToSql + Sync),
+    ) -> Result<Option<DeviceData>, String> {
+        let rows = self
+            .query(&format!("{} WHERE {}", DEVICE_FIELDS, condition), &[value])
+            .await?;
+        rows.first().map(read_device).transpose()
+    }
+
+    async fn positions(
+        &self,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<CoordinatesData>, String> {
+        self.query(query, params)
+            .await?
+            .iter()
+            .map(|row| read_position(row).map(|(_, position)| position))
+            .collect()
+    }
+
+    /// Creates the daily partitions `rows` fall into.
+    async fn create_partitions(&self, rows: &[CoordinatesData]) -> Result<(), String> {
+        let days: BTreeSet<NaiveDate> = {
+            let known = match self.partitions.lock() {
+                Ok(known) => known,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            rows.iter()
+                .map(|row| row.timestamp.date_naive())
+                .filter(|day| !known.contains(day))
+                .collect()
+        };
+        for day in days {
+            let next = day + Days::new(1);
+            let result = self
+                .client()
+                .await?
+                .batch_execute(&format!(
+                    "CREATE TABLE IF NOT EXISTS coordinates_p{} PARTITION OF coordinates \
+                     FOR VALUES FROM ('{} 00:00:00+00') TO ('{} 00:00:00+00')",
+                    day.format("%Y%m%d"),
+                    day,
+                    next
+                ))
+                .await;
+            match result {
+                Ok(_) => {}
+                // Another connection created it first.
+                Err(error)
+                    if error.code() == Some(&SqlState::DUPLICATE_TABLE)
+                        || error.code() == Some(&SqlState::UNIQUE_VIOLATION) => {}
+                Err(error) => return Err(format!("postgres error: {:?}", error)),
+            }
+            match self.partitions.lock() {
+                Ok(mut known) => known.insert(day),
+                Err(poisoned) => poisoned.into_inner().insert(day),
+            };
+        }
+        Ok(())
+    }
+
+    /// Streams the rows `query` returns after the highest `seq` of `table`
+    /// at the time of the call.
+    async fn watch<T: Send + 'static>(
+        &self,
+        table: &'static str,
+        query: &'static str,
+        read: fn(&Row) -> Result<(i64, T), String>,
+    ) -> Result<BoxStream<'static, T>, String> {
+        let last: i64 = self
+            .query_one(&format!("SELECT COALESCE(MAX(seq), 0) FROM {}", table), &[])
+            .await?
+            .get(0);
+        let store = self.clone();
+        Ok(poll(WATCH_INTERVAL, last, move |last| {
+            let store = store.clone();
+            async move {
+                store
+                    .query(query, &[&last])
+                    .await?
+                    .iter()
+                    .map(read)
+                    .collect()
+            }
+        }))
+    }
+}
+
+fn user_id(id: i64) -> RecordId {
+    RecordId::from_table_key("users", id)
+}
+
+fn device_id(id: i64) -> RecordId {
+    RecordId::from_table_key("devices", id)
+}
+
+fn key(id: &RecordId, table: &str) -> Result<i64, String> {
+    record_key(id, table).map_err(|error| format!("postgres error: {}", error))
+}
+
+fn read_user(row: &Row) -> Result<UserData, String> {
+    Ok(UserData {
+        id: Some(user_id(row.get(0))),
+        name: row.get(1),
+        username: row.get(2),
+        password: row.get(3),
+        disabled: row.get(4),
+    })
+}
+
+fn read_device(row: &Row) -> Result<DeviceData, String> {
+    let client_id: i64 = row.get(7);
+    Ok(DeviceData {
+        id: Some(device_id(row.get(0))),
+        serial: row.get(1),
+        name: row.get(2),
+        kind: row.get(3),
+        owner: user_id(row.get(4)),
+        protocol: row.get(5),
+        credentials: row.get(6),
+        client_id: u32::try_from(client_id)
+            .map_err(|error| format!("postgres error: {:?}", error))?,
+        disabled: row.get(8),
+    })
+}
+
+/// Reads a `POSITION_FIELDS` row; rows inserted without an id get one made
+/// from `seq`.
+fn read_position(row: &Row) -> Result<(i64, CoordinatesData), String> {
+    let seq: i64 = row.get(0);
+    let id = match row.get::<_, Option<String>>(1) {
+        Some(id) => {
+            RecordId::from_str(&id).map_err(|error| format!("postgres error: {:?}", error))?
+        }
+        None => RecordId::from_table_key("coordinates", seq),
+    };
+    let timestamp: DateTime<Utc> = row.get(5);
+    Ok((
+        seq,
+        CoordinatesData {
+            id: Some(id),
+            device: device_id(row.get(2)),
+            latitude: row.get(3),
+            longitude: row.get(4),
+            timestamp: Datetime::from(timestamp),
+        },
+    ))
+}
+
+fn read_session(row: &Row) -> Result<SessionData, String> {
+    let login: DateTime<Utc> = row.get(3);
+    let logout: Option<DateTime<Utc>> = row.get(4);
+    Ok(SessionData {
+        id: Some(RecordId::from_table_key("sessions", row.get::<_, i64>(0))),
+        device: device_id(row.get(1)),
+        source_address: row.get(2),
+        login: Datetime::from(login),
+        logout: logout.map(Datetime::from),
+        positions: row.get::<_, i64>(5) as u64,
+        heartbeats: row.get::<_, i64>(6) as u64,
+    })
+}
+
+fn read_event(row: &Row) -> Result<(i64, SessionEvent), String> {
+    let event: serde_json::Value = row.get(1);
+    match serde_json::from_value(event) {
+        Ok(event) => Ok((row.get(0), event)),
+        Err(error) => Err(format!("postgres error: {:?}", error)),
+    }
+}
+
+fn to_json(value: &impl serde::Serialize) -> Result<serde_json::Value, String> {
+    serde_json::to_value(value).map_err(|error| format!("postgres error: {:?}", error))
+}
+
+fn device_keys<'a>(devices: impl Iterator<Item = &'a RecordId>) -> Result<Vec<i64>, String> {
+    devices.map(|device| key(device, "devices")).collect()
+}
+
+#[async_trait]
+impl Store for PostgresStore {
+    async fn is_available(&self) -> bool {
+        matches!(
+            tokio::time::timeout(Duration::from_secs(1), self.query("SELECT 1", &[])).await,
+            Ok(Ok(_))
+        )
+    }
+
+    async fn users(&self) -> Result<Vec<UserData>, String> {
+        self.query(&format!("{} ORDER BY id", USER_FIELDS), &[])
+            .await?
+            .iter()
+            .map(read_user)
+            .collect()
+    }
+
+    async fn user_by_id(&self, id: &RecordId) -> Result<UserData, String> {
+        self.user("id = $1", &key(id, "users")?).await
+    }
+
+    async fn user_by_username(&self, username: &str) -> Result<Option<UserData>, String> {
+        let rows = self
+            .query(
+                &format!("{} WHERE username = $1", USER_FIELDS),
+                &[&username],
+            )
+            .await?;
+        rows.first().map(read_user).transpose()
+    }
+
+    async fn create_user(&self, user: &UserData) -> Result<UserData, String> {
+        let row = self
+            .query_one(
+                "INSERT INTO users (name, username, password, disabled) \
+                 VALUES ($1, $2, $3, $4) RETURNING id",
+                &[&user.name, &user.username, &user.password, &user.disabled],
+            )
+            .await?;
+        let mut user = user.clone();
+        user.id = Some(user_id(row.get(0)));
+        Ok(user)
+    }
+
+    async fn update_user(&self, user: &UserData) -> Result<(), String> {
+        let key = match &user.id {
+            Some(id) => key(id, "users")?,
+            None => return Err("postgres error: user has no id".to_string()),
+        };
+        let updated = self
+            .client()
+            .await?
+            .execute(
+                "UPDATE users SET name = $1, password = $2, disabled = $3 WHERE id = $4",
+                &[&user.name, &user.password, &user.disabled, &key],
+            )
+            .await
+            .map_err(|error| format!("postgres error: {:?}", error))?;
+        match updated {
+            0 => Err("user not found".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn delete_user(&self, id: &RecordId) -> Result<(), String> {
+        let key = key(id, "users")?;
+        self.query(
+            "WITH owned AS (SELECT id FROM devices WHERE owner_id = $1), \
+             positions AS (DELETE FROM coordinates WHERE device_id IN (SELECT id FROM owned)), \
+             heartbeats AS (DELETE FROM heartbeat WHERE device_id IN (SELECT id FROM owned)), \
+             sessions AS (DELETE FROM sessions WHERE device_id IN (SELECT id FROM owned)), \
+             devices AS (DELETE FROM devices WHERE owner_id = $1) \
+             DELETE FROM users WHERE id = $1",
+            &[&key],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn devices(&self, owner: Option<&RecordId>) -> Result<Vec<DeviceData>, String> {
+        let owner = match owner {
+            Some(owner) => Some(key(owner, "users")?),
+            None => None,
+        };
+        self.query(
+            &format!(
+                "{} WHERE $1::bigint IS NULL OR owner_id = $1 ORDER BY client_id",
+                DEVICE_FIELDS
+            ),
+            &[&owner],
+        )
+        .await?
+        .iter()
+        .map(read_device)
+        .collect()
+    }
+
+    async fn device_by_id(&self, id: &RecordId) -> Result<DeviceData, String> {
+        self.device("id = $1", &key(id, "devices")?)
+            .await?
+            .ok_or("device not found".to_string())
+    }
+
+    async fn device_by_client_id(&self, client_id: u32) -> Result<DeviceData, String> {
+        self.device("client_id = $1", &i64::from(client_id))
+            .await?
+            .ok_or("device not found".to_string())
+    }
+
+    async fn device_by_serial(&self, serial: &str) -> Result<Option<DeviceData>, String> {
+        self.device("serial = $1", &serial).await
+    }
+
+    async fn create_device(&self, device: &DeviceData) -> Result<DeviceData, String> {
+        let row = self
+            .query_one(
+                "INSERT INTO devices \
+                 (serial, name, kind, owner_id, protocol, credentials, client_id, disabled) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+                &[
+                    &device.serial,
+                    &device.name,
+                    &device.kind,
+                    &key(&device.owner, "users")?,
+                    &device.protocol,
+                    &device.credentials,
+                    &i64::from(device.client_id),
+                    &device.disabled,
+                ],
+            )
+            .await?;
+        let mut device = device.clone();
+        device.id = Some(device_id(row.get(0)));
+        Ok(device)
+    }
+
+    async fn update_device(&self, device: &DeviceData) -> Result<(), String> {
+        let id = match &device.id {
+            Some(id) => key(id, "devices")?,
+            None => return Err("postgres error: device has no id".to_string()),
+        };
+        let updated = self
+            .client()
+            .await?
+            .execute(
+                "UPDATE devices SET name = $1, kind = $2, owner_id = $3, protocol = $4, \
+                 credentials = $5, client_id = $6, disabled = $7 WHERE id = $8",
+                &[
+                    &device.name,
+                    &device.kind,
+                    &key(&device.owner, "users")?,
+                    &device.protocol,
+                    &device.credentials,
+                    &i64::from(device.client_id),
+                    &device.disabled,
+                    &id,
+                ],
+            )
+            .await
+            .map_err(|error| format!("postgres error: {:?}", error))?;
+        match updated {
+            0 => Err("device not found".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn delete_device(&self, id: &RecordId) -> Result<(), String> {
+        let key = key(id, "devices")?;
+        self.query(
+            "WITH positions AS (DELETE FROM coordinates WHERE device_id = $1), \
+             heartbeats AS (DELETE FROM heartbeat WHERE device_id = $1), \
+             sessions AS (DELETE FROM sessions WHERE device_id = $1) \
+             DELETE FROM devices WHERE id = $1",
+            &[&key],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn insert_positions(&self, rows: &[CoordinatesData]) -> Result<(), String> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        self.create_partitions(rows).await?;
+        let ids: Vec<Option<String>> = rows
+            .iter()
+            .map(|row| row.id.as_ref().map(|id| id.to_string()))
+            .collect();
+        let devices = device_keys(rows.iter().map(|row| &row.device))?;
+        let latitudes: Vec<f64> = rows.iter().map(|row| row.latitude).collect();
+        let longitudes: Vec<f64> = rows.iter().map(|row| row.longitude).collect();
+        let timestamps: Vec<DateTime<Utc>> = rows.iter().map(|row| *row.timestamp).collect();
+        self.query(
+            "INSERT INTO coordinates (id, device_id, position, timestamp) \
+             SELECT r.id, r.device_id, \
+                 ST_SetSRID(ST_MakePoint(r.longitude, r.latitude), 4326)::geography, r.timestamp \
+             FROM unnest($1::text[], $2::bigint[], $3::float8[], $4::float8[], $5::timestamptz[]) \
+                 AS r (id, device_id, latitude, longitude, timestamp) \
+             ON CONFLICT DO NOTHING",
+            &[&ids, &devices, &latitudes, &longitudes, &timestamps],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn insert_heartbeats(&self, rows: &[HeartbeatData]) -> Result<(), String> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<Option<String>> = rows
+            .iter()
+            .map(|row| row.id.as_ref().map(|id| id.to_string()))
+            .collect();
+        let devices = device_keys(rows.iter().map(|row| &row.device))?;
+        let addresses: Vec<&str> = rows.iter().map(|row| row.source_address.as_str()).collect();
+        let timestamps: Vec<DateTime<Utc>> = rows.iter().map(|row| *row.timestamp).collect();
+        self.query(
+            "INSERT INTO heartbeat (id, device_id, source_address, timestamp) \
+             SELECT * FROM unnest($1::text[], $2::bigint[], $3::text[], $4::timestamptz[]) \
+             ON CONFLICT DO NOTHING",
+            &[&ids, &devices, &addresses, &timestamps],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn track(
+        &self,
+        client_id: u32,
+        from: Datetime,
+        to: Datetime,
+    ) -> Result<Vec<CoordinatesData>, String> {
+        self.positions(
+            &format!(
+                "{} JOIN devices d ON d.id = c.device_id \
+                 WHERE d.client_id = $1 AND c.timestamp BETWEEN $2 AND $3 \
+                 ORDER BY c.timestamp, c.seq",
+                POSITION_FIELDS
+            ),
+            &[&i64::from(client_id), &*from, &*to],
+        )
+        .await
+    }
+
+    async fn area(
+        &self,
+        area: BoundingBox,
+        owner: Option<&RecordId>,
+        from: Datetime,
+        to: Datetime,
+    ) -> Result<Vec<CoordinatesData>, String> {
+        let owner = match owner {
+            Some(owner) => Some(key(owner, "users")?),
+            None => None,
+        };
+        // `&&` narrows the rows down with the GiST index; the exact bounds
+        // then drop points the envelope's curved edges let through.
+        self.positions(
+            &format!(
+                "{} JOIN devices d ON d.id = c.device_id \
+                 WHERE c.position && ST_MakeEnvelope($1, $2, $3, $4, 4326)::geography \
+                 AND ST_Y(c.position::geometry) BETWEEN $2 AND $4 \
+                 AND ST_X(c.position::geometry) BETWEEN $1 AND $3 \
+                 AND c.timestamp BETWEEN $5 AND $6 \
+                 AND ($7::bigint IS NULL OR d.owner_id = $7) \
+                 ORDER BY c.timestamp, c.seq",
+                POSITION_FIELDS
+            ),
+            &[
+                &area.west,
+                &area.south,
+                &area.east,
+                &area.north,
+                &*from,
+                &*to,
+                &owner,
+            ],
+        )
+        .await
+    }
+
+    async fn delete_device_history(&self, client_id: u32) -> Result<(), String> {
+        self.query(
+            "WITH device AS (SELECT id FROM devices WHERE client_id = $1), \
+             positions AS (DELETE FROM coordinates WHERE device_id IN (SELECT id FROM device)), \
+             heartbeats AS (DELETE FROM heartbeat WHERE device_id IN (SELECT id FROM device)) \
+             DELETE FROM sessions WHERE device_id IN (SELECT id FROM device)",
+            &[&i64::from(client_id)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn prune_positions(
+        &self,
+        device: &RecordId,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String> {
+        let device = key(device, "devices")?;
+        // Partitioned, so rows are only unique by seq and timestamp.
+        self.execute(
+            "DELETE FROM coordinates WHERE (seq, timestamp) IN (\
+             SELECT seq, timestamp FROM coordinates \
+             WHERE device_id = $1 AND timestamp < $2 ORDER BY timestamp LIMIT $3)",
+            &[&device, &*before, &(limit as i64)],
+        )
+        .await
+    }
+
+    async fn downsample_positions(
+        &self,
+        device: &RecordId,
+        from: Datetime,
+        to: Datetime,
+        interval_secs: u64,
+        limit: usize,
+    ) -> Result<u64, String> {
+        let device = key(device, "devices")?;
+        self.execute(
+            "DELETE FROM coordinates WHERE (seq, timestamp) IN (\
+             SELECT seq, timestamp FROM (SELECT seq, timestamp, ROW_NUMBER() OVER (\
+                 PARTITION BY floor(extract(epoch FROM timestamp) / $4::BIGINT) \
+                 ORDER BY timestamp, seq) AS n \
+             FROM coordinates \
+             WHERE device_id = $1 AND timestamp >= $2 AND timestamp < $3) AS window_rows \
+             WHERE n > 1 LIMIT $5)",
+            &[
+                &device,
+                &*from,
+                &*to,
+                &(interval_secs.max(1) as i64),
+                &(limit as i64),
+            ],
+        )
+        .await
+    }
+
+    async fn prune_heartbeats(
+        &self,
+        device: &RecordId,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String> {
+        let device = key(device, "devices")?;
+        self.execute(
+            "DELETE FROM heartbeat WHERE seq IN (SELECT seq FROM heartbeat \
+             WHERE device_id = $1 AND timestamp < $2 ORDER BY timestamp LIMIT $3)",
+            &[&device, &*before, &(limit as i64)],
+        )
+        .await
+    }
+
+    async fn create_session(&self, session: &SessionData) -> Result<SessionData, String> {
+        let device = key(&session.device, "devices")?;
+        let logout: Option<DateTime<Utc>> = session.logout.as_ref().map(|logout| **logout);
+        let row = self
+            .query_one(
+                "INSERT INTO sessions \
+                 (device_id, source_address, login, logout, positions, heartbeats) \
+                 VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+                &[
+                    &device,
+                    &session.source_address,
+                    &*session.login,
+                    &logout,
+                    &(session.positions as i64),
+                    &(session.heartbeats as i64),
+                ],
+            )
+            .await?;
+        let mut session = session.clone();
+        session.id = Some(RecordId::from_table_key("sessions", row.get::<_, i64>(0)));
+        Ok(session)
+    }
+
+    async fn close_session(
+        &self,
+        device: &RecordId,
+        logout: Datetime,
+    ) -> Result<Option<SessionData>, String> {
+        let device = key(device, "devices")?;
+        let rows = self
+            .query(
+                "UPDATE sessions s SET logout = $2, \
+                 positions = (SELECT COUNT(*) FROM coordinates c \
+                     WHERE c.device_id = s.device_id AND c.timestamp BETWEEN s.login AND $2), \
+                 heartbeats = (SELECT COUNT(*) FROM heartbeat h \
+                     WHERE h.device_id = s.device_id AND h.timestamp BETWEEN s.login AND $2) \
+                 WHERE s.device_id = $1 AND s.logout IS NULL \
+                 RETURNING s.id, s.device_id, s.source_address, s.login, s.logout, \
+                     s.positions, s.heartbeats",
+                &[&device, &*logout],
+            )
+            .await?;
+        rows.first().map(read_session).transpose()
+    }
+
+    async fn sessions(&self, device: &RecordId) -> Result<Vec<SessionData>, String> {
+        let device = key(device, "devices")?;
+        self.query(
+            &format!(
+                "{} WHERE device_id = $1 ORDER BY login DESC, id DESC",
+                SESSION_FIELDS
+            ),
+            &[&device],
+        )
+        .await?
+        .iter()
+        .map(read_session)
+        .collect()
+    }
+
+    async fn session_by_id(&self, id: &RecordId) -> Result<SessionData, String> {
+        let id = key(id, "sessions")?;
+        let rows = self
+            .query(&format!("{} WHERE id = $1", SESSION_FIELDS), &[&id])
+            .await?;
+        match rows.first() {
+            Some(row) => read_session(row),
+            None => Err("session not found".to_string()),
+        }
+    }
+
+    async fn save_session_event(&self, event: &SessionEvent) -> Result<(), String> {
+        self.query(
+            "WITH status AS ( \
+                 INSERT INTO device_status (client_id, session) VALUES ($1, $2) \
+                 ON CONFLICT (client_id) DO UPDATE SET session = EXCLUDED.session) \
+             INSERT INTO device_events (event) VALUES ($3)",
+            &[
+                &i64::from(event.session.client_id),
+                &to_json(&event.session)?,
+                &to_json(event)?,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn device_statuses(&self) -> Result<Vec<DeviceSession>, String> {
+        self.query("SELECT session FROM device_status ORDER BY client_id", &[])
+            .await?
+            .iter()
+            .map(|row| {
+                serde_json::from_value(row.get(0))
+                    .map_err(|error| format!("postgres error: {:?}", error))
+            })
+            .collect()
+    }
+
+    async fn watch_positions(&self) -> Result<BoxStream<'static, CoordinatesData>, String> {
+        self.watch(
+            "coordinates",
+            "SELECT c.seq, c.id, c.device_id, \
+                 ST_Y(c.position::geometry), ST_X(c.position::geometry), c.timestamp \
+             FROM coordinates c WHERE c.seq > $1 ORDER BY c.seq",
+            read_position,
+        )
+        .await
+    }
+
+    async fn watch_session_events(&self) -> Result<BoxStream<'static, SessionEvent>, String> {
+        self.watch(
+            "device_events",
+            "SELECT seq, event FROM device_events WHERE seq > $1 ORDER BY seq",
+            read_event,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test_postgres {
+    use super::*;
+    use crate::store::{add_device, add_user, check_store};
+
+    /// Creates a throwaway database on the server at `GPS_TEST_POSTGRES_HOST`
+    /// (127.0.0.1:5432 by default) and returns its config.
+    async fn throwaway_database() -> DatabaseConfig {
+        let mut config = DatabaseConfig {
+            host: std::env::var("GPS_TEST_POSTGRES_HOST").unwrap_or("127.0.0.1:5432".to_string()),
+            username: "postgres".to_string(),
+            password: std::env::var("GPS_TEST_POSTGRES_PASSWORD").unwrap_or("postgres".to_string()),
+            database: "postgres".to_string(),
+            pool_size: 2,
+            connect_attempts: 1,
+            ..DatabaseConfig::default()
+        };
+        let name = format!("gps_test_{}", std::process::id());
+        let admin = PostgresStore::open(&PostgresStore::client_config(&config).unwrap())
+            .await
+            .unwrap();
+        admin
+            .batch_execute(&format!(
+                "DROP DATABASE IF EXISTS {0}; CREATE DATABASE {0}",
+                name
+            ))
+            .await
+            .unwrap();
+        config.database = name;
+        config
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostGIS server, see the README"]
+    async fn test_store() {
+        let config = throwaway_database().await;
+        let store = PostgresStore::connect(&config).await.unwrap();
+        let root = add_user(&store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        let tracker = add_device(&store, &root, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        check_store(Arc::new(store.clone()), &root, &tracker).await;
+
+        let partitions = store
+            .query(
+                "SELECT count(*) FROM pg_inherits WHERE inhparent = 'coordinates'::regclass",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert!(partitions[0].get::<_, i64>(0) >= 1);
+    }
+}
@@ -0,0 +1,1053 @@
+use crate::actions::{CoordinatesData, HeartbeatData};
+use crate::device::DeviceData;
+use crate::password;
+use crate::session::{DeviceSession, SessionData, SessionEvent};
+use crate::store::{poll, record_key, BoundingBox, Store};
+use crate::user::UserData;
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::stream::BoxStream;
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use surrealdb::sql::Datetime;
+use surrealdb::RecordId;
+
+const SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS users (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        username TEXT NOT NULL UNIQUE,
+        password TEXT NOT NULL,
+        disabled INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS devices (
+        id INTEGER PRIMARY KEY,
+        serial TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        owner_id INTEGER NOT NULL REFERENCES users (id),
+        protocol TEXT NOT NULL,
+        credentials TEXT NOT NULL,
+        client_id INTEGER NOT NULL UNIQUE,
+        disabled INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS devices_owner ON devices (owner_id);
+    CREATE TABLE IF NOT EXISTS coordinates (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        id TEXT UNIQUE,
+        device_id INTEGER NOT NULL REFERENCES devices (id),
+        latitude REAL NOT NULL,
+        longitude REAL NOT NULL,
+        timestamp TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS coordinates_device_timestamp ON coordinates (device_id, timestamp);
+    CREATE INDEX IF NOT EXISTS coordinates_timestamp ON coordinates (timestamp);
+    CREATE TABLE IF NOT EXISTS heartbeat (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        id TEXT UNIQUE,
+        device_id INTEGER NOT NULL REFERENCES devices (id),
+        source_address TEXT NOT NULL,
+        timestamp TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS heartbeat_device_timestamp ON heartbeat (device_id, timestamp);
+    CREATE TABLE IF NOT EXISTS sessions (
+        id INTEGER PRIMARY KEY,
+        device_id INTEGER NOT NULL REFERENCES devices (id),
+        source_address TEXT NOT NULL,
+        login TEXT NOT NULL,
+        logout TEXT,
+        positions INTEGER NOT NULL DEFAULT 0,
+        heartbeats INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS sessions_device_login ON sessions (device_id, login);
+    CREATE TABLE IF NOT EXISTS device_status (
+        client_id INTEGER PRIMARY KEY,
+        session TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS device_events (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        event TEXT NOT NULL
+    );
+"#;
+
+/// Moves aside the tables of databases whose users carried the client id, so
+/// `SCHEMA` can create them anew. Their indexes go first, since they keep
+/// their names when their table is renamed.
+const SET_ASIDE_USERS: &str = r#"
+    DROP INDEX IF EXISTS coordinates_user_timestamp;
+    DROP INDEX IF EXISTS coordinates_timestamp;
+    DROP INDEX IF EXISTS heartbeat_user_timestamp;
+    ALTER TABLE users RENAME TO legacy_users;
+    ALTER TABLE coordinates RENAME TO legacy_coordinates;
+    ALTER TABLE heartbeat RENAME TO legacy_heartbeat;
+"#;
+
+/// Turns every legacy user into a user and a device with the same id, so
+/// positions and heartbeats keep pointing at the same row.
+const COPY_LEGACY_USERS: &str = r#"
+    INSERT INTO users (id, name, username, password, disabled)
+        SELECT id, name, username, password, disabled FROM legacy_users;
+    INSERT INTO devices (id, serial, name, kind, owner_id, protocol, credentials, client_id)
+        SELECT id, username, name, 'tracker', id, 'udp', password, client_id FROM legacy_users;
+    INSERT INTO coordinates (seq, id, device_id, latitude, longitude, timestamp)
+        SELECT seq, id, user_id, latitude, longitude, timestamp FROM legacy_coordinates;
+    INSERT INTO heartbeat (seq, id, device_id, source_address, timestamp)
+        SELECT seq, id, user_id, source_address, timestamp FROM legacy_heartbeat;
+    DROP TABLE legacy_coordinates;
+    DROP TABLE legacy_heartbeat;
+    DROP TABLE legacy_users;
+"#;
+
+const USER_FIELDS: &str = "SELECT id, name, username, password, disabled FROM users";
+
+const DEVICE_FIELDS: &str = "SELECT id, serial, name, kind, owner_id, protocol, credentials, \
+     client_id, disabled FROM devices";
+
+const SESSION_FIELDS: &str = "SELECT id, device_id, source_address, login, logout, positions, \
+     heartbeats FROM sessions";
+
+const POSITION_FIELDS: &str =
+    "SELECT c.seq, c.id, c.device_id, c.latitude, c.longitude, c.timestamp FROM coordinates c";
+
+/// How often watchers look for rows written by this or another process.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Store kept in a single SQLite file, for installations too small to run
+/// SurrealDB. Record ids keep their SurrealDB form, e.g. `users:1`.
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Opens or creates the database at `path` and its tables. `:memory:`
+    /// opens a private database that is dropped with the store.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let connection =
+            Connection::open(path).map_err(|error| format!("sqlite error: {:?}", error))?;
+        connection
+            .busy_timeout(Duration::from_secs(5))
+            .and_then(|_| connection.pragma_update(None, "journal_mode", "WAL"))
+            .map_err(|error| format!("sqlite error: {:?}", error))?;
+        Self::add_devices(&connection)?;
+        connection
+            .pragma_update(None, "foreign_keys", "ON")
+            .and_then(|_| connection.execute_batch(SCHEMA))
+            .map_err(|error| format!("sqlite error: {:?}", error))?;
+        Self::rehash(&connection, "users", "password")?;
+        Self::rehash(&connection, "devices", "credentials")?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    fn has_column(connection: &Connection, table: &str, column: &str) -> Result<bool, String> {
+        connection
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+                [table, column],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|count| count > 0)
+            .map_err(|error| format!("sqlite error: {:?}", error))
+    }
+
+    /// Moves the client id and credentials of the users of older databases
+    /// to one device each. Runs before foreign keys are enforced, since the
+    /// tables are rebuilt.
+    fn add_devices(connection: &Connection) -> Result<(), String> {
+        if !Self::has_column(connection, "users", "client_id")? {
+            return Ok(());
+        }
+        // Databases created before users could be disabled.
+        if !Self::has_column(connection, "users", "disabled")? {
+            connection
+                .execute_batch("ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0")
+                .map_err(|error| format!("sqlite error: {:?}", error))?;
+        }
+        connection
+            .execute_batch(&format!(
+                "BEGIN; {} {} {} COMMIT;",
+                SET_ASIDE_USERS, SCHEMA, COPY_LEGACY_USERS
+            ))
+            .map_err(|error| format!("sqlite error: {:?}", error))
+    }
+
+    /// Replaces the plaintext passwords or device keys of rows added by hand
+    /// or by older versions with their hash.
+    fn rehash(connection: &Connection, table: &str, column: &str) -> Result<(), String> {
+        let plaintext = connection
+            .prepare(&format!(
+                "SELECT id, {} FROM {} WHERE {} NOT LIKE '$argon2%'",
+                column, table, column
+            ))
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .map_err(|error| format!("sqlite error: {:?}", error))?;
+        for (id, plaintext) in plaintext {
+            connection
+                .execute(
+                    &format!("UPDATE {} SET {} = ?1 WHERE id = ?2", table, column),
+                    params![password::hash(&plaintext)?, id],
+                )
+                .map_err(|error| format!("sqlite error: {:?}", error))?;
+        }
+        Ok(())
+    }
+
+    /// Runs `query` on the blocking pool, since rusqlite calls block.
+    async fn call<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T, String> {
+        let connection = self.connection.clone();
+        match tokio::task::spawn_blocking(move || {
+            let mut connection = match connection.lock() {
+                Ok(connection) => connection,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            query(&mut connection)
+        })
+        .await
+        {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(error)) => Err(format!("sqlite error: {:?}", error)),
+            Err(error) => Err(format!("sqlite error: {:?}", error)),
+        }
+    }
+
+    async fn user(
+        &self,
+        condition: &'static str,
+        value: Value,
+    ) -> Result<Option<UserData>, String> {
+        self.call(move |connection| {
+            connection
+                .query_row(
+                    &format!("{} WHERE {}", USER_FIELDS, condition),
+                    [value],
+                    read_user,
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn device(
+        &self,
+        condition: &'static str,
+        value: Value,
+    ) -> Result<Option<DeviceData>, String> {
+        self.call(move |connection| {
+            connection
+                .query_row(
+                    &format!("{} WHERE {}", DEVICE_FIELDS, condition),
+                    [value],
+                    read_device,
+                )
+                .optional()
+        })
+        .await
+    }
+
+    /// Streams the rows of `table` written after the stream was opened,
+    /// polling by `seq` so writes from other processes show up too.
+    async fn watch<T: Send + 'static>(
+        &self,
+        table: &'static str,
+        query: &'static str,
+        read: fn(&Row) -> rusqlite::Result<(i64, T)>,
+    ) -> Result<BoxStream<'static, T>, String> {
+        let last = self
+            .call(move |connection| {
+                connection.query_row(
+                    &format!("SELECT COALESCE(MAX(seq), 0) FROM {}", table),
+                    [],
+                    |row| row.get::<_, i64>(0),
+                )
+            })
+            .await?;
+        let store = self.clone();
+        Ok(poll(WATCH_INTERVAL, last, move |last| {
+            let store = store.clone();
+            async move {
+                store
+                    .call(move |connection| {
+                        let mut statement = connection.prepare_cached(query)?;
+                        let rows = statement.query_map([last], read)?;
+                        rows.collect()
+                    })
+                    .await
+            }
+        }))
+    }
+
+    /// Runs a `POSITION_FIELDS` query whose parameters are `params`.
+    async fn positions(
+        &self,
+        query: String,
+        params: Vec<Value>,
+    ) -> Result<Vec<CoordinatesData>, String> {
+        self.call(move |connection| {
+            let mut statement = connection.prepare_cached(&query)?;
+            let rows = statement.query_map(rusqlite::params_from_iter(params), read_position)?;
+            rows.map(|row| row.map(|(_, position)| position)).collect()
+        })
+        .await
+    }
+
+    /// Deletes up to `limit` rows of `table` stored for `device` before
+    /// `before`, oldest first.
+    async fn prune(
+        &self,
+        device: &RecordId,
+        table: &'static str,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String> {
+        let device = key(device, "devices")?;
+        self.call(move |connection| {
+            connection
+                .execute(
+                    &format!(
+                        "DELETE FROM {0} WHERE seq IN (SELECT seq FROM {0} \
+                         WHERE device_id = ?1 AND timestamp < ?2 \
+                         ORDER BY timestamp LIMIT ?3)",
+                        table
+                    ),
+                    params![device, timestamp_text(&before), limit as i64],
+                )
+                .map(|deleted| deleted as u64)
+        })
+        .await
+    }
+}
+
+fn user_id(id: i64) -> RecordId {
+    RecordId::from_table_key("users", id)
+}
+
+fn device_id(id: i64) -> RecordId {
+    RecordId::from_table_key("devices", id)
+}
+
+fn rowid(id: &RecordId, table: &str) -> rusqlite::Result<i64> {
+    record_key(id, table).map_err(|error| rusqlite::Error::ToSqlConversionFailure(error.into()))
+}
+
+fn key(id: &RecordId, table: &str) -> Result<i64, String> {
+    record_key(id, table).map_err(|error| format!("sqlite error: {}", error))
+}
+
+/// Fixed width, so text order is time order.
+fn timestamp_text(timestamp: &Datetime) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+fn read_timestamp(row: &Row, index: usize) -> rusqlite::Result<Datetime> {
+    let text: String = row.get(index)?;
+    match DateTime::parse_from_rfc3339(&text) {
+        Ok(timestamp) => Ok(Datetime::from(timestamp.with_timezone(&Utc))),
+        Err(error) => Err(rusqlite::Error::FromSqlConversionFailure(
+            index,
+            rusqlite::types::Type::Text,
+            Box::new(error),
+        )),
+    }
+}
+
+/// Stored id, or one made from `seq` for rows inserted without an id.
+fn read_id(row: &Row, index: usize, table: &str) -> rusqlite::Result<RecordId> {
+    match row.get::<_, Option<String>>(index)? {
+        Some(id) => RecordId::from_str(&id).map_err(|error| {
+            rusqlite::Error::FromSqlConversionFailure(
+                index,
+                rusqlite::types::Type::Text,
+                error.to_string().into(),
+            )
+        }),
+        None => Ok(RecordId::from_table_key(table, row.get::<_, i64>(0)?)),
+    }
+}
+
+fn read_user(row: &Row) -> rusqlite::Result<UserData> {
+    Ok(UserData {
+        id: Some(user_id(row.get(0)?)),
+        name: row.get(1)?,
+        username: row.get(2)?,
+        password: row.get(3)?,
+        disabled: row.get(4)?,
+    })
+}
+
+fn read_device(row: &Row) -> rusqlite::Result<DeviceData> {
+    Ok(DeviceData {
+        id: Some(device_id(row.get(0)?)),
+        serial: row.get(1)?,
+        name: row.get(2)?,
+        kind: row.get(3)?,
+        owner: user_id(row.get(4)?),
+        protocol: row.get(5)?,
+        credentials: row.get(6)?,
+        client_id: row.get(7)?,
+        disabled: row.get(8)?,
+    })
+}
+
+/// Reads a `POSITION_FIELDS` row.
+fn read_position(row: &Row) -> rusqlite::Result<(i64, CoordinatesData)> {
+    Ok((
+        row.get(0)?,
+        CoordinatesData {
+            id: Some(read_id(row, 1, "coordinates")?),
+            device: device_id(row.get(2)?),
+            latitude: row.get(3)?,
+            longitude: row.get(4)?,
+            timestamp: read_timestamp(row, 5)?,
+        },
+    ))
+}
+
+fn read_session(row: &Row) -> rusqlite::Result<SessionData> {
+    let logout = match row.get::<_, Option<String>>(4)? {
+        Some(_) => Some(read_timestamp(row, 4)?),
+        None => None,
+    };
+    Ok(SessionData {
+        id: Some(RecordId::from_table_key("sessions", row.get::<_, i64>(0)?)),
+        device: device_id(row.get(1)?),
+        source_address: row.get(2)?,
+        login: read_timestamp(row, 3)?,
+        logout,
+        positions: row.get::<_, i64>(5)? as u64,
+        heartbeats: row.get::<_, i64>(6)? as u64,
+    })
+}
+
+fn read_event(row: &Row) -> rusqlite::Result<(i64, SessionEvent)> {
+    let event: String = row.get(1)?;
+    match serde_json::from_str(&event) {
+        Ok(event) => Ok((row.get(0)?, event)),
+        Err(error) => Err(rusqlite::Error::FromSqlConversionFailure(
+            1,
+            rusqlite::types::Type::Text,
+            Box::new(error),
+        )),
+    }
+}
+
+fn to_json(value: &impl serde::Serialize) -> rusqlite::Result<String> {
+    serde_json::to_string(value)
+        .map_err(|error| rusqlite::Error::ToSqlConversionFailure(Box::new(error)))
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn is_available(&self) -> bool {
+        self.call(|connection| connection.query_row("SELECT 1", [], |_| Ok(())))
+            .await
+            .is_ok()
+    }
+
+    async fn users(&self) -> Result<Vec<UserData>, String> {
+        self.call(|connection| {
+            let mut statement = connection.prepare(&format!("{} ORDER BY id", USER_FIELDS))?;
+            let users = statement.query_map([], read_user)?;
+            users.collect()
+        })
+        .await
+    }
+
+    async fn user_by_id(&self, id: &RecordId) -> Result<UserData, String> {
+        self.user("id = ?1", key(id, "users")?.into())
+            .await?
+            .ok_or("user not found".to_string())
+    }
+
+    async fn user_by_username(&self, username: &str) -> Result<Option<UserData>, String> {
+        self.user("username = ?1", username.to_string().into())
+            .await
+    }
+
+    async fn create_user(&self, user: &UserData) -> Result<UserData, String> {
+        let mut user = user.clone();
+        self.call(move |connection| {
+            connection.execute(
+                "INSERT INTO users (name, username, password, disabled) VALUES (?1, ?2, ?3, ?4)",
+                params![user.name, user.username, user.password, user.disabled],
+            )?;
+            user.id = Some(user_id(connection.last_insert_rowid()));
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn update_user(&self, user: &UserData) -> Result<(), String> {
+        let key = match &user.id {
+            Some(id) => key(id, "users")?,
+            None => return Err("sqlite error: user has no id".to_string()),
+        };
+        let user = user.clone();
+        let updated = self
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE users SET name = ?1, password = ?2, disabled = ?3 WHERE id = ?4",
+                    params![user.name, user.password, user.disabled, key],
+                )
+            })
+            .await?;
+        match updated {
+            0 => Err("user not found".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn delete_user(&self, id: &RecordId) -> Result<(), String> {
+        let key = key(id, "users")?;
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            for table in ["coordinates", "heartbeat", "sessions"] {
+                transaction.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE device_id IN (SELECT id FROM devices WHERE owner_id = ?1)",
+                        table
+                    ),
+                    [key],
+                )?;
+            }
+            transaction.execute("DELETE FROM devices WHERE owner_id = ?1", [key])?;
+            transaction.execute("DELETE FROM users WHERE id = ?1", [key])?;
+            transaction.commit()
+        })
+        .await
+    }
+
+    async fn devices(&self, owner: Option<&RecordId>) -> Result<Vec<DeviceData>, String> {
+        let owner = match owner {
+            Some(owner) => Some(key(owner, "users")?),
+            None => None,
+        };
+        self.call(move |connection| {
+            let mut statement = connection.prepare(&format!(
+                "{} WHERE ?1 IS NULL OR owner_id = ?1 ORDER BY client_id",
+                DEVICE_FIELDS
+            ))?;
+            let devices = statement.query_map([owner], read_device)?;
+            devices.collect()
+        })
+        .await
+    }
+
+    async fn device_by_id(&self, id: &RecordId) -> Result<DeviceData, String> {
+        self.device("id = ?1", key(id, "devices")?.into())
+            .await?
+            .ok_or("device not found".to_string())
+    }
+
+    async fn device_by_client_id(&self, client_id: u32) -> Result<DeviceData, String> {
+        self.device("client_id = ?1", i64::from(client_id).into())
+            .await?
+            .ok_or("device not found".to_string())
+    }
+
+    async fn device_by_serial(&self, serial: &str) -> Result<Option<DeviceData>, String> {
+        self.device("serial = ?1", serial.to_string().into()).await
+    }
+
+    async fn create_device(&self, device: &DeviceData) -> Result<DeviceData, String> {
+        let mut device = device.clone();
+        let owner = key(&device.owner, "users")?;
+        self.call(move |connection| {
+            connection.execute(
+                "INSERT INTO devices \
+                 (serial, name, kind, owner_id, protocol, credentials, client_id, disabled) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    device.serial,
+                    device.name,
+                    device.kind,
+                    owner,
+                    device.protocol,
+                    device.credentials,
+                    device.client_id,
+                    device.disabled
+                ],
+            )?;
+            device.id = Some(device_id(connection.last_insert_rowid()));
+            Ok(device)
+        })
+        .await
+    }
+
+    async fn update_device(&self, device: &DeviceData) -> Result<(), String> {
+        let id = match &device.id {
+            Some(id) => key(id, "devices")?,
+            None => return Err("sqlite error: device has no id".to_string()),
+        };
+        let owner = key(&device.owner, "users")?;
+        let device = device.clone();
+        let updated = self
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE devices SET name = ?1, kind = ?2, owner_id = ?3, protocol = ?4, \
+                     credentials = ?5, client_id = ?6, disabled = ?7 WHERE id = ?8",
+                    params![
+                        device.name,
+                        device.kind,
+                        owner,
+                        device.protocol,
+                        device.credentials,
+                        device.client_id,
+                        device.disabled,
+                        id
+                    ],
+                )
+            })
+            .await?;
+        match updated {
+            0 => Err("device not found".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn delete_device(&self, id: &RecordId) -> Result<(), String> {
+        let key = key(id, "devices")?;
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            for table in ["coordinates", "heartbeat", "sessions"] {
+                transaction.execute(
+                    &format!("DELETE FROM {} WHERE device_id = ?1", table),
+                    [key],
+                )?;
+            }
+            transaction.execute("DELETE FROM devices WHERE id = ?1", [key])?;
+            transaction.commit()
+        })
+        .await
+    }
+
+    async fn insert_positions(&self, rows: &[CoordinatesData]) -> Result<(), String> {
+        let rows = rows.to_vec();
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            {
+                let mut statement = transaction.prepare_cached(
+                    "INSERT OR IGNORE INTO coordinates (id, device_id, latitude, longitude, timestamp) \
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                )?;
+                for row in rows {
+                    statement.execute(params![
+                        row.id.map(|id| id.to_string()),
+                        rowid(&row.device, "devices")?,
+                        row.latitude,
+                        row.longitude,
+                        timestamp_text(&row.timestamp),
+                    ])?;
+                }
+            }
+            transaction.commit()
+        })
+        .await
+    }
+
+    async fn insert_heartbeats(&self, rows: &[HeartbeatData]) -> Result<(), String> {
+        let rows = rows.to_vec();
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            {
+                let mut statement = transaction.prepare_cached(
+                    "INSERT OR IGNORE INTO heartbeat (id, device_id, source_address, timestamp) \
+                     VALUES (?1, ?2, ?3, ?4)",
+                )?;
+                for row in rows {
+                    statement.execute(params![
+                        row.id.map(|id| id.to_string()),
+                        rowid(&row.device, "devices")?,
+                        row.source_address,
+                        timestamp_text(&row.timestamp),
+                    ])?;
+                }
+            }
+            transaction.commit()
+        })
+        .await
+    }
+
+    async fn track(
+        &self,
+        client_id: u32,
+        from: Datetime,
+        to: Datetime,
+    ) -> Result<Vec<CoordinatesData>, String> {
+        self.positions(
+            format!(
+                "{} JOIN devices d ON d.id = c.device_id \
+                 WHERE d.client_id = ?1 AND c.timestamp BETWEEN ?2 AND ?3 \
+                 ORDER BY c.timestamp, c.seq",
+                POSITION_FIELDS
+            ),
+            vec![
+                i64::from(client_id).into(),
+                timestamp_text(&from).into(),
+                timestamp_text(&to).into(),
+            ],
+        )
+        .await
+    }
+
+    async fn area(
+        &self,
+        area: BoundingBox,
+        owner: Option<&RecordId>,
+        from: Datetime,
+        to: Datetime,
+    ) -> Result<Vec<CoordinatesData>, String> {
+        let owner = match owner {
+            Some(owner) => Some(key(owner, "users")?),
+            None => None,
+        };
+        self.positions(
+            format!(
+                "{} JOIN devices d ON d.id = c.device_id \
+                 WHERE c.timestamp BETWEEN ?1 AND ?2 \
+                 AND c.latitude BETWEEN ?3 AND ?4 AND c.longitude BETWEEN ?5 AND ?6 \
+                 AND (?7 IS NULL OR d.owner_id = ?7) \
+                 ORDER BY c.timestamp, c.seq",
+                POSITION_FIELDS
+            ),
+            vec![
+                timestamp_text(&from).into(),
+                timestamp_text(&to).into(),
+                area.south.into(),
+                area.north.into(),
+                area.west.into(),
+                area.east.into(),
+                owner.into(),
+            ],
+        )
+        .await
+    }
+
+    async fn delete_device_history(&self, client_id: u32) -> Result<(), String> {
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            for table in ["coordinates", "heartbeat", "sessions"] {
+                transaction.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE device_id IN (SELECT id FROM devices WHERE client_id = ?1)",
+                        table
+                    ),
+                    [client_id],
+                )?;
+            }
+            transaction.commit()
+        })
+        .await
+    }
+
+    async fn prune_positions(
+        &self,
+        device: &RecordId,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String> {
+        self.prune(device, "coordinates", before, limit).await
+    }
+
+    async fn downsample_positions(
+        &self,
+        device: &RecordId,
+        from: Datetime,
+        to: Datetime,
+        interval_secs: u64,
+        limit: usize,
+    ) -> Result<u64, String> {
+        let device = key(device, "devices")?;
+        self.call(move |connection| {
+            connection
+                .execute(
+                    "DELETE FROM coordinates WHERE seq IN (\
+                     SELECT seq FROM (SELECT seq, ROW_NUMBER() OVER (\
+                         PARTITION BY CAST(strftime('%s', timestamp) AS INTEGER) / ?4 \
+                         ORDER BY timestamp, seq) AS n \
+                     FROM coordinates \
+                     WHERE device_id = ?1 AND timestamp >= ?2 AND timestamp < ?3) \
+                     WHERE n > 1 LIMIT ?5)",
+                    params![
+                        device,
+                        timestamp_text(&from),
+                        timestamp_text(&to),
+                        interval_secs.max(1) as i64,
+                        limit as i64
+                    ],
+                )
+                .map(|deleted| deleted as u64)
+        })
+        .await
+    }
+
+    async fn prune_heartbeats(
+        &self,
+        device: &RecordId,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String> {
+        self.prune(device, "heartbeat", before, limit).await
+    }
+
+    async fn create_session(&self, session: &SessionData) -> Result<SessionData, String> {
+        let mut session = session.clone();
+        let device = key(&session.device, "devices")?;
+        self.call(move |connection| {
+            connection.execute(
+                "INSERT INTO sessions \
+                 (device_id, source_address, login, logout, positions, heartbeats) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    device,
+                    session.source_address,
+                    timestamp_text(&session.login),
+                    session.logout.as_ref().map(timestamp_text),
+                    session.positions as i64,
+                    session.heartbeats as i64
+                ],
+            )?;
+            session.id = Some(RecordId::from_table_key(
+                "sessions",
+                connection.last_insert_rowid(),
+            ));
+            Ok(session)
+        })
+        .await
+    }
+
+    async fn close_session(
+        &self,
+        device: &RecordId,
+        logout: Datetime,
+    ) -> Result<Option<SessionData>, String> {
+        let device = key(device, "devices")?;
+        self.call(move |connection| {
+            connection
+                .query_row(
+                    "UPDATE sessions SET logout = ?2, \
+                     positions = (SELECT COUNT(*) FROM coordinates c \
+                         WHERE c.device_id = sessions.device_id \
+                         AND c.timestamp BETWEEN sessions.login AND ?2), \
+                     heartbeats = (SELECT COUNT(*) FROM heartbeat h \
+                         WHERE h.device_id = sessions.device_id \
+                         AND h.timestamp BETWEEN sessions.login AND ?2) \
+                     WHERE device_id = ?1 AND logout IS NULL \
+                     RETURNING id, device_id, source_address, login, logout, positions, heartbeats",
+                    params![device, timestamp_text(&logout)],
+                    read_session,
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn sessions(&self, device: &RecordId) -> Result<Vec<SessionData>, String> {
+        let device = key(device, "devices")?;
+        self.call(move |connection| {
+            let mut statement = connection.prepare(&format!(
+                "{} WHERE device_id = ?1 ORDER BY login DESC, id DESC",
+                SESSION_FIELDS
+            ))?;
+            let sessions = statement.query_map([device], read_session)?;
+            sessions.collect()
+        })
+        .await
+    }
+
+    async fn session_by_id(&self, id: &RecordId) -> Result<SessionData, String> {
+        let id = key(id, "sessions")?;
+        self.call(move |connection| {
+            connection
+                .query_row(
+                    &format!("{} WHERE id = ?1", SESSION_FIELDS),
+                    [id],
+                    read_session,
+                )
+                .optional()
+        })
+        .await?
+        .ok_or("session not found".to_string())
+    }
+
+    async fn save_session_event(&self, event: &SessionEvent) -> Result<(), String> {
+        let event = event.clone();
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT OR REPLACE INTO device_status (client_id, session) VALUES (?1, ?2)",
+                params![event.session.client_id, to_json(&event.session)?],
+            )?;
+            transaction.execute(
+                "INSERT INTO device_events (event) VALUES (?1)",
+                [to_json(&event)?],
+            )?;
+            transaction.commit()
+        })
+        .await
+    }
+
+    async fn device_statuses(&self) -> Result<Vec<DeviceSession>, String> {
+        let sessions = self
+            .call(|connection| {
+                let mut statement =
+                    connection.prepare("SELECT session FROM device_status ORDER BY client_id")?;
+                let sessions = statement.query_map([], |row| row.get::<_, String>(0))?;
+                sessions.collect::<rusqlite::Result<Vec<String>>>()
+            })
+            .await?;
+        sessions
+            .iter()
+            .map(|session| {
+                serde_json::from_str(session).map_err(|error| format!("sqlite error: {:?}", error))
+            })
+            .collect()
+    }
+
+    async fn watch_positions(&self) -> Result<BoxStream<'static, CoordinatesData>, String> {
+        self.watch(
+            "coordinates",
+            "SELECT c.seq, c.id, c.device_id, c.latitude, c.longitude, c.timestamp \
+             FROM coordinates c WHERE c.seq > ?1 ORDER BY c.seq",
+            read_position,
+        )
+        .await
+    }
+
+    async fn watch_session_events(&self) -> Result<BoxStream<'static, SessionEvent>, String> {
+        self.watch(
+            "device_events",
+            "SELECT seq, event FROM device_events WHERE seq > ?1 ORDER BY seq",
+            read_event,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test_sqlite {
+    use super::*;
+    use crate::store::{add_device, add_user, check_store};
+
+    fn remove(path: &str) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let root = add_user(&store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        assert_eq!(root.id, Some(RecordId::from_table_key("users", 1)));
+        assert!(add_user(&store, "Copy", "root", "x").await.is_err());
+        let tracker = add_device(&store, &root, "root", "notsecurepassword", 24564)
+            .await
+            .unwrap();
+        assert_eq!(tracker.id, Some(RecordId::from_table_key("devices", 1)));
+        check_store(Arc::new(store), &root, &tracker).await;
+    }
+
+    #[tokio::test]
+    async fn test_migrate_legacy_users() {
+        let path =
+            std::env::temp_dir().join(format!("gps-tracker-legacy-{}.sqlite", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        remove(&path);
+        // Tables as created before devices, and before users could be
+        // disabled, holding a plaintext password.
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                r#"
+                CREATE TABLE users (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    username TEXT NOT NULL UNIQUE,
+                    password TEXT NOT NULL,
+                    client_id INTEGER NOT NULL UNIQUE
+                );
+                CREATE TABLE coordinates (
+                    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                    id TEXT UNIQUE,
+                    user_id INTEGER NOT NULL REFERENCES users (id),
+                    latitude REAL NOT NULL,
+                    longitude REAL NOT NULL,
+                    timestamp TEXT NOT NULL
+                );
+                CREATE INDEX coordinates_user_timestamp ON coordinates (user_id, timestamp);
+                CREATE INDEX coordinates_timestamp ON coordinates (timestamp);
+                CREATE TABLE heartbeat (
+                    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                    id TEXT UNIQUE,
+                    user_id INTEGER NOT NULL REFERENCES users (id),
+                    source_address TEXT NOT NULL,
+                    timestamp TEXT NOT NULL
+                );
+                CREATE INDEX heartbeat_user_timestamp ON heartbeat (user_id, timestamp);
+                INSERT INTO users (id, name, username, password, client_id)
+                    VALUES (7, 'Root', 'root', 'notsecurepassword', 24564);
+                INSERT INTO coordinates (id, user_id, latitude, longitude, timestamp)
+                    VALUES ('coordinates:fix', 7, 14.65, 121.04, '2024-01-01T00:00:00.000000000Z');
+                "#,
+            )
+            .unwrap();
+
+        let store = SqliteStore::open(&path).unwrap();
+        let user = store.user_by_username("root").await.unwrap().unwrap();
+        assert_eq!(user.id, Some(RecordId::from_table_key("users", 7)));
+        assert!(password::verify("notsecurepassword", &user.password));
+        let device = store.device_by_client_id(24564).await.unwrap();
+        assert_eq!(device.id, Some(RecordId::from_table_key("devices", 7)));
+        assert_eq!(device.serial, "root");
+        assert_eq!(device.owner, user.id.clone().unwrap());
+        assert!(password::verify("notsecurepassword", &device.credentials));
+        let from = Datetime::from(DateTime::<Utc>::MIN_UTC);
+        let to = Datetime::from(Utc::now());
+        let track = store.track(24564, from, to).await.unwrap();
+        assert_eq!(track.len(), 1);
+        assert_eq!(track[0].device, device.id.clone().unwrap());
+        // Rows written after the migration do not reuse the legacy seq.
+        let mut row = track[0].clone();
+        row.id = None;
+        store.insert_positions(&[row]).await.unwrap();
+        drop(store);
+
+        // Opening the migrated database again leaves it as it is.
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.devices(None).await.unwrap().len(), 1);
+        remove(&path);
+    }
+
+    #[tokio::test]
+    async fn test_reopen_file() {
+        let path = std::env::temp_dir().join(format!("gps-tracker-{}.sqlite", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let store = SqliteStore::open(&path).unwrap();
+        let root = add_user(&store, "Root", "root", "notsecurepassword")
+            .await
+            .unwrap();
+        drop(store);
+
+        let store = SqliteStore::open(&path).unwrap();
+        let user = store.user_by_id(root.id.as_ref().unwrap()).await.unwrap();
+        assert_eq!(user.username, "root");
+        assert_eq!(user.password, root.password);
+        remove(&path);
+    }
+}
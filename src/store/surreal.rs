@@ -0,0 +1,747 @@
+use crate::actions::{CoordinatesData, HeartbeatData};
+use crate::config::DatabaseConfig;
+use crate::db::Db;
+use crate::device::DeviceData;
+use crate::migrations;
+use crate::session::{DeviceSession, SessionData, SessionEvent};
+use crate::store::{thinned, BoundingBox, Store};
+use crate::user::UserData;
+use async_trait::async_trait;
+use futures::stream::{BoxStream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use surrealdb::sql::Datetime;
+use surrealdb::RecordId;
+
+const USER_FIELDS: &str = "SELECT `id`,`name`,`username`,`password`,`disabled` FROM users";
+
+const DEVICE_FIELDS: &str = "SELECT `id`,`serial`,`name`,`kind`,`owner`,`protocol`,\
+     `credentials`,`client_id`,`disabled` FROM devices";
+
+/// Id and timestamp of a position or heartbeat, for retention.
+#[derive(Debug, Deserialize)]
+struct Stamped {
+    id: RecordId,
+    timestamp: Datetime,
+}
+
+/// Store backed by the pooled SurrealDB connections of `Db`.
+#[derive(Debug, Clone)]
+pub struct SurrealStore {
+    db: Db,
+}
+
+impl SurrealStore {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    /// Opens the connection pool described by `config` and brings the schema
+    /// up to date, or checks it is when `migrate_on_start` is off.
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self, String> {
+        let db = Db::connect(config).await?;
+        if config.migrate_on_start {
+            migrations::migrate(&db).await?;
+        } else {
+            migrations::check(&db).await?;
+        }
+        Ok(Self::new(db))
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// Runs a user query expected to match at most one user.
+    async fn user(
+        &self,
+        operation: &str,
+        condition: &str,
+        bindings: impl serde::Serialize + 'static + Send,
+    ) -> Result<Option<UserData>, String> {
+        match self
+            .db
+            .client()
+            .query(format!("{} WHERE {}", USER_FIELDS, condition))
+            .bind(bindings)
+            .await
+        {
+            Ok(mut result) => match result.take::<Option<UserData>>(0) {
+                Ok(record) => Ok(record),
+                Err(error) => Err(format!("user.{} error: {:?}", operation, error)),
+            },
+            Err(error) => Err(format!("user.{} error: {:?}", operation, error)),
+        }
+    }
+
+    /// Runs a device query expected to match at most one device.
+    async fn device(
+        &self,
+        operation: &str,
+        condition: &str,
+        bindings: impl serde::Serialize + 'static + Send,
+    ) -> Result<Option<DeviceData>, String> {
+        match self
+            .db
+            .client()
+            .query(format!("{} WHERE {}", DEVICE_FIELDS, condition))
+            .bind(bindings)
+            .await
+        {
+            Ok(mut result) => match result.take::<Option<DeviceData>>(0) {
+                Ok(record) => Ok(record),
+                Err(error) => Err(format!("device.{} error: {:?}", operation, error)),
+            },
+            Err(error) => Err(format!("device.{} error: {:?}", operation, error)),
+        }
+    }
+
+    async fn insert_ignore<T: serde::Serialize + Clone + 'static>(
+        &self,
+        table: &str,
+        rows: &[T],
+    ) -> Result<(), String> {
+        match self
+            .db
+            .client()
+            .query(format!("INSERT IGNORE INTO {} $rows", table))
+            .bind(("rows", rows.to_vec()))
+            .await
+        {
+            Ok(response) => match response.check() {
+                Ok(_) => Ok(()),
+                Err(error) => Err(format!("{} error: {:?}", table, error)),
+            },
+            Err(error) => Err(format!("{} error: {:?}", table, error)),
+        }
+    }
+
+    /// Rows of `table` stored for `device` from `from`, when given, up to
+    /// `to` exclusive, oldest first.
+    async fn stamped(
+        &self,
+        table: &str,
+        device: &RecordId,
+        from: Option<Datetime>,
+        to: Datetime,
+        limit: Option<usize>,
+    ) -> Result<Vec<Stamped>, String> {
+        let mut query = format!(
+            "SELECT `id`,`timestamp` FROM {} WHERE `device`=$device AND `timestamp` < $to",
+            table
+        );
+        if from.is_some() {
+            query.push_str(" AND `timestamp` >= $from");
+        }
+        query.push_str(" ORDER BY `timestamp`");
+        if let Some(limit) = limit {
+            query.push_str(&format!(" LIMIT {}", limit));
+        }
+        match self
+            .db
+            .client()
+            .query(query)
+            .bind(("device", device.clone()))
+            .bind(("from", from))
+            .bind(("to", to))
+            .await
+        {
+            Ok(mut result) => match result.take::<Vec<Stamped>>(0) {
+                Ok(rows) => Ok(rows),
+                Err(error) => Err(format!("{} error: {:?}", table, error)),
+            },
+            Err(error) => Err(format!("{} error: {:?}", table, error)),
+        }
+    }
+
+    /// Deletes the records `ids` of `table` and returns how many there were.
+    async fn delete_ids(&self, table: &str, ids: Vec<RecordId>) -> Result<u64, String> {
+        let deleted = ids.len() as u64;
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        match self
+            .db
+            .client()
+            .query("DELETE $ids")
+            .bind(("ids", ids))
+            .await
+        {
+            Ok(response) => match response.check() {
+                Ok(_) => Ok(deleted),
+                Err(error) => Err(format!("{} error: {:?}", table, error)),
+            },
+            Err(error) => Err(format!("{} error: {:?}", table, error)),
+        }
+    }
+
+    /// Deletes up to `limit` rows of `table` stored for `device` before
+    /// `before`, oldest first.
+    async fn prune(
+        &self,
+        table: &str,
+        device: &RecordId,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String> {
+        let rows = self
+            .stamped(table, device, None, before, Some(limit))
+            .await?;
+        self.delete_ids(table, rows.into_iter().map(|row| row.id).collect())
+            .await
+    }
+
+    async fn watch<T: DeserializeOwned + Unpin + Send + 'static>(
+        &self,
+        table: &str,
+    ) -> Result<BoxStream<'static, T>, String> {
+        // Dropping the stream kills the live query.
+        match self.db.client().select::<Vec<T>>(table).live().await {
+            Ok(stream) => Ok(stream
+                .filter_map(|item| async move { item.ok().map(|notification| notification.data) })
+                .boxed()),
+            Err(error) => Err(format!("{} error: {:?}", table, error)),
+        }
+    }
+}
+
+#[async_trait]
+impl Store for SurrealStore {
+    async fn is_available(&self) -> bool {
+        self.db.is_reachable().await
+    }
+
+    async fn users(&self) -> Result<Vec<UserData>, String> {
+        match self.db.client().query(USER_FIELDS).await {
+            Ok(mut result) => match result.take::<Vec<UserData>>(0) {
+                Ok(data) => Ok(data),
+                Err(error) => Err(format!("user.get_users error: {:?}", error)),
+            },
+            Err(error) => Err(format!("user.get_users error: {:?}", error)),
+        }
+    }
+
+    async fn user_by_id(&self, id: &RecordId) -> Result<UserData, String> {
+        self.user("get_by_id", "`id`=$id", ("id", id.clone()))
+            .await?
+            .ok_or("user not found".to_string())
+    }
+
+    async fn user_by_username(&self, username: &str) -> Result<Option<UserData>, String> {
+        self.user(
+            "get_by_username",
+            "`username`=$username",
+            ("username", username.to_string()),
+        )
+        .await
+    }
+
+    async fn create_user(&self, user: &UserData) -> Result<UserData, String> {
+        // Bound field by field: `UserData` never serializes its password.
+        match self
+            .db
+            .client()
+            .query(
+                "CREATE users SET `name`=$name, `username`=$username, `password`=$password, \
+                 `disabled`=$disabled",
+            )
+            .bind(("name", user.name.clone()))
+            .bind(("username", user.username.clone()))
+            .bind(("password", user.password.clone()))
+            .bind(("disabled", user.disabled))
+            .await
+        {
+            Ok(mut result) => match result.take::<Option<UserData>>(0) {
+                Ok(Some(user)) => Ok(user),
+                Ok(None) => Err("user.create error: nothing created".to_string()),
+                Err(error) => Err(format!("user.create error: {:?}", error)),
+            },
+            Err(error) => Err(format!("user.create error: {:?}", error)),
+        }
+    }
+
+    async fn update_user(&self, user: &UserData) -> Result<(), String> {
+        let id = match &user.id {
+            Some(id) => id.clone(),
+            None => return Err("user.update error: user has no id".to_string()),
+        };
+        match self
+            .db
+            .client()
+            .query("UPDATE $id SET `name`=$name, `password`=$password, `disabled`=$disabled")
+            .bind(("id", id))
+            .bind(("name", user.name.clone()))
+            .bind(("password", user.password.clone()))
+            .bind(("disabled", user.disabled))
+            .await
+        {
+            Ok(mut result) => match result.take::<Vec<UserData>>(0) {
+                Ok(updated) if updated.is_empty() => Err("user not found".to_string()),
+                Ok(_) => Ok(()),
+                Err(error) => Err(format!("user.update error: {:?}", error)),
+            },
+            Err(error) => Err(format!("user.update error: {:?}", error)),
+        }
+    }
+
+    async fn delete_user(&self, id: &RecordId) -> Result<(), String> {
+        match self
+            .db
+            .client()
+            .query(
+                r#"
+                    BEGIN TRANSACTION;
+                    LET $devices = (SELECT VALUE `id` FROM devices WHERE `owner`=$id);
+                    DELETE FROM coordinates WHERE `device` IN $devices;
+                    DELETE FROM heartbeat WHERE `device` IN $devices;
+                    DELETE FROM sessions WHERE `device` IN $devices;
+                    DELETE FROM devices WHERE `owner`=$id;
+                    DELETE $id;
+                    COMMIT TRANSACTION;
+                "#,
+            )
+            .bind(("id", id.clone()))
+            .await
+        {
+            Ok(response) => match response.check() {
+                Ok(_) => Ok(()),
+                Err(error) => Err(format!("user.delete error: {:?}", error)),
+            },
+            Err(error) => Err(format!("user.delete error: {:?}", error)),
+        }
+    }
+
+    async fn devices(&self, owner: Option<&RecordId>) -> Result<Vec<DeviceData>, String> {
+        let query = match owner {
+            Some(_) => format!(
+                "{} WHERE `owner`=$owner ORDER BY `client_id`",
+                DEVICE_FIELDS
+            ),
+            None => format!("{} ORDER BY `client_id`", DEVICE_FIELDS),
+        };
+        match self
+            .db
+            .client()
+            .query(query)
+            .bind(("owner", owner.cloned()))
+            .await
+        {
+            Ok(mut result) => match result.take::<Vec<DeviceData>>(0) {
+                Ok(data) => Ok(data),
+                Err(error) => Err(format!("device.get_devices error: {:?}", error)),
+            },
+            Err(error) => Err(format!("device.get_devices error: {:?}", error)),
+        }
+    }
+
+    async fn device_by_id(&self, id: &RecordId) -> Result<DeviceData, String> {
+        self.device("get_by_id", "`id`=$id", ("id", id.clone()))
+            .await?
+            .ok_or("device not found".to_string())
+    }
+
+    async fn device_by_client_id(&self, client_id: u32) -> Result<DeviceData, String> {
+        self.device(
+            "get_by_client_id",
+            "`client_id`=$client_id",
+            ("client_id", client_id),
+        )
+        .await?
+        .ok_or("device not found".to_string())
+    }
+
+    async fn device_by_serial(&self, serial: &str) -> Result<Option<DeviceData>, String> {
+        self.device(
+            "get_by_serial",
+            "`serial`=$serial",
+            ("serial", serial.to_string()),
+        )
+        .await
+    }
+
+    async fn create_device(&self, device: &DeviceData) -> Result<DeviceData, String> {
+        // Bound field by field: `DeviceData` never serializes its credentials.
+        match self
+            .db
+            .client()
+            .query(
+                "CREATE devices SET `serial`=$serial, `name`=$name, `kind`=$kind, \
+                 `owner`=$owner, `protocol`=$protocol, `credentials`=$credentials, \
+                 `client_id`=$client_id, `disabled`=$disabled",
+            )
+            .bind(("serial", device.serial.clone()))
+            .bind(("name", device.name.clone()))
+            .bind(("kind", device.kind.clone()))
+            .bind(("owner", device.owner.clone()))
+            .bind(("protocol", device.protocol.clone()))
+            .bind(("credentials", device.credentials.clone()))
+            .bind(("client_id", device.client_id))
+            .bind(("disabled", device.disabled))
+            .await
+        {
+            Ok(mut result) => match result.take::<Option<DeviceData>>(0) {
+                Ok(Some(device)) => Ok(device),
+                Ok(None) => Err("device.create error: nothing created".to_string()),
+                Err(error) => Err(format!("device.create error: {:?}", error)),
+            },
+            Err(error) => Err(format!("device.create error: {:?}", error)),
+        }
+    }
+
+    async fn update_device(&self, device: &DeviceData) -> Result<(), String> {
+        let id = match &device.id {
+            Some(id) => id.clone(),
+            None => return Err("device.update error: device has no id".to_string()),
+        };
+        match self
+            .db
+            .client()
+            .query(
+                "UPDATE $id SET `name`=$name, `kind`=$kind, `owner`=$owner, \
+                 `protocol`=$protocol, `credentials`=$credentials, \
+                 `client_id`=$client_id, `disabled`=$disabled",
+            )
+            .bind(("id", id))
+            .bind(("name", device.name.clone()))
+            .bind(("kind", device.kind.clone()))
+            .bind(("owner", device.owner.clone()))
+            .bind(("protocol", device.protocol.clone()))
+            .bind(("credentials", device.credentials.clone()))
+            .bind(("client_id", device.client_id))
+            .bind(("disabled", device.disabled))
+            .await
+        {
+            Ok(mut result) => match result.take::<Vec<DeviceData>>(0) {
+                Ok(updated) if updated.is_empty() => Err("device not found".to_string()),
+                Ok(_) => Ok(()),
+                Err(error) => Err(format!("device.update error: {:?}", error)),
+            },
+            Err(error) => Err(format!("device.update error: {:?}", error)),
+        }
+    }
+
+    async fn delete_device(&self, id: &RecordId) -> Result<(), String> {
+        match self
+            .db
+            .client()
+            .query(
+                r#"
+                    BEGIN TRANSACTION;
+                    DELETE FROM coordinates WHERE `device`=$id;
+                    DELETE FROM heartbeat WHERE `device`=$id;
+                    DELETE FROM sessions WHERE `device`=$id;
+                    DELETE $id;
+                    COMMIT TRANSACTION;
+                "#,
+            )
+            .bind(("id", id.clone()))
+            .await
+        {
+            Ok(response) => match response.check() {
+                Ok(_) => Ok(()),
+                Err(error) => Err(format!("device.delete error: {:?}", error)),
+            },
+            Err(error) => Err(format!("device.delete error: {:?}", error)),
+        }
+    }
+
+    async fn insert_positions(&self, rows: &[CoordinatesData]) -> Result<(), String> {
+        self.insert_ignore("coordinates", rows).await
+    }
+
+    async fn insert_heartbeats(&self, rows: &[HeartbeatData]) -> Result<(), String> {
+        self.insert_ignore("heartbeat", rows).await
+    }
+
+    async fn track(
+        &self,
+        client_id: u32,
+        from: Datetime,
+        to: Datetime,
+    ) -> Result<Vec<CoordinatesData>, String> {
+        match self
+            .db
+            .client()
+            .query(
+                "SELECT * FROM coordinates WHERE `device`.`client_id`=$client_id \
+                 AND `timestamp` >= $from AND `timestamp` <= $to ORDER BY `timestamp`",
+            )
+            .bind(("client_id", client_id))
+            .bind(("from", from))
+            .bind(("to", to))
+            .await
+        {
+            Ok(mut result) => match result.take::<Vec<CoordinatesData>>(0) {
+                Ok(data) => Ok(data),
+                Err(error) => Err(format!("coordinates error: {:?}", error)),
+            },
+            Err(error) => Err(format!("coordinates error: {:?}", error)),
+        }
+    }
+
+    async fn area(
+        &self,
+        area: BoundingBox,
+        owner: Option<&RecordId>,
+        from: Datetime,
+        to: Datetime,
+    ) -> Result<Vec<CoordinatesData>, String> {
+        let owned = match owner {
+            Some(_) => " AND `device`.`owner`=$owner",
+            None => "",
+        };
+        match self
+            .db
+            .client()
+            .query(format!(
+                "SELECT * FROM coordinates WHERE `timestamp` >= $from AND `timestamp` <= $to \
+                 AND `latitude` >= $south AND `latitude` <= $north \
+                 AND `longitude` >= $west AND `longitude` <= $east{} ORDER BY `timestamp`",
+                owned
+            ))
+            .bind(("owner", owner.cloned()))
+            .bind(("from", from))
+            .bind(("to", to))
+            .bind(area)
+            .await
+        {
+            Ok(mut result) => match result.take::<Vec<CoordinatesData>>(0) {
+                Ok(data) => Ok(data),
+                Err(error) => Err(format!("coordinates error: {:?}", error)),
+            },
+            Err(error) => Err(format!("coordinates error: {:?}", error)),
+        }
+    }
+
+    async fn delete_device_history(&self, client_id: u32) -> Result<(), String> {
+        match self
+            .db
+            .client()
+            .query(
+                r#"
+                    DELETE FROM coordinates WHERE `device`.`client_id`=$client_id;
+                    DELETE FROM heartbeat WHERE `device`.`client_id`=$client_id;
+                    DELETE FROM sessions WHERE `device`.`client_id`=$client_id;
+                "#,
+            )
+            .bind(("client_id", client_id))
+            .await
+        {
+            Ok(response) => match response.check() {
+                Ok(_) => Ok(()),
+                Err(error) => Err(error.to_string()),
+            },
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    async fn prune_positions(
+        &self,
+        device: &RecordId,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String> {
+        self.prune("coordinates", device, before, limit).await
+    }
+
+    async fn downsample_positions(
+        &self,
+        device: &RecordId,
+        from: Datetime,
+        to: Datetime,
+        interval_secs: u64,
+        limit: usize,
+    ) -> Result<u64, String> {
+        let rows = self
+            .stamped("coordinates", device, Some(from), to, None)
+            .await?;
+        let ids = thinned(
+            rows.into_iter().map(|row| (row.id, row.timestamp)),
+            interval_secs,
+            limit,
+        );
+        self.delete_ids("coordinates", ids).await
+    }
+
+    async fn prune_heartbeats(
+        &self,
+        device: &RecordId,
+        before: Datetime,
+        limit: usize,
+    ) -> Result<u64, String> {
+        self.prune("heartbeat", device, before, limit).await
+    }
+
+    async fn create_session(&self, session: &SessionData) -> Result<SessionData, String> {
+        match self
+            .db
+            .client()
+            .query(
+                "CREATE sessions SET `device`=$device, `source_address`=$source_address, \
+                 `login`=$login, `logout`=$logout, `positions`=$positions, \
+                 `heartbeats`=$heartbeats",
+            )
+            .bind(("device", session.device.clone()))
+            .bind(("source_address", session.source_address.clone()))
+            .bind(("login", session.login.clone()))
+            .bind(("logout", session.logout.clone()))
+            .bind(("positions", session.positions))
+            .bind(("heartbeats", session.heartbeats))
+            .await
+        {
+            Ok(mut result) => match result.take::<Option<SessionData>>(0) {
+                Ok(Some(session)) => Ok(session),
+                Ok(None) => Err("session.create error: nothing created".to_string()),
+                Err(error) => Err(format!("session.create error: {:?}", error)),
+            },
+            Err(error) => Err(format!("session.create error: {:?}", error)),
+        }
+    }
+
+    async fn close_session(
+        &self,
+        device: &RecordId,
+        logout: Datetime,
+    ) -> Result<Option<SessionData>, String> {
+        match self
+            .db
+            .client()
+            .query(
+                r#"
+                    LET $open = (SELECT * FROM sessions WHERE `device`=$device AND `logout`=NONE);
+                    FOR $session IN $open {
+                        UPDATE $session.id SET `logout`=$logout,
+                            `positions`=count((SELECT VALUE `id` FROM coordinates
+                                WHERE `device`=$device AND `timestamp` >= $session.login
+                                AND `timestamp` <= $logout)),
+                            `heartbeats`=count((SELECT VALUE `id` FROM heartbeat
+                                WHERE `device`=$device AND `timestamp` >= $session.login
+                                AND `timestamp` <= $logout));
+                    };
+                    SELECT * FROM sessions WHERE `id` IN $open.id;
+                "#,
+            )
+            .bind(("device", device.clone()))
+            .bind(("logout", logout))
+            .await
+        {
+            Ok(mut result) => match result.take::<Vec<SessionData>>(2) {
+                Ok(closed) => Ok(closed.into_iter().next()),
+                Err(error) => Err(format!("session.close error: {:?}", error)),
+            },
+            Err(error) => Err(format!("session.close error: {:?}", error)),
+        }
+    }
+
+    async fn sessions(&self, device: &RecordId) -> Result<Vec<SessionData>, String> {
+        match self
+            .db
+            .client()
+            .query("SELECT * FROM sessions WHERE `device`=$device ORDER BY `login` DESC")
+            .bind(("device", device.clone()))
+            .await
+        {
+            Ok(mut result) => match result.take::<Vec<SessionData>>(0) {
+                Ok(data) => Ok(data),
+                Err(error) => Err(format!("session.get_sessions error: {:?}", error)),
+            },
+            Err(error) => Err(format!("session.get_sessions error: {:?}", error)),
+        }
+    }
+
+    async fn session_by_id(&self, id: &RecordId) -> Result<SessionData, String> {
+        match self
+            .db
+            .client()
+            .select::<Option<SessionData>>(id.clone())
+            .await
+        {
+            Ok(Some(session)) => Ok(session),
+            Ok(None) => Err("session not found".to_string()),
+            Err(error) => Err(format!("session.get_by_id error: {:?}", error)),
+        }
+    }
+
+    async fn save_session_event(&self, event: &SessionEvent) -> Result<(), String> {
+        let client = self.db.client();
+        if let Err(error) = client
+            .upsert::<Option<DeviceSession>>(("device_status", i64::from(event.session.client_id)))
+            .content(event.session.clone())
+            .await
+        {
+            return Err(format!("session error: {:?}", error));
+        }
+        match client
+            .insert::<Vec<SessionEvent>>("device_events")
+            .content(event.clone())
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("session error: {:?}", error)),
+        }
+    }
+
+    async fn device_statuses(&self) -> Result<Vec<DeviceSession>, String> {
+        match self
+            .db
+            .client()
+            .select::<Vec<DeviceSession>>("device_status")
+            .await
+        {
+            Ok(mut data) => {
+                data.sort_by_key(|device| device.client_id);
+                Ok(data)
+            }
+            Err(error) => Err(format!("session error: {:?}", error)),
+        }
+    }
+
+    async fn watch_positions(&self) -> Result<BoxStream<'static, CoordinatesData>, String> {
+        self.watch("coordinates").await
+    }
+
+    async fn watch_session_events(&self) -> Result<BoxStream<'static, SessionEvent>, String> {
+        self.watch("device_events").await
+    }
+}
+
+#[cfg(test)]
+mod test_surreal {
+    use super::*;
+    use crate::config::DatabaseEngine;
+    use crate::store::check_store;
+    use std::sync::Arc;
+
+    /// Opens a fresh database inside the test process.
+    async fn memory_store() -> SurrealStore {
+        let config = DatabaseConfig {
+            engine: DatabaseEngine::Memory,
+            ..DatabaseConfig::default()
+        };
+        SurrealStore::connect(&config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_embedded_memory() {
+        let store = memory_store().await;
+        store
+            .db()
+            .client()
+            .query(
+                "CREATE users:root SET name='Root', username='root', \
+                 password=crypto::argon2::generate('notsecurepassword'); \
+                 CREATE devices:root SET serial='root', name='Root', owner=users:root, \
+                 credentials=crypto::argon2::generate('notsecurepassword'), client_id=24564",
+            )
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+        let root = store.user_by_username("root").await.unwrap().unwrap();
+        let tracker = store.device_by_client_id(24564).await.unwrap();
+        check_store(Arc::new(store), &root, &tracker).await;
+
+        // Every store gets its own database.
+        assert!(memory_store().await.users().await.unwrap().is_empty());
+    }
+}
@@ -1,181 +1,276 @@
-use crate::actions::{Coordinates, Heartbeat, Login, Logout};
-use crate::config::Config;
+use crate::capture::{Direction, Recorder};
+use crate::context::AppContext;
+use crate::handler::{HandlerContext, HandlerRegistry};
+use crate::metrics::Metrics;
 use crate::payload::Payload;
-use crate::user::User;
+use crate::rate_limit::{RateLimitDecision, RateLimiter};
+use crate::response::{generate_slow_down_response, generate_status_response, ResponseType};
+use crate::validation::{RequestError, ValidationError};
 use crate::{RequestPacket, RequestType};
-use std::net::{SocketAddr, UdpSocket};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{debug, error, info, warn, Instrument};
 
 #[derive(Debug)]
-pub struct UdpServer;
+pub struct UdpServer {
+    context: AppContext,
+    rate_limiter: Arc<RateLimiter>,
+    registry: HandlerRegistry,
+    recorder: Option<Recorder>,
+}
 
 impl UdpServer {
+    /// Creates a server handling the built-in request types.
+    pub fn new(context: AppContext) -> Self {
+        Self::with_registry(context, HandlerRegistry::with_defaults())
+    }
+
+    /// Creates a server handling the request types in `registry`.
+    pub fn with_registry(context: AppContext, registry: HandlerRegistry) -> Self {
+        let rate_limiter = Arc::new(RateLimiter::new(&context.config.server.rate_limit));
+        Self {
+            context,
+            rate_limiter,
+            registry,
+            recorder: None,
+        }
+    }
+
+    /// Records every datagram received and sent through `recorder`.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub fn context(&self) -> &AppContext {
+        &self.context
+    }
+
+    /// Shared so that config reloads can change the limits while running.
+    pub fn rate_limiter(&self) -> &Arc<RateLimiter> {
+        &self.rate_limiter
+    }
+
+    /// Sends a response, returning the bytes sent.
     pub async fn respond(
         socket: &UdpSocket,
         source_address: SocketAddr,
         response_data: &str,
-    ) -> Result<(), String> {
+    ) -> Result<Vec<u8>, String> {
         let response_binary = Payload::to_binary(response_data)?;
-        println!("Binary Data: {:?}", response_binary);
-        if let Err(error) = socket.send_to(&response_binary, source_address) {
-            return Err(format!(
-                "unable to send login response, reason: {}",
-                error.to_string()
-            ));
+        debug!(bytes = response_binary.len(), "sending response");
+        if let Err(error) = socket.send_to(&response_binary, source_address).await {
+            return Err(format!("unable to send login response, reason: {}", error));
+        }
+        Ok(response_binary)
+    }
+
+    /// Sends a response and records it when capturing.
+    async fn reply(
+        &self,
+        socket: &UdpSocket,
+        source_address: SocketAddr,
+        response_data: &str,
+    ) -> Result<(), String> {
+        let response_binary = Self::respond(socket, source_address, response_data).await?;
+        if let Some(recorder) = &self.recorder {
+            recorder.record(Direction::Sent, source_address, &response_binary);
         }
         Ok(())
     }
-    pub async fn launch() -> Result<(), String> {
-        let config: Config = Config::load(None).await?;
-        let server_config = config.server;
-        println!("UDP Server: {}", server_config.host);
-        let socket = UdpSocket::bind(server_config.host).unwrap();
-        loop {
-            let mut buf = [0; 64];
-            let (size, source_address) = socket.recv_from(&mut buf).unwrap();
-            let filled = &mut buf[..size];
-            println!("Filled: {:?}", String::from_utf8(filled.to_vec()));
-            match RequestPacket::parse(filled) {
-                Ok(request_packet) => {
-                    println!("Request Packet: {:x?}", request_packet);
-                    match request_packet.request_type {
-                        RequestType::Login => {
-                            let login_data = Login::parse(
-                                request_packet.payload_length,
-                                &request_packet.payload,
-                            )
-                            .await?;
 
-                            println!("Login Data: {:?}", login_data);
-                            let response_data: String = match Login::authenticate(login_data).await
-                            {
-                                Ok(user_data) => {
-                                    Login::generate_response(user_data.client_id.to_string(), false)
-                                        .await?
-                                }
-                                Err(error) => {
-                                    eprintln!("{}", error);
-                                    Login::generate_response("0".to_string(), true).await?
-                                }
-                            };
-                            if let Err(error) =
-                                Self::respond(&socket, source_address, response_data.as_str()).await
-                            {
-                                eprint!("LOGIN RESPONSE ERROR: {}", error);
-                            }
+    /// Receives datagrams until shutdown is triggered, then drains the packets
+    /// already queued on the socket within the configured grace period.
+    pub async fn launch(&self) -> Result<(), String> {
+        let server_config = &self.context.config.server;
+        let shutdown = &self.context.shutdown;
+        info!(host = %server_config.host, "udp server listening");
+        let socket = match UdpSocket::bind(server_config.host.as_str()).await {
+            Ok(socket) => socket,
+            Err(error) => return Err(format!("unable to bind udp server, reason: {}", error)),
+        };
+        let mut buf = self.receive_buffer();
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                received = socket.recv_from(&mut buf) => match received {
+                    Ok((size, source_address)) => {
+                        if let Err(error) = self.handle(&socket, source_address, &buf[..size]).await {
+                            warn!(%source_address, %error, "request failed");
                         }
-                        RequestType::HeartBeat => {
-                            let heartbeat_data = Heartbeat::parse(
-                                source_address.to_string(),
-                                request_packet.payload_length,
-                                &request_packet.payload,
-                            )
-                            .await?;
+                    }
+                    Err(error) => error!(%error, "unable to receive datagram"),
+                },
+            }
+        }
 
-                            println!("Heartbeat Data: {:?}", heartbeat_data);
-                            let hb: Heartbeat = Heartbeat::new().await?;
-                            let response_data: String = match hb.create(heartbeat_data).await {
-                                Ok(data) => {
-                                    let user = User::new().await?;
-                                    match user.get_by_id(data.user).await {
-                                        Ok(user_data) => {
-                                            Heartbeat::generate_response(
-                                                user_data.client_id.to_string(),
-                                                false,
-                                            )
-                                            .await?
-                                        }
-                                        Err(error) => {
-                                            eprintln!("HEARTBEAT ERROR: {}", error);
-                                            Heartbeat::generate_response(
-                                                "000000000".to_string(),
-                                                true,
-                                            )
-                                            .await?
-                                        }
-                                    }
-                                }
-                                Err(error) => {
-                                    return Err(format!("{:?}", error));
-                                }
-                            };
-                            if let Err(error) =
-                                Self::respond(&socket, source_address, response_data.as_str()).await
-                            {
-                                eprint!("HEART BEAT RESPONSE ERROR: {}", error);
-                            }
-                        }
-                        RequestType::Logout => {
-                            let client_id = Logout::parse(
-                                request_packet.payload_length,
-                                &request_packet.payload,
-                            )
-                            .await?;
-                            println!("Logout Data: {:?}", client_id);
+        info!("udp server shutting down");
+        let grace_period = Duration::from_secs(server_config.shutdown_grace_period_secs);
+        match tokio::time::timeout(grace_period, self.drain(&socket)).await {
+            Ok(count) => info!(count, "drained queued packets"),
+            Err(_) => warn!(
+                ?grace_period,
+                "grace period elapsed before the queue was drained"
+            ),
+        }
+        Ok(())
+    }
 
-                            let logout = Logout::new().await?;
-                            let response_data = match logout.logout(client_id.clone()).await {
-                                Ok(_) => {
-                                    Logout::generate_response(client_id.to_string(), false).await?
-                                }
-                                Err(error) => {
-                                    eprintln!("{:?}", error);
-                                    Logout::generate_response(client_id.to_string(), true).await?
+    /// Allocates a buffer one byte larger than the maximum datagram size, so a
+    /// datagram that filled it completely is known to be oversized rather than
+    /// silently truncated.
+    fn receive_buffer(&self) -> Vec<u8> {
+        vec![0; self.context.config.server.max_datagram_size + 1]
+    }
+
+    /// Handles the datagrams still waiting in the socket buffer without
+    /// accepting new ones, returning how many were processed.
+    async fn drain(&self, socket: &UdpSocket) -> usize {
+        let mut buf = self.receive_buffer();
+        let mut count: usize = 0;
+        loop {
+            match socket.try_recv_from(&mut buf) {
+                Ok((size, source_address)) => {
+                    count += 1;
+                    if let Err(error) = self.handle(socket, source_address, &buf[..size]).await {
+                        warn!(%source_address, %error, "request failed");
+                    }
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return count,
+                Err(error) => {
+                    error!(%error, "unable to receive datagram");
+                    return count;
+                }
+            }
+        }
+    }
+
+    /// Handles a single datagram and records its metrics.
+    pub async fn handle(
+        &self,
+        socket: &UdpSocket,
+        source_address: SocketAddr,
+        filled: &[u8],
+    ) -> Result<(), String> {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(Direction::Received, source_address, filled);
+        }
+        let metrics = Metrics::global();
+        let request_type = match filled.first() {
+            Some(value) => self
+                .registry
+                .name(*value)
+                .unwrap_or(RequestType::Invalid.name()),
+            None => RequestType::Invalid.name(),
+        };
+        metrics.record_packet(request_type);
+        let timer = metrics
+            .request_duration
+            .with_label_values(&[request_type])
+            .start_timer();
+        let span = tracing::info_span!(
+            "request",
+            %source_address,
+            request_type,
+            client_id = tracing::field::Empty,
+        );
+        let result = self
+            .process(socket, source_address, filled)
+            .instrument(span)
+            .await;
+        timer.observe_duration();
+        if result.is_err() {
+            metrics.record_request_error(request_type);
+        }
+        result
+    }
+
+    /// Parses a single datagram, runs its action and sends the response.
+    async fn process(
+        &self,
+        socket: &UdpSocket,
+        source_address: SocketAddr,
+        filled: &[u8],
+    ) -> Result<(), String> {
+        let metrics = Metrics::global();
+        // Raw payloads may carry credentials, so only their size is logged.
+        debug!(bytes = filled.len(), "datagram received");
+        let request_type_value: u8 = filled.first().copied().unwrap_or_default();
+        if let RateLimitDecision::ThrottledBySource =
+            self.rate_limiter.check_source(source_address.ip())
+        {
+            warn!("rate limited by source address");
+            metrics.rate_limited.with_label_values(&["source"]).inc();
+            let response_data = generate_slow_down_response(request_type_value, "0".to_string());
+            return self
+                .reply(socket, source_address, response_data.as_str())
+                .await;
+        }
+        let max_datagram_size = self.context.config.server.max_datagram_size;
+        if filled.len() > max_datagram_size {
+            warn!(max_datagram_size, "datagram exceeds the maximum size");
+            metrics.record_validation_error(&ValidationError::DatagramTooLarge);
+            let response_data =
+                generate_status_response(request_type_value, ResponseType::Error, "0".to_string());
+            return self
+                .reply(socket, source_address, response_data.as_str())
+                .await;
+        }
+        match RequestPacket::parse(filled) {
+            Ok(request_packet) => {
+                debug!(
+                    payload_length = request_packet.payload_length,
+                    "request parsed"
+                );
+                if let Some(client_id) = self.registry.client_id(&request_packet) {
+                    tracing::Span::current().record("client_id", client_id);
+                    if let RateLimitDecision::ThrottledByClient =
+                        self.rate_limiter.check_client(client_id)
+                    {
+                        warn!("rate limited by client id");
+                        metrics.rate_limited.with_label_values(&["client"]).inc();
+                        let response_data =
+                            generate_slow_down_response(request_type_value, client_id.to_string());
+                        return self
+                            .reply(socket, source_address, response_data.as_str())
+                            .await;
+                    }
+                }
+                let ctx = HandlerContext {
+                    context: &self.context,
+                    source_address,
+                };
+                match self.registry.dispatch(&ctx, &request_packet).await {
+                    Some(response_data) => {
+                        let response_data = match response_data {
+                            Ok(response_data) => response_data,
+                            Err(error) => {
+                                if let RequestError::Invalid(validation_error) = &error {
+                                    metrics.record_validation_error(validation_error);
                                 }
-                            };
-                            if let Err(error) =
-                                Self::respond(&socket, source_address, response_data.as_str()).await
-                            {
-                                eprint!("LOGOUT RESPONSE ERROR: {}", error);
-                            }
-                        }
-                        RequestType::Coordinates => {
-                            let coordinates_data = Coordinates::parse(
-                                request_packet.payload_length,
-                                &request_packet.payload,
-                            )
-                            .await?;
-                            let coordinates = Coordinates::new().await?;
-                            let coordinates_data = coordinates.create(coordinates_data).await?;
-                            println!("Coordinates Data: {:?}", coordinates_data);
-                            let response_data: String =
-                                match coordinates.create(coordinates_data).await {
-                                    Ok(data) => {
-                                        let user = User::new().await?;
-                                        match user.get_by_id(data.user).await {
-                                            Ok(user_data) => {
-                                                Coordinates::generate_response(
-                                                    user_data.client_id.to_string(),
-                                                    false,
-                                                )
-                                                .await?
-                                            }
-                                            Err(error) => {
-                                                eprintln!("COORDINATES ERROR: {}", error);
-                                                Coordinates::generate_response(
-                                                    "000000000".to_string(),
-                                                    true,
-                                                )
-                                                .await?
-                                            }
-                                        }
-                                    }
-                                    Err(error) => {
-                                        return Err(format!("{:?}", error));
-                                    }
-                                };
-                            if let Err(error) =
-                                Self::respond(&socket, source_address, response_data.as_str()).await
-                            {
-                                eprint!("HEART BEAT RESPONSE ERROR: {}", error);
+                                return Err(error.to_string());
                             }
+                        };
+                        if let Err(error) = self
+                            .reply(socket, source_address, response_data.as_str())
+                            .await
+                        {
+                            error!(%error, "unable to send response");
                         }
-                        _ => {
-                            eprint!("Invalid Request Type");
-                        }
+                    }
+                    None => {
+                        warn!("invalid request type");
                     }
                 }
-                Err(error) => eprint!("{:?}", error),
+            }
+            Err(error) => {
+                metrics.record_validation_error(&error);
+                warn!(%error, "invalid request packet")
             }
         }
+        Ok(())
     }
 }
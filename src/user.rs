@@ -1,132 +1,121 @@
 use serde::{ Serialize, Deserialize};
 use surrealdb::RecordId;
-use crate::db::Db;
+use crate::logging::Redacted;
+use crate::metrics::Metrics;
+use crate::password;
+use crate::store::SharedStore;
 
-#[derive(Debug,Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct UserData {
     pub id: Option<RecordId>,
     pub name: String,
     pub username: String,
+    /// Argon2id hash of the password. Never serialized, so no API can leak it.
+    #[serde(skip_serializing)]
     pub password: String,
-    pub client_id: u32,
+    /// Disabled users, and their devices, cannot log in.
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl std::fmt::Debug for UserData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserData")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("username", &self.username)
+            .field("password", &Redacted(&self.password))
+            .field("disabled", &self.disabled)
+            .finish()
+    }
 }
 
 #[derive(Debug)]
 pub struct User {
-    db: Db
+    store: SharedStore
 }
 
 impl User {
 
-    pub async fn new() -> Result<Self,String>{
-        let db = Db::connect().await?;
-        Ok(Self {
-            db
-        })
+    pub fn new(store: SharedStore) -> Self {
+        Self {
+            store
+        }
     }
 
-    fn get_table(&self) -> String {
-        String::from("users")
+    pub async fn get_users(&self) -> Result<Vec<UserData>,String> {
+        let _timer = Metrics::global().start_db_timer("users.get_users");
+        self.store.users().await
     }
 
+    pub async fn get_by_id(&self,id: RecordId) -> Result<UserData,String> {
+        let _timer = Metrics::global().start_db_timer("users.get_by_id");
+        self.store.user_by_id(&id).await
+    }
 
-    pub async fn get_users(&self) -> Result<Vec<UserData>,String> {
-        
-        match self.db.client.query("SELECT `id`,`client_id`,`name`,`username`,`password` FROM type::table($table)")
-            .bind(("table",self.get_table())).await {
-
-            Ok(mut result) => {
-
-                match result.take::<Vec<UserData>>(0) {
-                    Ok(data)=> {
-                        
-                        Ok(data)
-                    }
-                    Err(error) => {
-                        Err(format!("user.get_id error: {:?}",error))    
-                    }
-                }
-                
-            }
-            Err(error) => {            
-                Err(format!("user.get_by_client_id  error: {:?}",error))    
-            }
+    pub async fn get_by_username_and_password(&self,username: &str, password: &str ) -> Result<UserData,String> {
+        let _timer = Metrics::global().start_db_timer("users.get_by_username_and_password");
+        let data = self.store.user_by_username(username).await?;
+        let stored = data.as_ref().map(|data| data.password.clone());
+        match (data, password::verify_async(password, stored).await?) {
+            (Some(data), true) if data.disabled => Err("user is disabled".to_string()),
+            (Some(data), true) => Ok(data),
+            _ => Err("invalid username or password".to_string()),
         }
     }
-    pub async fn get_by_id(&self,id: RecordId) -> Result<UserData,String> {
-        
-        match self.db.client.query("SELECT `id`,`client_id`,`name`,`username`,`password` FROM type::table($table) WHERE `id`=$id")
-            .bind(("table",self.get_table()))
-            .bind(("id",id)).await {
-            Ok(mut result) => {
-
-                match result.take::<Option<UserData>>(0) {
-                    Ok(record)=> {
-                        if record.is_none() {
-                            return Err("user not found".to_string());
-                        }
-                        Ok(record.unwrap())
-                    }
-                    Err(error) => {
-                        Err(format!("user.get_id error: {:?}",error))    
-                    }
-                }
-                
-            }
-            Err(error) => {            
-                Err(format!("user.get_by_client_id  error: {:?}",error))    
-            }
+
+    pub async fn get_by_username(&self,username: &str) -> Result<UserData,String> {
+        let _timer = Metrics::global().start_db_timer("users.get_by_username");
+        match self.store.user_by_username(username).await? {
+            Some(data) => Ok(data),
+            None => Err(format!("user {} not found", username)),
         }
     }
 
+    /// Adds a user, hashing `password`.
+    pub async fn create(&self,name: &str, username: &str, password: &str) -> Result<UserData,String> {
+        let hash = password::hash_async(password).await?;
+        self.create_hashed(name, username, &hash).await
+    }
 
-    pub async fn get_by_client_id(&self,client_id: u32) -> Result<UserData,String> {
-        
-        match self.db.client.query("SELECT `id`,`client_id`,`name`,`username`,`password` FROM type::table($table) WHERE `client_id`=$client_id")
-            .bind(("table",self.get_table()))
-            .bind(("client_id",client_id)).await {
-            Ok(mut result) => {
-
-                match result.take::<Option<UserData>>(0) {
-                    Ok(record)=> {
-                        if record.is_none() {
-                            return Err("user not found".to_string());
-                        }
-                        Ok(record.unwrap())
-                    }
-                    Err(error) => {
-                        Err(format!("user.get_by_client_id error: {:?}",error))    
-                    }
-                }
-                
-            }
-            Err(error) => {            
-                Err(format!("user.get_by_client_id  error: {:?}",error))    
-            }
+    /// Adds a user whose password is already an Argon2 hash, e.g. one
+    /// exported from another installation.
+    pub async fn create_hashed(&self,name: &str, username: &str, hash: &str) -> Result<UserData,String> {
+        let _timer = Metrics::global().start_db_timer("users.create");
+        if !password::is_hashed(hash) {
+            return Err(format!("user {} has no password hash", username));
         }
+        let data = UserData {
+            id: None,
+            name: name.to_string(),
+            username: username.to_string(),
+            password: hash.to_string(),
+            disabled: false,
+        };
+        self.store.create_user(&data).await
     }
 
-    pub async fn get_by_username_and_password(&self,username: &str, password: &str ) -> Result<UserData,String> {
-         match self.db.client.query("SELECT `id`,`client_id`,`name`,`username`,`password` FROM type::table($table) WHERE `username`=$username AND `password`=$password")
-            .bind(("table",self.get_table()))
-            .bind(("username",username.to_string()))
-            .bind(("password",password.to_string())).await {
-            Ok(mut result) => {
-                match result.take::<Option<UserData>>(0) {
-                    Ok(record)=> {
-                        if record.is_none() {
-                            return Err("user not found".to_string());
-                        }
-                        Ok(record.unwrap())
-                    }
-                    Err(error) => {
-                        Err(format!("user.get_by_username_and_password error: {:?}",error))    
-                    }
-                } 
-            }
-            Err(error) => {            
-                Err(format!("user.get_by_username_and_password error: {:?}",error))    
-            }
+    pub async fn set_password(&self,username: &str, password: &str) -> Result<UserData,String> {
+        let mut data = self.get_by_username(username).await?;
+        data.password = password::hash_async(password).await?;
+        self.store.update_user(&data).await?;
+        Ok(data)
+    }
+
+    pub async fn set_disabled(&self,username: &str, disabled: bool) -> Result<UserData,String> {
+        let mut data = self.get_by_username(username).await?;
+        data.disabled = disabled;
+        self.store.update_user(&data).await?;
+        Ok(data)
+    }
+
+    /// Deletes a user along with its devices and their positions, heartbeats
+    /// and sessions.
+    pub async fn delete(&self,username: &str) -> Result<(),String> {
+        let data = self.get_by_username(username).await?;
+        match data.id {
+            Some(id) => self.store.delete_user(&id).await,
+            None => Err(format!("user {} has no id", username)),
         }
     }
 }
@@ -135,22 +124,70 @@ impl User {
 #[cfg(test)]
 mod test_user {
     use super::*;
+    use crate::store::{add_device, add_user, MemoryStore};
+    use std::sync::Arc;
 
     #[tokio::test]
     async fn test_get_user_by_username_and_password()  {
 
-        let user = User::new().await;
-        assert!(user.is_ok(),"{:?}",user.err());
-
-        let user = user.unwrap();
+        let store = MemoryStore::new();
+        add_user(&store, "Root", "root", "notsecurepassword").await.unwrap();
+        let user = User::new(Arc::new(store));
 
         let data = user.get_by_username_and_password("root", "notsecurepassword").await;
         assert!(data.is_ok(),"{:?}",data.err());
 
-        let data = user.get_by_client_id(data.unwrap().client_id).await;
+        let data = user.get_by_id(data.unwrap().id.unwrap()).await;
         assert!(data.is_ok(),"{:?}",data.err());
         println!("{:#?}",data);
 
+        let data = user.get_by_username_and_password("root", "wrongpassword").await;
+        assert!(data.is_err());
+
+        let data = user.get_by_username_and_password("nobody", "notsecurepassword").await;
+        assert_eq!(data.err(), Some("invalid username or password".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_manage_users()  {
+
+        let store = MemoryStore::new();
+        let root = add_user(&store, "Root", "root", "notsecurepassword").await.unwrap();
+        add_device(&store, &root, "root", "notsecurepassword", 24564).await.unwrap();
+        let store: SharedStore = Arc::new(store);
+        let user = User::new(store.clone());
+
+        user.create("Van", "van", "vanpassword").await.unwrap();
+        assert!(user.create("Copy", "van", "x").await.is_err());
+
+        assert!(user.get_by_username_and_password("van", "vanpassword").await.is_ok());
+        user.set_password("van", "newpassword").await.unwrap();
+        assert!(user.get_by_username_and_password("van", "vanpassword").await.is_err());
+        assert!(user.get_by_username_and_password("van", "newpassword").await.is_ok());
+
+        user.set_disabled("van", true).await.unwrap();
+        assert!(user.get_by_username_and_password("van", "newpassword").await.is_err());
+        user.set_disabled("van", false).await.unwrap();
+        assert!(user.get_by_username_and_password("van", "newpassword").await.is_ok());
+
+        user.delete("van").await.unwrap();
+        assert!(user.get_by_username("van").await.is_err());
+        assert!(user.delete("van").await.is_err());
+
+        // Deleting a user deletes its devices.
+        user.delete("root").await.unwrap();
+        assert!(store.device_by_client_id(24564).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_password_is_never_serialized()  {
+
+        let store = MemoryStore::new();
+        let root = add_user(&store, "Root", "root", "notsecurepassword").await.unwrap();
+        assert!(crate::password::is_hashed(&root.password));
 
+        let json = serde_json::to_string(&root).unwrap();
+        assert!(!json.contains("password"),"{}",json);
+        assert!(!json.contains("argon2"),"{}",json);
     }
 }
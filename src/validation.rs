@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ValidationError {
     InvalidLogin,
     InvalidLoginPayload,
@@ -15,13 +15,57 @@ pub enum ValidationError {
     InvalidRequestPacketPayloadLength,
     InvalidRequestPacketPayload,
     UnableToParseRequestPayloadLength,
-    InvalidUserId,
+    InvalidDeviceId,
+    DatagramTooLarge,
 }
 
 impl ValidationError {
+    pub const ALL: [ValidationError; 17] = [
+        Self::InvalidLogin,
+        Self::InvalidLoginPayload,
+        Self::InvalidClientId,
+        Self::InvalidLogoutPayload,
+        Self::InvalidHeartbeatPayload,
+        Self::InvalidCoordinatesPayload,
+        Self::InvalidLatitude,
+        Self::InvalidLongitude,
+        Self::ClientIdEmpty,
+        Self::UnableToParseLatitude,
+        Self::UnableToParseLongitude,
+        Self::InvalidRequestPacket,
+        Self::InvalidRequestPacketPayloadLength,
+        Self::InvalidRequestPacketPayload,
+        Self::UnableToParseRequestPayloadLength,
+        Self::InvalidDeviceId,
+        Self::DatagramTooLarge,
+    ];
+
     pub fn to_hex() -> String {
         todo!("not implemented");
     }
+
+    /// Returns the variant name, used as a metrics label.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::InvalidLogin => "InvalidLogin",
+            Self::InvalidLoginPayload => "InvalidLoginPayload",
+            Self::InvalidClientId => "InvalidClientId",
+            Self::InvalidLogoutPayload => "InvalidLogoutPayload",
+            Self::InvalidHeartbeatPayload => "InvalidHeartbeatPayload",
+            Self::InvalidCoordinatesPayload => "InvalidCoordinatesPayload",
+            Self::InvalidLatitude => "InvalidLatitude",
+            Self::InvalidLongitude => "InvalidLongitude",
+            Self::ClientIdEmpty => "ClientIdEmpty",
+            Self::UnableToParseLatitude => "UnableToParseLatitude",
+            Self::UnableToParseLongitude => "UnableToParseLongitude",
+            Self::InvalidRequestPacket => "InvalidRequestPacket",
+            Self::InvalidRequestPacketPayloadLength => "InvalidRequestPacketPayloadLength",
+            Self::InvalidRequestPacketPayload => "InvalidRequestPacketPayload",
+            Self::UnableToParseRequestPayloadLength => "UnableToParseRequestPayloadLength",
+            Self::InvalidDeviceId => "InvalidDeviceId",
+            Self::DatagramTooLarge => "DatagramTooLarge",
+        }
+    }
 }
 impl std::fmt::Display for ValidationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -45,8 +89,64 @@ impl std::fmt::Display for ValidationError {
             ValidationError::UnableToParseRequestPayloadLength => {
                 "Unable to parse request payload length"
             }
-            Self::InvalidUserId => "Invalid UserId",
+            Self::InvalidDeviceId => "Invalid DeviceId",
+            Self::DatagramTooLarge => "Datagram exceeds the maximum size",
         };
         write!(f, "{}", message)
     }
 }
+
+/// Why a request was not answered: a malformed packet, counted by its
+/// variant in the metrics, or anything else such as a store error.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestError {
+    Invalid(ValidationError),
+    Failed(String),
+}
+
+impl From<ValidationError> for RequestError {
+    fn from(error: ValidationError) -> Self {
+        Self::Invalid(error)
+    }
+}
+
+impl From<String> for RequestError {
+    fn from(error: String) -> Self {
+        Self::Failed(error)
+    }
+}
+
+impl From<RequestError> for String {
+    fn from(error: RequestError) -> Self {
+        error.to_string()
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(error) => write!(f, "{}", error),
+            Self::Failed(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_validation {
+    use super::*;
+
+    #[test]
+    fn test_request_error() {
+        let error: RequestError = ValidationError::InvalidClientId.into();
+        assert_eq!(
+            error,
+            RequestError::Invalid(ValidationError::InvalidClientId)
+        );
+        assert_eq!(String::from(error), "Invalid client ID");
+        let error: RequestError = "store error: unavailable".to_string().into();
+        assert_eq!(
+            error,
+            RequestError::Failed("store error: unavailable".to_string())
+        );
+    }
+}
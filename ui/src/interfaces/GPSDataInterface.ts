@@ -1,5 +1,5 @@
 export interface CoordinatesData {
-  user: string;
+  device_id: string;
   lon: number;
   lat: number;
 }